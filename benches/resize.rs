@@ -0,0 +1,37 @@
+// Compares resizing a 12 MP photo with the image crate to the `fast-resize` feature, run with
+// `cargo bench --features fast-resize`
+
+use std::time::{Duration, Instant};
+
+use image::{imageops::FilterType, DynamicImage, Rgb, RgbImage};
+
+const ITERATIONS: u32 = 5;
+
+/// The default --max-cached-image-size among sizes commonly added with --cache-sizes
+const SIZES: [u32; 3] = [2000, 1000, 400];
+
+fn main() {
+    let photo = DynamicImage::ImageRgb8(RgbImage::from_fn(4000, 3000, |x, y| {
+        Rgb([(x % 256) as u8, (y % 256) as u8, ((x ^ y) % 256) as u8])
+    }));
+    for size in SIZES {
+        let image_crate = time(|| photo.resize(size, size, FilterType::Lanczos3));
+        let fast = time(|| moments::resize_lanczos3(&photo, size).unwrap());
+        println!(
+            "4000x3000 to {size}: image crate {image_crate:?}, fast-resize {fast:?}, \
+             {:.1}x as fast",
+            image_crate.as_secs_f64() / fast.as_secs_f64()
+        );
+    }
+}
+
+/// Mean duration of `resize` over [`ITERATIONS`] runs
+fn time(resize: impl Fn() -> DynamicImage) -> Duration {
+    // once to warm up caches and the thread pool
+    std::hint::black_box(resize());
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        std::hint::black_box(resize());
+    }
+    start.elapsed() / ITERATIONS
+}
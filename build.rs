@@ -0,0 +1,90 @@
+use std::{
+    env,
+    fs::{canonicalize, read_dir, read_to_string, write},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+fn main() {
+    embed_frontend();
+    embed_build_info();
+}
+
+/// Generates the table of frontend files embedded into the binary, sorted by path
+fn embed_frontend() {
+    let frontend = Path::new("frontend");
+    println!("cargo:rerun-if-changed={}", frontend.display());
+    let mut files = Vec::new();
+    collect_files(frontend, &mut files);
+    let mut entries: Vec<_> = files
+        .into_iter()
+        .map(|file| {
+            let relative = file
+                .strip_prefix(frontend)
+                .unwrap()
+                .components()
+                .map(|component| component.as_os_str().to_str().unwrap())
+                .collect::<Vec<_>>()
+                .join("/");
+            (relative, canonicalize(&file).unwrap())
+        })
+        .collect();
+    entries.sort();
+    let mut table = String::from("&[\n");
+    for (relative, absolute) in entries {
+        table.push_str(&format!(
+            "    ({relative:?}, include_bytes!({absolute:?})),\n"
+        ));
+    }
+    table.push_str("]\n");
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    write(out_dir.join("frontend.rs"), table).unwrap();
+}
+
+/// Writes the build time and enabled features to `built.rs` and passes the git commit as
+/// environment variable read by `env!`, reproducible builds may fix the time with
+/// `SOURCE_DATE_EPOCH`
+///
+/// The commit is asked from git because the `git2` feature of `built` is not worth building
+/// libgit2 for.
+fn embed_build_info() {
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+    if let Some(reference) = read_to_string(".git/HEAD")
+        .ok()
+        .and_then(|head| Some(head.strip_prefix("ref: ")?.trim().to_string()))
+    {
+        println!("cargo:rerun-if-changed=.git/{reference}");
+    }
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    built::write_built_file().unwrap();
+
+    // builds from a source archive like in the Dockerfile have no repository
+    let commit = git(&["rev-parse", "--short=12", "HEAD"]).map_or_else(
+        || "unknown".to_string(),
+        |commit| match git(&["status", "--porcelain", "--untracked-files=no"]) {
+            Some(changes) if !changes.is_empty() => format!("{commit}-dirty"),
+            _ => commit,
+        },
+    );
+    println!("cargo:rustc-env=MOMENTS_GIT_COMMIT={commit}");
+}
+
+fn git(arguments: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(arguments).output().ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn collect_files(directory: &Path, files: &mut Vec<PathBuf>) {
+    for entry in read_dir(directory).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            collect_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
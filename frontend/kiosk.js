@@ -1,4 +1,9 @@
-if (!window.location.hash) {
+// set in galleries written by `moments export-static`, which have no server behind them
+const staticIndex = document.querySelector(
+  'meta[name="moments-static-index"]',
+)?.content;
+
+if (!staticIndex && !window.location.hash) {
   const newHash = prompt("Please enter the event's secret");
   if (newHash !== null) {
     window.location.hash = `#${newHash}`;
@@ -15,9 +20,37 @@ const options = {
   easing: "cubic-bezier(0.65, 0.05, 0.36, 1)",
   amountOfRows: 5,
   stopIteration: false,
+  recentLimit: 500, // only the newest images are requested initially, later additions still arrive
   secret: window.location.hash.substring(1),
+  reconnectDelay: 2000,
+  secretInPath: false, // for servers started with --secret-in-path that predate tokens
 };
 
+// stays the same across reloads, so the server keeps this kiosk's turn and regions for highlights
+function kioskId() {
+  let id = localStorage.getItem("moments-kiosk-id");
+  if (id === null) {
+    // crypto.randomUUID() is missing without HTTPS
+    id = Array.from(crypto.getRandomValues(new Uint8Array(8)), (byte) =>
+      byte.toString(16).padStart(2, "0"),
+    ).join("");
+    localStorage.setItem("moments-kiosk-id", id);
+  }
+  return id;
+}
+
+function authenticatedUrl(path) {
+  if (staticIndex) {
+    return new URL(`./${path}`, window.location);
+  }
+  if (options.secretInPath) {
+    return new URL(`./${options.secret}/${path}`, window.location);
+  }
+  const url = new URL(`./${path}`, window.location);
+  url.searchParams.set("token", options.secret);
+  return url;
+}
+
 class AwaitableCondition {
   constructor(conditionPredicate) {
     this.conditionPredicate = conditionPredicate;
@@ -77,33 +110,202 @@ class Recommender {
     this.notYetShown = new Bucket();
     this.alreadyShown = new Bucket();
     this.currentlyShowing = new Bucket();
+    // images removed while on screen must not return to the rotation
+    this.removed = new Set();
+    // cached paths by hash, updates of an image may move it to another path
+    this.paths = new Map();
+    // highlights the server scheduled for this kiosk, in order
+    this.highlights = [];
+    this.highlightArrived = () => {};
+    // duration of the last highlight received, the kiosk waits for the server's as long as
+    // they keep coming
+    this.highlightDuration = null;
+    // images of snapshot parts received so far
+    this.snapshot = [];
     this.imagesAvailable = new AwaitableCondition(
       () => this.notYetShown.length > 0 || this.alreadyShown.length > 0,
     );
 
-    this.webSocket = new WebSocket(url);
+    // revision of the last complete snapshot or change, reconnects resume from it
+    this.revision = null;
+    // compressed messages are decoded asynchronously but must be handled in order
+    this.decoded = Promise.resolve();
+    if (staticIndex) {
+      this.#load(url);
+    } else {
+      this.#connect(url);
+    }
+  }
+  async #load(url) {
+    try {
+      const response = await fetch(url);
+      this.#handleSnapshot(await response.json());
+    } catch (error) {
+      document.body.style.setProperty("background-color", "red");
+      console.error(error);
+    }
+  }
+  #connect(url) {
+    const connectionUrl = new URL(url);
+    if (this.revision !== null) {
+      connectionUrl.searchParams.set("since", this.revision);
+    }
+    this.webSocket = new WebSocket(connectionUrl);
+    this.webSocket.addEventListener("open", () => {
+      document.body.style.removeProperty("background-color");
+      // takes part in the highlights of servers started with --highlight-interval
+      this.webSocket.send(
+        JSON.stringify({ type: "hello", role: "kiosk", id: kioskId() }),
+      );
+    });
     this.webSocket.addEventListener("close", () => {
       document.body.style.setProperty("background-color", "red");
-      alert("Server connection disconnected, please reload");
+      this.snapshot = [];
+      setTimeout(() => this.#connect(url), options.reconnectDelay);
     });
     this.webSocket.addEventListener("message", (event) => {
-      this.#handleMessage(JSON.parse(event.data));
+      const text =
+        typeof event.data === "string"
+          ? event.data
+          : new Response(
+              event.data.stream().pipeThrough(new DecompressionStream("gzip")),
+            ).text();
+      this.decoded = this.decoded
+        .then(() => text)
+        .then((text) => this.#handleMessage(JSON.parse(text)));
     });
   }
   #handleMessage(message) {
     console.log("message", message);
-    if (Array.isArray(message)) {
-      for (const image of message) {
-        this.alreadyShown.add(image.path);
+    if (message.type === "snapshot") {
+      this.snapshot.push(...message.images);
+    } else if (message.type === "snapshot_complete") {
+      this.#handleSnapshot(this.snapshot);
+      this.snapshot = [];
+      this.revision = message.revision;
+    } else if (message.type === "change") {
+      this.#handleChange(message.change);
+      this.revision = message.revision;
+    } else if (message.type === "changes") {
+      for (const item of message.items) {
+        this.#handleChange(item.change);
+        this.revision = item.revision;
+      }
+    } else if (message.type === "highlight") {
+      this.highlights.push(message);
+      this.highlightDuration = message.duration_ms;
+      this.highlightArrived();
+    } else if (message.type === "upload_code") {
+      // only sent to kiosk and admin secrets of servers started with --upload-code-period
+      showUploadCode(message.code, new Date(message.valid_until));
+    } else {
+      console.error(`Unexpected message ${message}`);
+    }
+  }
+  #handleSnapshot(images) {
+    // a resync snapshot replaces everything received before
+    const paths = new Set(images.map((image) => image.cached_path));
+    this.paths = new Map(
+      images.map((image) => [image.hash, image.cached_path]),
+    );
+    for (const bucket of [this.notYetShown, this.alreadyShown]) {
+      for (const path of Object.keys(bucket.items)) {
+        if (!paths.has(path)) {
+          bucket.delete(path);
+          this.removed.add(path);
+        }
+      }
+    }
+    for (const path of paths) {
+      this.removed.delete(path);
+      if (
+        !this.notYetShown.contains(path) &&
+        !this.currentlyShowing.contains(path)
+      ) {
+        this.alreadyShown.add(path);
         this.imagesAvailable.notifyOne();
       }
-    } else if (typeof message.Addition === "object") {
-      this.notYetShown.add(message.Addition.image.path);
+    }
+  }
+  #handleChange(change) {
+    if (typeof change.Addition === "object") {
+      const image = change.Addition.image;
+      this.paths.set(image.hash, image.cached_path);
+      this.removed.delete(image.cached_path);
+      this.notYetShown.add(image.cached_path);
+      this.imagesAvailable.notifyOne();
+    } else if (typeof change.Removal === "object") {
+      const image = change.Removal.image;
+      this.paths.delete(image.hash);
+      this.#remove(image.cached_path);
+    } else if (typeof change.Update === "object") {
+      // pins and reactions leave the rotation as it is, but when another path of the image
+      // became canonical, its derivative moved with it
+      const image = change.Update.image;
+      const previousPath = this.paths.get(image.hash);
+      this.paths.set(image.hash, image.cached_path);
+      if (previousPath === undefined || previousPath === image.cached_path) {
+        return;
+      }
+      const notYetShown = this.notYetShown.contains(previousPath);
+      this.#remove(previousPath);
+      this.removed.delete(image.cached_path);
+      (notYetShown ? this.notYetShown : this.alreadyShown).add(
+        image.cached_path,
+      );
       this.imagesAvailable.notifyOne();
     } else {
-      console.error(`Unexpected message ${message}`);
+      console.error(`Unexpected change ${change}`);
+    }
+  }
+  #remove(path) {
+    this.notYetShown.delete(path);
+    this.alreadyShown.delete(path);
+    this.removed.add(path);
+  }
+  // the next highlight scheduled by the server, undefined if it sends none, then the kiosk
+  // picks its own
+  async nextHighlight() {
+    if (this.highlights.length === 0 && this.highlightDuration !== null) {
+      await new Promise((resolve) => {
+        this.highlightArrived = resolve;
+        // the server stopped highlighting, e.g. after a reload set the interval to 0
+        setTimeout(resolve, 2 * this.highlightDuration);
+      });
+      this.highlightArrived = () => {};
+      if (this.highlights.length === 0) {
+        this.highlightDuration = null;
+      }
+    }
+    // highlights that ended while this kiosk was busy are skipped
+    while (this.highlights.length > 0) {
+      const highlight = this.highlights.shift();
+      const endsAt =
+        new Date(highlight.starts_at).getTime() + highlight.duration_ms;
+      if (endsAt > Date.now()) {
+        return highlight;
+      }
+    }
+  }
+  confirmHighlight(highlight) {
+    if (this.webSocket?.readyState === WebSocket.OPEN) {
+      this.webSocket.send(
+        JSON.stringify({ type: "highlight_shown", id: highlight.id }),
+      );
     }
   }
+  // takes the image at `path` out of the rotation for showing it, undefined while it is
+  // already on screen
+  take(path) {
+    if (this.currentlyShowing.contains(path)) {
+      return undefined;
+    }
+    this.notYetShown.delete(path);
+    this.alreadyShown.delete(path);
+    this.removed.delete(path);
+    this.currentlyShowing.add(path);
+    return { path };
+  }
   async prolaag() {
     await this.imagesAvailable.wait();
 
@@ -127,21 +329,23 @@ class Recommender {
   }
   verhoog(image) {
     this.currentlyShowing.delete(image.path);
+    if (this.removed.has(image.path)) {
+      return;
+    }
     this.alreadyShown.add(image.path);
     this.imagesAvailable.notifyOne();
   }
 }
 
 (async () => {
-  if (!options.secret) {
+  if (!options.secret && !staticIndex) {
     document.body.style.setProperty("background-color", "red");
     throw { message: "No secret provided" };
   }
 
-  const recommenderUrl = new URL(`./${options.secret}/index`, window.location);
-  recommenderUrl.protocol =
-    recommenderUrl.protocol === "http:" ? "ws:" : "wss:";
-  const recommender = new Recommender(recommenderUrl);
+  const recommender = new Recommender(
+    staticIndex ? authenticatedUrl(staticIndex) : indexUrl(),
+  );
   await recommender.imagesReceived;
   const rows = Array.from({ length: options.amountOfRows }, () => {
     const row = document.body.appendChild(document.createElement("div"));
@@ -164,6 +368,36 @@ class Recommender {
   }
 })();
 
+let uploadCodeExpiry = null;
+
+function showUploadCode(code, validUntil) {
+  let element = document.getElementById("upload-code");
+  if (element === null) {
+    element = document.body.appendChild(document.createElement("div"));
+    element.id = "upload-code";
+  }
+  element.textContent = `Upload code ${code}`;
+  // an expired code must not stay on screen, e.g. while reconnecting, the next one replaces it
+  // right when it expires
+  clearTimeout(uploadCodeExpiry);
+  uploadCodeExpiry = setTimeout(
+    () => element.remove(),
+    Math.max(validUntil - Date.now(), 0) + options.reconnectDelay,
+  );
+}
+
+function indexUrl() {
+  const url = authenticatedUrl("index");
+  url.protocol = url.protocol === "http:" ? "ws:" : "wss:";
+  url.searchParams.set("recent_limit", options.recentLimit);
+  url.searchParams.set("protocol", 2);
+  if ("DecompressionStream" in window) {
+    // only honored by servers started with --websocket-compression
+    url.searchParams.set("compression", "gzip");
+  }
+  return url;
+}
+
 async function addImagesUntilScreenIsFull(options, rows, recommender) {
   for (const selectedRow of rows) {
     while (true) {
@@ -202,7 +436,20 @@ async function addImage(options, rows, recommender) {
     };
   }
 
-  const selectedRow = rows[Math.floor(Math.random() * rows.length)];
+  const highlight = await recommender.nextHighlight();
+  let selectedRow = rows[Math.floor(Math.random() * rows.length)];
+  let highlightDuration = options.highlightDuration;
+  if (highlight !== undefined) {
+    // the row through the middle of the region the server suggested
+    const { y, height } = highlight.region;
+    const row = Math.floor((y + height / 2) * rows.length);
+    selectedRow = rows[Math.min(row, rows.length - 1)];
+    highlightDuration = Math.max(
+      highlight.duration_ms - options.popUpDuration - options.popDownDuration,
+      0,
+    );
+    await sleep(new Date(highlight.starts_at) - Date.now());
+  }
   const imagesInRow = Array.from(selectedRow.querySelectorAll("img"));
 
   const image = await loadAndInsertImage(
@@ -210,10 +457,14 @@ async function addImage(options, rows, recommender) {
     selectedRow,
     imagesInRow,
     recommender,
+    highlight,
   );
   const width = (20 / image.naturalHeight) * image.naturalWidth;
   await animatePopUp(options, image, width);
-  await sleep(options.highlightDuration);
+  if (highlight !== undefined && image.dataset.highlight !== undefined) {
+    recommender.confirmHighlight(highlight);
+  }
+  await sleep(highlightDuration);
   await Promise.all([
     animatePopDown(options, image, width),
     removeOutOfViewportImages(options, selectedRow, recommender),
@@ -226,6 +477,7 @@ async function loadAndInsertImage(
   selectedRow,
   imagesInRow,
   recommender,
+  highlight,
 ) {
   let image = null;
   if (imagesInRow.length > 0) {
@@ -265,7 +517,14 @@ async function loadAndInsertImage(
   image.style.setProperty("width", "0");
   image.style.setProperty("z-index", "1");
 
-  const recommendedImage = await recommender.prolaag();
+  // a highlight of an image already on screen is left out, the server sends the next one
+  let recommendedImage =
+    highlight && recommender.take(highlight.image.cached_path);
+  if (recommendedImage) {
+    image.dataset.highlight = highlight.id;
+  } else {
+    recommendedImage = await recommender.prolaag();
+  }
   image.setAttribute("data-metadata", JSON.stringify(recommendedImage));
 
   await new Promise((resolve, reject) => {
@@ -278,10 +537,7 @@ async function loadAndInsertImage(
       reject(error);
     });
     // console.log(recommendedImage);
-    image.src = new URL(
-      `./${options.secret}/images/${recommendedImage.path}`,
-      window.location,
-    );
+    image.src = authenticatedUrl(`images/${recommendedImage.path}`);
   });
 
   // rect without scaling
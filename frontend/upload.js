@@ -5,23 +5,63 @@ const selectButtons = [
   "button-select-another-after-error",
 ].map((id) => document.getElementById(id));
 const uploadButton = document.getElementById("upload-button-ready-to-upload");
+const secretInPath = false; // for servers started with --secret-in-path that predate tokens
+// personal links minted with /admin/invites upload without the event's secret
+const invite = new URLSearchParams(window.location.search).get("invite");
 
-if (!window.location.hash) {
+// registered once the secret worked, further uploads are attributed to this device
+const deviceTokenKey = "moments-device-token";
+
+function authenticatedUrl(path) {
+  const secret = window.location.hash.substring(1).toLowerCase();
+  if (secretInPath) {
+    return new URL(`./${secret}/${path}`, window.location);
+  }
+  const url = new URL(`./${path}`, window.location);
+  url.searchParams.set("token", secret);
+  return url;
+}
+
+function uploadUrl() {
+  if (invite !== null) {
+    const url = new URL("./upload/invited", window.location);
+    url.searchParams.set("invite", invite);
+    return url;
+  }
+  return authenticatedUrl("upload");
+}
+
+async function registerDevice() {
+  const response = await fetch(authenticatedUrl("devices"), {
+    method: "POST",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify({ nickname: navigator.platform || null }),
+  });
+  if (response.ok) {
+    localStorage.setItem(deviceTokenKey, (await response.json()).token);
+  }
+}
+
+if (invite !== null) {
+  document.body.className = "state-select-first";
+} else if (!window.location.hash) {
   const newHash = prompt("Please enter the event's secret");
   if (newHash !== null) {
     window.location.hash = `#${newHash}`;
   }
 }
-if (window.location.hash) {
+if (invite === null && window.location.hash) {
   (async () => {
     const secret = window.location.hash.substring(1).toLowerCase();
-    const response = await fetch(
-      new URL(`./${secret}/upload`, window.location),
-      {
-        method: "OPTIONS",
-      }
-    );
+    const response = await fetch(uploadUrl(), {
+      method: "OPTIONS",
+    });
     console.log(response);
+    if (response.status == 401) {
+      alert(`Incorrect event secret "${secret}"`);
+      window.location.hash = "";
+      return;
+    }
     if (response.status != 405) {
       alert("Failed to check for correct secret (!= 405)");
       window.location.hash = "";
@@ -65,24 +105,45 @@ selectButtons.forEach((selectButton) =>
   })
 );
 
-uploadButton.addEventListener("click", async () => {
+function upload(code) {
   const form = new FormData();
   form.append("image", filePicker.files[0]);
+  if (code !== null) {
+    form.append("code", code);
+  }
+  const deviceToken = localStorage.getItem(deviceTokenKey);
+  return fetch(uploadUrl(), {
+    method: "POST",
+    headers:
+      deviceToken !== null && invite === null
+        ? { "X-Device-Token": deviceToken }
+        : {},
+    body: form,
+  });
+}
+
+uploadButton.addEventListener("click", async () => {
   try {
     document.body.className = "state-progress";
-    const response = await fetch(
-      new URL(
-        `./${window.location.hash.substring(1).toLowerCase()}/upload`,
-        window.location
-      ),
-      {
-        method: "POST",
-        body: form,
+    let response = await upload(null);
+    // a revoked device falls back to the secret
+    if (response.status == 401 && localStorage.getItem(deviceTokenKey) !== null) {
+      localStorage.removeItem(deviceTokenKey);
+      response = await upload(null);
+    }
+    // servers started with --upload-code-period ask once for the code shown on the screens
+    if (response.status == 403 && invite === null) {
+      const code = prompt("Please enter the code shown on the screens");
+      if (code !== null) {
+        response = await upload(code);
       }
-    );
+    }
     if (!response.ok) {
       throw await response.text();
     }
+    if (invite === null && localStorage.getItem(deviceTokenKey) === null) {
+      registerDevice().catch(console.error);
+    }
     document.body.className = "state-select-another";
   } catch (error) {
     document.body.className = "state-select-another-after-error";
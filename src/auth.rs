@@ -0,0 +1,161 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+
+use crate::{prefix::replace_path, reload::SharedConfiguration, secrets::Role};
+
+#[derive(Deserialize)]
+struct TokenParameters {
+    token: Option<String>,
+}
+
+/// Role of the secret a request was authenticated with, attached to it as extension
+#[derive(Clone, Copy, Debug)]
+pub struct Authenticated(pub Role);
+
+/// Rejects requests that carry neither `?token=` nor an `Authorization: Bearer` header with one
+/// of the secrets, unless [`strip_secret_prefix`] authenticated them already, wraps the routes
+/// mounted at stable paths
+pub async fn require_secret(
+    State(configuration): State<Arc<SharedConfiguration>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let configuration = configuration.load();
+    if request.extensions().get::<Authenticated>().is_none() {
+        let Some(role) = token(&request).and_then(|token| configuration.secrets.role(&token))
+        else {
+            return unauthorized();
+        };
+        request.extensions_mut().insert(Authenticated(role));
+    }
+    next.run(request).await
+}
+
+/// Answers requests not authenticated with an admin secret with 404 as if the `/admin/` routes
+/// it wraps did not exist, so the secret shown to guests does not even reveal them
+pub async fn require_admin(
+    State(configuration): State<Arc<SharedConfiguration>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let configuration = configuration.load();
+    let role = request
+        .extensions()
+        .get::<Authenticated>()
+        .map(|Authenticated(role)| *role)
+        .or_else(|| token(&request).and_then(|token| configuration.secrets.role(&token)));
+    if role != Some(Role::Admin) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    request.extensions_mut().insert(Authenticated(Role::Admin));
+    next.run(request).await
+}
+
+/// Like [`require_secret`] but with the `--download-secret` or an admin secret if there is a
+/// download secret, so guests knowing their secret cannot download the originals
+pub async fn require_download_secret(
+    State(configuration): State<Arc<SharedConfiguration>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let configuration = configuration.load();
+    let token = token(&request);
+    let role = request
+        .extensions()
+        .get::<Authenticated>()
+        .map(|Authenticated(role)| *role)
+        .or_else(|| {
+            token
+                .as_deref()
+                .and_then(|token| configuration.secrets.role(token))
+        });
+    let authorized = match &configuration.download_secret {
+        Some(download_secret) => {
+            role == Some(Role::Admin)
+                || token.is_some_and(|token| {
+                    constant_time_eq(token.as_bytes(), download_secret.as_bytes())
+                })
+        }
+        None => role.is_some(),
+    };
+    if authorized {
+        next.run(request).await
+    } else {
+        unauthorized()
+    }
+}
+
+/// Authenticates requests below `/<secret>/` like earlier versions served all routes and strips
+/// the secret from their path before routing, if `--secret-in-path` is given
+pub async fn strip_secret_prefix(
+    State(configuration): State<Arc<SharedConfiguration>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let configuration = configuration.load();
+    if configuration.secret_in_path {
+        let path = request.uri().path();
+        let (segment, rest) = path[1..].split_once('/').unwrap_or((&path[1..], ""));
+        if let Some(role) = configuration
+            .secrets
+            .role(&percent_decode_str(segment).decode_utf8_lossy())
+        {
+            let rest = format!("/{rest}");
+            replace_path(&mut request, &rest);
+            request.extensions_mut().insert(Authenticated(role));
+        }
+    }
+    next.run(request).await
+}
+
+fn token(request: &Request) -> Option<String> {
+    let bearer = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_owned);
+    bearer.or_else(|| {
+        Query::<TokenParameters>::try_from_uri(request.uri())
+            .ok()
+            .and_then(|Query(parameters)| parameters.token)
+    })
+}
+
+fn unauthorized() -> Response {
+    (StatusCode::UNAUTHORIZED, "missing or incorrect token").into_response()
+}
+
+/// Compares without exiting early, so response times do not hint at how much of a guess matched
+pub fn constant_time_eq(left: &[u8], right: &[u8]) -> bool {
+    left.len() == right.len()
+        && left
+            .iter()
+            .zip(right)
+            .fold(0, |difference, (left, right)| difference | (left ^ right))
+            == 0
+}
+
+/// Hex of `length` random bytes from the operating system, for tokens nobody may guess
+pub fn random_token(length: usize) -> Result<String, getrandom::Error> {
+    let mut bytes = vec![0; length];
+    getrandom::getrandom(&mut bytes)?;
+    Ok(bytes.iter().map(|byte| format!("{byte:02x}")).collect())
+}
+
+/// Hash of a token to store instead of it, so stored files do not leak usable tokens
+pub fn hash_token(token: &str) -> String {
+    Sha1::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
@@ -1,87 +1,621 @@
-use std::{io::Cursor, path::Path};
-
-use image::{
-    codecs::jpeg::JpegEncoder,
-    error::{UnsupportedError, UnsupportedErrorKind},
-    imageops::FilterType,
-    ImageError, ImageFormat,
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::Permissions,
+    future::Future,
+    io::{SeekFrom, Write},
+    iter::once,
+    os::unix::fs::PermissionsExt,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
+
+use clap::ValueEnum;
+use image::{ImageError, ImageFormat};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
 use tokio::{
-    fs::{try_exists, File},
-    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    fs::{create_dir_all, remove_file, try_exists, File},
+    io::{self, AsyncReadExt, AsyncSeekExt, BufReader},
+    sync::{Mutex as AsyncMutex, OwnedMutexGuard, Semaphore, SemaphorePermit},
     task::spawn_blocking,
+    time::timeout,
 };
 
+use crate::{
+    placeholder::Placeholder,
+    processing::{process_image, ProcessingOptions, ResizeFilter},
+    storage::Storage,
+};
+
+/// Directory inside the cache holding bookkeeping instead of derivatives
+pub const INTERNAL_DIRECTORY: &str = ".moments";
+
+const JPEG_START_OF_IMAGE: [u8; 2] = [0xFF, 0xD8];
+pub const JPEG_END_OF_IMAGE: [u8; 2] = [0xFF, 0xD9];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheFormat {
+    Jpeg,
+    Webp,
+}
+
+impl CacheFormat {
+    pub fn image_format(self) -> ImageFormat {
+        match self {
+            CacheFormat::Jpeg => ImageFormat::Jpeg,
+            CacheFormat::Webp => ImageFormat::WebP,
+        }
+    }
+
+    /// Extension appended to cached file names, JPEG keeps the original names of existing caches
+    fn extension(self) -> Option<&'static str> {
+        match self {
+            CacheFormat::Jpeg => None,
+            CacheFormat::Webp => Some("webp"),
+        }
+    }
+}
+
+/// Describes which derivatives exist for an image and where they live in the cache
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheLayout {
+    /// size of the default derivative stored at the cache root
+    pub max_size: u32,
+    /// additional sizes, each stored in a subdirectory named after the size
+    pub sizes: Vec<u32>,
+    pub format: CacheFormat,
+    /// sizes only generated when requested with `?w=`, stored like additional sizes
+    #[serde(default)]
+    pub on_demand_sizes: Vec<u32>,
+}
+
+impl CacheLayout {
+    /// Default derivative path relative to the cache directory for an image in storage
+    pub fn cached_path(&self, path: &Path) -> PathBuf {
+        match self.format.extension() {
+            Some(extension) => {
+                let mut file_name = OsString::from(path);
+                file_name.push(".");
+                file_name.push(extension);
+                PathBuf::from(file_name)
+            }
+            None => path.to_path_buf(),
+        }
+    }
+
+    /// All derivative paths relative to the cache directory together with their sizes
+    pub fn derivative_paths(&self, path: &Path) -> Vec<(u32, PathBuf)> {
+        let cached_path = self.cached_path(path);
+        once((self.max_size, cached_path.clone()))
+            .chain(
+                self.sizes
+                    .iter()
+                    .map(|size| (*size, Path::new(&size.to_string()).join(&cached_path))),
+            )
+            .collect()
+    }
+
+    /// Path relative to the cache directory of the derivative with `size` of the default
+    /// derivative at `cached_path`, `None` if the size is neither cached nor generated on demand
+    pub fn variant_path(&self, cached_path: &Path, size: u32) -> Option<PathBuf> {
+        if size == self.max_size {
+            Some(cached_path.to_path_buf())
+        } else if self.sizes.contains(&size) || self.on_demand_sizes.contains(&size) {
+            Some(Path::new(&size.to_string()).join(cached_path))
+        } else {
+            None
+        }
+    }
+
+    /// Derivative paths relative to the cache directory of sizes generated on demand, which
+    /// [`CacheLayout::derivative_paths`] leaves out
+    pub fn on_demand_paths(&self, path: &Path) -> Vec<(u32, PathBuf)> {
+        let cached_path = self.cached_path(path);
+        self.on_demand_sizes
+            .iter()
+            .filter(|size| !self.sizes.contains(size) && **size != self.max_size)
+            .map(|size| (*size, Path::new(&size.to_string()).join(&cached_path)))
+            .collect()
+    }
+
+    /// Maps a path relative to the cache directory to its original relative to storage, `None`
+    /// if the path is not a derivative in this layout
+    pub fn original_path(&self, cache_path: &Path) -> Option<PathBuf> {
+        let mut components = cache_path.components();
+        let cached_path = match components.next() {
+            Some(Component::Normal(first))
+                if components.clone().next().is_some()
+                    && self
+                        .sizes
+                        .iter()
+                        .chain(&self.on_demand_sizes)
+                        .any(|size| first.to_str() == Some(&size.to_string())) =>
+            {
+                components.as_path()
+            }
+            _ => cache_path,
+        };
+        match self.format.extension() {
+            Some(extension) => {
+                let file_name = cached_path.to_str()?;
+                file_name
+                    .strip_suffix(extension)
+                    .and_then(|file_name| file_name.strip_suffix('.'))
+                    .map(PathBuf::from)
+            }
+            None => Some(cached_path.to_path_buf()),
+        }
+    }
+}
+
+/// Everything that determines the content of derivatives, persisted in the cache to notice when
+/// existing derivatives were generated differently
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheSettings {
+    #[serde(flatten)]
+    pub layout: CacheLayout,
+    pub jpeg_image_quality: u8,
+    /// caches from before the filter was configurable were resized with Lanczos3
+    #[serde(default)]
+    pub resize_filter: ResizeFilter,
+}
+
+impl CacheSettings {
+    /// Which derivatives generated with `previous` settings need to be regenerated, in the order
+    /// of [`CacheLayout::derivative_paths`]
+    pub fn stale_derivatives(&self, previous: &Self) -> Vec<bool> {
+        let encoding_changed = self.encoding_changed(previous);
+        // additional sizes live in directories named after their size, so only the default
+        // derivative can change its size in place
+        once(encoding_changed || self.layout.max_size != previous.layout.max_size)
+            .chain(self.layout.sizes.iter().map(|_| encoding_changed))
+            .collect()
+    }
+
+    /// Whether derivatives generated with `previous` settings look different at the same size
+    pub fn encoding_changed(&self, previous: &Self) -> bool {
+        self.layout.format != previous.layout.format
+            || self.jpeg_image_quality != previous.jpeg_image_quality
+            || self.resize_filter != previous.resize_filter
+    }
+}
+
+/// A resized variant of an image written to the cache
+#[derive(Clone, Debug)]
+pub struct Derivative {
+    pub destination: PathBuf,
+    /// maximum size of the longest edge in pixels
+    pub max_size: u32,
+}
+
+/// Serializes cache generation per source image, so background population and on-demand
+/// generation never write the same derivatives concurrently
+#[derive(Default)]
+pub struct CacheLocks {
+    locks: Mutex<HashMap<PathBuf, Arc<AsyncMutex<()>>>>,
+}
+
+impl CacheLocks {
+    pub async fn lock(self: &Arc<Self>, source: &Path) -> CacheLockGuard {
+        let lock = self
+            .locks
+            .lock()
+            .unwrap()
+            .entry(source.to_path_buf())
+            .or_default()
+            .clone();
+        CacheLockGuard {
+            _guard: lock.lock_owned().await,
+            locks: self.clone(),
+            source: source.to_path_buf(),
+        }
+    }
+}
+
+pub struct CacheLockGuard {
+    _guard: OwnedMutexGuard<()>,
+    locks: Arc<CacheLocks>,
+    source: PathBuf,
+}
+
+impl Drop for CacheLockGuard {
+    fn drop(&mut self) {
+        let mut locks = self.locks.locks.lock().unwrap();
+        // only the map and this guard hold the lock, nobody else is waiting for it
+        if locks
+            .get(&self.source)
+            .is_some_and(|lock| Arc::strong_count(lock) == 2)
+        {
+            locks.remove(&self.source);
+        }
+    }
+}
+
+/// Bounds how many images are decoded, resized and encoded at the same time, excess work waits
+/// in line instead of exhausting memory and the blocking thread pool
+pub struct ProcessingQueue {
+    permits: Semaphore,
+    workers: usize,
+    queued: AtomicUsize,
+    processed: AtomicU64,
+    /// processing of a single image taking longer than this is given up
+    timeout: Duration,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProcessingStatistics {
+    pub workers: usize,
+    pub active: usize,
+    pub queued: usize,
+    /// images decoded since the start, each for all of its derivatives missing at the time
+    pub processed: u64,
+}
+
+impl ProcessingQueue {
+    pub fn new(workers: usize, timeout: Duration) -> Self {
+        Self {
+            permits: Semaphore::new(workers),
+            workers,
+            queued: AtomicUsize::new(0),
+            processed: AtomicU64::new(0),
+            timeout,
+        }
+    }
+
+    async fn acquire(&self) -> SemaphorePermit<'_> {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        // leaves the queue on drop, also when the waiting request is cancelled
+        let _queued = QueuedGuard(&self.queued);
+        self.permits.acquire().await.unwrap()
+    }
+
+    pub fn statistics(&self) -> ProcessingStatistics {
+        ProcessingStatistics {
+            workers: self.workers,
+            active: self.workers - self.permits.available_permits(),
+            queued: self.queued.load(Ordering::Relaxed),
+            processed: self.processed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+struct QueuedGuard<'a>(&'a AtomicUsize);
+
+impl Drop for QueuedGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Writes all `derivatives` of `source` that do not exist yet, decoding the source only once.
+/// Computes its blurhash and average color as well if `with_placeholder` is set. Derivatives
+/// must be below `cache`, at `<name>` or `<size>/<name>`.
 pub async fn cache_image(
     source: impl AsRef<Path>,
-    destination: impl AsRef<Path>,
-    max_size: u32,
-    jpeg_image_quality: u8,
-) -> Result<(), ImageError> {
-    if let Ok(true) = try_exists(&destination).await {
-        return Ok(());
-    }
-    let file = File::open(&source).await?;
+    cache: &Path,
+    derivatives: &[Derivative],
+    options: &ProcessingOptions,
+    queue: &ProcessingQueue,
+    with_placeholder: bool,
+) -> Result<Option<Placeholder>, CacheError> {
+    let source = source.as_ref();
+    cache_source(
+        read_source(source),
+        source,
+        cache,
+        derivatives,
+        options,
+        queue,
+        with_placeholder,
+    )
+    .await
+}
+
+/// Like [`cache_image`] for the original at `path` in `storage`
+pub async fn cache_stored_image(
+    storage: &dyn Storage,
+    path: &Path,
+    cache: &Path,
+    derivatives: &[Derivative],
+    options: &ProcessingOptions,
+    queue: &ProcessingQueue,
+    with_placeholder: bool,
+) -> Result<Option<Placeholder>, CacheError> {
+    cache_source(
+        storage.read(path),
+        path,
+        cache,
+        derivatives,
+        options,
+        queue,
+        with_placeholder,
+    )
+    .await
+}
+
+/// Reads the source with `read` only if a derivative is missing or a placeholder wanted
+async fn cache_source(
+    read: impl Future<Output = Result<Vec<u8>, io::Error>>,
+    source: &Path,
+    cache: &Path,
+    derivatives: &[Derivative],
+    options: &ProcessingOptions,
+    queue: &ProcessingQueue,
+    with_placeholder: bool,
+) -> Result<Option<Placeholder>, CacheError> {
+    let mut missing_derivatives = Vec::new();
+    for derivative in derivatives {
+        check_destination(cache, &derivative.destination)?;
+        let exists = try_exists(&derivative.destination)
+            .await
+            .map_err(|error| CacheError::destination(&derivative.destination, error))?;
+        if !exists {
+            missing_derivatives.push(derivative.clone());
+        }
+    }
+    if missing_derivatives.is_empty() && !with_placeholder {
+        return Ok(None);
+    }
+
+    // also covers reading, the whole source is held in memory
+    let _permit = queue.acquire().await;
+    queue.processed.fetch_add(1, Ordering::Relaxed);
+    let buffer = read.await.map_err(|error| CacheError::SourceRead {
+        path: source.to_path_buf(),
+        source: error,
+    })?;
+
+    let max_sizes: Vec<_> = missing_derivatives
+        .iter()
+        .map(|derivative| derivative.max_size)
+        .collect();
+    let options = *options;
+    let processing =
+        spawn_blocking(move || process_image(buffer, &max_sizes, &options, with_placeholder));
+    let processed_image = match timeout(queue.timeout, processing).await {
+        Ok(result) => result.map_err(|_| CacheError::Panicked)??,
+        // the blocking thread cannot be stopped and finishes in the background, but its permit
+        // is released so other images are no longer held up
+        Err(_) => {
+            return Err(CacheError::TimedOut {
+                timeout: queue.timeout,
+            })
+        }
+    };
+
+    for (derivative, encoded_image) in missing_derivatives.iter().zip(processed_image.derivatives) {
+        if let Some(size_directory) = check_destination(cache, &derivative.destination)? {
+            create_dir_all(&size_directory)
+                .await
+                .map_err(|error| CacheError::destination(&size_directory, error))?;
+        }
+        write_atomically(&derivative.destination, encoded_image)
+            .await
+            .map_err(|error| CacheError::destination(&derivative.destination, error))?;
+    }
+    Ok(processed_image.placeholder)
+}
+
+/// Refuses a `destination` that is neither `<name>` nor `<size>/<name>` below `cache`, e.g.
+/// from a file name with `..` in it, returns the size directory of the latter
+fn check_destination(cache: &Path, destination: &Path) -> Result<Option<PathBuf>, CacheError> {
+    let components: Vec<_> = match destination.strip_prefix(cache) {
+        Ok(relative) => relative.components().collect(),
+        Err(_) => Vec::new(),
+    };
+    match components[..] {
+        [Component::Normal(_)] => Ok(None),
+        [Component::Normal(size), Component::Normal(_)]
+            if size
+                .to_str()
+                .is_some_and(|size| size.parse::<u32>().is_ok()) =>
+        {
+            Ok(Some(cache.join(size)))
+        }
+        _ => Err(CacheError::destination(
+            destination,
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("not a derivative in {}", cache.display()),
+            ),
+        )),
+    }
+}
+
+async fn read_source(source: &Path) -> Result<Vec<u8>, io::Error> {
+    let file = File::open(source).await?;
     let mut buffer = Vec::with_capacity(file.metadata().await?.len() as usize);
     BufReader::new(file).read_to_end(&mut buffer).await?;
+    Ok(buffer)
+}
 
-    let encoded_image =
-        spawn_blocking(move || load_and_resize(buffer, max_size, jpeg_image_quality))
-            .await
-            .unwrap()?;
+/// Failure of generating derivatives, by the stage it happened in
+#[derive(Debug, Error)]
+pub enum CacheError {
+    #[error("failed to read {}: {source}", path.display())]
+    SourceRead { path: PathBuf, source: io::Error },
+    #[error("failed to decode image: {0}")]
+    Decode(ImageError),
+    #[error("failed to encode derivative: {0}")]
+    Encode(ImageError),
+    #[error("failed to write {}: {source}", path.display())]
+    DestinationWrite { path: PathBuf, source: io::Error },
+    #[error("processing took longer than {}s", timeout.as_secs())]
+    TimedOut { timeout: Duration },
+    #[error("processing the image panicked")]
+    Panicked,
+}
 
-    File::create(&destination)
-        .await?
-        .write_all(&encoded_image)
-        .await?;
-    Ok(())
+impl CacheError {
+    fn destination(path: &Path, source: io::Error) -> Self {
+        Self::DestinationWrite {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+
+    /// Whether the source itself is at fault, retrying will not help until it is replaced
+    pub fn is_permanent(&self) -> bool {
+        matches!(
+            self,
+            Self::Decode(_) | Self::TimedOut { .. } | Self::Panicked
+        )
+    }
 }
 
-fn load_and_resize(
-    buffer: Vec<u8>,
-    max_size: u32,
-    jpeg_image_quality: u8,
-) -> Result<Vec<u8>, ImageError> {
-    let orientation = extract_exif_orientation(&buffer);
-    let image = image::load_from_memory(&buffer)?;
-    let resized_image = image.resize(max_size, max_size, FilterType::Lanczos3);
-
-    let transformed_image = match orientation {
-        0 | 1 => resized_image,
-        2 => resized_image.fliph(),
-        3 => resized_image.rotate180(),
-        4 => resized_image.flipv(),
-        5 => resized_image.rotate90().fliph(),
-        6 => resized_image.rotate90(),
-        7 => resized_image.rotate270().fliph(),
-        8 => resized_image.rotate270(),
-        _ => {
-            return Err(ImageError::Unsupported(
-                UnsupportedError::from_format_and_kind(
-                    ImageFormat::Jpeg.into(),
-                    UnsupportedErrorKind::GenericFeature(format!(
-                        "unsupported exif orientation: {}",
-                        orientation
-                    )),
-                ),
-            ));
+/// Removes all existing `derivatives`, e.g. because their source changed
+pub async fn remove_derivatives(derivatives: &[Derivative]) -> Result<(), io::Error> {
+    for derivative in derivatives {
+        match remove_file(&derivative.destination).await {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => {}
         }
-    };
+    }
+    Ok(())
+}
+
+/// Writes to a temporary file next to `destination` and renames it into place, so readers never
+/// observe a partially written derivative
+pub async fn write_atomically(destination: &Path, contents: Vec<u8>) -> Result<(), io::Error> {
+    let destination = destination.to_path_buf();
+    spawn_blocking(move || replace_file(&destination, |file| file.write_all(&contents))).await?
+}
 
-    let mut encoded_image = Vec::with_capacity(buffer.len());
-    let encoder = JpegEncoder::new_with_quality(&mut encoded_image, jpeg_image_quality);
-    transformed_image.write_with_encoder(encoder)?;
-    Ok(encoded_image)
+/// Replaces `destination` with what `write` wrote to a temporary file, which is removed instead
+/// if writing fails
+fn replace_file(
+    destination: &Path,
+    write: impl FnOnce(&mut std::fs::File) -> Result<(), io::Error>,
+) -> Result<(), io::Error> {
+    let directory = destination.parent().unwrap_or(Path::new("."));
+    // hidden and without a derivative name, so leftovers are swept up as orphans
+    let mut file = tempfile::Builder::new()
+        .prefix(".")
+        .suffix(".tmp")
+        .permissions(Permissions::from_mode(0o644))
+        .tempfile_in(directory)?;
+    write(file.as_file_mut())?;
+    file.as_file().sync_all()?;
+    file.persist(destination).map_err(|error| error.error)?;
+    Ok(())
+}
+
+/// Cheaply checks that a cached file is complete by looking at its container markers
+pub async fn is_intact(path: &Path, format: CacheFormat) -> Result<bool, io::Error> {
+    let mut file = File::open(path).await?;
+    let length = file.metadata().await?.len();
+    if length < 12 {
+        return Ok(false);
+    }
+    let mut header = [0; 12];
+    file.read_exact(&mut header).await?;
+    match format {
+        CacheFormat::Jpeg => {
+            let mut trailer = [0; 2];
+            file.seek(SeekFrom::End(-2)).await?;
+            file.read_exact(&mut trailer).await?;
+            Ok(header.starts_with(&JPEG_START_OF_IMAGE) && trailer == JPEG_END_OF_IMAGE)
+        }
+        CacheFormat::Webp => {
+            let riff_length = u32::from_le_bytes(header[4..8].try_into().unwrap());
+            Ok(header.starts_with(b"RIFF")
+                && &header[8..12] == b"WEBP"
+                && u64::from(riff_length) + 8 == length)
+        }
+    }
 }
 
-fn extract_exif_orientation(buffer: &Vec<u8>) -> u32 {
-    let exifreader = exif::Reader::new();
-    exifreader
-        .read_from_container(&mut Cursor::new(buffer))
-        .ok()
-        .and_then(|exif| {
-            exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)
-                .and_then(|field| field.value.get_uint(0))
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    /// A JPEG with start and end markers around some made up data
+    fn jpeg() -> Vec<u8> {
+        [&JPEG_START_OF_IMAGE[..], &[0x42; 64], &JPEG_END_OF_IMAGE].concat()
+    }
+
+    fn webp(length: u32) -> Vec<u8> {
+        [b"RIFF", &length.to_le_bytes()[..], b"WEBP", &[0x42; 32]].concat()
+    }
+
+    #[test]
+    fn failing_writes_keep_the_previous_file() {
+        let cache = tempfile::tempdir().unwrap();
+        let destination = cache.path().join("image.jpg");
+        replace_file(&destination, |file| file.write_all(&jpeg())).unwrap();
+
+        let error = replace_file(&destination, |file| {
+            file.write_all(&JPEG_START_OF_IMAGE)?;
+            file.write_all(&[0x13; 512])?;
+            Err(io::Error::other("no space left on device"))
         })
-        .unwrap_or(1)
+        .unwrap_err();
+        assert_eq!(error.to_string(), "no space left on device");
+        assert_eq!(fs::read(&destination).unwrap(), jpeg());
+        // the partial file is gone as well
+        assert_eq!(fs::read_dir(cache.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn writer_dying_midway_keeps_the_previous_file() {
+        let cache = tempfile::tempdir().unwrap();
+        let destination = cache.path().join("image.jpg");
+        replace_file(&destination, |file| file.write_all(&jpeg())).unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            replace_file(&destination, |file| {
+                file.write_all(&[0x13; 512])?;
+                panic!("killed while writing");
+            })
+        });
+        assert!(result.is_err());
+        assert_eq!(fs::read(&destination).unwrap(), jpeg());
+        assert_eq!(fs::read_dir(cache.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn truncated_jpegs_are_broken() {
+        let cache = tempfile::tempdir().unwrap();
+        let path = cache.path().join("image.jpg");
+        let jpeg = jpeg();
+        for (contents, intact) in [
+            (&jpeg[..], true),
+            (&jpeg[..jpeg.len() - 1], false),
+            (&jpeg[..jpeg.len() / 2], false),
+            (&jpeg[1..], false),
+            (&[][..], false),
+        ] {
+            fs::write(&path, contents).unwrap();
+            assert_eq!(
+                is_intact(&path, CacheFormat::Jpeg).await.unwrap(),
+                intact,
+                "{} bytes",
+                contents.len()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn webps_shorter_than_their_header_says_are_broken() {
+        let cache = tempfile::tempdir().unwrap();
+        let path = cache.path().join("image.webp");
+        let complete = webp(4 + 32);
+        for (contents, intact) in [
+            (&complete[..], true),
+            (&complete[..complete.len() - 1], false),
+            (&webp(4 + 64)[..], false),
+            (&jpeg()[..], false),
+        ] {
+            fs::write(&path, contents).unwrap();
+            assert_eq!(is_intact(&path, CacheFormat::Webp).await.unwrap(), intact);
+        }
+    }
 }
@@ -0,0 +1,75 @@
+use std::io::Cursor;
+
+use exif::{DateTime, Exif, Field, In, Tag, Value};
+use serde::{Deserialize, Serialize};
+
+/// Information about how and when a photo was taken, read from its EXIF data
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct CaptureMetadata {
+    /// local capture time as `YYYY-MM-DDTHH:MM:SS`, suffixed with the UTC offset if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub taken_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub camera_make: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub camera_model: Option<String>,
+}
+
+impl CaptureMetadata {
+    /// Extracts capture metadata from an encoded image, missing or malformed EXIF yields empty
+    /// metadata
+    pub fn from_exif(buffer: &[u8]) -> Self {
+        let Ok(exif) = exif::Reader::new().read_from_container(&mut Cursor::new(buffer)) else {
+            return Self::default();
+        };
+        Self {
+            taken_at: extract_taken_at(&exif),
+            camera_make: extract_ascii(&exif, Tag::Make),
+            camera_model: extract_ascii(&exif, Tag::Model),
+        }
+    }
+}
+
+fn extract_taken_at(exif: &Exif) -> Option<String> {
+    let field = exif
+        .get_field(Tag::DateTimeOriginal, In::PRIMARY)
+        .or_else(|| exif.get_field(Tag::DateTime, In::PRIMARY))?;
+    let mut date_time = DateTime::from_ascii(first_ascii(field)?).ok()?;
+    if let Some(offset) = exif
+        .get_field(Tag::OffsetTimeOriginal, In::PRIMARY)
+        .and_then(first_ascii)
+    {
+        // an unparsable offset still leaves a usable local time
+        let _ = date_time.parse_offset(offset);
+    }
+    let mut taken_at = format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        date_time.year,
+        date_time.month,
+        date_time.day,
+        date_time.hour,
+        date_time.minute,
+        date_time.second
+    );
+    if let Some(offset) = date_time.offset {
+        let sign = if offset < 0 { '-' } else { '+' };
+        let offset = offset.unsigned_abs();
+        taken_at.push_str(&format!("{sign}{:02}:{:02}", offset / 60, offset % 60));
+    }
+    Some(taken_at)
+}
+
+fn extract_ascii(exif: &Exif, tag: Tag) -> Option<String> {
+    let value = exif.get_field(tag, In::PRIMARY).and_then(first_ascii)?;
+    let value = String::from_utf8_lossy(value)
+        .trim_matches(|character: char| character == '\0' || character.is_whitespace())
+        .to_string();
+    (!value.is_empty()).then_some(value)
+}
+
+fn first_ascii(field: &Field) -> Option<&[u8]> {
+    match &field.value {
+        Value::Ascii(values) => values.first().map(Vec::as_slice),
+        _ => None,
+    }
+}
@@ -0,0 +1,298 @@
+use std::{
+    io::{self, Write},
+    mem::take,
+    sync::Arc,
+};
+
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use flate2::{write::GzEncoder, Compression};
+use futures_util::{stream, StreamExt};
+
+use crate::reload::SharedConfiguration;
+
+/// Bodies smaller than this are sent as they are, gzip would gain little or even grow them
+const MINIMUM_SIZE: u64 = 1024;
+
+/// Gzip-compresses textual responses like the frontend bundle and JSON for clients accepting it,
+/// as their bodies arrive. Images are compressed already and never touched, neither are event
+/// streams.
+pub async fn compress_responses(
+    State(configuration): State<Arc<SharedConfiguration>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let configuration = configuration.load();
+    let accepts_gzip = accepts_gzip(request.headers());
+    let is_head = request.method() == Method::HEAD;
+    let mut response = next.run(request).await;
+    if !is_compressible(response.headers()) {
+        return response;
+    }
+    // caches must keep the variants apart even if this client got the uncompressed one
+    response.headers_mut().append(
+        header::VARY,
+        HeaderValue::from_static(header::ACCEPT_ENCODING.as_str()),
+    );
+    if !configuration.compression
+        || !accepts_gzip
+        || is_head
+        || response.status() != StatusCode::OK
+        || response.headers().contains_key(header::CONTENT_ENCODING)
+    {
+        return response;
+    }
+    let length = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok()?.parse().ok())
+        .or_else(|| response.body().size_hint().exact());
+    if length.is_some_and(|length| length < MINIMUM_SIZE) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    // the compressed entity differs from the uncompressed one, a weak tag still revalidates it
+    if let Some(etag) = parts.headers.remove(header::ETAG) {
+        let etag = etag.to_str().unwrap_or_default();
+        if let Ok(weak) = HeaderValue::from_str(&format!("W/{}", etag.trim_start_matches("W/"))) {
+            parts.headers.insert(header::ETAG, weak);
+        }
+    }
+    Response::from_parts(parts, gzip(body))
+}
+
+/// Compresses `body` part by part, flushing after each so streamed parts are not held back.
+/// A body failing to be read fails the compressed one, aborting the response.
+fn gzip(body: Body) -> Body {
+    let encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let state = (body.into_data_stream(), Some(encoder));
+    let compressed = stream::try_unfold(state, |(mut parts, encoder)| async move {
+        let Some(mut encoder) = encoder else {
+            return Ok(None);
+        };
+        let Some(part) = parts.next().await else {
+            let trailer = Bytes::from(encoder.finish()?);
+            return Ok(Some((trailer, (parts, None))));
+        };
+        encoder.write_all(&part.map_err(io::Error::other)?)?;
+        encoder.flush()?;
+        let compressed = Bytes::from(take(encoder.get_mut()));
+        Ok::<_, io::Error>(Some((compressed, (parts, Some(encoder)))))
+    });
+    Body::from_stream(compressed)
+}
+
+/// Whether `Accept-Encoding` lists gzip or `*` without a quality of 0
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut parameters = coding.split(';').map(str::trim);
+            let name = parameters.next().unwrap_or_default();
+            let rejected = parameters.any(|parameter| {
+                parameter
+                    .strip_prefix("q=")
+                    .and_then(|quality| quality.parse::<f32>().ok())
+                    .is_some_and(|quality| quality == 0.0)
+            });
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && !rejected
+        })
+}
+
+/// Whether the content type is textual, images other than SVG and fonts are compressed already
+fn is_compressible(headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+    else {
+        return false;
+    };
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    match essence.as_str() {
+        // small events sent one at a time, gzip would gain nothing
+        "text/event-stream" => false,
+        "application/json"
+        | "application/javascript"
+        | "application/manifest+json"
+        | "application/xml"
+        | "image/svg+xml" => true,
+        essence => essence.starts_with("text/"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use axum::{body::to_bytes, middleware::from_fn_with_state, routing::get, Router};
+    use clap::Parser;
+    use flate2::read::GzDecoder;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{configure, Arguments};
+
+    /// Random enough to stay above the minimum size when compressed
+    fn json() -> String {
+        let mut random = fastrand::Rng::with_seed(0);
+        let paths: Vec<_> = (0..100)
+            .map(|_| format!("\"{}.jpg\"", random.u64(..)))
+            .collect();
+        format!("[{}]", paths.join(","))
+    }
+
+    fn gzip(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn gunzip(bytes: &[u8]) -> Vec<u8> {
+        let mut decompressed = Vec::new();
+        GzDecoder::new(bytes)
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        decompressed
+    }
+
+    /// Routes answering with JSON, JSON gzipped by the handler, a JPEG, JSON streamed in parts and
+    /// JSON failing halfway, each large enough to be compressed, behind `layers` compression
+    /// layers
+    fn router(layers: usize) -> Router {
+        let arguments = Arguments::try_parse_from(["moments", "--secret", "secret"]).unwrap();
+        let configuration = Arc::new(SharedConfiguration::new(Arc::new(
+            configure(arguments).unwrap(),
+        )));
+        let mut router = Router::new()
+            .route(
+                "/json",
+                get(|| async { ([(header::CONTENT_TYPE, "application/json")], json()) }),
+            )
+            .route(
+                "/gzipped",
+                get(|| async {
+                    (
+                        [
+                            (header::CONTENT_TYPE, "application/json"),
+                            (header::CONTENT_ENCODING, "gzip"),
+                        ],
+                        gzip(json().as_bytes()),
+                    )
+                }),
+            )
+            .route(
+                "/image",
+                get(|| async { ([(header::CONTENT_TYPE, "image/jpeg")], vec![0xff; 4096]) }),
+            )
+            .route(
+                "/streamed",
+                get(|| async {
+                    let parts = [json(), json()].map(|part| Ok::<_, io::Error>(Bytes::from(part)));
+                    (
+                        [(header::CONTENT_TYPE, "application/json")],
+                        Body::from_stream(stream::iter(parts)),
+                    )
+                }),
+            )
+            .route(
+                "/broken",
+                get(|| async {
+                    let chunks = stream::iter([
+                        Ok(Bytes::from(json())),
+                        Err(io::Error::other("storage went away")),
+                    ]);
+                    (
+                        [
+                            (header::CONTENT_TYPE, "application/json"),
+                            (header::CONTENT_LENGTH, "8192"),
+                        ],
+                        Body::from_stream(chunks),
+                    )
+                }),
+            );
+        for _ in 0..layers {
+            router = router.layer(from_fn_with_state(
+                configuration.clone(),
+                compress_responses,
+            ));
+        }
+        router
+    }
+
+    async fn get_gzip(router: Router, uri: &str) -> (HeaderMap, Vec<u8>) {
+        let request = Request::get(uri)
+            .header(header::ACCEPT_ENCODING, "gzip, deflate")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers().clone();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (headers, body.to_vec())
+    }
+
+    #[tokio::test]
+    async fn json_is_compressed() {
+        let (headers, body) = get_gzip(router(1), "/json").await;
+        assert_eq!(headers[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(headers[header::VARY], "accept-encoding");
+        assert_eq!(gunzip(&body), json().as_bytes());
+    }
+
+    #[tokio::test]
+    async fn compressed_responses_are_not_compressed_again() {
+        let (headers, body) = get_gzip(router(2), "/json").await;
+        assert_eq!(headers[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(gunzip(&body), json().as_bytes());
+
+        let (headers, body) = get_gzip(router(1), "/gzipped").await;
+        assert_eq!(headers[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(gunzip(&body), json().as_bytes());
+    }
+
+    #[tokio::test]
+    async fn images_are_not_compressed() {
+        let (headers, body) = get_gzip(router(1), "/image").await;
+        assert!(!headers.contains_key(header::CONTENT_ENCODING));
+        assert!(!headers.contains_key(header::VARY));
+        assert_eq!(body, [0xff; 4096]);
+    }
+
+    #[tokio::test]
+    async fn streamed_bodies_are_compressed() {
+        let (headers, body) = get_gzip(router(1), "/streamed").await;
+        assert_eq!(headers[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(gunzip(&body), (json() + &json()).as_bytes());
+    }
+
+    #[tokio::test]
+    async fn bodies_failing_to_be_read_fail_compressed() {
+        let request = Request::get("/broken")
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(Body::empty())
+            .unwrap();
+        let response = router(1).oneshot(request).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        // not the length of the lost body
+        assert!(!response.headers().contains_key(header::CONTENT_LENGTH));
+        assert!(to_bytes(response.into_body(), usize::MAX).await.is_err());
+    }
+}
@@ -0,0 +1,534 @@
+use std::{
+    ffi::{OsStr, OsString},
+    fs::read_to_string,
+    io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use clap::{parser::ValueSource, ArgAction, ArgMatches, Command};
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+/// Path of the configuration file given with `--config` among the raw command line arguments,
+/// needed before the arguments can be parsed as a whole. Arguments need not be UTF-8, paths
+/// after all are not.
+fn config_path(arguments: &[OsString]) -> Option<PathBuf> {
+    let mut arguments = arguments.iter().skip(1);
+    while let Some(argument) = arguments.next() {
+        if argument == "--" {
+            return None;
+        }
+        if argument == "--config" {
+            return arguments.next().map(PathBuf::from);
+        }
+        if let Some(path) = argument.as_bytes().strip_prefix(b"--config=") {
+            return Some(PathBuf::from(OsStr::from_bytes(path)));
+        }
+    }
+    None
+}
+
+/// The command line with the arguments from the configuration file given with `--config`
+/// inserted before the actual ones, leaving out those also given on the command line
+pub fn with_file_arguments(
+    command: &Command,
+    command_line: &[OsString],
+) -> Result<Vec<OsString>, ConfigError> {
+    let Some(path) = config_path(command_line) else {
+        return Ok(command_line.to_vec());
+    };
+    // errors are reported when the complete arguments are parsed
+    let given = command
+        .clone()
+        .ignore_errors(true)
+        .get_matches_from(command_line);
+    let mut arguments = file_arguments(command, &path, &given)?;
+    arguments.splice(0..0, command_line.first().cloned());
+    arguments.extend(command_line.iter().skip(1).cloned());
+    Ok(arguments)
+}
+
+/// Translates a configuration file with the argument names as keys into command line
+/// arguments, YAML with a `.yaml` or `.yml` extension, e.g. `cache_sizes: [320, 2160]`, JSON
+/// otherwise. Keys `given` on the command line or whose environment variable is set are
+/// skipped, so both override the file instead of adding to lists such as `secret`.
+fn file_arguments(
+    command: &Command,
+    path: &Path,
+    given: &ArgMatches,
+) -> Result<Vec<OsString>, ConfigError> {
+    let contents = read_to_string(path).map_err(|source| ConfigError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let is_yaml = path
+        .extension()
+        .is_some_and(|extension| extension == "yaml" || extension == "yml");
+    let values = if is_yaml {
+        parse_yaml(&contents).map_err(|(line, message)| ConfigError::ParseYaml {
+            path: path.to_path_buf(),
+            line,
+            message,
+        })?
+    } else {
+        serde_json::from_str(&contents).map_err(|source| ConfigError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })?
+    };
+
+    let mut arguments = Vec::new();
+    for (key, value) in values {
+        let Some(argument) = command
+            .get_arguments()
+            .find(|argument| argument.get_id() == key.as_str() && argument.get_long().is_some())
+            .filter(|argument| {
+                argument.get_id() != "config"
+                    && matches!(
+                        argument.get_action(),
+                        ArgAction::Set | ArgAction::Append | ArgAction::SetTrue
+                    )
+            })
+        else {
+            return Err(ConfigError::UnknownKey {
+                path: path.to_path_buf(),
+                key,
+            });
+        };
+        if given.value_source(argument.get_id().as_str()) == Some(ValueSource::CommandLine)
+            || argument
+                .get_env()
+                .is_some_and(|variable| std::env::var_os(variable).is_some())
+        {
+            continue;
+        }
+        let flag = format!("--{}", argument.get_long().unwrap());
+        let is_switch = matches!(argument.get_action(), ArgAction::SetTrue);
+        match value {
+            Value::Null => {}
+            Value::Bool(enabled) if is_switch => {
+                if enabled {
+                    arguments.push(flag.into());
+                }
+            }
+            Value::Bool(_) | Value::Number(_) | Value::String(_) if !is_switch => {
+                arguments.push(flag.into());
+                arguments.push(scalar(&value).into());
+            }
+            // arguments such as secrets may contain commas, so they are repeated instead
+            Value::Array(items)
+                if argument.get_value_delimiter().is_none()
+                    && matches!(argument.get_action(), ArgAction::Append)
+                    && items.iter().all(is_scalar) =>
+            {
+                for item in &items {
+                    arguments.push(flag.clone().into());
+                    arguments.push(scalar(item).into());
+                }
+            }
+            Value::Array(items) if !is_switch && items.iter().all(is_scalar) => {
+                arguments.push(flag.into());
+                arguments.push(
+                    items
+                        .iter()
+                        .map(scalar)
+                        .collect::<Vec<_>>()
+                        .join(",")
+                        .into(),
+                );
+            }
+            _ => {
+                return Err(ConfigError::InvalidValue {
+                    path: path.to_path_buf(),
+                    key,
+                })
+            }
+        }
+    }
+    Ok(arguments)
+}
+
+/// Parses the YAML configuration files need: a mapping of keys to scalars or lists of scalars,
+/// in flow style like `[320, 2160]` or as `- 320` lines below the key, with comments. Anything
+/// else, nested mappings, anchors or multi-line strings, is rejected with its line.
+fn parse_yaml(contents: &str) -> Result<Map<String, Value>, (usize, &'static str)> {
+    let mut values = Map::new();
+    // the key without a value on its line that list items are added to
+    let mut list = None;
+    for (index, line) in contents.lines().enumerate() {
+        let error = |message| (index + 1, message);
+        let line = strip_yaml_comment(line);
+        if line.trim().is_empty() || line == "---" {
+            continue;
+        }
+        if line.starts_with([' ', '\t', '-']) {
+            let item = line
+                .trim_start()
+                .strip_prefix('-')
+                .filter(|item| item.is_empty() || item.starts_with(' '))
+                .ok_or(error("expected a list item or a key without indentation"))?;
+            let key: &String = list.as_ref().ok_or(error("list item without a key"))?;
+            let item = yaml_scalar(item.trim()).map_err(error)?;
+            match &mut values[key] {
+                Value::Array(items) => items.push(item),
+                value => *value = Value::Array(vec![item]),
+            }
+            continue;
+        }
+        let (key, value) = match line.split_once(": ") {
+            Some((key, value)) => (key, value.trim()),
+            None => (
+                line.strip_suffix(':')
+                    .ok_or(error("expected `key: value`"))?,
+                "",
+            ),
+        };
+        let key = key.trim().to_string();
+        if values.contains_key(&key) {
+            return Err(error("duplicate key"));
+        }
+        list = value.is_empty().then(|| key.clone());
+        values.insert(key, yaml_scalar(value).map_err(error)?);
+    }
+    Ok(values)
+}
+
+/// `line` up to a `#` at its start or after whitespace outside of quotes
+fn strip_yaml_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut previous = ' ';
+    for (index, character) in line.char_indices() {
+        match (quote, character) {
+            (None, '#') if previous.is_whitespace() => return line[..index].trim_end(),
+            (None, '"' | '\'') => quote = Some(character),
+            (Some('"'), '\\') if previous == '\\' => {
+                // an escaped backslash does not escape the next character
+                previous = ' ';
+                continue;
+            }
+            (Some(open), _) if character == open && !(open == '"' && previous == '\\') => {
+                quote = None
+            }
+            _ => {}
+        }
+        previous = character;
+    }
+    line.trim_end()
+}
+
+/// A plain, quoted or flow list value, numbers and `true` or `false` like JSON, empty and `~`
+/// as null
+fn yaml_scalar(text: &str) -> Result<Value, &'static str> {
+    if let Some(items) = text.strip_prefix('[') {
+        let items = items
+            .strip_suffix(']')
+            .ok_or("expected `]` at the end of the list")?;
+        if items.trim().is_empty() {
+            return Ok(Value::Array(Vec::new()));
+        }
+        return split_flow_list(items)?
+            .into_iter()
+            .map(|item| match yaml_scalar(item.trim())? {
+                Value::Array(_) => Err("nested lists are not supported"),
+                item => Ok(item),
+            })
+            .collect::<Result<_, _>>()
+            .map(Value::Array);
+    }
+    if let Some(quoted) = text.strip_prefix('"') {
+        let quoted = quoted
+            .strip_suffix('"')
+            .ok_or("expected `\"` at the end of the string")?;
+        let mut string = String::with_capacity(quoted.len());
+        let mut characters = quoted.chars();
+        while let Some(character) = characters.next() {
+            string.push(match character {
+                '\\' => match characters.next() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some(escaped @ ('"' | '\\' | '/')) => escaped,
+                    _ => return Err("unsupported escape sequence"),
+                },
+                character => character,
+            });
+        }
+        return Ok(Value::String(string));
+    }
+    if let Some(quoted) = text.strip_prefix('\'') {
+        let quoted = quoted
+            .strip_suffix('\'')
+            .ok_or("expected `'` at the end of the string")?;
+        return Ok(Value::String(quoted.replace("''", "'")));
+    }
+    if text.starts_with(['{', '|', '>', '&', '*', '!']) {
+        return Err("only scalars and lists of them are supported");
+    }
+    Ok(match text {
+        "" | "~" | "null" => Value::Null,
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        text => match (text.parse::<i64>(), text.parse::<f64>()) {
+            (Ok(integer), _) => integer.into(),
+            (_, Ok(float)) if float.is_finite() => float.into(),
+            _ => Value::String(text.to_string()),
+        },
+    })
+}
+
+/// The items of a flow list between its brackets, split at commas outside of quotes
+fn split_flow_list(items: &str) -> Result<Vec<&str>, &'static str> {
+    let mut split = Vec::new();
+    let mut quote = None;
+    let mut start = 0;
+    let mut escaped = false;
+    for (index, character) in items.char_indices() {
+        match (quote, character) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(open), _) if character == open && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(character),
+            (None, '[') => return Err("nested lists are not supported"),
+            (None, ',') => {
+                split.push(&items[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+        escaped = false;
+    }
+    split.push(&items[start..]);
+    Ok(split)
+}
+
+fn is_scalar(value: &Value) -> bool {
+    matches!(value, Value::Bool(_) | Value::Number(_) | Value::String(_))
+}
+
+fn scalar(value: &Value) -> String {
+    match value {
+        Value::String(string) => string.clone(),
+        value => value.to_string(),
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("failed to read configuration file {}", path.display())]
+    Read { path: PathBuf, source: io::Error },
+    #[error("failed to parse configuration file {} as JSON object", path.display())]
+    Parse {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    #[error(
+        "failed to parse configuration file {} as YAML, line {line}: {message}",
+        path.display()
+    )]
+    ParseYaml {
+        path: PathBuf,
+        line: usize,
+        message: &'static str,
+    },
+    #[error("unknown key `{key}` in configuration file {}", path.display())]
+    UnknownKey { path: PathBuf, key: String },
+    #[error(
+        "invalid value for `{key}` in configuration file {}, expected a string, number or list",
+        path.display()
+    )]
+    InvalidValue { path: PathBuf, key: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsString, os::unix::ffi::OsStringExt};
+
+    use clap::{CommandFactory, Parser};
+    use serde_json::json;
+
+    use super::*;
+    use crate::Arguments;
+
+    /// The arguments parsed from `command_line` with the configuration file `name` containing
+    /// `contents`, as `--config` is placed at the end of the command line
+    fn parse(name: &str, contents: &str, command_line: &[&str]) -> Result<Arguments, ConfigError> {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join(name);
+        std::fs::write(&path, contents).unwrap();
+        let command_line: Vec<OsString> = ["moments"]
+            .iter()
+            .chain(command_line)
+            .map(OsString::from)
+            .chain(["--config".into(), path.into()])
+            .collect();
+        let arguments = with_file_arguments(&Arguments::command(), &command_line)?;
+        Ok(Arguments::try_parse_from(arguments).unwrap())
+    }
+
+    #[test]
+    fn yaml_is_parsed_like_json() {
+        let yaml = parse_yaml(
+            "---\n\
+             # a comment\n\
+             secret_file: /run/secrets/moments # after the value\n\
+             cache_sizes: [320, \"2160\"]\n\
+             secret:\n\
+             \x20 - 'it''s a secret'\n\
+             \x20 - \"with # and \\\"quotes\\\"\"\n\
+             read_only: true\n\
+             jpeg_image_quality: 90\n\
+             public_url: ~\n\
+             admin_secret:\n",
+        )
+        .unwrap();
+        assert_eq!(
+            Value::Object(yaml),
+            json!({
+                "secret_file": "/run/secrets/moments",
+                "cache_sizes": [320, "2160"],
+                "secret": ["it's a secret", "with # and \"quotes\""],
+                "read_only": true,
+                "jpeg_image_quality": 90,
+                "public_url": null,
+                "admin_secret": null,
+            })
+        );
+    }
+
+    #[test]
+    fn unsupported_yaml_is_rejected_with_its_line() {
+        for (yaml, line) in [
+            ("secret: a\nsecret: b\n", 2),
+            ("storage:\n  nested: mapping\n", 2),
+            ("cache_sizes: [[320]]\n", 1),
+            ("secret: &anchor value\n", 1),
+            ("secret: |\n  multi-line\n", 1),
+            ("  - item without key\n", 1),
+            ("\n# comment\nno mapping\n", 3),
+            ("secret: \"unterminated\n", 1),
+        ] {
+            assert_eq!(
+                parse_yaml(yaml).map_err(|(line, _)| line),
+                Err(line),
+                "{yaml}"
+            );
+        }
+    }
+
+    #[test]
+    fn yaml_files_configure_arguments() {
+        let arguments = parse(
+            "moments.yaml",
+            "max_cached_image_size: 1200\ncache_sizes:\n  - 320\n  - 2160\nread_only: true\n",
+            &[],
+        )
+        .unwrap();
+        assert_eq!(arguments.max_cached_image_size, 1200);
+        assert_eq!(arguments.cache_sizes, [320, 2160]);
+        assert!(arguments.read_only);
+
+        let arguments = parse("moments.yml", "max_cached_image_size: 1200\n", &[]).unwrap();
+        assert_eq!(arguments.max_cached_image_size, 1200);
+    }
+
+    #[test]
+    fn other_extensions_are_parsed_as_json() {
+        let arguments = parse("moments.conf", r#"{"max_cached_image_size": 1200}"#, &[]).unwrap();
+        assert_eq!(arguments.max_cached_image_size, 1200);
+        assert!(matches!(
+            parse("moments.json", "max_cached_image_size: 1200", &[]),
+            Err(ConfigError::Parse { .. })
+        ));
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(matches!(
+            parse("moments.yaml", "max_cached_image_sizes: 1200\n", &[]),
+            Err(ConfigError::UnknownKey { key, .. }) if key == "max_cached_image_sizes"
+        ));
+        assert!(matches!(
+            parse("moments.yaml", "config: other.yaml\n", &[]),
+            Err(ConfigError::UnknownKey { key, .. }) if key == "config"
+        ));
+    }
+
+    #[test]
+    fn flags_override_the_environment_overriding_the_file_overriding_defaults() {
+        let default = parse("moments.yaml", "", &[]).unwrap();
+        assert_eq!(default.max_cached_image_size, 1000);
+        assert_eq!(default.mirror_secret, None);
+
+        let file = "max_cached_image_size: 1200\nmirror_secret: file-secret\n";
+        let arguments = parse("moments.yaml", file, &[]).unwrap();
+        assert_eq!(arguments.max_cached_image_size, 1200);
+        assert_eq!(arguments.mirror_secret.as_deref(), Some("file-secret"));
+
+        let arguments = parse("moments.yaml", file, &["--max-cached-image-size", "1400"]).unwrap();
+        assert_eq!(arguments.max_cached_image_size, 1400);
+
+        // no other test reads this variable
+        std::env::set_var("MOMENTS_MIRROR_SECRET", "environment-secret");
+        let from_environment = parse("moments.yaml", file, &[]).unwrap();
+        let from_flag = parse("moments.yaml", file, &["--mirror-secret", "flag-secret"]).unwrap();
+        std::env::remove_var("MOMENTS_MIRROR_SECRET");
+        assert_eq!(
+            from_environment.mirror_secret.as_deref(),
+            Some("environment-secret")
+        );
+        assert_eq!(from_flag.mirror_secret.as_deref(), Some("flag-secret"));
+    }
+
+    #[test]
+    fn lists_on_the_command_line_replace_those_of_the_file() {
+        let file = "secret: [file-secret]\ncache_sizes: [320, 2160]\nhost: '::'\n";
+        let arguments = parse("moments.yaml", file, &[]).unwrap();
+        assert_eq!(arguments.secret, ["file-secret"]);
+        assert_eq!(arguments.cache_sizes, [320, 2160]);
+
+        let arguments = parse(
+            "moments.yaml",
+            file,
+            &[
+                "--secret",
+                "flag-secret",
+                "--cache-sizes",
+                "640",
+                "--host",
+                "0.0.0.0",
+            ],
+        )
+        .unwrap();
+        assert_eq!(arguments.secret, ["flag-secret"]);
+        assert_eq!(arguments.cache_sizes, [640]);
+        assert_eq!(arguments.host, [std::net::Ipv4Addr::UNSPECIFIED]);
+    }
+
+    #[test]
+    fn config_is_found_after_arguments_that_are_not_utf8() {
+        let not_utf8 = OsString::from_vec(b"caf\xe9".to_vec());
+        let command_line = |arguments: &[OsString]| {
+            [
+                OsString::from("moments"),
+                "--frontend-dir".into(),
+                not_utf8.clone(),
+            ]
+            .into_iter()
+            .chain(arguments.iter().cloned())
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            config_path(&command_line(&["--config".into(), "moments.yaml".into()])),
+            Some(PathBuf::from("moments.yaml"))
+        );
+        let mut equals = b"--config=".to_vec();
+        equals.extend(not_utf8.as_bytes());
+        assert_eq!(
+            config_path(&command_line(&[OsString::from_vec(equals)])),
+            Some(PathBuf::from(&not_utf8))
+        );
+        assert_eq!(config_path(&command_line(&[])), None);
+    }
+}
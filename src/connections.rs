@@ -0,0 +1,208 @@
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use axum::{extract::State, Json};
+use log::info;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tokio::sync::watch;
+
+/// Websocket connections currently open, and the signal for them and event streams to close
+#[derive(Default)]
+pub struct Connections {
+    next_id: AtomicU64,
+    connections: Mutex<BTreeMap<u64, Connection>>,
+    closing: watch::Sender<bool>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Connection {
+    pub id: u64,
+    pub address: SocketAddr,
+    #[serde(with = "time::serde::rfc3339")]
+    pub connected_at: OffsetDateTime,
+    pub messages_sent: u64,
+    /// what the client said it is in its hello, unknown for clients not sending one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<ClientRole>,
+    /// stable identity the client gave in its hello, the same across reconnects
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientRole {
+    /// a display on the wall, taking part in highlights
+    Kiosk,
+    Uploader,
+    Viewer,
+}
+
+/// A kiosk by its stable identity, with the connections it currently has open
+#[derive(Debug, Serialize)]
+pub struct KioskSession {
+    pub id: String,
+    pub connections: Vec<u64>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub connected_at: OffsetDateTime,
+}
+
+impl Connections {
+    /// Adds a connection until the returned guard is dropped
+    pub fn register(self: &Arc<Self>, address: SocketAddr) -> ConnectionGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        info!("websocket client {address} connected");
+        self.connections.lock().unwrap().insert(
+            id,
+            Connection {
+                id,
+                address,
+                connected_at: OffsetDateTime::now_utc(),
+                messages_sent: 0,
+                role: None,
+                client_id: None,
+            },
+        );
+        ConnectionGuard {
+            connections: self.clone(),
+            id,
+            address,
+        }
+    }
+
+    pub fn list(&self) -> Vec<Connection> {
+        self.connections.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Kiosks that introduced themselves, by identity, connected the longest first
+    pub fn kiosks(&self) -> Vec<KioskSession> {
+        let mut kiosks: BTreeMap<String, KioskSession> = BTreeMap::new();
+        for connection in self.connections.lock().unwrap().values() {
+            let (Some(ClientRole::Kiosk), Some(id)) = (connection.role, &connection.client_id)
+            else {
+                continue;
+            };
+            let session = kiosks.entry(id.clone()).or_insert_with(|| KioskSession {
+                id: id.clone(),
+                connections: Vec::new(),
+                connected_at: connection.connected_at,
+            });
+            session.connections.push(connection.id);
+            session.connected_at = session.connected_at.min(connection.connected_at);
+        }
+        let mut kiosks: Vec<_> = kiosks.into_values().collect();
+        kiosks.sort_by_key(|session| session.connected_at);
+        kiosks
+    }
+
+    /// Asks all open and future connections to close, e.g. when shutting down
+    pub fn close_all(&self) {
+        self.closing.send_replace(true);
+    }
+
+    /// Completes once connections are asked to close
+    pub fn closing(&self) -> impl Future<Output = ()> {
+        let mut closing = self.closing.subscribe();
+        async move {
+            let _ = closing.wait_for(|closing| *closing).await;
+        }
+    }
+}
+
+/// Removes its connection from the registry when dropped, including during unwinding
+pub struct ConnectionGuard {
+    connections: Arc<Connections>,
+    id: u64,
+    address: SocketAddr,
+}
+
+impl ConnectionGuard {
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Lets the handler record the hello while the guard is with the writer
+    pub fn session(&self) -> Session {
+        Session {
+            connections: self.connections.clone(),
+            id: self.id,
+        }
+    }
+
+    /// Completes once connections are asked to close
+    pub fn closing(&self) -> impl Future<Output = ()> {
+        self.connections.closing()
+    }
+
+    pub fn message_sent(&self) {
+        if let Some(connection) = self
+            .connections
+            .connections
+            .lock()
+            .unwrap()
+            .get_mut(&self.id)
+        {
+            connection.messages_sent += 1;
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        // a poisoned registry must not turn an unwinding handler into an abort
+        let Ok(mut connections) = self.connections.connections.lock() else {
+            return;
+        };
+        if let Some(connection) = connections.remove(&self.id) {
+            info!(
+                "websocket client {} disconnected after {} messages",
+                connection.address, connection.messages_sent
+            );
+        }
+    }
+}
+
+/// The entry of an open connection in the registry
+pub struct Session {
+    connections: Arc<Connections>,
+    id: u64,
+}
+
+impl Session {
+    /// Records what the client introduced itself as
+    pub fn identify(&self, role: ClientRole, client_id: String) {
+        if let Some(connection) = self
+            .connections
+            .connections
+            .lock()
+            .unwrap()
+            .get_mut(&self.id)
+        {
+            info!(
+                "websocket client {} is {role:?} {client_id:?}",
+                connection.address
+            );
+            connection.role = Some(role);
+            connection.client_id = Some(client_id);
+        }
+    }
+}
+
+pub async fn handle_connections(
+    State(connections): State<Arc<Connections>>,
+) -> Json<Vec<Connection>> {
+    Json(connections.list())
+}
+
+/// Lists the kiosks that introduced themselves over the websocket
+pub async fn handle_kiosks(State(connections): State<Arc<Connections>>) -> Json<Vec<KioskSession>> {
+    Json(connections.kiosks())
+}
@@ -0,0 +1,50 @@
+use std::time::Duration;
+
+use axum::http::{header, HeaderName, HeaderValue, Method};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::devices::DEVICE_TOKEN_HEADER;
+
+/// How long browsers may cache the answer to a preflight request
+const PREFLIGHT_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Parses an `--cors-allowed-origin` like `https://example.org` or `http://localhost:8080`, or `*`
+/// for any origin
+pub fn parse_origin(origin: &str) -> Result<HeaderValue, String> {
+    let error = || format!("expected an origin like https://example.org or *, got {origin:?}");
+    if origin != "*" {
+        let (scheme, host) = origin.split_once("://").ok_or_else(error)?;
+        if !matches!(scheme, "http" | "https") || host.is_empty() || host.contains(['/', '?', '#'])
+        {
+            return Err(error());
+        }
+    }
+    HeaderValue::from_str(origin).map_err(|_| error())
+}
+
+/// Lets pages from `origins` call the routes it wraps, e.g. an upload form embedded into another
+/// event site, none without origins so browsers keep them same-origin. Authentication is only by
+/// bearer token, `?token=` or device token, so cookies and other credentials are never allowed.
+pub fn cors_layer(origins: &[HeaderValue]) -> Option<CorsLayer> {
+    if origins.is_empty() {
+        return None;
+    }
+    let allow_origin = if origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(origins.iter().cloned())
+    };
+    Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods([Method::GET, Method::POST])
+            // multipart bodies are sent as a simple request, the bearer token needs a preflight
+            .allow_headers([
+                header::AUTHORIZATION,
+                header::CONTENT_TYPE,
+                DEVICE_TOKEN_HEADER.clone(),
+            ])
+            .expose_headers([HeaderName::from_static("x-request-id")])
+            .max_age(PREFLIGHT_MAX_AGE),
+    )
+}
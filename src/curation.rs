@@ -0,0 +1,276 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use axum::{
+    extract::{ConnectInfo, Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use log::info;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use time::OffsetDateTime;
+use tokio::io;
+
+use crate::{
+    index::{hex_hash, Image, ImageFlag, ImageHash, Indexer, IndexerGone},
+    reload::SharedConfiguration,
+    sidecars::save_sidecar,
+    sources::{Report, SourceRecords},
+    Configuration,
+};
+
+/// Characters of a report reason kept, the rest is cut off
+const MAX_REASON_LENGTH: usize = 500;
+
+pub type CurationState = (Arc<Indexer>, Arc<SourceRecords>);
+
+pub type FlagState = (Arc<SharedConfiguration>, Arc<Indexer>, Arc<SourceRecords>);
+
+/// An image as in the index with the reports on it for review
+#[derive(Serialize)]
+pub struct ListedImage {
+    #[serde(flatten)]
+    image: Image,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reports: Option<ReportSummary>,
+}
+
+#[derive(Serialize)]
+pub struct ReportSummary {
+    count: usize,
+    /// of the reports that gave one
+    reasons: Vec<String>,
+}
+
+/// Lists all images like the index, including hidden ones, with a summary of their reports
+pub async fn handle_list_images(
+    State((indexer, sources)): State<CurationState>,
+) -> Result<Json<Vec<ListedImage>>, CurationError> {
+    let images = indexer.index_including_hidden().await?;
+    Ok(Json(
+        images
+            .into_iter()
+            .map(|image| listed(image, &sources))
+            .collect(),
+    ))
+}
+
+/// The image with the hash like in [`handle_list_images`], e.g. for an instance mirroring to
+/// this one to tell which images it has already
+pub async fn handle_get_image(
+    State((indexer, sources)): State<CurationState>,
+    Path(hash): Path<String>,
+) -> Result<Json<ListedImage>, CurationError> {
+    let hash = hex_hash::from_str(&hash).ok_or(CurationError::NotFound)?;
+    let image = indexer
+        .index_including_hidden()
+        .await?
+        .into_iter()
+        .find(|image| image.hash == hash)
+        .ok_or(CurationError::NotFound)?;
+    Ok(Json(listed(image, &sources)))
+}
+
+fn listed(image: Image, sources: &SourceRecords) -> ListedImage {
+    let reports = sources.reports(image.hash);
+    let reports = (!reports.is_empty()).then(|| ReportSummary {
+        count: reports.len(),
+        reasons: reports
+            .into_iter()
+            .filter_map(|report| report.reason)
+            .collect(),
+    });
+    ListedImage { image, reports }
+}
+
+/// Pins the image with the hash, so it is recommended and highlighted at least every
+/// `--pin-interval` and kiosks may badge it
+pub async fn handle_pin(
+    State((configuration, indexer, sources)): State<FlagState>,
+    Path(hash): Path<String>,
+) -> Result<Json<Image>, CurationError> {
+    set_flag(
+        &configuration.load(),
+        &indexer,
+        &sources,
+        &hash,
+        ImageFlag::Pinned,
+        true,
+    )
+    .await
+    .map(Json)
+}
+
+/// Unpins the image with the hash again
+pub async fn handle_unpin(
+    State((configuration, indexer, sources)): State<FlagState>,
+    Path(hash): Path<String>,
+) -> Result<Json<Image>, CurationError> {
+    set_flag(
+        &configuration.load(),
+        &indexer,
+        &sources,
+        &hash,
+        ImageFlag::Pinned,
+        false,
+    )
+    .await
+    .map(Json)
+}
+
+/// Hides the image with the hash from the kiosks, recommendations and highlights, keeping it in
+/// storage, so uploading it again is still rejected as duplicate
+pub async fn handle_hide(
+    State((configuration, indexer, sources)): State<FlagState>,
+    Path(hash): Path<String>,
+) -> Result<Json<Image>, CurationError> {
+    set_flag(
+        &configuration.load(),
+        &indexer,
+        &sources,
+        &hash,
+        ImageFlag::Hidden,
+        true,
+    )
+    .await
+    .map(Json)
+}
+
+/// Shows the image with the hash on the kiosks again
+pub async fn handle_unhide(
+    State((configuration, indexer, sources)): State<FlagState>,
+    Path(hash): Path<String>,
+) -> Result<Json<Image>, CurationError> {
+    set_flag(
+        &configuration.load(),
+        &indexer,
+        &sources,
+        &hash,
+        ImageFlag::Hidden,
+        false,
+    )
+    .await
+    .map(Json)
+}
+
+pub type ReportState = (Arc<SharedConfiguration>, Arc<Indexer>, Arc<SourceRecords>);
+
+/// A guest asking to remove an image
+#[derive(Deserialize)]
+pub struct ReportRequest {
+    #[serde(with = "hex_hash")]
+    hash: ImageHash,
+    reason: Option<String>,
+}
+
+/// Records a guest's report on an image, once per client address, and hides the image once
+/// `--reports-to-hide` reported it
+pub async fn handle_report(
+    State((configuration, indexer, sources)): State<ReportState>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Json(ReportRequest { hash, reason }): Json<ReportRequest>,
+) -> Result<StatusCode, CurationError> {
+    let configuration = configuration.load();
+    let image = indexer
+        .index_including_hidden()
+        .await?
+        .into_iter()
+        .find(|image| image.hash == hash)
+        .ok_or(CurationError::NotFound)?;
+    let report = Report {
+        reporter: client_key(address.ip()),
+        reason: reason
+            .map(|reason| reason.trim().chars().take(MAX_REASON_LENGTH).collect())
+            .filter(|reason: &String| !reason.is_empty()),
+        reported_at: OffsetDateTime::now_utc(),
+    };
+    // guests cannot wait for the image to be cached like admins, to them it is not there yet
+    let reports = sources
+        .add_report(hash, report)
+        .ok_or(CurationError::NotFound)?;
+    let threshold = configuration.reports_to_hide;
+    if threshold > 0 && reports >= threshold && !image.hidden {
+        info!("hiding {} after {reports} reports", image.path.display());
+        sources.set_flag(hash, ImageFlag::Hidden, true);
+        if let Some(image) = indexer.set_flag(hash, ImageFlag::Hidden, true).await? {
+            save_sidecar(&configuration, &image)
+                .await
+                .map_err(CurationError::Save)?;
+        }
+    }
+    sources.save().await.map_err(CurationError::Save)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Identifies a guest loosely by address, IPv6 clients by their /64 network as they often get a
+/// new address from it
+pub fn client_key(address: IpAddr) -> String {
+    match address.to_canonical() {
+        IpAddr::V4(address) => address.to_string(),
+        IpAddr::V6(address) => {
+            let [a, b, c, d, ..] = address.segments();
+            format!("{a:x}:{b:x}:{c:x}:{d:x}::/64")
+        }
+    }
+}
+
+/// Persists the flag with the source record first, so the broadcast change survives restarts, and
+/// in the sidecar with `--sidecars`
+async fn set_flag(
+    configuration: &Configuration,
+    indexer: &Indexer,
+    sources: &SourceRecords,
+    hash: &str,
+    flag: ImageFlag,
+    value: bool,
+) -> Result<Image, CurationError> {
+    let hash = hex_hash::from_str(hash).ok_or(CurationError::NotFound)?;
+    if !indexer
+        .index_including_hidden()
+        .await?
+        .iter()
+        .any(|image| image.hash == hash)
+    {
+        return Err(CurationError::NotFound);
+    }
+    if !sources.set_flag(hash, flag, value) {
+        return Err(CurationError::NotCached);
+    }
+    sources.save().await.map_err(CurationError::Save)?;
+    let image = indexer
+        .set_flag(hash, flag, value)
+        .await?
+        .ok_or(CurationError::NotFound)?;
+    save_sidecar(configuration, &image)
+        .await
+        .map_err(CurationError::Save)?;
+    Ok(image)
+}
+
+#[derive(Debug, Error)]
+pub enum CurationError {
+    #[error("image not found")]
+    NotFound,
+    #[error("image has not been cached yet, try again once it was shown")]
+    NotCached,
+    #[error("failed to save the change: {0}")]
+    Save(io::Error),
+    #[error(transparent)]
+    IndexerGone(#[from] IndexerGone),
+}
+
+impl IntoResponse for CurationError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            CurationError::NotFound => StatusCode::NOT_FOUND,
+            CurationError::NotCached => StatusCode::CONFLICT,
+            CurationError::Save(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            CurationError::IndexerGone(_) => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
@@ -0,0 +1,291 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display, Formatter},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Path, Request, State},
+    http::{HeaderMap, HeaderName, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use time::OffsetDateTime;
+use tokio::{fs::read, io};
+
+use crate::{
+    auth::{hash_token, random_token, Authenticated},
+    cache::write_atomically,
+    reload::SharedConfiguration,
+    secrets::Role,
+    upload::{authorize_upload, UploadError},
+};
+
+/// Header uploads authenticate with instead of the secret once the device has a token
+pub static DEVICE_TOKEN_HEADER: HeaderName = HeaderName::from_static("x-device-token");
+
+/// Characters of a device nickname kept, the rest is cut off
+const MAX_NICKNAME_LENGTH: usize = 50;
+
+/// Random bytes of a device token
+const TOKEN_LENGTH: usize = 16;
+
+/// A guest's phone or camera that proved once to be at the event, with only the hash of its
+/// token kept
+#[derive(Clone, Serialize, Deserialize)]
+struct Device {
+    token_hash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nickname: Option<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+    #[serde(default)]
+    uploads: u64,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "time::serde::rfc3339::option"
+    )]
+    last_upload_at: Option<OffsetDateTime>,
+}
+
+/// Devices by their id, persisted in the cache directory across restarts
+pub struct Devices {
+    file: PathBuf,
+    devices: Mutex<BTreeMap<String, Device>>,
+}
+
+impl Devices {
+    pub async fn load(file: PathBuf) -> Self {
+        let devices = match read(&file).await {
+            Ok(contents) => serde_json::from_slice(&contents).unwrap_or_else(|error| {
+                warn!("ignoring corrupt devices {}: {error}", file.display());
+                BTreeMap::new()
+            }),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(error) => {
+                warn!("ignoring unreadable devices {}: {error}", file.display());
+                BTreeMap::new()
+            }
+        };
+        Self {
+            file,
+            devices: Mutex::new(devices),
+        }
+    }
+
+    pub async fn save(&self) -> Result<(), io::Error> {
+        let contents = serde_json::to_vec(&*self.devices.lock().unwrap())?;
+        if let Some(parent) = self.file.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        write_atomically(&self.file, contents).await
+    }
+
+    /// The device with `token`, unless it is unknown or was revoked
+    fn authenticate(&self, token: &str) -> Option<AuthenticatedDevice> {
+        let token_hash = hash_token(token);
+        self.devices
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, device)| device.token_hash == token_hash)
+            .map(|(id, device)| AuthenticatedDevice {
+                id: id.clone(),
+                nickname: device.nickname.clone(),
+            })
+    }
+
+    /// Counts an upload stored from the device with the id
+    pub fn record_upload(&self, id: &str) {
+        if let Some(device) = self.devices.lock().unwrap().get_mut(id) {
+            device.uploads += 1;
+            device.last_upload_at = Some(OffsetDateTime::now_utc());
+        }
+    }
+}
+
+/// The device a request was authenticated with, attached to it as extension
+#[derive(Clone, Debug)]
+pub struct AuthenticatedDevice {
+    pub id: String,
+    pub nickname: Option<String>,
+}
+
+/// E.g. `device 1f2e3d4c "Anna's phone"`, to attribute uploads in the log
+impl Display for AuthenticatedDevice {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> fmt::Result {
+        write!(formatter, "device {}", self.id)?;
+        match &self.nickname {
+            Some(nickname) => write!(formatter, " {nickname:?}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Authenticates requests carrying a device token as guests, before [`require_secret`] checks
+/// the secret, and rejects those with an unknown or revoked one even if they also carry a
+/// secret, so revoking a device takes effect
+///
+/// [`require_secret`]: crate::auth::require_secret
+pub async fn authenticate_device(
+    State(devices): State<Arc<Devices>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(token) = request.headers().get(&DEVICE_TOKEN_HEADER) else {
+        return next.run(request).await;
+    };
+    let Some(device) = token
+        .to_str()
+        .ok()
+        .and_then(|token| devices.authenticate(token))
+    else {
+        return (StatusCode::UNAUTHORIZED, "unknown or revoked device token").into_response();
+    };
+    let extensions = request.extensions_mut();
+    if extensions.get::<Authenticated>().is_none() {
+        extensions.insert(Authenticated(Role::Guest));
+    }
+    extensions.insert(device);
+    next.run(request).await
+}
+
+pub type DeviceState = (Arc<SharedConfiguration>, Arc<Devices>);
+
+#[derive(Deserialize)]
+pub struct DeviceRequest {
+    nickname: Option<String>,
+    /// shown on the kiosks, required with `--upload-code-period` unless the guest has a pass
+    code: Option<String>,
+}
+
+/// A device as listed, without its token
+#[derive(Serialize)]
+pub struct DeviceSummary {
+    id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nickname: Option<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+    uploads: u64,
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        with = "time::serde::rfc3339::option"
+    )]
+    last_upload_at: Option<OffsetDateTime>,
+}
+
+impl DeviceSummary {
+    fn new(id: String, device: Device) -> Self {
+        Self {
+            id,
+            nickname: device.nickname,
+            created_at: device.created_at,
+            uploads: device.uploads,
+            last_upload_at: device.last_upload_at,
+        }
+    }
+}
+
+/// A new device with its token, which is shown only this once
+#[derive(Serialize)]
+pub struct RegisteredDevice {
+    #[serde(flatten)]
+    device: DeviceSummary,
+    token: String,
+}
+
+/// Issues a token for a guest authenticated with the secret, and the upload code if required,
+/// which further uploads send in [`DEVICE_TOKEN_HEADER`] instead
+pub async fn handle_register_device(
+    State((configuration, devices)): State<DeviceState>,
+    Extension(Authenticated(role)): Extension<Authenticated>,
+    headers: HeaderMap,
+    Json(DeviceRequest { nickname, code }): Json<DeviceRequest>,
+) -> Result<(StatusCode, Json<RegisteredDevice>), DeviceError> {
+    let configuration = configuration.load();
+    authorize_upload(&configuration, role, &headers, code.as_deref())?;
+    let token = random_token(TOKEN_LENGTH).map_err(DeviceError::Random)?;
+    let id = random_token(4).map_err(DeviceError::Random)?;
+    let device = Device {
+        token_hash: hash_token(&token),
+        nickname: nickname
+            .map(|nickname| nickname.trim().chars().take(MAX_NICKNAME_LENGTH).collect())
+            .filter(|nickname: &String| !nickname.is_empty()),
+        created_at: OffsetDateTime::now_utc(),
+        uploads: 0,
+        last_upload_at: None,
+    };
+    devices
+        .devices
+        .lock()
+        .unwrap()
+        .insert(id.clone(), device.clone());
+    devices.save().await.map_err(DeviceError::Save)?;
+    info!(device = id.as_str(), nickname = device.nickname.as_deref(); "registered device");
+    Ok((
+        StatusCode::CREATED,
+        Json(RegisteredDevice {
+            device: DeviceSummary::new(id, device),
+            token,
+        }),
+    ))
+}
+
+pub async fn handle_list_devices(State(devices): State<Arc<Devices>>) -> Json<Vec<DeviceSummary>> {
+    Json(
+        devices
+            .devices
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, device)| DeviceSummary::new(id.clone(), device.clone()))
+            .collect(),
+    )
+}
+
+/// Revokes the device with the id, its uploads are rejected from then on while everybody else
+/// keeps using the secret
+pub async fn handle_revoke_device(
+    State(devices): State<Arc<Devices>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, DeviceError> {
+    devices
+        .devices
+        .lock()
+        .unwrap()
+        .remove(&id)
+        .ok_or(DeviceError::NotFound)?;
+    devices.save().await.map_err(DeviceError::Save)?;
+    info!(device = id.as_str(); "revoked device");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Error)]
+pub enum DeviceError {
+    #[error("device not found")]
+    NotFound,
+    #[error(transparent)]
+    Unauthorized(#[from] UploadError),
+    #[error("failed to generate a token: {0}")]
+    Random(getrandom::Error),
+    #[error("failed to save devices: {0}")]
+    Save(io::Error),
+}
+
+impl IntoResponse for DeviceError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            DeviceError::NotFound => StatusCode::NOT_FOUND,
+            DeviceError::Unauthorized(error) => return error.into_response(),
+            DeviceError::Random(_) | DeviceError::Save(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
@@ -0,0 +1,295 @@
+use std::{
+    ffi::CString,
+    fmt::{self, Display, Formatter},
+    io::Cursor,
+    mem::MaybeUninit,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+
+use image::{ImageFormat, Rgb, RgbImage};
+use tokio::{
+    io,
+    net::TcpStream,
+    time::{timeout, Duration},
+};
+
+use crate::{
+    configure,
+    frontend::embedded_file,
+    listeners::bind_listeners,
+    processing::process_image,
+    validation::{check_directories, Problem},
+    Arguments,
+};
+
+/// Free space below which uploads and derivatives soon fail
+const MINIMUM_FREE_BYTES: u64 = 100 * 1024 * 1024;
+
+/// Free space below which an event with many uploads may run out of it
+const LOW_FREE_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// Frontend files the kiosk and uploader pages cannot work without
+const FRONTEND_FILES: [&str; 7] = [
+    "index.html",
+    "upload.js",
+    "upload.css",
+    "kiosk.html",
+    "kiosk.js",
+    "kiosk.css",
+    "favicon.png",
+];
+
+/// How long connecting to `--public-url` may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    /// works for now but needs attention, does not fail the diagnosis
+    Warn,
+    Fail,
+    /// not applicable to the configuration
+    Skip,
+}
+
+/// One line of the diagnosis
+#[derive(Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Outcome,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, outcome: Outcome, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            outcome,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// The checks of [`diagnose`] in the order they ran
+#[derive(Debug)]
+pub struct Diagnosis(pub Vec<Check>);
+
+impl Diagnosis {
+    pub fn failures(&self) -> usize {
+        self.0
+            .iter()
+            .filter(|check| check.outcome == Outcome::Fail)
+            .count()
+    }
+}
+
+/// A table of one check per line, e.g. `FAIL  port  failed to bind 0.0.0.0:3000: ...`
+impl Display for Diagnosis {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> fmt::Result {
+        let width = self
+            .0
+            .iter()
+            .map(|check| check.name.len())
+            .max()
+            .unwrap_or(0);
+        for check in &self.0 {
+            let outcome = match check.outcome {
+                Outcome::Pass => "PASS",
+                Outcome::Warn => "WARN",
+                Outcome::Fail => "FAIL",
+                Outcome::Skip => "SKIP",
+            };
+            writeln!(
+                formatter,
+                "{outcome}  {:width$}  {}",
+                check.name, check.detail
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks what keeps a server started with `arguments` from working, with the same code
+/// startup uses where there is one: the configuration, storage and cache directories and the
+/// space left in them, the frontend, decoding and encoding an image like the cache does and
+/// binding the ports. With `check_public_url`, it also connects to the host of `--public-url`,
+/// without TLS or a request, only showing that something listens there.
+pub async fn diagnose(arguments: &Arguments, check_public_url: bool) -> Diagnosis {
+    let mut checks = Vec::new();
+    let configuration = match configure(arguments.clone()) {
+        Ok(configuration) => {
+            checks.push(Check::new("configuration", Outcome::Pass, "valid"));
+            Some(configuration)
+        }
+        Err(error) => {
+            checks.push(Check::new(
+                "configuration",
+                Outcome::Fail,
+                format!("{error:#}").replace('\n', " "),
+            ));
+            None
+        }
+    };
+
+    let problems = match check_directories(&arguments.storage, &arguments.cache).await {
+        Ok(()) => Vec::new(),
+        Err(problems) => problems.0,
+    };
+    for (name, path) in [("storage", &arguments.storage), ("cache", &arguments.cache)] {
+        let problem = problems.iter().find(|problem| {
+            matches!(problem, Problem::Unwritable { name: unwritable, .. }
+                if *unwritable == name)
+        });
+        checks.push(match problem {
+            Some(problem) => Check::new(name, Outcome::Fail, problem.to_string()),
+            None => Check::new(
+                name,
+                Outcome::Pass,
+                format!("{} is writable", path.display()),
+            ),
+        });
+    }
+    for (name, path) in [
+        ("storage space", &arguments.storage),
+        ("cache space", &arguments.cache),
+    ] {
+        checks.push(match available_bytes(path) {
+            Ok(bytes) => {
+                let detail = format!("{} MiB free", bytes / 1024 / 1024);
+                if bytes < MINIMUM_FREE_BYTES {
+                    Check::new(name, Outcome::Fail, detail)
+                } else if bytes < LOW_FREE_BYTES {
+                    Check::new(name, Outcome::Warn, detail)
+                } else {
+                    Check::new(name, Outcome::Pass, detail)
+                }
+            }
+            Err(error) => Check::new(name, Outcome::Fail, format!("unknown: {error}")),
+        });
+    }
+
+    let missing: Vec<_> = FRONTEND_FILES
+        .into_iter()
+        .filter(|file| match &arguments.frontend_dir {
+            Some(frontend_dir) => !frontend_dir.join(file).is_file(),
+            None => embedded_file(file).is_none(),
+        })
+        .collect();
+    let source = match &arguments.frontend_dir {
+        Some(frontend_dir) => format!("in {}", frontend_dir.display()),
+        None => "built in".to_string(),
+    };
+    checks.push(if missing.is_empty() {
+        Check::new("frontend", Outcome::Pass, format!("complete, {source}"))
+    } else {
+        Check::new(
+            "frontend",
+            Outcome::Fail,
+            format!("{} missing {source}", missing.join(", ")),
+        )
+    });
+
+    checks.push(match &configuration {
+        Some(configuration) => {
+            let options = configuration.processing_options();
+            let mut test_image = Vec::new();
+            RgbImage::from_pixel(64, 48, Rgb([200, 80, 40]))
+                .write_to(&mut Cursor::new(&mut test_image), ImageFormat::Png)
+                .expect("encoding to memory does not fail");
+            match process_image(test_image, &[32], &options, true) {
+                Ok(_) => Check::new(
+                    "image processing",
+                    Outcome::Pass,
+                    format!(
+                        "decoded a test image and encoded it as {:?}",
+                        options.format
+                    ),
+                ),
+                Err(error) => Check::new("image processing", Outcome::Fail, error.to_string()),
+            }
+        }
+        None => Check::new(
+            "image processing",
+            Outcome::Skip,
+            "needs a valid configuration",
+        ),
+    });
+
+    let mut ports = vec![("port", &arguments.host, arguments.port)];
+    if let Some(port) = arguments.maintenance_port {
+        ports.push(("maintenance port", &arguments.maintenance_host, port));
+    }
+    for (name, hosts, port) in ports {
+        // released right away for the server to bind
+        checks.push(match bind_listeners(hosts, port) {
+            Ok(_) => Check::new(name, Outcome::Pass, format!("{port} is free")),
+            Err(error) => Check::new(
+                name,
+                Outcome::Fail,
+                format!(
+                    "{error}: {}, is another server running?",
+                    std::error::Error::source(&error)
+                        .map(ToString::to_string)
+                        .unwrap_or_default()
+                ),
+            ),
+        });
+    }
+
+    checks.push(match (&arguments.public_url, check_public_url) {
+        (Some(public_url), true) => match reach(public_url).await {
+            Ok(address) => Check::new(
+                "public url",
+                Outcome::Pass,
+                format!("{address} accepts connections"),
+            ),
+            Err(error) => Check::new("public url", Outcome::Fail, error.to_string()),
+        },
+        (Some(_), false) => Check::new(
+            "public url",
+            Outcome::Skip,
+            "pass --check-public-url to connect to it",
+        ),
+        (None, _) => Check::new("public url", Outcome::Skip, "no --public-url"),
+    });
+    Diagnosis(checks)
+}
+
+/// Connects to the host and port of `url`, an `http://` or `https://` URL
+async fn reach(url: &str) -> io::Result<String> {
+    let (default_port, rest) = match url.strip_prefix("https://") {
+        Some(rest) => (443, rest),
+        None => (80, url.trim_start_matches("http://")),
+    };
+    let authority = rest.split('/').next().unwrap_or_default();
+    let address = match authority.rsplit_once(':') {
+        Some((_, port)) if !port.contains(']') => authority.to_string(),
+        _ => format!("{authority}:{default_port}"),
+    };
+    match timeout(CONNECT_TIMEOUT, TcpStream::connect(&address)).await {
+        Ok(Ok(_)) => Ok(address),
+        Ok(Err(error)) => Err(io::Error::new(
+            error.kind(),
+            format!("cannot connect to {address}: {error}"),
+        )),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("connecting to {address} timed out"),
+        )),
+    }
+}
+
+/// Bytes unprivileged processes may still write to the file system of `path`
+fn available_bytes(path: &Path) -> io::Result<u64> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut statistics = MaybeUninit::<libc::statvfs>::uninit();
+    // the path is terminated and the buffer large enough for the call to fill
+    if unsafe { libc::statvfs(path.as_ptr(), statistics.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let statistics = unsafe { statistics.assume_init() };
+    // the field types differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Ok(statistics.f_bavail as u64 * statistics.f_frsize as u64)
+}
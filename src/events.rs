@@ -0,0 +1,105 @@
+use std::{convert::Infallible, sync::Arc};
+
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive},
+        IntoResponse, Response, Sse,
+    },
+};
+use futures_util::{stream, Stream, StreamExt};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{
+    connections::Connections,
+    index::{Catchup, Indexer, IndexerGone, RevisedChange, Subscription},
+    reload::SharedConfiguration,
+    websocket::ServerMessage,
+};
+
+#[derive(Deserialize)]
+pub struct EventParameters {
+    /// only send the newest N images in the snapshot, later additions are always sent
+    recent_limit: Option<usize>,
+    /// revision to resume from like `Last-Event-ID`, for clients that cannot set headers
+    since: Option<u64>,
+}
+
+/// Streams the index as `snapshot` events and a `snapshot_complete` event followed by `change`
+/// events, with the same payloads as the websocket protocol version 2.
+/// Event IDs are revisions, clients reconnecting with the ID of the last event they received
+/// only get the changes after it if they are still remembered.
+pub type EventsState = (Arc<SharedConfiguration>, Arc<Indexer>, Arc<Connections>);
+
+pub async fn handle_events(
+    State((configuration, indexer, connections)): State<EventsState>,
+    Query(parameters): Query<EventParameters>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, EventsError> {
+    let configuration = configuration.load();
+    let since = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok()?.parse().ok())
+        .or(parameters.since);
+    let Subscription {
+        catchup,
+        revision,
+        changes,
+    } = indexer.subscribe(parameters.recent_limit, since).await?;
+    let catchup: Vec<_> = match catchup {
+        Catchup::Snapshot(images) => {
+            ServerMessage::snapshot(&images, configuration.snapshot_chunk_size, revision)
+                .into_iter()
+                .map(|message| {
+                    let event = Event::default().json_data(&message).unwrap();
+                    // only a complete snapshot counts as received, interrupted ones are sent again
+                    Ok(match message {
+                        ServerMessage::SnapshotComplete { revision } => {
+                            event.event("snapshot_complete").id(revision.to_string())
+                        }
+                        _ => event.event("snapshot"),
+                    })
+                })
+                .collect()
+        }
+        Catchup::Changes(changes) => changes
+            .iter()
+            .map(|change| Ok(change_event(change)))
+            .collect(),
+    };
+    // ends when changes were missed, the reconnecting client then catches up
+    let changes = stream::unfold(changes, |mut changes| async move {
+        let change = changes.recv().await.ok()?;
+        Some((Ok(change_event(&change)), changes))
+    });
+    // ends the response with the server instead of holding up its shutdown
+    let events = stream::iter(catchup)
+        .chain(changes)
+        .take_until(connections.closing());
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+fn change_event(change: &RevisedChange) -> Event {
+    Event::default()
+        .event("change")
+        .id(change.revision.to_string())
+        .json_data(ServerMessage::change(change))
+        .unwrap()
+}
+
+#[derive(Debug, Error)]
+pub enum EventsError {
+    #[error(transparent)]
+    IndexerGone(#[from] IndexerGone),
+}
+
+impl IntoResponse for EventsError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            EventsError::IndexerGone(_) => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
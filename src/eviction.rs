@@ -0,0 +1,148 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use log::{info, warn};
+use percent_encoding::percent_decode_str;
+use tokio::{fs::remove_file, io, task::spawn_blocking, time::interval};
+
+use crate::{cache::CacheLocks, reconcile::list_files, reload::SharedConfiguration, Configuration};
+
+/// How often the cache size is measured and the budget enforced
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Cache files served or written this recently are never evicted, they are likely on screen
+const RECENT_ACCESS: Duration = Duration::from_secs(10 * 60);
+
+/// Tracks the size of the cache and when each cached file was last served
+#[derive(Default)]
+pub struct CacheUsage {
+    /// last access by path relative to the cache directory, since startup
+    accessed: Mutex<HashMap<PathBuf, SystemTime>>,
+    bytes: AtomicU64,
+}
+
+impl CacheUsage {
+    /// Size of the cache in bytes as of the last measurement
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+}
+
+/// Records successfully served or revalidated cache files as accessed, wraps the images service
+pub async fn track_access(
+    State(usage): State<Arc<CacheUsage>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let path = percent_decode_str(request.uri().path().trim_start_matches('/'))
+        .decode_utf8_lossy()
+        .into_owned();
+    let response = next.run(request).await;
+    // revalidated files are still shown
+    if response.status().is_success() || response.status() == StatusCode::NOT_MODIFIED {
+        usage
+            .accessed
+            .lock()
+            .unwrap()
+            .insert(PathBuf::from(path), SystemTime::now());
+    }
+    response
+}
+
+/// Periodically measures the cache and evicts the least recently used files if it exceeds
+/// `max_cache_bytes`, evicted derivatives are regenerated on demand
+pub async fn enforce_cache_budget(
+    configuration: Arc<SharedConfiguration>,
+    locks: Arc<CacheLocks>,
+    usage: Arc<CacheUsage>,
+) {
+    let mut interval = interval(EVICTION_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(error) = evict(&configuration.load(), &locks, &usage).await {
+            warn!("failed to enforce cache budget: {error}");
+        }
+    }
+}
+
+async fn evict(
+    configuration: &Configuration,
+    locks: &Arc<CacheLocks>,
+    usage: &CacheUsage,
+) -> Result<(), io::Error> {
+    let cache = configuration.cache.clone();
+    let mut files = spawn_blocking(move || {
+        list_files(&cache)?
+            .into_iter()
+            .map(|path| {
+                let metadata = std::fs::metadata(cache.join(&path))?;
+                Ok((path, metadata.len(), metadata.modified()?))
+            })
+            .collect::<Result<Vec<_>, io::Error>>()
+    })
+    .await
+    .unwrap()?;
+    let mut bytes: u64 = files.iter().map(|(_, size, _)| size).sum();
+    usage.bytes.store(bytes, Ordering::Relaxed);
+    let Some(max_cache_bytes) = configuration.max_cache_bytes else {
+        return Ok(());
+    };
+    if bytes <= max_cache_bytes {
+        return Ok(());
+    }
+
+    // files not served since startup count as used when they were written
+    {
+        let accessed = usage.accessed.lock().unwrap();
+        for (path, _, last_used) in &mut files {
+            if let Some(accessed) = accessed.get(path) {
+                *last_used = (*last_used).max(*accessed);
+            }
+        }
+    }
+    files.sort_by_key(|(_, _, last_used)| *last_used);
+
+    let now = SystemTime::now();
+    let mut evicted = 0;
+    for (path, size, last_used) in files {
+        if bytes <= max_cache_bytes
+            || now
+                .duration_since(last_used)
+                .is_ok_and(|age| age < RECENT_ACCESS)
+        {
+            break;
+        }
+        let _guard = match configuration.cache_layout.original_path(&path) {
+            Some(original_path) => Some(locks.lock(&original_path).await),
+            None => None,
+        };
+        match remove_file(configuration.cache.join(&path)).await {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => {}
+        }
+        usage.accessed.lock().unwrap().remove(&path);
+        bytes -= size;
+        evicted += 1;
+    }
+    usage.bytes.store(bytes, Ordering::Relaxed);
+    if evicted > 0 {
+        info!("evicted {evicted} cache files, cache is now {bytes} bytes");
+    }
+    if bytes > max_cache_bytes {
+        warn!("cache exceeds its budget of {max_cache_bytes} bytes with recently used files only");
+    }
+    Ok(())
+}
@@ -0,0 +1,340 @@
+use std::{mem::take, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use log::{info, warn};
+use serde::Deserialize;
+use thiserror::Error;
+use time::OffsetDateTime;
+use tokio::{
+    io::{self, duplex, AsyncWrite, AsyncWriteExt},
+    spawn,
+};
+use tokio_util::io::ReaderStream;
+
+use crate::{
+    index::{Image, Indexer, IndexerGone},
+    reload::SharedConfiguration,
+    storage::Storage,
+};
+
+/// Bytes of the archive buffered towards the client, writing waits for slow ones beyond that
+const BUFFER_SIZE: usize = 256 * 1024;
+
+/// Entry after the originals with their index entries
+const METADATA_ENTRY: &str = "metadata.json";
+
+/// Sizes and offsets from which the classic ZIP fields overflow and ZIP64 records are needed
+const ZIP64_LIMIT: u64 = u32::MAX as u64;
+
+/// Entries from which the classic end of central directory record overflows
+const ZIP64_ENTRY_LIMIT: usize = u16::MAX as usize;
+
+const LOCAL_FILE_HEADER: u32 = 0x0403_4b50;
+const CENTRAL_DIRECTORY_HEADER: u32 = 0x0201_4b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY: u32 = 0x0606_4b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR: u32 = 0x0706_4b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+
+/// General purpose flag marking names as UTF-8
+const UTF8_NAMES: u16 = 1 << 11;
+
+pub type ExportState = (Arc<SharedConfiguration>, Arc<Indexer>);
+
+#[derive(Deserialize)]
+pub struct ExportParameters {
+    /// only images created at or after, RFC 3339
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    from: Option<OffsetDateTime>,
+    /// only images created before, RFC 3339
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    until: Option<OffsetDateTime>,
+}
+
+/// Streams a ZIP archive of the originals created between `from` and `until` by path, hidden
+/// ones included, followed by their index entries as `metadata.json`. Images are compressed
+/// already, so they are stored as they are, read one after another while the client downloads.
+/// Without a known length, the download cannot be resumed, it has to be started again.
+pub async fn handle_export(
+    State((configuration, indexer)): State<ExportState>,
+    Query(ExportParameters { from, until }): Query<ExportParameters>,
+) -> Result<Response, ExportError> {
+    let mut images: Vec<_> = indexer
+        .index_including_hidden()
+        .await?
+        .into_iter()
+        .filter(|image| from.is_none_or(|from| image.created_at >= from))
+        .filter(|image| until.is_none_or(|until| image.created_at < until))
+        .collect();
+    images.sort_by(|left, right| left.path.cmp(&right.path));
+    let originals = configuration.load().originals.clone();
+    let (writer, reader) = duplex(BUFFER_SIZE);
+    spawn(async move {
+        match write_archive(writer, originals.as_ref(), images).await {
+            Ok(exported) => info!("exported {exported} images"),
+            // mostly the client going away, which drops the reading end
+            Err(error) => warn!("export stopped: {error}"),
+        }
+    });
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/zip"),
+            ),
+            (
+                header::CONTENT_DISPOSITION,
+                HeaderValue::from_static("attachment; filename=\"moments.zip\""),
+            ),
+        ],
+        Body::from_stream(ReaderStream::new(reader)),
+    )
+        .into_response())
+}
+
+/// Writes the archive, leaving out originals that vanished or cannot be read, and returns how
+/// many were exported
+async fn write_archive(
+    writer: impl AsyncWrite + Unpin,
+    originals: &dyn Storage,
+    images: Vec<Image>,
+) -> io::Result<usize> {
+    let mut archive = ZipWriter::new(writer);
+    let mut exported = Vec::with_capacity(images.len());
+    for image in images {
+        let contents = match originals.read(&image.path).await {
+            Ok(contents) => contents,
+            Err(error) => {
+                warn!(
+                    "leaving {} out of the export: {error}",
+                    image.path.display()
+                );
+                continue;
+            }
+        };
+        archive
+            .add(&image.path.to_string_lossy(), image.created_at, &contents)
+            .await?;
+        exported.push(image);
+    }
+    let metadata = serde_json::to_vec_pretty(&exported)?;
+    archive
+        .add(METADATA_ENTRY, OffsetDateTime::now_utc(), &metadata)
+        .await?;
+    archive.finish().await?;
+    Ok(exported.len())
+}
+
+/// An entry written, for the central directory
+struct Entry {
+    name: String,
+    time: u16,
+    date: u16,
+    crc: u32,
+    size: u64,
+    offset: u64,
+}
+
+/// Writes a ZIP archive of uncompressed entries front to back, with ZIP64 records where sizes,
+/// offsets or the number of entries do not fit the classic ones
+struct ZipWriter<W> {
+    writer: W,
+    offset: u64,
+    entries: Vec<Entry>,
+}
+
+impl<W: AsyncWrite + Unpin> ZipWriter<W> {
+    fn new(writer: W) -> Self {
+        Self {
+            writer,
+            offset: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    async fn add(
+        &mut self,
+        name: &str,
+        modified: OffsetDateTime,
+        contents: &[u8],
+    ) -> io::Result<()> {
+        let (time, date) = dos_date_time(modified);
+        let entry = Entry {
+            name: name.to_string(),
+            time,
+            date,
+            crc: crc32fast::hash(contents),
+            size: contents.len() as u64,
+            offset: self.offset,
+        };
+        let zip64 = entry.size >= ZIP64_LIMIT;
+        let extra = if zip64 {
+            zip64_extra(&[entry.size, entry.size])
+        } else {
+            Vec::new()
+        };
+        let size = if zip64 { u32::MAX } else { entry.size as u32 };
+        let mut header = Vec::with_capacity(30 + name.len() + extra.len());
+        header.extend(LOCAL_FILE_HEADER.to_le_bytes());
+        header.extend(version_needed(zip64).to_le_bytes());
+        header.extend(UTF8_NAMES.to_le_bytes());
+        // stored
+        header.extend(0u16.to_le_bytes());
+        header.extend(time.to_le_bytes());
+        header.extend(date.to_le_bytes());
+        header.extend(entry.crc.to_le_bytes());
+        header.extend(size.to_le_bytes());
+        header.extend(size.to_le_bytes());
+        header.extend((name.len() as u16).to_le_bytes());
+        header.extend((extra.len() as u16).to_le_bytes());
+        header.extend(name.as_bytes());
+        header.extend(extra);
+        self.write(&header).await?;
+        self.write(contents).await?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Writes the central directory and the end records, then shuts the writer down
+    async fn finish(mut self) -> io::Result<()> {
+        let directory_offset = self.offset;
+        let entries = take(&mut self.entries);
+        for entry in &entries {
+            let mut zip64_fields = Vec::new();
+            let size = if entry.size >= ZIP64_LIMIT {
+                zip64_fields.extend([entry.size, entry.size]);
+                u32::MAX
+            } else {
+                entry.size as u32
+            };
+            let offset = if entry.offset >= ZIP64_LIMIT {
+                zip64_fields.push(entry.offset);
+                u32::MAX
+            } else {
+                entry.offset as u32
+            };
+            let extra = if zip64_fields.is_empty() {
+                Vec::new()
+            } else {
+                zip64_extra(&zip64_fields)
+            };
+            let version = version_needed(!zip64_fields.is_empty());
+            let mut header = Vec::with_capacity(46 + entry.name.len() + extra.len());
+            header.extend(CENTRAL_DIRECTORY_HEADER.to_le_bytes());
+            header.extend(version.to_le_bytes());
+            header.extend(version.to_le_bytes());
+            header.extend(UTF8_NAMES.to_le_bytes());
+            header.extend(0u16.to_le_bytes());
+            header.extend(entry.time.to_le_bytes());
+            header.extend(entry.date.to_le_bytes());
+            header.extend(entry.crc.to_le_bytes());
+            header.extend(size.to_le_bytes());
+            header.extend(size.to_le_bytes());
+            header.extend((entry.name.len() as u16).to_le_bytes());
+            header.extend((extra.len() as u16).to_le_bytes());
+            // comment length, disk number and internal attributes
+            header.extend([0; 6]);
+            // external attributes
+            header.extend(0u32.to_le_bytes());
+            header.extend(offset.to_le_bytes());
+            header.extend(entry.name.as_bytes());
+            header.extend(extra);
+            self.write(&header).await?;
+        }
+        let directory_size = self.offset - directory_offset;
+        let count = entries.len();
+
+        let mut end = Vec::new();
+        if count >= ZIP64_ENTRY_LIMIT
+            || directory_size >= ZIP64_LIMIT
+            || directory_offset >= ZIP64_LIMIT
+        {
+            let record_offset = self.offset;
+            end.extend(ZIP64_END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+            // size of the rest of the record
+            end.extend(44u64.to_le_bytes());
+            end.extend(version_needed(true).to_le_bytes());
+            end.extend(version_needed(true).to_le_bytes());
+            // this disk and the one with the central directory
+            end.extend([0; 8]);
+            end.extend((count as u64).to_le_bytes());
+            end.extend((count as u64).to_le_bytes());
+            end.extend(directory_size.to_le_bytes());
+            end.extend(directory_offset.to_le_bytes());
+            end.extend(ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR.to_le_bytes());
+            end.extend(0u32.to_le_bytes());
+            end.extend(record_offset.to_le_bytes());
+            end.extend(1u32.to_le_bytes());
+        }
+        let count = count.min(ZIP64_ENTRY_LIMIT) as u16;
+        end.extend(END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+        end.extend([0; 4]);
+        end.extend(count.to_le_bytes());
+        end.extend(count.to_le_bytes());
+        end.extend((directory_size.min(ZIP64_LIMIT) as u32).to_le_bytes());
+        end.extend((directory_offset.min(ZIP64_LIMIT) as u32).to_le_bytes());
+        // comment length
+        end.extend(0u16.to_le_bytes());
+        self.write(&end).await?;
+        self.writer.shutdown().await
+    }
+
+    async fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.writer.write_all(bytes).await?;
+        self.offset += bytes.len() as u64;
+        Ok(())
+    }
+}
+
+fn version_needed(zip64: bool) -> u16 {
+    if zip64 {
+        45
+    } else {
+        20
+    }
+}
+
+/// ZIP64 extended information with those of uncompressed size, compressed size and offset that
+/// overflowed, in this order
+fn zip64_extra(fields: &[u64]) -> Vec<u8> {
+    let mut extra = Vec::with_capacity(4 + 8 * fields.len());
+    extra.extend(0x0001u16.to_le_bytes());
+    extra.extend((8 * fields.len() as u16).to_le_bytes());
+    for field in fields {
+        extra.extend(field.to_le_bytes());
+    }
+    extra
+}
+
+/// MS-DOS time and date as in ZIP headers, in UTC and clamped to the years 1980 to 2107 they
+/// can hold
+fn dos_date_time(time: OffsetDateTime) -> (u16, u16) {
+    let time = time.to_offset(time::UtcOffset::UTC);
+    if time.year() < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let years = (time.year() - 1980).min(127) as u16;
+    let dos_time =
+        ((time.hour() as u16) << 11) | ((time.minute() as u16) << 5) | (time.second() as u16 / 2);
+    let dos_date = (years << 9) | ((u8::from(time.month()) as u16) << 5) | time.day() as u16;
+    (dos_time, dos_date)
+}
+
+#[derive(Debug, Error)]
+pub enum ExportError {
+    #[error(transparent)]
+    IndexerGone(#[from] IndexerGone),
+}
+
+impl IntoResponse for ExportError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            ExportError::IndexerGone(_) => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
@@ -0,0 +1,240 @@
+use std::f32::consts::PI;
+
+use image::{DynamicImage, ImageBuffer, Pixel};
+use rayon::prelude::*;
+
+/// Fractional bits of the fixed-point weights
+const PRECISION: u32 = 14;
+
+/// Half the width of the Lanczos3 kernel in source pixels when not scaling
+const SUPPORT: f32 = 3.0;
+
+/// Scales `image` down like [`DynamicImage::resize`] with Lanczos3, to the same dimensions and
+/// the same look, but in fixed point and with rows spread over all cores: a 12 MP photo takes
+/// a fraction of the time. [`None`] for images with more than 8 bits per channel, which are left
+/// to the `image` crate.
+pub fn resize_lanczos3(image: &DynamicImage, max_size: u32) -> Option<DynamicImage> {
+    let (width, height) = dimensions(image.width(), image.height(), max_size);
+    Some(match image {
+        DynamicImage::ImageLuma8(image) => {
+            DynamicImage::ImageLuma8(resize::<_, 1>(image, width, height))
+        }
+        DynamicImage::ImageLumaA8(image) => {
+            DynamicImage::ImageLumaA8(resize::<_, 2>(image, width, height))
+        }
+        DynamicImage::ImageRgb8(image) => {
+            DynamicImage::ImageRgb8(resize::<_, 3>(image, width, height))
+        }
+        DynamicImage::ImageRgba8(image) => {
+            DynamicImage::ImageRgba8(resize::<_, 4>(image, width, height))
+        }
+        _ => return None,
+    })
+}
+
+/// Dimensions fitting into `max_size` with the aspect ratio kept, rounded like the `image`
+/// crate does
+fn dimensions(width: u32, height: u32, max_size: u32) -> (u32, u32) {
+    let ratio = f64::min(
+        f64::from(max_size) / f64::from(width),
+        f64::from(max_size) / f64::from(height),
+    );
+    let scale = |length: u32| ((f64::from(length) * ratio).round() as u32).max(1);
+    (scale(width), scale(height))
+}
+
+/// Resizes pixels of `CHANNELS` subpixels, known at compile time so all of them are summed up
+/// at once
+fn resize<P: Pixel<Subpixel = u8>, const CHANNELS: usize>(
+    image: &ImageBuffer<P, Vec<u8>>,
+    width: u32,
+    height: u32,
+) -> ImageBuffer<P, Vec<u8>> {
+    let (source_width, source_height) = (image.width() as usize, image.height() as usize);
+    let (width, height) = (width as usize, height as usize);
+
+    // rows first, shrinking the amount of pixels the columns are filtered over
+    let columns = Weights::new(source_width, width);
+    let mut narrowed = vec![0; width * source_height * CHANNELS];
+    narrowed
+        .par_chunks_mut(width * CHANNELS)
+        .zip(image.as_raw().par_chunks(source_width * CHANNELS))
+        .for_each(|(output, input)| {
+            for (x, output) in output.chunks_exact_mut(CHANNELS).enumerate() {
+                let (start, weights) = columns.of(x);
+                let mut sums = [0i32; CHANNELS];
+                for (pixel, weight) in input[start * CHANNELS..]
+                    .chunks_exact(CHANNELS)
+                    .zip(weights)
+                {
+                    for (sum, &subpixel) in sums.iter_mut().zip(pixel) {
+                        *sum += weight * i32::from(subpixel);
+                    }
+                }
+                for (output, sum) in output.iter_mut().zip(sums) {
+                    *output = to_subpixel(sum);
+                }
+            }
+        });
+
+    let rows = Weights::new(source_height, height);
+    let stride = width * CHANNELS;
+    let mut resized = vec![0; stride * height];
+    resized
+        .par_chunks_mut(stride)
+        .enumerate()
+        .for_each(|(y, output)| {
+            let (start, weights) = rows.of(y);
+            let mut sums = vec![0i32; stride];
+            for (offset, weight) in weights.iter().enumerate() {
+                let row = &narrowed[(start + offset) * stride..][..stride];
+                for (sum, &subpixel) in sums.iter_mut().zip(row) {
+                    *sum += weight * i32::from(subpixel);
+                }
+            }
+            for (output, sum) in output.iter_mut().zip(sums) {
+                *output = to_subpixel(sum);
+            }
+        });
+    ImageBuffer::from_raw(width as u32, height as u32, resized).unwrap()
+}
+
+fn to_subpixel(sum: i32) -> u8 {
+    ((sum + (1 << (PRECISION - 1))) >> PRECISION).clamp(0, 255) as u8
+}
+
+/// The fixed-point weights of the source pixels contributing to each output pixel along one
+/// axis, sampled as by the `image` crate
+struct Weights {
+    /// first contributing source pixel and the range of its weights in `weights`, per output
+    /// pixel
+    ranges: Vec<(usize, usize, usize)>,
+    weights: Vec<i32>,
+}
+
+impl Weights {
+    fn new(source_length: usize, length: usize) -> Self {
+        let ratio = source_length as f32 / length as f32;
+        let scale = ratio.max(1.0);
+        let support = SUPPORT * scale;
+        let mut ranges = Vec::with_capacity(length);
+        let mut weights = Vec::new();
+        for output in 0..length {
+            let center = (output as f32 + 0.5) * ratio;
+            let left = ((center - support).floor() as i64).clamp(0, source_length as i64 - 1);
+            let right =
+                ((center + support).ceil() as i64).clamp(left + 1, source_length as i64) as usize;
+            let left = left as usize;
+            let lobes: Vec<f32> = (left..right)
+                .map(|input| lanczos3((input as f32 - (center - 0.5)) / scale))
+                .collect();
+            let total: f32 = lobes.iter().sum();
+            let start = weights.len();
+            weights.extend(
+                lobes
+                    .iter()
+                    .map(|lobe| (lobe / total * (1 << PRECISION) as f32).round() as i32),
+            );
+            ranges.push((left, start, weights.len()));
+        }
+        Self { ranges, weights }
+    }
+
+    fn of(&self, output: usize) -> (usize, &[i32]) {
+        let (first, start, end) = self.ranges[output];
+        (first, &self.weights[start..end])
+    }
+}
+
+fn lanczos3(x: f32) -> f32 {
+    if x.abs() < SUPPORT {
+        sinc(x) * sinc(x / SUPPORT)
+    } else {
+        0.0
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (x * PI).sin() / (x * PI)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{imageops::FilterType, GrayImage, Luma, Rgb, RgbImage, Rgba, RgbaImage};
+
+    use super::*;
+
+    /// Smooth gradients, hard edges and fine noise, like a photo of people in front of a poster
+    fn photo(width: u32, height: u32) -> RgbImage {
+        let mut noise = 0x2545_f491_u32;
+        RgbImage::from_fn(width, height, |x, y| {
+            noise ^= noise << 13;
+            noise ^= noise >> 17;
+            noise ^= noise << 5;
+            let grain = (noise % 24) as u8;
+            let stripe = if (x / 37 + y / 23) % 2 == 0 { 180 } else { 20 };
+            Rgb([
+                (x * 255 / width) as u8 / 2 + grain,
+                stripe + grain / 2,
+                (y * 255 / height) as u8 / 2 + grain,
+            ])
+        })
+    }
+
+    /// Mean and largest absolute difference of the subpixels of `a` and `b`
+    fn difference(a: &DynamicImage, b: &DynamicImage) -> (f64, u8) {
+        assert_eq!(a.color(), b.color());
+        assert_eq!((a.width(), a.height()), (b.width(), b.height()));
+        let differences: Vec<u8> = a
+            .as_bytes()
+            .iter()
+            .zip(b.as_bytes())
+            .map(|(a, b)| a.abs_diff(*b))
+            .collect();
+        let mean =
+            differences.iter().map(|&d| f64::from(d)).sum::<f64>() / differences.len() as f64;
+        (mean, differences.into_iter().max().unwrap())
+    }
+
+    #[test]
+    fn output_stays_visually_equivalent_to_the_image_crate() {
+        let photo = photo(601, 403);
+        let images = [
+            DynamicImage::ImageRgb8(photo.clone()),
+            DynamicImage::ImageLuma8(GrayImage::from_fn(403, 601, |x, y| {
+                Luma([photo.get_pixel(y, x)[1]])
+            })),
+            DynamicImage::ImageRgba8(RgbaImage::from_fn(601, 403, |x, y| {
+                let [red, green, blue] = photo.get_pixel(x, y).0;
+                Rgba([red, green, blue, (x % 256) as u8])
+            })),
+        ];
+        // rounding differs by at most one level, far below what is visible
+        for image in images {
+            for max_size in [600, 320, 125, 47] {
+                let expected = image.resize(max_size, max_size, FilterType::Lanczos3);
+                let resized = resize_lanczos3(&image, max_size).unwrap();
+                let (mean, largest) = difference(&expected, &resized);
+                let color = image.color();
+                assert!(
+                    mean < 0.25,
+                    "{color:?} at {max_size}: mean difference {mean}"
+                );
+                assert!(
+                    largest <= 1,
+                    "{color:?} at {max_size}: difference {largest}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn images_with_more_bits_are_left_to_the_image_crate() {
+        let image = DynamicImage::new_rgb16(20, 10);
+        assert!(resize_lanczos3(&image, 10).is_none());
+    }
+}
@@ -0,0 +1,173 @@
+use std::{sync::Arc, time::SystemTime};
+
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use httpdate::HttpDate;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use thiserror::Error;
+use time::format_description::well_known::Rfc3339;
+
+use crate::{
+    auth::Authenticated,
+    index::{hex_hash, Image, Indexer, IndexerGone},
+    prefix::Prefix,
+    qr::{base_url, shared_secret},
+    recommend::encode_path,
+    reload::SharedConfiguration,
+};
+
+/// Characters escaped in the secret in links, all but the unreserved ones
+const QUERY_VALUE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Title of the feed and author of its entries
+const FEED_TITLE: &str = "moments";
+
+pub type FeedState = (Arc<SharedConfiguration>, Arc<Indexer>);
+
+/// Answers with the `--feed-size` newest images shown on the kiosks as Atom feed for following
+/// the gallery in a feed reader, e.g. at `/feed.atom?token=` or below `/<secret>/` with
+/// `--secret-in-path`. Entries link their default derivative, absolute from `--public-url` or
+/// the host and with the secret like the QR code, as feed readers send no tokens of their own.
+/// It is last modified when the newest image was created, so polling with `If-Modified-Since`
+/// is answered with 304 until another one arrives.
+pub async fn handle_feed(
+    State((configuration, indexer)): State<FeedState>,
+    Extension(Authenticated(role)): Extension<Authenticated>,
+    Extension(prefix): Extension<Prefix>,
+    headers: HeaderMap,
+) -> Result<Response, FeedError> {
+    let configuration = configuration.load();
+    let base = base_url(&configuration, &prefix, &headers).ok_or(FeedError::UnknownHost)?;
+    let secret = shared_secret(&configuration, role, None).ok_or(FeedError::UnknownSecret)?;
+    let images = indexer.index(Some(configuration.feed_size)).await?;
+
+    // whole seconds like the header, so the time sent compares equal when it comes back
+    let last_modified = images
+        .first()
+        .map(|image| HttpDate::from(SystemTime::from(image.created_at)));
+    let modified_since = headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<HttpDate>().ok());
+    let mut response = match (last_modified, modified_since) {
+        (Some(last_modified), Some(since)) if last_modified <= since => {
+            StatusCode::NOT_MODIFIED.into_response()
+        }
+        _ => (
+            [(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/atom+xml; charset=utf-8"),
+            )],
+            atom_feed(&images, &base, &secret.value),
+        )
+            .into_response(),
+    };
+    let response_headers = response.headers_mut();
+    // contains a secret, readers may keep it but must revalidate
+    response_headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static("private, no-cache"),
+    );
+    if let Some(last_modified) = last_modified {
+        response_headers.insert(
+            header::LAST_MODIFIED,
+            HeaderValue::from_str(&last_modified.to_string()).unwrap(),
+        );
+    }
+    Ok(response)
+}
+
+/// The images newest first as Atom entries titled with their file names and linking their
+/// default derivatives below `base`
+fn atom_feed(images: &[Image], base: &str, secret: &str) -> String {
+    let token = utf8_percent_encode(secret, QUERY_VALUE);
+    let updated = images
+        .first()
+        .and_then(|image| image.created_at.format(&Rfc3339).ok())
+        .unwrap_or_else(|| "1970-01-01T00:00:00Z".to_string());
+    let mut feed = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <feed xmlns=\"http://www.w3.org/2005/Atom\">\n  \
+         <title>{FEED_TITLE}</title>\n  \
+         <id>{}</id>\n  \
+         <link rel=\"self\" href=\"{}\" />\n  \
+         <updated>{updated}</updated>\n  \
+         <author><name>{FEED_TITLE}</name></author>\n",
+        escape(&format!("{base}/feed.atom")),
+        escape(&format!("{base}/feed.atom?token={token}")),
+    );
+    for image in images {
+        let title = image
+            .path
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+        let url = escape(&format!(
+            "{base}/images/{}?token={token}",
+            encode_path(&image.cached_path)
+        ));
+        let content_type = mime_guess::from_path(&image.cached_path).first_or_octet_stream();
+        let published = image.created_at.format(&Rfc3339).unwrap_or_default();
+        feed.push_str(&format!(
+            "  <entry>\n    \
+             <title>{}</title>\n    \
+             <id>urn:moments:{}</id>\n    \
+             <published>{published}</published>\n    \
+             <updated>{published}</updated>\n    \
+             <link rel=\"alternate\" type=\"{content_type}\" href=\"{url}\" />\n    \
+             <link rel=\"enclosure\" type=\"{content_type}\" href=\"{url}\" />\n    \
+             <content type=\"html\">{}</content>\n  \
+             </entry>\n",
+            escape(&title),
+            hex_hash::to_string(&image.hash),
+            escape(&format!("<img src=\"{url}\" alt=\"{}\" />", escape(&title))),
+        ));
+    }
+    feed.push_str("</feed>\n");
+    feed
+}
+
+/// Escapes text for XML content and quoted attribute values
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        match character {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            character => escaped.push(character),
+        }
+    }
+    escaped
+}
+
+#[derive(Debug, Error)]
+pub enum FeedError {
+    #[error("request lacks a host, pass --public-url")]
+    UnknownHost,
+    #[error("no secret to link images with")]
+    UnknownSecret,
+    #[error(transparent)]
+    IndexerGone(#[from] IndexerGone),
+}
+
+impl IntoResponse for FeedError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            FeedError::UnknownHost => StatusCode::BAD_REQUEST,
+            FeedError::UnknownSecret => StatusCode::NOT_FOUND,
+            FeedError::IndexerGone(_) => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
@@ -0,0 +1,34 @@
+use axum::{
+    http::{header, StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+use percent_encoding::percent_decode_str;
+
+/// Files below `frontend/` by their path relative to it, sorted, embedded by the build script
+static FILES: &[(&str, &[u8])] = include!(concat!(env!("OUT_DIR"), "/frontend.rs"));
+
+/// Serves the frontend built into the binary, directories with their `index.html` like
+/// `ServeDir` does for `--frontend-dir`
+pub async fn serve_frontend(uri: Uri) -> Response {
+    let mut path = percent_decode_str(uri.path().trim_start_matches('/'))
+        .decode_utf8_lossy()
+        .into_owned();
+    if path.is_empty() || path.ends_with('/') {
+        path.push_str("index.html");
+    }
+    match embedded_file(&path) {
+        Some(contents) => {
+            let content_type = mime_guess::from_path(&path).first_or_octet_stream();
+            ([(header::CONTENT_TYPE, content_type.as_ref())], contents).into_response()
+        }
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// The file built into the binary at `path` relative to `frontend/`
+pub fn embedded_file(path: &str) -> Option<&'static [u8]> {
+    FILES
+        .binary_search_by_key(&path, |(path, _)| path)
+        .ok()
+        .map(|index| FILES[index].1)
+}
@@ -0,0 +1,114 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use tempfile::NamedTempFile;
+use tokio::{io, task::spawn_blocking, time::timeout};
+
+use crate::{index::Indexer, reload::SharedConfiguration};
+
+/// How long the indexer may take to answer a readiness check
+const INDEXER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Progress of the cache population running in the background after startup
+#[derive(Default)]
+pub struct CachePopulation(Mutex<PopulationStatus>);
+
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PopulationStatus {
+    #[default]
+    Running,
+    Finished,
+    Failed,
+    /// not run at startup with `--lazy-cache`
+    Skipped,
+}
+
+impl CachePopulation {
+    pub fn set(&self, status: PopulationStatus) {
+        *self.0.lock().unwrap() = status;
+    }
+
+    pub fn status(&self) -> PopulationStatus {
+        *self.0.lock().unwrap()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    /// what keeps the server from being ready, empty if it is
+    pub reasons: Vec<String>,
+    /// images missing from the cache are generated on demand while it is running, so it does not
+    /// affect readiness
+    pub cache_population: PopulationStatus,
+}
+
+/// Answers as long as the process runs, unauthenticated for container health checks
+pub async fn handle_health() -> &'static str {
+    "ok"
+}
+
+pub type ReadinessState = (Arc<SharedConfiguration>, Arc<Indexer>, Arc<CachePopulation>);
+
+/// Checks that storage and cache are writable and the indexer responds, answers 503 listing the
+/// reasons otherwise. Unauthenticated for container health checks.
+pub async fn handle_readiness(
+    State((configuration, indexer, population)): State<ReadinessState>,
+) -> Response {
+    let configuration = configuration.load();
+    let mut reasons = Vec::new();
+    match configuration.originals.local_directory() {
+        Some(directory) => {
+            if let Err(error) = check_writable(directory.to_path_buf()).await {
+                reasons.push(format!("storage directory is not writable: {error}"));
+            }
+        }
+        // writing to remote storage on every probe would be costly, listing shows it is reachable
+        None => {
+            if let Err(error) = configuration.originals.list().await {
+                reasons.push(format!("storage is not reachable: {error}"));
+            }
+        }
+    }
+    if let Err(error) = check_writable(configuration.cache.clone()).await {
+        reasons.push(format!("cache directory is not writable: {error}"));
+    }
+    match timeout(INDEXER_TIMEOUT, indexer.index(Some(0))).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(error)) => reasons.push(error.to_string()),
+        Err(_) => reasons.push(format!(
+            "indexer did not respond within {}s",
+            INDEXER_TIMEOUT.as_secs()
+        )),
+    }
+
+    let status = if reasons.is_empty() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let readiness = Readiness {
+        ready: reasons.is_empty(),
+        reasons,
+        cache_population: population.status(),
+    };
+    (status, Json(readiness)).into_response()
+}
+
+/// Creates and removes a hidden temporary file, which the storage watcher ignores
+pub async fn check_writable(directory: PathBuf) -> Result<(), io::Error> {
+    spawn_blocking(move || NamedTempFile::new_in(directory).map(drop))
+        .await
+        .unwrap()
+}
@@ -0,0 +1,289 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use log::warn;
+use serde::Serialize;
+use time::OffsetDateTime;
+use tokio::{
+    spawn,
+    sync::mpsc,
+    time::{sleep, Duration},
+};
+
+use crate::{
+    index::{Image, ImageHash, Indexer},
+    recommend::rank_images,
+    reload::SharedConfiguration,
+    sources::SourceRecords,
+    Configuration,
+};
+
+/// Highlights queued for a kiosk, further ones are dropped until it took them
+const HIGHLIGHT_QUEUE_SIZE: usize = 4;
+
+/// How often a disabled scheduler checks whether a reload enabled it
+const DISABLED_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Parts of the screen suggested for highlights in turn, as fractions of its width and height
+const REGIONS: [Region; 4] = [
+    Region::new(0.0, 0.0),
+    Region::new(0.5, 0.5),
+    Region::new(0.5, 0.0),
+    Region::new(0.0, 0.5),
+];
+
+/// An image a kiosk is asked to show prominently from `starts_at` for `duration_ms`
+#[derive(Clone, Debug, Serialize)]
+pub struct Highlight {
+    pub id: u64,
+    pub image: Image,
+    #[serde(with = "time::serde::rfc3339")]
+    pub starts_at: OffsetDateTime,
+    pub duration_ms: u64,
+    pub region: Region,
+}
+
+/// A rectangle of the screen with coordinates and sizes as fractions of it
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Region {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Region {
+    const fn new(x: f32, y: f32) -> Self {
+        Self {
+            x,
+            y,
+            width: 0.5,
+            height: 0.5,
+        }
+    }
+}
+
+/// A connection receiving highlights, by the kiosk's stable identity once it said hello
+struct Kiosk {
+    name: Option<String>,
+    sender: mpsc::Sender<Highlight>,
+}
+
+/// A highlight assigned to a kiosk that has not ended yet
+struct Assignment {
+    image: Image,
+    ends_at: OffsetDateTime,
+}
+
+/// Kiosks receiving highlights and the highlights currently shown by them, so every image is
+/// highlighted by at most one kiosk at a time
+pub struct Highlights {
+    sources: Arc<SourceRecords>,
+    next_kiosk: AtomicU64,
+    next_highlight: AtomicU64,
+    kiosks: Mutex<BTreeMap<u64, Kiosk>>,
+    assignments: Mutex<HashMap<u64, Assignment>>,
+    /// fresh images each kiosk highlighted by its key, so it highlights them ahead of others
+    /// only once, even across reconnects of named kiosks
+    surfaced: Mutex<HashMap<String, HashSet<ImageHash>>>,
+}
+
+impl Highlights {
+    pub fn new(sources: Arc<SourceRecords>) -> Self {
+        Self {
+            sources,
+            next_kiosk: AtomicU64::new(0),
+            next_highlight: AtomicU64::new(0),
+            kiosks: Mutex::default(),
+            assignments: Mutex::default(),
+            surfaced: Mutex::default(),
+        }
+    }
+
+    /// Adds a kiosk until the returned receiver is dropped
+    pub fn register(self: &Arc<Self>) -> HighlightReceiver {
+        let id = self.next_kiosk.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = mpsc::channel(HIGHLIGHT_QUEUE_SIZE);
+        self.kiosks
+            .lock()
+            .unwrap()
+            .insert(id, Kiosk { name: None, sender });
+        HighlightReceiver {
+            highlights: self.clone(),
+            id,
+            receiver,
+        }
+    }
+
+    /// Assigns each connected kiosk a different image of those it ranks first that no kiosk is
+    /// highlighting, starting one after another spread over `--highlight-interval` so they do
+    /// not change at the same time. Named kiosks keep their turn and regions across reconnects,
+    /// several connections with the same name count as one, the newest of them is highlighted.
+    fn assign(&self, images: Vec<Image>, configuration: &Configuration, round: usize) {
+        let kiosks: BTreeMap<_, _> = self
+            .kiosks
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, kiosk)| (kiosk_key(*id, kiosk), kiosk.sender.clone()))
+            .collect();
+        if kiosks.is_empty() {
+            return;
+        }
+        let duration = configuration.highlight_interval;
+        let now = OffsetDateTime::now_utc();
+        let mut assignments = self.assignments.lock().unwrap();
+        assignments.retain(|_, assignment| assignment.ends_at > now);
+        let mut taken: HashSet<_> = assignments
+            .values()
+            .map(|assignment| assignment.image.hash)
+            .collect();
+        let fresh: HashSet<_> = images
+            .iter()
+            .filter(|image| image.fresh)
+            .map(|image| image.hash)
+            .collect();
+        let mut surfaced = self.surfaced.lock().unwrap();
+        let count = kiosks.len() as u32;
+        surfaced.retain(|_, hashes| {
+            hashes.retain(|hash| fresh.contains(hash));
+            !hashes.is_empty()
+        });
+        for (index, (key, kiosk)) in kiosks.into_iter().enumerate() {
+            let kiosk_surfaced = surfaced.entry(key).or_default();
+            let Some(image) =
+                rank_images(images.clone(), &self.sources, configuration, kiosk_surfaced)
+                    .into_iter()
+                    .find(|image| !taken.contains(&image.hash))
+            else {
+                break;
+            };
+            taken.insert(image.hash);
+            let starts_at = now + duration * index as u32 / count;
+            let highlight = Highlight {
+                id: self.next_highlight.fetch_add(1, Ordering::Relaxed),
+                image: image.clone(),
+                starts_at,
+                duration_ms: duration.as_millis() as u64,
+                region: REGIONS[(index + round) % REGIONS.len()],
+            };
+            let id = highlight.id;
+            // a kiosk not taking its highlights misses some, the next round has new ones
+            if kiosk.try_send(highlight).is_ok() {
+                if image.fresh {
+                    kiosk_surfaced.insert(image.hash);
+                }
+                assignments.insert(
+                    id,
+                    Assignment {
+                        image,
+                        ends_at: starts_at + duration,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Records the image of a highlight a kiosk confirmed as shown, for recommendations and
+    /// the next rounds
+    fn acknowledge(&self, id: u64) {
+        let assignments = self.assignments.lock().unwrap();
+        let Some(assignment) = assignments.get(&id) else {
+            return;
+        };
+        self.sources
+            .set_shown(assignment.image.hash, OffsetDateTime::now_utc());
+        let sources = self.sources.clone();
+        spawn(async move {
+            if let Err(error) = sources.save().await {
+                warn!("failed to save when a highlight was shown: {error}");
+            }
+        });
+    }
+}
+
+/// Identifies a kiosk by its name, or by its connection until it said hello
+fn kiosk_key(id: u64, kiosk: &Kiosk) -> String {
+    match &kiosk.name {
+        Some(name) => format!("kiosk {name}"),
+        None => format!("connection {id}"),
+    }
+}
+
+/// Highlights for one kiosk, which stops receiving them when this is dropped
+pub struct HighlightReceiver {
+    highlights: Arc<Highlights>,
+    id: u64,
+    receiver: mpsc::Receiver<Highlight>,
+}
+
+impl HighlightReceiver {
+    pub async fn recv(&mut self) -> Option<Highlight> {
+        self.receiver.recv().await
+    }
+
+    /// Marks the image of highlight `id` as shown, unknown and ended ones are ignored
+    pub fn acknowledge(&self, id: u64) {
+        self.highlights.acknowledge(id);
+    }
+
+    /// Names the kiosk with the stable identity from its hello
+    pub fn identify(&self, name: String) {
+        if let Some(kiosk) = self.highlights.kiosks.lock().unwrap().get_mut(&self.id) {
+            kiosk.name = Some(name);
+        }
+    }
+}
+
+impl Drop for HighlightReceiver {
+    fn drop(&mut self) {
+        // a poisoned registry must not turn an unwinding handler into an abort
+        let Some(kiosk) = self
+            .highlights
+            .kiosks
+            .lock()
+            .ok()
+            .and_then(|mut kiosks| kiosks.remove(&self.id))
+        else {
+            return;
+        };
+        // named kiosks keep theirs for when they reconnect, until those are no longer fresh
+        if kiosk.name.is_none() {
+            if let Ok(mut surfaced) = self.highlights.surfaced.lock() {
+                surfaced.remove(&kiosk_key(self.id, &kiosk));
+            }
+        }
+    }
+}
+
+/// Hands out highlights to the connected kiosks every `--highlight-interval`, one image each
+/// lasting the interval, fresh ones once per kiosk first, then the least recently shown. A
+/// single kiosk simply gets one image after another.
+pub async fn schedule_highlights(
+    configuration: Arc<SharedConfiguration>,
+    indexer: Arc<Indexer>,
+    highlights: Arc<Highlights>,
+) {
+    let mut round = 0;
+    loop {
+        let configuration = configuration.load();
+        let interval = configuration.highlight_interval;
+        if interval.is_zero() {
+            sleep(DISABLED_CHECK_INTERVAL).await;
+            continue;
+        }
+        if !highlights.kiosks.lock().unwrap().is_empty() {
+            let Ok(images) = indexer.index(None).await else {
+                return;
+            };
+            highlights.assign(images, &configuration, round);
+            round += 1;
+        }
+        sleep(interval).await;
+    }
+}
@@ -0,0 +1,339 @@
+use std::{future::Future, ops::Range};
+
+use axum::body::Bytes;
+use futures_util::{stream, StreamExt};
+use log::warn;
+use thiserror::Error;
+use tokio::{
+    io::{self, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time::{sleep, timeout, Duration},
+};
+
+use crate::{http_url::HttpUrl, storage::ByteStream};
+
+/// How long connecting to a server may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a request may go without any progress, generous as e.g. a Nextcloud share can
+/// take a while to assemble a large upload before answering
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Requests failing with a connection problem or a server error are sent this many times
+const ATTEMPTS: u32 = 3;
+
+/// Wait after the first failed attempt of a request, doubled after each further one
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Most bytes read from or written to the connection at once
+const PART_SIZE: usize = 64 * 1024;
+
+/// Answered with a server error or too many requests, which may be gone on the next attempt
+#[derive(Debug, Error)]
+#[error("{host} is unavailable: {status} {reason}")]
+struct Unavailable {
+    host: String,
+    status: u16,
+    reason: String,
+}
+
+/// Sends a single request over a new connection to `url`, returns the answer once its head
+/// arrived. `target` is the path with the query, `headers` are sent besides `Host`,
+/// `Content-Length` and `Connection`.
+pub async fn send(
+    url: &HttpUrl,
+    method: &str,
+    target: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> io::Result<Answer> {
+    let address = (url.host.as_str(), url.port);
+    let stream = match timeout(CONNECT_TIMEOUT, TcpStream::connect(address)).await {
+        Ok(stream) => stream?,
+        Err(_) => return Err(io::ErrorKind::TimedOut.into()),
+    };
+    let mut connection = BufReader::new(stream);
+    let mut head = format!(
+        "{method} {target} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n",
+        url.authority(),
+        body.len()
+    );
+    for (name, value) in headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+    idle(connection.write_all(head.as_bytes())).await?;
+    for part in body.chunks(PART_SIZE) {
+        idle(connection.write_all(part)).await?;
+    }
+    idle(connection.flush()).await?;
+    Answer::read_head(&url.host, method, target, connection).await
+}
+
+/// Runs `operation` again after connection problems and server errors, with a delay
+pub async fn retrying<T, F, O>(description: &str, operation: O) -> io::Result<T>
+where
+    F: Future<Output = io::Result<T>>,
+    O: Fn() -> F,
+{
+    let mut delay = RETRY_DELAY;
+    for _ in 1..ATTEMPTS {
+        match operation().await {
+            Err(error) if is_transient(&error) => {
+                warn!("failed to {description}, retrying in {delay:?}: {error}");
+                sleep(delay).await;
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+    operation().await
+}
+
+fn is_transient(error: &io::Error) -> bool {
+    use io::ErrorKind::*;
+    matches!(
+        error.kind(),
+        TimedOut
+            | ConnectionRefused
+            | ConnectionReset
+            | ConnectionAborted
+            | BrokenPipe
+            | UnexpectedEof
+    ) || error
+        .get_ref()
+        .is_some_and(|error| error.is::<Unavailable>())
+}
+
+/// Fails with [`io::ErrorKind::TimedOut`] if `future` does not complete within
+/// [`IDLE_TIMEOUT`]
+async fn idle<T>(future: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+    match timeout(IDLE_TIMEOUT, future).await {
+        Ok(result) => result,
+        Err(_) => Err(io::ErrorKind::TimedOut.into()),
+    }
+}
+
+/// How the end of a body is recognized
+enum Framing {
+    /// with the bytes left
+    Length(u64),
+    /// with the bytes left of the current chunk, none before the first one
+    Chunked(Option<u64>),
+    UntilClose,
+    Done,
+}
+
+/// The answer of a server, with the body read on demand
+pub struct Answer {
+    pub status: u16,
+    pub reason: String,
+    #[cfg_attr(not(feature = "s3"), allow(dead_code))]
+    headers: Vec<(String, String)>,
+    host: String,
+    method: String,
+    target: String,
+    connection: BufReader<TcpStream>,
+    framing: Framing,
+}
+
+impl Answer {
+    async fn read_head(
+        host: &str,
+        method: &str,
+        target: &str,
+        mut connection: BufReader<TcpStream>,
+    ) -> io::Result<Self> {
+        loop {
+            let status_line = read_line(&mut connection).await?;
+            let mut parts = status_line.splitn(3, ' ').skip(1);
+            let status: u16 = parts
+                .next()
+                .and_then(|status| status.parse().ok())
+                .ok_or_else(|| io::Error::other(format!("{host} sent no HTTP response")))?;
+            let reason = parts.next().unwrap_or_default().to_string();
+            let mut framing = Framing::UntilClose;
+            let mut headers = Vec::new();
+            loop {
+                let line = read_line(&mut connection).await?;
+                if line.is_empty() {
+                    break;
+                }
+                let Some((name, value)) = line.split_once(':') else {
+                    continue;
+                };
+                let value = value.trim();
+                if name.eq_ignore_ascii_case("content-length") {
+                    if let Ok(length) = value.parse() {
+                        framing = Framing::Length(length);
+                    }
+                } else if name.eq_ignore_ascii_case("transfer-encoding")
+                    && value.to_ascii_lowercase().contains("chunked")
+                {
+                    framing = Framing::Chunked(None);
+                }
+                headers.push((name.to_string(), value.to_string()));
+            }
+            // e.g. 100 Continue, followed by the actual answer
+            if (100..200).contains(&status) {
+                continue;
+            }
+            if matches!(status, 204 | 304) || method == "HEAD" {
+                framing = Framing::Done;
+            }
+            return Ok(Self {
+                status,
+                reason,
+                headers,
+                host: host.to_string(),
+                method: method.to_string(),
+                target: target.to_string(),
+                connection,
+                framing,
+            });
+        }
+    }
+
+    /// The value of the header `name` in the answer
+    #[cfg_attr(not(feature = "s3"), allow(dead_code))]
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Fails unless the status is one of `expected`, with [`io::ErrorKind::NotFound`] for 404
+    pub fn expect(self, expected: &[u16]) -> io::Result<Self> {
+        if expected.contains(&self.status) {
+            return Ok(self);
+        }
+        let Self {
+            host,
+            method,
+            target,
+            status,
+            reason,
+            ..
+        } = self;
+        let message = format!("{host} answered {method} {target} with {status} {reason}");
+        Err(match status {
+            404 | 410 => io::Error::new(io::ErrorKind::NotFound, message),
+            401 | 403 => io::Error::new(io::ErrorKind::PermissionDenied, message),
+            429 | 500..=599 => io::Error::other(Unavailable {
+                host,
+                status,
+                reason,
+            }),
+            _ => io::Error::other(message),
+        })
+    }
+
+    /// The next part of the body, [`None`] at its end
+    pub async fn part(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            let wanted = match self.framing {
+                Framing::Done => return Ok(None),
+                Framing::Length(0) | Framing::Chunked(Some(0)) => {
+                    if let Framing::Chunked(_) = self.framing {
+                        // the line break after the data of a chunk
+                        read_line(&mut self.connection).await?;
+                        self.framing = Framing::Chunked(None);
+                        continue;
+                    }
+                    self.framing = Framing::Done;
+                    return Ok(None);
+                }
+                Framing::Chunked(None) => {
+                    let line = read_line(&mut self.connection).await?;
+                    let size = line.split(';').next().unwrap_or_default().trim();
+                    let size = u64::from_str_radix(size, 16).map_err(|_| {
+                        io::Error::new(io::ErrorKind::InvalidData, "invalid chunk size")
+                    })?;
+                    if size == 0 {
+                        // trailers until the empty line
+                        while !read_line(&mut self.connection).await?.is_empty() {}
+                        self.framing = Framing::Done;
+                        return Ok(None);
+                    }
+                    self.framing = Framing::Chunked(Some(size));
+                    continue;
+                }
+                Framing::Length(left) | Framing::Chunked(Some(left)) => {
+                    left.min(PART_SIZE as u64) as usize
+                }
+                Framing::UntilClose => PART_SIZE,
+            };
+            let mut part = vec![0; wanted];
+            let length = idle(self.connection.read(&mut part)).await?;
+            part.truncate(length);
+            self.framing = match self.framing {
+                Framing::UntilClose if length == 0 => Framing::Done,
+                Framing::UntilClose => Framing::UntilClose,
+                _ if length == 0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Framing::Length(left) => Framing::Length(left - length as u64),
+                Framing::Chunked(Some(left)) => Framing::Chunked(Some(left - length as u64)),
+                Framing::Chunked(None) | Framing::Done => unreachable!("nothing is read"),
+            };
+            if !part.is_empty() {
+                return Ok(Some(part));
+            }
+        }
+    }
+
+    /// The whole body
+    pub async fn bytes(mut self) -> io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        while let Some(part) = self.part().await? {
+            body.extend_from_slice(&part);
+        }
+        Ok(body)
+    }
+
+    /// The bytes in `range` of the requested file as they arrive, skipping to its start unless
+    /// the server answered with only the range
+    pub fn range(self, range: Range<u64>) -> ByteStream {
+        let skip = match self.status {
+            206 => 0,
+            _ => range.start,
+        };
+        let state = (self, skip, range.end - range.start);
+        stream::try_unfold(state, |(mut answer, mut skip, left)| async move {
+            loop {
+                if left == 0 {
+                    return Ok(None);
+                }
+                let Some(mut part) = answer.part().await? else {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                };
+                let skipped = skip.min(part.len() as u64);
+                skip -= skipped;
+                part.drain(..skipped as usize);
+                part.truncate(left.min(part.len() as u64) as usize);
+                if !part.is_empty() {
+                    let left = left - part.len() as u64;
+                    return Ok(Some((Bytes::from(part), (answer, skip, left))));
+                }
+            }
+        })
+        .boxed()
+    }
+
+    pub async fn text(self) -> io::Result<String> {
+        String::from_utf8(self.bytes().await?)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "answer is not UTF-8"))
+    }
+}
+
+/// A line of the head of an answer without its line break, failing at the end of the
+/// connection
+async fn read_line(connection: &mut BufReader<TcpStream>) -> io::Result<String> {
+    let mut line = String::new();
+    if idle(connection.read_line(&mut line)).await? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
@@ -0,0 +1,162 @@
+use std::net::IpAddr;
+
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC};
+
+const HTTP_DEFAULT_PORT: u16 = 80;
+
+/// Everything but unreserved characters is escaped in path segments and query values
+pub const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Where a remote server is reached over plain HTTP, there is no TLS
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HttpUrl {
+    pub host: String,
+    pub port: u16,
+    /// path of the URL, empty or starting with `/` and without trailing slash
+    pub base_path: String,
+}
+
+impl HttpUrl {
+    /// The value of the `Host` header
+    pub fn authority(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// Parses `http://host[:port][/base-path]`, credentials have flags of their own
+pub fn parse_http_url(url: &str) -> Result<HttpUrl, String> {
+    let Some(rest) = url.strip_prefix("http://") else {
+        return Err(if url.starts_with("https://") {
+            "https:// is not supported as there is no TLS, use http:// or a TLS-terminating \
+             proxy on this machine"
+                .to_string()
+        } else {
+            "expected a URL like http://example.org/wall".to_string()
+        });
+    };
+    let (authority, base_path) = match rest.split_once('/') {
+        Some((authority, path)) => (authority, format!("/{}", path.trim_end_matches('/'))),
+        None => (rest, String::new()),
+    };
+    if authority.contains('@') {
+        return Err("pass credentials with their flags instead of in the URL".to_string());
+    }
+    let (host, port) = match authority.rsplit_once(':') {
+        // not within the brackets of an IPv6 address
+        Some((host, port)) if !port.contains(']') => (
+            host,
+            port.parse()
+                .map_err(|_| format!("expected a port number, got {port:?}"))?,
+        ),
+        _ => (authority, HTTP_DEFAULT_PORT),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err("expected a host in the URL".to_string());
+    }
+    Ok(HttpUrl {
+        host: host.to_string(),
+        port,
+        base_path: if base_path == "/" {
+            String::new()
+        } else {
+            base_path
+        },
+    })
+}
+
+/// Parses like [`parse_http_url`] but only accepts hosts on this machine, for servers that are
+/// sent credentials
+///
+/// Over the network these would travel in cleartext, remote servers are reached through a
+/// TLS tunnel listening on this machine instead.
+pub fn parse_loopback_http_url(url: &str) -> Result<HttpUrl, String> {
+    let parsed = parse_http_url(url)?;
+    let is_loopback = parsed.host.eq_ignore_ascii_case("localhost")
+        || parsed
+            .host
+            .parse::<IpAddr>()
+            .is_ok_and(|address| address.is_loopback());
+    if !is_loopback {
+        return Err(format!(
+            "{} would be sent credentials in cleartext over plain HTTP, only hosts on this \
+             machine are allowed, reach it through a TLS tunnel like stunnel listening on \
+             localhost",
+            parsed.host
+        ));
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn http_urls_are_parsed() {
+        let url = |host: &str, port, base_path: &str| {
+            Ok(HttpUrl {
+                host: host.to_string(),
+                port,
+                base_path: base_path.to_string(),
+            })
+        };
+        assert_eq!(
+            parse_http_url("http://example.org"),
+            url("example.org", 80, "")
+        );
+        assert_eq!(
+            parse_http_url("http://example.org/"),
+            url("example.org", 80, "")
+        );
+        assert_eq!(
+            parse_http_url("http://example.org:8080/wall/"),
+            url("example.org", 8080, "/wall")
+        );
+        assert_eq!(
+            parse_http_url("http://[::1]:3000/a/b"),
+            url("::1", 3000, "/a/b")
+        );
+        assert_eq!(
+            parse_http_url("http://[::1]:3000").unwrap().authority(),
+            "[::1]:3000"
+        );
+        for url in [
+            "https://example.org/wall",
+            "example.org",
+            "http://secret@example.org",
+            "http://example.org:port",
+            "http://:3000",
+        ] {
+            assert!(parse_http_url(url).is_err(), "{url}");
+        }
+    }
+
+    #[test]
+    fn only_loopback_hosts_are_sent_credentials() {
+        for url in [
+            "http://localhost:8080/dav",
+            "http://127.0.0.1",
+            "http://127.1.2.3:3000",
+            "http://[::1]:3000/wall",
+        ] {
+            assert!(parse_loopback_http_url(url).is_ok(), "{url}");
+        }
+        for url in [
+            "http://cloud.example.org/dav",
+            "http://192.168.1.10:3000",
+            "http://[2001:db8::1]",
+            "http://localhost.example.org",
+        ] {
+            assert!(parse_loopback_http_url(url).is_err(), "{url}");
+        }
+    }
+}
@@ -0,0 +1,611 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    io::Cursor,
+    path::{Component, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::Body,
+    extract::{Path, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use highway::{HighwayHash, HighwayHasher, Key};
+use httpdate::HttpDate;
+use image::{guess_format, ImageFormat, ImageReader};
+use log::{info, warn};
+use percent_encoding::percent_decode_str;
+use thiserror::Error;
+use tokio::{
+    fs::{canonicalize, File},
+    io::{self, AsyncReadExt, AsyncSeekExt, ErrorKind},
+    spawn,
+    sync::watch,
+    task::spawn_blocking,
+};
+use tokio_util::io::ReaderStream;
+
+use crate::{
+    cache::{cache_stored_image, remove_derivatives, CacheError, CacheLocks, ProcessingQueue},
+    index::{hex_hash, ImageHash, Indexer},
+    missing::MissingImages,
+    originals::content_disposition,
+    prefix::replace_path,
+    processing::blank_jpeg,
+    reload::SharedConfiguration,
+    sources::SourceRecords,
+    watcher::remove_file,
+    Configuration,
+};
+
+pub type ServeState = (
+    Arc<SharedConfiguration>,
+    Arc<Indexer>,
+    Arc<CacheLocks>,
+    Arc<SourceRecords>,
+    Arc<ProcessingQueue>,
+    Arc<PendingGenerations>,
+    Arc<MissingImages>,
+);
+
+/// Outcome of generating derivatives, shared with every request waiting for it
+type Generation = Result<(), Arc<ServeError>>;
+
+/// Generations of derivatives in progress by requested cache path, so concurrent requests for
+/// the same missing image wait for the first one instead of each checking and decoding it again
+#[derive(Default)]
+pub struct PendingGenerations(Mutex<HashMap<PathBuf, watch::Receiver<Option<Generation>>>>);
+
+impl PendingGenerations {
+    /// Runs `generate` unless a generation of `path` is in progress already, and waits for it.
+    /// It runs in its own task, so it finishes for the others if the first request goes away.
+    async fn join(
+        self: &Arc<Self>,
+        path: &std::path::Path,
+        generate: impl Future<Output = Result<(), ServeError>> + Send + 'static,
+    ) -> Generation {
+        let mut receiver = {
+            let mut pending = self.0.lock().unwrap();
+            match pending.get(path) {
+                Some(receiver) => receiver.clone(),
+                None => {
+                    let (sender, receiver) = watch::channel(None);
+                    pending.insert(path.to_path_buf(), receiver.clone());
+                    let generations = self.clone();
+                    let path = path.to_path_buf();
+                    spawn(async move {
+                        let generation = generate.await.map_err(Arc::new);
+                        // requests from here on check the cache themselves
+                        generations.0.lock().unwrap().remove(&path);
+                        sender.send_replace(Some(generation));
+                    });
+                    receiver
+                }
+            }
+        };
+        let generation = match receiver.wait_for(Option::is_some).await {
+            Ok(generation) => generation.clone().unwrap(),
+            Err(_) => Err(Arc::new(ServeError::Interrupted)),
+        };
+        generation
+    }
+}
+
+/// Serves a cached image, generating its derivatives from storage first if they are missing.
+/// Images in the index that cannot be served are answered with a placeholder instead, those
+/// missing from both are remembered for a while to answer repeated requests right away.
+/// `If-Modified-Since` is answered with 304 by the modification time of the derivative, unless
+/// `If-None-Match` is given, which [`tag_images`] answers.
+pub async fn serve_and_cache(
+    State((configuration, indexer, locks, sources, queue, generations, missing)): State<ServeState>,
+    Path(path): Path<PathBuf>,
+    headers: HeaderMap,
+) -> Result<Response, ServeError> {
+    let configuration = configuration.load();
+    if !path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(ServeError::NotFound);
+    }
+    let original_path = configuration
+        .cache_layout
+        .original_path(&path)
+        .ok_or(ServeError::NotFound)?;
+    if missing.contains(&configuration, &original_path) {
+        return Err(ServeError::SourceMissing);
+    }
+    let generation = generate(
+        configuration.clone(),
+        locks.clone(),
+        sources.clone(),
+        queue,
+        path.clone(),
+        original_path.clone(),
+    );
+    let result = match generations.join(&path, generation).await {
+        Ok(()) => serve(&configuration, &path, &headers).await,
+        Err(error) => Err(error.into()),
+    };
+    let error = match result {
+        Ok(response) => return Ok(response),
+        Err(error) => error,
+    };
+    if !error.is_broken_image() {
+        return Err(error);
+    }
+    if !is_indexed(&indexer, &original_path).await {
+        if error.is_source_missing() {
+            missing.insert(&configuration, &original_path);
+        }
+        return Err(error);
+    }
+    warn!(
+        "serving a placeholder for {}: {error}",
+        original_path.display()
+    );
+    let original = configuration.originals.read(&original_path).await;
+    if original
+        .as_ref()
+        .is_err_and(|error| error.kind() == ErrorKind::NotFound)
+    {
+        // the watcher missed the deletion, kiosks stop asking once it is gone from the index
+        let configuration = configuration.clone();
+        spawn(async move {
+            match remove_file(&configuration, &indexer, &locks, &sources, &original_path).await {
+                Ok(_) => info!("removed {} missing from storage", original_path.display()),
+                Err(error) => warn!(
+                    "failed to remove {} missing from storage: {error}",
+                    original_path.display()
+                ),
+            }
+        });
+    }
+    serve_placeholder(&configuration, &path, original.ok()).await
+}
+
+/// Serves the existing derivative at `path` relative to the cache directory
+async fn serve(
+    configuration: &Configuration,
+    path: &std::path::Path,
+    headers: &HeaderMap,
+) -> Result<Response, ServeError> {
+    // removed since it was generated, e.g. because its original was just deleted
+    let vanished = |error| match error {
+        ServeError::NotFound => ServeError::Vanished,
+        error => error,
+    };
+    let cached_path = resolve_within(&configuration.cache, &configuration.cache.join(path))
+        .await
+        .map_err(vanished)?;
+    let mut file = File::open(cached_path)
+        .await
+        .map_err(|error| vanished(not_found_or(error)))?;
+    let metadata = file.metadata().await?;
+    // whole seconds like the header, so the time sent compares equal when it comes back
+    let last_modified = HttpDate::from(metadata.modified()?);
+    let last_modified_header = HeaderValue::from_str(&last_modified.to_string()).unwrap();
+    let modified_since = headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<HttpDate>().ok());
+    if let Some(since) = modified_since {
+        if !headers.contains_key(header::IF_NONE_MATCH) && last_modified <= since {
+            return Ok((
+                StatusCode::NOT_MODIFIED,
+                [(header::LAST_MODIFIED, last_modified_header)],
+            )
+                .into_response());
+        }
+    }
+    // derivatives passed through or left from other settings need not match the cache format
+    let mut signature = [0; 16];
+    let length = file.read(&mut signature).await?;
+    file.rewind().await?;
+    let format = guess_format(&signature[..length])
+        .map(EncodedFormat)
+        .unwrap_or(EncodedFormat(
+            configuration.cache_layout.format.image_format(),
+        ));
+    let mut response = (
+        [
+            (header::CONTENT_LENGTH, HeaderValue::from(metadata.len())),
+            (header::LAST_MODIFIED, last_modified_header),
+        ],
+        Body::from_stream(ReaderStream::new(file)),
+    )
+        .into_response();
+    response.extensions_mut().insert(format);
+    Ok(response)
+}
+
+/// Actual format of a served derivative, for [`label_images`]
+#[derive(Clone, Copy)]
+struct EncodedFormat(ImageFormat);
+
+/// Sets the content type of served derivatives, which `ServeDir` guesses from the extension
+/// although derivatives in JPEG keep the names of their originals, e.g. `.png`. Adds an inline
+/// `Content-Disposition` with the name of the original and a matching extension for saving it.
+pub async fn label_images(
+    State(configuration): State<Arc<SharedConfiguration>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let configuration = configuration.load();
+    let path = percent_decode_str(request.uri().path().trim_start_matches('/'))
+        .decode_utf8_lossy()
+        .into_owned();
+    let mut response = next.run(request).await;
+    if !response.status().is_success() {
+        return response;
+    }
+    let EncodedFormat(format) = response
+        .extensions()
+        .get::<EncodedFormat>()
+        .copied()
+        .unwrap_or(EncodedFormat(
+            configuration.cache_layout.format.image_format(),
+        ));
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(format.to_mime_type()),
+    );
+    let file_name = configuration
+        .cache_layout
+        .original_path(std::path::Path::new(&path))
+        .and_then(|original_path| {
+            let stem = original_path.file_stem()?.to_str()?.to_string();
+            Some(format!("{stem}.{}", format.extensions_str()[0]))
+        });
+    if let Some(value) = file_name.and_then(|name| content_disposition("inline", &name)) {
+        headers.insert(header::CONTENT_DISPOSITION, value);
+    }
+    response
+}
+
+/// Generates the derivatives of `original_path` that are missing or outdated, together with the
+/// one at `path` if it is only generated on demand
+async fn generate(
+    configuration: Arc<Configuration>,
+    locks: Arc<CacheLocks>,
+    sources: Arc<SourceRecords>,
+    queue: Arc<ProcessingQueue>,
+    path: PathBuf,
+    original_path: PathBuf,
+) -> Result<(), ServeError> {
+    let originals = configuration.originals.as_ref();
+    let source_missing = |error| match error {
+        ServeError::NotFound => ServeError::SourceMissing,
+        error => error,
+    };
+    // symbolic links in a local directory must not reach outside of it
+    if let Some(directory) = originals.local_directory() {
+        resolve_within(directory, &directory.join(&original_path))
+            .await
+            .map_err(source_missing)?;
+    }
+    let _guard = locks.lock(&original_path).await;
+    let requested = configuration.cache.join(&path);
+    let mut derivatives = configuration.derivatives(&original_path);
+    derivatives.extend(
+        configuration
+            .on_demand_derivatives(&original_path)
+            .into_iter()
+            .filter(|derivative| derivative.destination == requested),
+    );
+    let fingerprint = originals
+        .stat(&original_path)
+        .await
+        .map_err(|error| source_missing(not_found_or(error)))?;
+    if !sources.matches(&original_path, fingerprint) {
+        let hash = originals.hash(&original_path).await?;
+        if !sources.is_current(&original_path, fingerprint, hash) {
+            remove_derivatives(&configuration.all_derivatives(&original_path)).await?;
+            sources.record(&original_path, fingerprint, hash);
+        }
+        sources.save().await?;
+    }
+    cache_stored_image(
+        originals,
+        &original_path,
+        &configuration.cache,
+        &derivatives,
+        &configuration.processing_options(),
+        &queue,
+        false,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Whether `path` relative to the storage directory is in the index, as image or alias
+async fn is_indexed(indexer: &Indexer, path: &std::path::Path) -> bool {
+    indexer.index_including_hidden().await.is_ok_and(|images| {
+        images
+            .iter()
+            .any(|image| image.path == path || image.aliases.iter().any(|alias| alias == path))
+    })
+}
+
+/// Answers with a blank JPEG in --placeholder-color instead of a broken image on the wall, with
+/// the aspect ratio of the source if its header can still be read
+async fn serve_placeholder(
+    configuration: &Configuration,
+    path: &std::path::Path,
+    original: Option<Vec<u8>>,
+) -> Result<Response, ServeError> {
+    let requested = configuration.cache.join(path);
+    let max_size = configuration
+        .all_derivatives(
+            &configuration
+                .cache_layout
+                .original_path(path)
+                .unwrap_or_default(),
+        )
+        .into_iter()
+        .find(|derivative| derivative.destination == requested)
+        .map_or(configuration.cache_layout.max_size, |derivative| {
+            derivative.max_size
+        });
+    let color = configuration.placeholder_color;
+    let quality = configuration.jpeg_image_quality;
+    let encoded_image = spawn_blocking(move || {
+        let (width, height) = original
+            .and_then(|original| {
+                ImageReader::new(Cursor::new(original))
+                    .with_guessed_format()
+                    .ok()
+            })
+            .and_then(|reader| reader.into_dimensions().ok())
+            .unwrap_or((max_size, max_size));
+        // like resizing, never larger than the source
+        let scale = (max_size as f64 / width.max(height).max(1) as f64).min(1.0);
+        let width = ((width as f64 * scale).round() as u32).max(1);
+        let height = ((height as f64 * scale).round() as u32).max(1);
+        blank_jpeg(width, height, color, quality)
+    })
+    .await
+    .map_err(|_| ServeError::Interrupted)?
+    .map_err(CacheError::Encode)?;
+    let mut response = (
+        // shown until the image is fixed or gone, never in place of it
+        [(header::CACHE_CONTROL, "no-store")],
+        encoded_image,
+    )
+        .into_response();
+    response
+        .extensions_mut()
+        .insert(EncodedFormat(ImageFormat::Jpeg));
+    Ok(response)
+}
+
+/// Parses a `--placeholder-color` like `#808080`
+pub fn parse_color(color: &str) -> Result<[u8; 3], String> {
+    let error = || format!("expected a color like #808080, got {color:?}");
+    let hex = color.strip_prefix('#').ok_or_else(error)?;
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err(error());
+    }
+    let channel = |index: usize| u8::from_str_radix(&hex[index..index + 2], 16);
+    match (channel(0), channel(2), channel(4)) {
+        (Ok(red), Ok(green), Ok(blue)) => Ok([red, green, blue]),
+        _ => Err(error()),
+    }
+}
+
+/// Not found for missing files, whose path is reported instead, any other error as it is
+fn not_found_or(error: io::Error) -> ServeError {
+    match error.kind() {
+        ErrorKind::NotFound => ServeError::NotFound,
+        _ => ServeError::Io(error),
+    }
+}
+
+/// Resolves symbolic links in `path`, not found unless it exists and stays inside `root`
+pub async fn resolve_within(
+    root: &std::path::Path,
+    path: &std::path::Path,
+) -> Result<PathBuf, ServeError> {
+    let resolved = canonicalize(path).await.map_err(not_found_or)?;
+    if resolved.starts_with(canonicalize(root).await?) {
+        Ok(resolved)
+    } else {
+        Err(ServeError::NotFound)
+    }
+}
+
+/// Answers 404 for paths with a segment starting with a dot, e.g. the bookkeeping in
+/// `.moments/` sharing the cache directory with derivatives. Storage never indexes such files.
+pub async fn refuse_hidden_paths(request: Request, next: Next) -> Response {
+    let path = percent_decode_str(request.uri().path())
+        .decode_utf8_lossy()
+        .into_owned();
+    if path.split('/').any(|segment| segment.starts_with('.')) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    next.run(request).await
+}
+
+/// Rewrites requests for `?w=<size>` to the derivative with that size, answering 400 for sizes
+/// not in --cache-sizes or --on-demand-sizes so arbitrary sizes cannot fill the cache
+pub async fn select_size(
+    State(configuration): State<Arc<SharedConfiguration>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let configuration = configuration.load();
+    let Some(size) = request.uri().query().and_then(|query| {
+        query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("w="))
+            .map(str::to_string)
+    }) else {
+        return next.run(request).await;
+    };
+    let path = request.uri().path().trim_start_matches('/').to_string();
+    let Some(variant_path) = size
+        .parse()
+        .ok()
+        .and_then(|size| {
+            configuration
+                .cache_layout
+                .variant_path(std::path::Path::new(&path), size)
+        })
+        .and_then(|variant_path| variant_path.to_str().map(str::to_string))
+    else {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("unsupported size {size:?}"),
+        )
+            .into_response();
+    };
+    replace_path(&mut request, &format!("/{variant_path}"));
+    next.run(request).await
+}
+
+pub type TagState = (Arc<SharedConfiguration>, Arc<SourceRecords>);
+
+/// Adds strong ETags to served derivatives and answers matching `If-None-Match` requests with
+/// 304 without reading the file. Tags combine the hash of the source with the cache settings, so
+/// they change whenever the derivative does. Derivatives of sources still unrecorded after
+/// serving them are not tagged.
+pub async fn tag_images(
+    State((configuration, sources)): State<TagState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let configuration = configuration.load();
+    let path = percent_decode_str(request.uri().path().trim_start_matches('/'))
+        .decode_utf8_lossy()
+        .into_owned();
+    let tag_of = |path: &str| {
+        configuration
+            .cache_layout
+            .original_path(std::path::Path::new(path))
+            .and_then(|original_path| sources.hash(&original_path))
+            .and_then(|hash| entity_tag(&configuration, hash))
+    };
+    let Some(tag) = tag_of(&path) else {
+        let mut response = next.run(request).await;
+        // sources are recorded while generating their first derivative
+        if let Some(tag) = tag_of(&path).filter(|_| response.status().is_success()) {
+            insert_tag(&mut response, &tag);
+        }
+        return response;
+    };
+    let matches = request
+        .headers()
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|candidate| {
+            let candidate = candidate.trim();
+            candidate == "*" || candidate.trim_start_matches("W/") == tag
+        });
+    let mut response = if matches {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        next.run(request).await
+    };
+    if response.status().is_success() || response.status() == StatusCode::NOT_MODIFIED {
+        insert_tag(&mut response, &tag);
+    }
+    response
+}
+
+fn insert_tag(response: &mut Response, tag: &str) {
+    if let Ok(value) = HeaderValue::from_str(tag) {
+        response.headers_mut().insert(header::ETAG, value);
+    }
+}
+
+fn entity_tag(configuration: &Configuration, hash: ImageHash) -> Option<String> {
+    let settings = serde_json::to_vec(&configuration.cache_settings()).ok()?;
+    let settings_hash = HighwayHasher::new(Key([1, 3, 3, 7])).hash64(&settings);
+    Some(format!(
+        "\"{}-{settings_hash:016x}\"",
+        hex_hash::to_string(&hash)
+    ))
+}
+
+#[derive(Debug, Error)]
+pub enum ServeError {
+    #[error("image not found")]
+    NotFound,
+    #[error("original image is missing from storage")]
+    SourceMissing,
+    #[error("cached image vanished while serving it")]
+    Vanished,
+    #[error(transparent)]
+    Cache(#[from] CacheError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("generating the image was interrupted")]
+    Interrupted,
+    /// failure of a generation other requests waited for as well
+    #[error(transparent)]
+    Shared(#[from] Arc<ServeError>),
+}
+
+impl ServeError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ServeError::NotFound | ServeError::SourceMissing | ServeError::Vanished => {
+                StatusCode::NOT_FOUND
+            }
+            ServeError::Cache(CacheError::DestinationWrite { source, .. })
+            | ServeError::Io(source)
+                if is_out_of_space(source) =>
+            {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ServeError::Cache(_) | ServeError::Io(_) | ServeError::Interrupted => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            ServeError::Shared(error) => error.status(),
+        }
+    }
+
+    /// Whether the image itself is at fault rather than the cache, so a placeholder is served
+    fn is_broken_image(&self) -> bool {
+        match self {
+            ServeError::SourceMissing => true,
+            ServeError::Cache(error) => matches!(
+                error,
+                CacheError::SourceRead { .. }
+                    | CacheError::Decode(_)
+                    | CacheError::TimedOut { .. }
+                    | CacheError::Panicked
+            ),
+            ServeError::Shared(error) => error.is_broken_image(),
+            _ => false,
+        }
+    }
+
+    fn is_source_missing(&self) -> bool {
+        match self {
+            ServeError::SourceMissing => true,
+            ServeError::Shared(error) => error.is_source_missing(),
+            _ => false,
+        }
+    }
+}
+
+/// Full disks and exceeded quotas, which go away once space is freed
+fn is_out_of_space(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::StorageFull | ErrorKind::QuotaExceeded
+    )
+}
+
+impl IntoResponse for ServeError {
+    fn into_response(self) -> Response {
+        (self.status(), self.to_string()).into_response()
+    }
+}
@@ -0,0 +1,98 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    path::Path,
+};
+
+use log::{info, warn};
+use time::OffsetDateTime;
+use tokio::{
+    fs::{metadata, read_dir},
+    io,
+};
+
+use crate::{
+    cache::ProcessingQueue,
+    index::{hex_hash, IndexError, Indexer},
+    sources::SourceRecords,
+    upload::{store_upload, stored_file_name, UploadError},
+    Configuration,
+};
+
+/// How an import went, failures are logged one by one
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    pub imported: usize,
+    pub duplicates: usize,
+    pub failed: usize,
+}
+
+/// E.g. `imported 198 images, skipped 2 duplicates, 0 failed`
+impl Display for ImportSummary {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            formatter,
+            "imported {} images, skipped {} duplicates, {} failed",
+            self.imported, self.duplicates, self.failed
+        )
+    }
+}
+
+/// Stores the files directly in `directory` one after another by name like uploads, named with
+/// the time of the import and with all derivatives generated. Images already in storage are
+/// skipped as duplicates, files that are no images count as failed.
+pub async fn import_directory(
+    configuration: &Configuration,
+    indexer: &Indexer,
+    sources: &SourceRecords,
+    queue: &ProcessingQueue,
+    directory: &Path,
+) -> Result<ImportSummary, io::Error> {
+    let mut files = Vec::new();
+    let mut entries = read_dir(directory).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+            warn!("skipping {}, its name is not UTF-8", entry.path().display());
+            continue;
+        };
+        // following symbolic links to files, like storage does
+        if !name.starts_with('.') && metadata(entry.path()).await?.is_file() {
+            files.push(name);
+        }
+    }
+    files.sort();
+
+    let mut summary = ImportSummary::default();
+    for name in files {
+        let now = OffsetDateTime::now_utc();
+        let file_name = stored_file_name(now, Some(&name));
+        let result = store_upload(
+            configuration,
+            indexer,
+            sources,
+            queue,
+            &directory.join(&name),
+            &file_name,
+            now,
+        )
+        .await;
+        match result {
+            Ok(hash) => {
+                summary.imported += 1;
+                info!(
+                    image = file_name.as_str(),
+                    hash = hex_hash::to_string(&hash).as_str();
+                    "imported {name}"
+                );
+            }
+            Err(UploadError::Index(IndexError::Duplicate { path })) => {
+                summary.duplicates += 1;
+                info!("skipped {name}, it is in storage as {}", path.display());
+            }
+            Err(error) => {
+                summary.failed += 1;
+                warn!("failed to import {name}: {error}");
+            }
+        }
+    }
+    Ok(summary)
+}
@@ -1,164 +1,987 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
-    fs::read_dir,
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
     path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::Result;
 use highway::{HighwayHash, HighwayHasher, Key};
+use log::{error, warn};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use time::OffsetDateTime;
 use tokio::{
     fs::read,
     io, spawn,
-    sync::{
-        broadcast::{self, error::SendError},
-        mpsc, oneshot,
-    },
+    sync::{broadcast, mpsc, oneshot},
     task::spawn_blocking,
 };
 
+use crate::{
+    cache::CacheLayout, capture::CaptureMetadata, placeholder::Placeholder, sidecars::is_sidecar,
+    sources::SourceRecords, storage::Storage,
+};
+
+/// Number of changes remembered for subscribers catching up after a reconnect
+const RECENT_CHANGES: usize = 1000;
+
+/// Number of changes a subscriber may fall behind before it lags, well above what a batch of
+/// uploads or reactions produces
+const CHANGE_CAPACITY: usize = 1024;
+
+/// How much more likely than old images a just created one is picked with freshness weighting
+const FRESHNESS_BOOST: f64 = 10.0;
+
+/// Age after which the freshness boost of an image has halved
+const FRESHNESS_HALF_LIFE: time::Duration = time::Duration::hours(1);
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub struct Image {
+    /// content hash, serialized as 32 hexadecimal digits
+    #[serde(with = "hex_hash")]
+    pub hash: ImageHash,
+    /// canonical path, the lexicographically smallest of all paths with this content
     pub path: PathBuf,
+    /// further paths with byte-identical content, sorted
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<PathBuf>,
+    /// upload time, or modification time for images found in storage
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    #[serde(default)]
+    pub capture: CaptureMetadata,
+    /// path of the default derivative relative to the images route
+    #[serde(default)]
+    pub cached_path: PathBuf,
+    /// paths of the additional cached sizes relative to the images route, by maximum size
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub derivatives: BTreeMap<u32, PathBuf>,
+    /// blurhash and average color to show while the image loads, missing until the image was
+    /// first cached
+    #[serde(flatten)]
+    pub placeholder: Option<Placeholder>,
+    /// kept prominent on the kiosks by an organizer
+    #[serde(default)]
+    pub pinned: bool,
+    /// kept from the kiosks by an organizer, only listed for admins
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub hidden: bool,
+    /// how often guests reacted with each emoji
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub reactions: BTreeMap<String, u64>,
+    /// created less than `--fresh-window` before the index or change was sent, for kiosks to
+    /// welcome it with an animation
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub fresh: bool,
+}
+
+/// What organizers can set per image, persisted with the source records
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageFlag {
+    Pinned,
+    Hidden,
+}
+
+impl Image {
+    pub fn new(
+        hash: ImageHash,
+        path: PathBuf,
+        created_at: OffsetDateTime,
+        capture: CaptureMetadata,
+    ) -> Self {
+        Self {
+            hash,
+            path,
+            aliases: Vec::new(),
+            created_at,
+            capture,
+            cached_path: PathBuf::new(),
+            derivatives: BTreeMap::new(),
+            placeholder: None,
+            pinned: false,
+            hidden: false,
+            reactions: BTreeMap::new(),
+            fresh: false,
+        }
+    }
+
+    fn set_flag(&mut self, flag: ImageFlag, value: bool) {
+        match flag {
+            ImageFlag::Pinned => self.pinned = value,
+            ImageFlag::Hidden => self.hidden = value,
+        }
+    }
+
+    fn attach_derivatives(&mut self, cache_layout: &CacheLayout) {
+        self.cached_path = cache_layout.cached_path(&self.path);
+        self.derivatives = cache_layout
+            .derivative_paths(&self.path)
+            .into_iter()
+            .skip(1)
+            .collect();
+    }
+
+    /// Adds another path with the same content, keeping the smallest path canonical, returns
+    /// whether it was not known yet
+    fn add_alias(&mut self, path: PathBuf) -> bool {
+        if path == self.path || self.aliases.contains(&path) {
+            return false;
+        }
+        if path < self.path {
+            let previous = std::mem::replace(&mut self.path, path);
+            self.aliases.push(previous);
+        } else {
+            self.aliases.push(path);
+        }
+        self.aliases.sort();
+        true
+    }
 }
 
 enum Command {
     AddImage {
         hash: ImageHash,
-        image: Image,
+        image: Box<Image>,
         response: oneshot::Sender<Result<(), IndexError>>,
     },
+    AddAlias {
+        hash: ImageHash,
+        path: PathBuf,
+        response: oneshot::Sender<Option<Image>>,
+    },
     GetIndex {
+        recent_limit: Option<usize>,
+        include_hidden: bool,
         response: oneshot::Sender<Vec<Image>>,
     },
+    Subscribe {
+        recent_limit: Option<usize>,
+        since: Option<u64>,
+        response: oneshot::Sender<Subscription>,
+    },
+    SetPlaceholder {
+        hash: ImageHash,
+        placeholder: Placeholder,
+    },
+    SetFreshWindow {
+        window: Duration,
+    },
+    SetFlag {
+        hash: ImageHash,
+        flag: ImageFlag,
+        value: bool,
+        response: oneshot::Sender<Option<Image>>,
+    },
+    React {
+        hash: ImageHash,
+        emoji: String,
+        response: oneshot::Sender<Option<Image>>,
+    },
+    PickRandom {
+        fresh: bool,
+        excluded: HashSet<ImageHash>,
+        response: oneshot::Sender<Option<Image>>,
+    },
+    RemoveImage {
+        hash: ImageHash,
+        response: oneshot::Sender<Option<Image>>,
+    },
+    RemovePath {
+        path: PathBuf,
+        response: oneshot::Sender<Option<Image>>,
+    },
+}
+
+/// All indexed images with a secondary ordering by creation time for "newest N" queries
+struct Index {
+    images: HashMap<ImageHash, Image>,
+    by_creation: BTreeSet<(OffsetDateTime, ImageHash)>,
+    /// incremented by every change, starts at the startup time in microseconds so revisions of
+    /// earlier runs are never mistaken for current ones
+    revision: u64,
+    /// the last [`RECENT_CHANGES`] changes for subscribers catching up
+    recent: VecDeque<RevisedChange>,
+    /// how long after their creation images are marked fresh
+    fresh_window: Duration,
+}
+
+impl Index {
+    fn new(
+        mut images: HashMap<ImageHash, Image>,
+        cache_layout: &CacheLayout,
+        sources: &SourceRecords,
+        fresh_window: Duration,
+    ) -> Self {
+        for image in images.values_mut() {
+            image.attach_derivatives(cache_layout);
+            image.placeholder = sources.placeholder(&image.path);
+            image.pinned = sources.flag(image.hash, ImageFlag::Pinned);
+            image.hidden = sources.flag(image.hash, ImageFlag::Hidden);
+            image.reactions = sources.reactions(image.hash);
+        }
+        let by_creation = images
+            .iter()
+            .map(|(hash, image)| (image.created_at, *hash))
+            .collect();
+        Self {
+            images,
+            by_creation,
+            revision: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_micros() as u64,
+            recent: VecDeque::with_capacity(RECENT_CHANGES),
+            fresh_window,
+        }
+    }
+
+    /// A copy of the image as handed out, marked fresh if created within the fresh window
+    fn handed_out(&self, image: &Image) -> Image {
+        let mut image = image.clone();
+        image.fresh = OffsetDateTime::now_utc() - image.created_at < self.fresh_window;
+        image
+    }
+
+    /// Advances the revision and remembers the change, returns it as broadcast to subscribers
+    fn record(&mut self, mut change: Change) -> RevisedChange {
+        let (Change::Addition { image } | Change::Removal { image } | Change::Update { image }) =
+            &mut change;
+        *image = self.handed_out(image);
+        self.revision += 1;
+        let change = RevisedChange {
+            revision: self.revision,
+            change,
+        };
+        if self.recent.len() == RECENT_CHANGES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(change.clone());
+        change
+    }
+
+    /// Removes the image and records its removal
+    fn remove(&mut self, hash: ImageHash) -> Option<(Image, RevisedChange)> {
+        let image = self.images.remove(&hash)?;
+        self.by_creation.remove(&(image.created_at, hash));
+        let change = self.record(Change::Removal {
+            image: image.clone(),
+        });
+        Some((image, change))
+    }
+
+    /// A random image not `excluded`, more likely the newer with `fresh`, by weighted reservoir
+    /// sampling in a single pass without copying the index
+    fn pick_random(&self, fresh: bool, excluded: &HashSet<ImageHash>) -> Option<&Image> {
+        let now = OffsetDateTime::now_utc();
+        let mut total = 0.0;
+        let mut picked = None;
+        for image in self.images.values() {
+            if image.hidden || excluded.contains(&image.hash) {
+                continue;
+            }
+            let weight = if fresh {
+                let half_lives = ((now - image.created_at) / FRESHNESS_HALF_LIFE).max(0.0);
+                1.0 + FRESHNESS_BOOST * 0.5_f64.powf(half_lives)
+            } else {
+                1.0
+            };
+            total += weight;
+            if fastrand::f64() * total < weight {
+                picked = Some(image);
+            }
+        }
+        picked
+    }
+
+    /// Changes after revision `since`, `None` if some of them are no longer remembered or `since`
+    /// is not a revision of this run
+    fn changes_since(&self, since: u64) -> Option<Vec<RevisedChange>> {
+        let missed = self.revision.checked_sub(since)?;
+        if missed > self.recent.len() as u64 {
+            return None;
+        }
+        Some(
+            self.recent
+                .iter()
+                .filter(|change| change.revision > since)
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// Images shown on the kiosks, optionally the newest `recent_limit` of them, with
+    /// `include_hidden` all of them
+    fn images(&self, recent_limit: Option<usize>, include_hidden: bool) -> Vec<Image> {
+        let listed = |image: &&Image| include_hidden || !image.hidden;
+        match recent_limit {
+            Some(limit) => self
+                .by_creation
+                .iter()
+                .rev()
+                .map(|(_, hash)| &self.images[hash])
+                .filter(listed)
+                .take(limit)
+                .map(|image| self.handed_out(image))
+                .collect(),
+            None => self
+                .images
+                .values()
+                .filter(listed)
+                .map(|image| self.handed_out(image))
+                .collect(),
+        }
+    }
 }
 
 pub struct Indexer {
-    pub change_receiver: broadcast::Receiver<Change>,
     command_sender: mpsc::Sender<Command>,
 }
 
 impl Indexer {
-    pub async fn spawn(directory: impl AsRef<Path>) -> Result<Self> {
-        let directory = directory.as_ref().to_owned();
-        let (change_sender, change_receiver) = broadcast::channel::<Change>(10);
+    pub async fn spawn(
+        storage: &dyn Storage,
+        cache_layout: CacheLayout,
+        sources: &SourceRecords,
+        fresh_window: Duration,
+    ) -> Result<Self> {
+        let (change_sender, _) = broadcast::channel::<RevisedChange>(CHANGE_CAPACITY);
         let (command_sender, mut command_receiver) = mpsc::channel(10);
+        let mut index = Index::new(
+            collect_images(storage).await?,
+            &cache_layout,
+            sources,
+            fresh_window,
+        );
 
         spawn({
             async move {
-                let mut images = collect_images(&directory).await.unwrap();
                 while let Some(command) = command_receiver.recv().await {
                     match command {
                         Command::AddImage {
                             hash,
-                            image,
+                            mut image,
                             response,
-                        } => match images.entry(hash) {
+                        } => match index.images.entry(hash) {
                             Entry::Vacant(entry) => {
-                                entry.insert(image.clone());
-                                change_sender.send(Change::Addition { image }).unwrap();
-                                response.send(Ok(())).unwrap();
+                                image.attach_derivatives(&cache_layout);
+                                index.by_creation.insert((image.created_at, hash));
+                                entry.insert(*image.clone());
+                                let change = index.record(Change::Addition { image: *image });
+                                // without subscribers there is nobody to tell
+                                let _ = change_sender.send(change);
+                                // the requester may have gone away in the meantime, e.g. a closed
+                                // HTTP connection, which must not take down the indexer
+                                let _ = response.send(Ok(()));
                             }
                             Entry::Occupied(entry) => {
-                                response
-                                    .send(Err(IndexError::Duplicate {
-                                        path: entry.get().path.clone(),
-                                    }))
-                                    .unwrap();
+                                let _ = response.send(Err(IndexError::Duplicate {
+                                    path: entry.get().path.clone(),
+                                }));
                             }
                         },
-                        Command::GetIndex { response } => {
-                            response.send(images.values().cloned().collect()).unwrap();
+                        Command::AddAlias {
+                            hash,
+                            path,
+                            response,
+                        } => {
+                            let Some(image) = index.images.get_mut(&hash) else {
+                                let _ = response.send(None);
+                                continue;
+                            };
+                            let added = image.add_alias(path);
+                            // the alias may have become canonical
+                            image.attach_derivatives(&cache_layout);
+                            let image = image.clone();
+                            if added && !image.hidden {
+                                let change = index.record(Change::Update {
+                                    image: image.clone(),
+                                });
+                                let _ = change_sender.send(change);
+                            }
+                            let _ = response.send(Some(image));
+                        }
+                        Command::GetIndex {
+                            recent_limit,
+                            include_hidden,
+                            response,
+                        } => {
+                            let _ = response.send(index.images(recent_limit, include_hidden));
+                        }
+                        Command::Subscribe {
+                            recent_limit,
+                            since,
+                            response,
+                        } => {
+                            // catch-up and subscription are taken between the same two commands,
+                            // so every change is either part of the catch-up or received later
+                            let catchup = match since.and_then(|since| index.changes_since(since)) {
+                                Some(changes) => Catchup::Changes(changes),
+                                None => Catchup::Snapshot(index.images(recent_limit, false)),
+                            };
+                            let _ = response.send(Subscription {
+                                catchup,
+                                revision: index.revision,
+                                changes: change_sender.subscribe(),
+                            });
+                        }
+                        Command::SetPlaceholder { hash, placeholder } => {
+                            // clients pick it up with the next index, a missing placeholder only
+                            // means a plain background while loading
+                            if let Some(image) = index.images.get_mut(&hash) {
+                                image.placeholder = Some(placeholder);
+                            }
+                        }
+                        Command::SetFreshWindow { window } => index.fresh_window = window,
+                        Command::SetFlag {
+                            hash,
+                            flag,
+                            value,
+                            response,
+                        } => {
+                            let Some(image) = index.images.get_mut(&hash) else {
+                                let _ = response.send(None);
+                                continue;
+                            };
+                            let was_hidden = image.hidden;
+                            image.set_flag(flag, value);
+                            let image = image.clone();
+                            // kiosks only ever see images that are not hidden
+                            let change = match (was_hidden, image.hidden) {
+                                (false, true) => Some(Change::Removal {
+                                    image: image.clone(),
+                                }),
+                                (true, false) => Some(Change::Addition {
+                                    image: image.clone(),
+                                }),
+                                (false, false) => Some(Change::Update {
+                                    image: image.clone(),
+                                }),
+                                (true, true) => None,
+                            };
+                            if let Some(change) = change {
+                                let change = index.record(change);
+                                let _ = change_sender.send(change);
+                            }
+                            let _ = response.send(Some(image));
+                        }
+                        Command::React {
+                            hash,
+                            emoji,
+                            response,
+                        } => {
+                            // hidden images cannot be seen, let alone reacted to
+                            let Some(image) =
+                                index.images.get_mut(&hash).filter(|image| !image.hidden)
+                            else {
+                                let _ = response.send(None);
+                                continue;
+                            };
+                            *image.reactions.entry(emoji).or_default() += 1;
+                            let image = image.clone();
+                            let change = index.record(Change::Update {
+                                image: image.clone(),
+                            });
+                            let _ = change_sender.send(change);
+                            let _ = response.send(Some(image));
+                        }
+                        Command::PickRandom {
+                            fresh,
+                            excluded,
+                            response,
+                        } => {
+                            // everything excluded only means a repeat is unavoidable
+                            let image = index
+                                .pick_random(fresh, &excluded)
+                                .or_else(|| index.pick_random(fresh, &HashSet::new()));
+                            let _ = response.send(image.map(|image| index.handed_out(image)));
+                        }
+                        Command::RemoveImage { hash, response } => {
+                            let image = index.remove(hash).map(|(image, change)| {
+                                let _ = change_sender.send(change);
+                                image
+                            });
+                            let _ = response.send(image);
+                        }
+                        Command::RemovePath { path, response } => {
+                            let hash = index
+                                .images
+                                .values()
+                                .find(|image| image.path == path || image.aliases.contains(&path))
+                                .map(|image| image.hash);
+                            let removed = match hash {
+                                Some(hash)
+                                    if index.images[&hash].path == path
+                                        && index.images[&hash].aliases.is_empty() =>
+                                {
+                                    index.remove(hash).map(|(image, change)| {
+                                        let _ = change_sender.send(change);
+                                        image
+                                    })
+                                }
+                                // the smallest remaining alias becomes canonical in place, so
+                                // clients update the image, its derivatives are generated on
+                                // demand
+                                Some(hash) if index.images[&hash].path == path => {
+                                    let image = index.images.get_mut(&hash).unwrap();
+                                    let removed = image.clone();
+                                    image.path = image.aliases.remove(0);
+                                    image.attach_derivatives(&cache_layout);
+                                    if !image.hidden {
+                                        let image = image.clone();
+                                        let change = index.record(Change::Update { image });
+                                        let _ = change_sender.send(change);
+                                    }
+                                    Some(removed)
+                                }
+                                Some(hash) => {
+                                    let image = index.images.get_mut(&hash).unwrap();
+                                    image.aliases.retain(|alias| alias != &path);
+                                    if !image.hidden {
+                                        let image = image.clone();
+                                        let change = index.record(Change::Update { image });
+                                        let _ = change_sender.send(change);
+                                    }
+                                    None
+                                }
+                                None => None,
+                            };
+                            let _ = response.send(removed);
                         }
                     }
                 }
             }
         });
-        Ok(Self {
-            change_receiver,
-            command_sender,
-        })
+        Ok(Self { command_sender })
     }
 
-    pub async fn index(&self) -> Vec<Image> {
+    /// Returns all images shown on the kiosks or, with a `recent_limit`, only the newest ones by
+    /// creation time
+    pub async fn index(&self, recent_limit: Option<usize>) -> Result<Vec<Image>, IndexerGone> {
+        self.get_index(recent_limit, false).await
+    }
+
+    /// Returns all images including hidden ones, for admins and for maintaining the cache
+    pub async fn index_including_hidden(&self) -> Result<Vec<Image>, IndexerGone> {
+        self.get_index(None, true).await
+    }
+
+    async fn get_index(
+        &self,
+        recent_limit: Option<usize>,
+        include_hidden: bool,
+    ) -> Result<Vec<Image>, IndexerGone> {
         let (sender, receiver) = oneshot::channel();
-        self.command_sender
-            .send(Command::GetIndex { response: sender })
-            .await
-            .unwrap();
-        receiver.await.unwrap()
+        self.request(
+            Command::GetIndex {
+                recent_limit,
+                include_hidden,
+                response: sender,
+            },
+            receiver,
+        )
+        .await
+    }
+
+    /// Sets `flag` of the image with `hash`, returns it updated or `None` if it is not indexed.
+    /// Kiosks are told about hiding as a removal and about unhiding as an addition.
+    pub async fn set_flag(
+        &self,
+        hash: ImageHash,
+        flag: ImageFlag,
+        value: bool,
+    ) -> Result<Option<Image>, IndexerGone> {
+        let (sender, receiver) = oneshot::channel();
+        self.request(
+            Command::SetFlag {
+                hash,
+                flag,
+                value,
+                response: sender,
+            },
+            receiver,
+        )
+        .await
+    }
+
+    /// Counts a reaction with `emoji` on the visible image with `hash`, returns it updated or
+    /// `None` if there is none
+    pub async fn react(
+        &self,
+        hash: ImageHash,
+        emoji: String,
+    ) -> Result<Option<Image>, IndexerGone> {
+        let (sender, receiver) = oneshot::channel();
+        self.request(
+            Command::React {
+                hash,
+                emoji,
+                response: sender,
+            },
+            receiver,
+        )
+        .await
+    }
+
+    /// Picks a random image, avoiding the `excluded` ones unless there are no others and
+    /// preferring newer ones with `fresh`, `None` if the index is empty
+    pub async fn pick_random(
+        &self,
+        fresh: bool,
+        excluded: HashSet<ImageHash>,
+    ) -> Result<Option<Image>, IndexerGone> {
+        let (sender, receiver) = oneshot::channel();
+        self.request(
+            Command::PickRandom {
+                fresh,
+                excluded,
+                response: sender,
+            },
+            receiver,
+        )
+        .await
+    }
+
+    /// Returns the index like [`Self::index`] together with a receiver of all changes after it.
+    /// With a revision `since` that is recent enough only the changes after it are returned
+    /// instead of the index.
+    pub async fn subscribe(
+        &self,
+        recent_limit: Option<usize>,
+        since: Option<u64>,
+    ) -> Result<Subscription, IndexerGone> {
+        let (sender, receiver) = oneshot::channel();
+        self.request(
+            Command::Subscribe {
+                recent_limit,
+                since,
+                response: sender,
+            },
+            receiver,
+        )
+        .await
     }
 
     pub async fn add_image(&self, hash: ImageHash, image: Image) -> Result<(), IndexError> {
         let (sender, receiver) = oneshot::channel();
-        self.command_sender
-            .send(Command::AddImage {
+        self.request(
+            Command::AddImage {
                 hash,
-                image,
+                image: Box::new(image),
                 response: sender,
-            })
+            },
+            receiver,
+        )
+        .await?
+    }
+
+    /// Adds another path with the content of the image with `hash`, returns the image with it
+    /// or `None` if it is not indexed
+    pub async fn add_alias(
+        &self,
+        hash: ImageHash,
+        path: PathBuf,
+    ) -> Result<Option<Image>, IndexerGone> {
+        let (sender, receiver) = oneshot::channel();
+        self.request(
+            Command::AddAlias {
+                hash,
+                path,
+                response: sender,
+            },
+            receiver,
+        )
+        .await
+    }
+
+    /// Attaches a placeholder computed after the image was indexed
+    pub async fn set_placeholder(
+        &self,
+        hash: ImageHash,
+        placeholder: Placeholder,
+    ) -> Result<(), IndexerGone> {
+        if self
+            .command_sender
+            .send(Command::SetPlaceholder { hash, placeholder })
+            .await
+            .is_err()
+        {
+            error!("indexer task is gone, requests can no longer be answered");
+            return Err(IndexerGone);
+        }
+        Ok(())
+    }
+
+    /// Marks images created less than `window` ago as fresh from now on, e.g. after a reload
+    pub async fn set_fresh_window(&self, window: Duration) -> Result<(), IndexerGone> {
+        if self
+            .command_sender
+            .send(Command::SetFreshWindow { window })
             .await
-            .unwrap();
-        receiver.await.unwrap()
+            .is_err()
+        {
+            error!("indexer task is gone, requests can no longer be answered");
+            return Err(IndexerGone);
+        }
+        Ok(())
+    }
+
+    /// Removes an image from the index and announces it, returns the image if it was indexed
+    pub async fn remove_image(&self, hash: ImageHash) -> Result<Option<Image>, IndexerGone> {
+        let (sender, receiver) = oneshot::channel();
+        self.request(
+            Command::RemoveImage {
+                hash,
+                response: sender,
+            },
+            receiver,
+        )
+        .await
+    }
+
+    /// Forgets a path that vanished from storage. Returns the removed image if it was canonical,
+    /// in which case a remaining alias takes its place as an update of the image.
+    pub async fn remove_path(&self, path: PathBuf) -> Result<Option<Image>, IndexerGone> {
+        let (sender, receiver) = oneshot::channel();
+        self.request(
+            Command::RemovePath {
+                path,
+                response: sender,
+            },
+            receiver,
+        )
+        .await
+    }
+
+    async fn request<T>(
+        &self,
+        command: Command,
+        receiver: oneshot::Receiver<T>,
+    ) -> Result<T, IndexerGone> {
+        if self.command_sender.send(command).await.is_err() {
+            error!("indexer task is gone, requests can no longer be answered");
+            return Err(IndexerGone);
+        }
+        receiver.await.map_err(|_| {
+            error!("indexer task dropped a request, it is probably gone");
+            IndexerGone
+        })
     }
 }
 
+/// The indexer task has terminated and cannot answer requests anymore
+#[derive(Debug, Error)]
+#[error("indexer is unavailable")]
+pub struct IndexerGone;
+
 #[derive(Debug, Error)]
 pub enum IndexError {
     #[error(transparent)]
-    Internal(#[from] SendError<Change>),
+    Gone(#[from] IndexerGone),
     #[error("duplicate image {}", path.display())]
     Duplicate { path: PathBuf },
 }
 
+/// What a subscriber needs to reach `revision`, and a receiver of all changes after it
+#[derive(Debug)]
+pub struct Subscription {
+    pub catchup: Catchup,
+    pub revision: u64,
+    pub changes: broadcast::Receiver<RevisedChange>,
+}
+
+#[derive(Debug)]
+pub enum Catchup {
+    /// the index at the revision
+    Snapshot(Vec<Image>),
+    /// the changes from the requested revision up to the current one
+    Changes(Vec<RevisedChange>),
+}
+
+/// A change together with the revision of the index it results in
+#[derive(Debug, Clone)]
+pub struct RevisedChange {
+    pub revision: u64,
+    pub change: Change,
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub enum Change {
-    Addition { image: Image },
+    Addition {
+        image: Image,
+    },
+    Removal {
+        image: Image,
+    },
+    /// the image with the same hash changed in place, e.g. it was pinned
+    Update {
+        image: Image,
+    },
 }
 
 pub type ImageHash = [u64; 2];
 
+/// Serializes hashes as hexadecimal strings, JSON numbers cannot represent all 64-bit integers
+pub mod hex_hash {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    use super::ImageHash;
+
+    pub fn to_string(hash: &ImageHash) -> String {
+        format!("{:016x}{:016x}", hash[0], hash[1])
+    }
+
+    pub fn from_str(hash: &str) -> Option<ImageHash> {
+        if hash.len() != 32 || !hash.is_ascii() {
+            return None;
+        }
+        Some([
+            u64::from_str_radix(&hash[..16], 16).ok()?,
+            u64::from_str_radix(&hash[16..], 16).ok()?,
+        ])
+    }
+
+    pub fn serialize<S: Serializer>(hash: &ImageHash, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&to_string(hash))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<ImageHash, D::Error> {
+        let hash = String::deserialize(deserializer)?;
+        from_str(&hash).ok_or_else(|| D::Error::custom("invalid image hash"))
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum CollectionError {
-    #[error(transparent)]
-    Internal(#[from] SendError<Change>),
     #[error(transparent)]
     Io(#[from] io::Error),
-    #[error("duplicate image {}", path.display())]
-    Duplicate { path: PathBuf },
 }
 
 pub async fn collect_images(
-    path: impl AsRef<Path>,
+    storage: &dyn Storage,
 ) -> Result<HashMap<ImageHash, Image>, CollectionError> {
-    let entries = read_dir(&path)?;
+    let mut entries = storage.list().await?;
+    // listing order is unspecified, sorting makes the canonical path deterministic
+    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
     let mut images: HashMap<ImageHash, Image> = HashMap::new();
-    for entry in entries {
-        let entry = entry?;
-        if entry.file_type()?.is_dir() {
+    for (stripped_path, fingerprint) in entries {
+        if is_sidecar(&stripped_path) {
             continue;
         }
-        let hash = hash_file(entry.path()).await?;
-        let stripped_path = entry.path().strip_prefix(&path).unwrap().to_path_buf();
-        if images.contains_key(&hash) {
-            return Err(CollectionError::Duplicate { path: entry.path() });
+        let created_at = fingerprint.modified;
+        let (hash, capture) = inspect(storage.read(&stripped_path).await?).await;
+        match images.entry(hash) {
+            Entry::Vacant(entry) => {
+                entry.insert(Image::new(hash, stripped_path, created_at, capture));
+            }
+            Entry::Occupied(mut entry) => {
+                warn!(
+                    "{} has the same content as {}, indexing it as alias",
+                    stripped_path.display(),
+                    entry.get().path.display()
+                );
+                entry.get_mut().add_alias(stripped_path);
+            }
         }
-        images.insert(
-            hash,
-            Image {
-                path: stripped_path,
-            },
-        );
     }
     Ok(images)
 }
 
-pub async fn hash_file(path: impl AsRef<Path>) -> Result<[u64; 2], io::Error> {
-    let key = Key([1, 3, 3, 7]);
-    let mut hasher = HighwayHasher::new(key);
-    let bytes = read(&path).await?;
-    hasher.append(&bytes);
-    let hash = spawn_blocking(move || hasher.finalize128()).await.unwrap();
-    Ok(hash)
+/// Reads a file once to compute its content hash and extract its capture metadata
+pub async fn inspect_file(
+    path: impl AsRef<Path>,
+) -> Result<(ImageHash, CaptureMetadata), io::Error> {
+    Ok(inspect(read(&path).await?).await)
+}
+
+/// Content hash and capture metadata of a file read already, e.g. from storage
+pub async fn inspect(bytes: Vec<u8>) -> (ImageHash, CaptureMetadata) {
+    spawn_blocking(move || (hash_bytes(&bytes), CaptureMetadata::from_exif(&bytes)))
+        .await
+        .unwrap()
+}
+
+pub async fn hash_contents(bytes: Vec<u8>) -> ImageHash {
+    spawn_blocking(move || hash_bytes(&bytes)).await.unwrap()
+}
+
+fn hash_bytes(bytes: &[u8]) -> ImageHash {
+    let mut hasher = content_hasher();
+    hasher.append(bytes);
+    hasher.finalize128()
+}
+
+/// Computes the same hash as [`hash_contents`] over contents appended in parts
+pub fn content_hasher() -> HighwayHasher {
+    HighwayHasher::new(Key([1, 3, 3, 7]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(revision: u64) -> Index {
+        Index {
+            images: HashMap::new(),
+            by_creation: BTreeSet::new(),
+            revision,
+            recent: VecDeque::with_capacity(RECENT_CHANGES),
+            fresh_window: Duration::ZERO,
+        }
+    }
+
+    /// Records `count` updates of a single image, like repeated reactions
+    fn record_updates(index: &mut Index, count: usize) {
+        let image = Image::new(
+            [1, 2],
+            PathBuf::from("image.jpg"),
+            OffsetDateTime::UNIX_EPOCH,
+            CaptureMetadata::default(),
+        );
+        for _ in 0..count {
+            index.record(Change::Update {
+                image: image.clone(),
+            });
+        }
+    }
+
+    fn revisions(changes: Option<Vec<RevisedChange>>) -> Option<Vec<u64>> {
+        changes.map(|changes| changes.iter().map(|change| change.revision).collect())
+    }
+
+    #[test]
+    fn changes_after_a_remembered_revision_are_caught_up() {
+        let mut index = index(100);
+        record_updates(&mut index, 3);
+        assert_eq!(revisions(index.changes_since(101)), Some(vec![102, 103]));
+        assert_eq!(revisions(index.changes_since(103)), Some(Vec::new()));
+        assert_eq!(
+            revisions(index.changes_since(100)),
+            Some(vec![101, 102, 103])
+        );
+    }
+
+    #[test]
+    fn revisions_no_longer_remembered_fall_back_to_a_snapshot() {
+        let mut index = index(100);
+        record_updates(&mut index, RECENT_CHANGES + 5);
+        assert_eq!(index.recent.len(), RECENT_CHANGES);
+        let oldest = index.revision - RECENT_CHANGES as u64;
+        assert_eq!(
+            index.changes_since(oldest).map(|changes| changes.len()),
+            Some(RECENT_CHANGES)
+        );
+        assert_eq!(revisions(index.changes_since(oldest - 1)), None);
+        assert_eq!(revisions(index.changes_since(100)), None);
+    }
+
+    #[test]
+    fn revisions_of_other_runs_fall_back_to_a_snapshot() {
+        let mut index = index(100);
+        record_updates(&mut index, 3);
+        // from a run that started later, e.g. before the clock was set back
+        assert_eq!(revisions(index.changes_since(200)), None);
+        // from a run that started earlier
+        assert_eq!(revisions(index.changes_since(50)), None);
+    }
 }
@@ -0,0 +1,257 @@
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use time::{Duration, OffsetDateTime};
+use tokio::{fs::read, io};
+
+use crate::{
+    auth::{hash_token, random_token},
+    cache::write_atomically,
+};
+
+/// How long an invite is valid if the admin does not say otherwise
+const DEFAULT_VALIDITY_HOURS: u64 = 7 * 24;
+
+/// Characters of an invite label kept, the rest is cut off
+const MAX_LABEL_LENGTH: usize = 100;
+
+/// Random bytes of an invite token
+const TOKEN_LENGTH: usize = 16;
+
+/// A personal upload link for somebody not at the event, usable `uses_left` more times until
+/// `expires_at`. Only the hash of its token is kept, so the file does not leak usable links.
+#[derive(Clone, Serialize, Deserialize)]
+struct Invite {
+    token_hash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+    uses_left: u32,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    expires_at: OffsetDateTime,
+}
+
+/// Outstanding invites by their id, persisted in the cache directory across restarts
+pub struct Invites {
+    file: PathBuf,
+    invites: Mutex<BTreeMap<String, Invite>>,
+}
+
+impl Invites {
+    pub async fn load(file: PathBuf) -> Self {
+        let invites = match read(&file).await {
+            Ok(contents) => serde_json::from_slice(&contents).unwrap_or_else(|error| {
+                warn!("ignoring corrupt invites {}: {error}", file.display());
+                BTreeMap::new()
+            }),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(error) => {
+                warn!("ignoring unreadable invites {}: {error}", file.display());
+                BTreeMap::new()
+            }
+        };
+        Self {
+            file,
+            invites: Mutex::new(invites),
+        }
+    }
+
+    /// Saves the invites, dropping expired ones
+    pub async fn save(&self) -> Result<(), io::Error> {
+        let contents = {
+            let mut invites = self.invites.lock().unwrap();
+            let now = OffsetDateTime::now_utc();
+            invites.retain(|_, invite| invite.expires_at > now);
+            serde_json::to_vec(&*invites)?
+        };
+        if let Some(parent) = self.file.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        write_atomically(&self.file, contents).await
+    }
+
+    /// Takes one use of the invite with `token`, returns its id unless it is unknown, used up
+    /// or expired
+    pub fn consume(&self, token: &str) -> Option<String> {
+        let token_hash = hash_token(token);
+        let now = OffsetDateTime::now_utc();
+        let mut invites = self.invites.lock().unwrap();
+        let (id, invite) = invites.iter_mut().find(|(_, invite)| {
+            invite.token_hash == token_hash && invite.uses_left > 0 && invite.expires_at > now
+        })?;
+        invite.uses_left -= 1;
+        Some(id.clone())
+    }
+
+    /// Gives back the use of an upload that failed, e.g. as a duplicate
+    pub fn refund(&self, id: &str) {
+        if let Some(invite) = self.invites.lock().unwrap().get_mut(id) {
+            invite.uses_left += 1;
+        }
+    }
+}
+
+pub type InviteState = Arc<Invites>;
+
+#[derive(Deserialize)]
+pub struct InviteRequest {
+    label: Option<String>,
+    #[serde(default = "one")]
+    uses: u32,
+    #[serde(default = "default_validity_hours")]
+    valid_for_hours: u64,
+}
+
+fn one() -> u32 {
+    1
+}
+
+fn default_validity_hours() -> u64 {
+    DEFAULT_VALIDITY_HOURS
+}
+
+/// An invite as listed, without its token
+#[derive(Serialize)]
+pub struct InviteSummary {
+    id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+    uses_left: u32,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    expires_at: OffsetDateTime,
+}
+
+impl InviteSummary {
+    fn new(id: String, invite: Invite) -> Self {
+        Self {
+            id,
+            label: invite.label,
+            uses_left: invite.uses_left,
+            created_at: invite.created_at,
+            expires_at: invite.expires_at,
+        }
+    }
+}
+
+/// A new invite with the token to send, which is shown only this once
+#[derive(Serialize)]
+pub struct MintedInvite {
+    #[serde(flatten)]
+    invite: InviteSummary,
+    token: String,
+}
+
+/// Mints an invite for `uses` uploads with `/upload/invited?invite=<token>` within
+/// `valid_for_hours`, without the event secret
+pub async fn handle_create_invite(
+    State(invites): State<InviteState>,
+    Json(request): Json<InviteRequest>,
+) -> Result<(StatusCode, Json<MintedInvite>), InviteError> {
+    if request.uses == 0 {
+        return Err(InviteError::NoUses);
+    }
+    let now = OffsetDateTime::now_utc();
+    let expires_at = i64::try_from(request.valid_for_hours)
+        .ok()
+        .filter(|hours| *hours > 0)
+        .and_then(|hours| hours.checked_mul(60 * 60))
+        .and_then(|seconds| now.checked_add(Duration::seconds(seconds)))
+        .ok_or(InviteError::InvalidValidity)?;
+    let token = random_token(TOKEN_LENGTH).map_err(InviteError::Random)?;
+    let id = random_token(4).map_err(InviteError::Random)?;
+    let invite = Invite {
+        token_hash: hash_token(&token),
+        label: request
+            .label
+            .map(|label| label.trim().chars().take(MAX_LABEL_LENGTH).collect())
+            .filter(|label: &String| !label.is_empty()),
+        uses_left: request.uses,
+        created_at: now,
+        expires_at,
+    };
+    invites
+        .invites
+        .lock()
+        .unwrap()
+        .insert(id.clone(), invite.clone());
+    invites.save().await.map_err(InviteError::Save)?;
+    info!("minted invite {id} for {} uploads", request.uses);
+    Ok((
+        StatusCode::CREATED,
+        Json(MintedInvite {
+            invite: InviteSummary::new(id, invite),
+            token,
+        }),
+    ))
+}
+
+/// Lists the invites that did not expire yet, including used up ones
+pub async fn handle_list_invites(State(invites): State<InviteState>) -> Json<Vec<InviteSummary>> {
+    let now = OffsetDateTime::now_utc();
+    Json(
+        invites
+            .invites
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, invite)| invite.expires_at > now)
+            .map(|(id, invite)| InviteSummary::new(id.clone(), invite.clone()))
+            .collect(),
+    )
+}
+
+/// Revokes the invite with the id, its link stops working immediately
+pub async fn handle_revoke_invite(
+    State(invites): State<InviteState>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, InviteError> {
+    invites
+        .invites
+        .lock()
+        .unwrap()
+        .remove(&id)
+        .ok_or(InviteError::NotFound)?;
+    invites.save().await.map_err(InviteError::Save)?;
+    info!("revoked invite {id}");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Error)]
+pub enum InviteError {
+    #[error("invite not found")]
+    NotFound,
+    #[error("an invite needs at least one use")]
+    NoUses,
+    #[error("valid_for_hours is out of range")]
+    InvalidValidity,
+    #[error("failed to generate a token: {0}")]
+    Random(getrandom::Error),
+    #[error("failed to save invites: {0}")]
+    Save(io::Error),
+}
+
+impl IntoResponse for InviteError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            InviteError::NotFound => StatusCode::NOT_FOUND,
+            InviteError::NoUses | InviteError::InvalidValidity => StatusCode::BAD_REQUEST,
+            InviteError::Random(_) | InviteError::Save(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
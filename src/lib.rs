@@ -0,0 +1,1380 @@
+use std::{
+    ffi::OsString,
+    fs::read_to_string,
+    future::Future,
+    net::IpAddr,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread::available_parallelism,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use auth::{require_admin, require_download_secret, require_secret, strip_secret_prefix};
+use axum::{
+    extract::DefaultBodyLimit,
+    handler::HandlerWithoutStateExt,
+    http::{header, HeaderValue, StatusCode},
+    middleware::{from_fn, from_fn_with_state},
+    response::Response,
+    routing::{delete, get, post, put},
+    Router,
+};
+use cache::{CacheSettings, Derivative, INTERNAL_DIRECTORY};
+use clap::{Args, Parser, Subcommand};
+use compression::compress_responses;
+use connections::{handle_connections, handle_kiosks, Connections};
+use cors::{cors_layer, parse_origin};
+use curation::{
+    handle_get_image, handle_hide, handle_list_images, handle_pin, handle_report, handle_unhide,
+    handle_unpin,
+};
+use devices::{
+    authenticate_device, handle_list_devices, handle_register_device, handle_revoke_device, Devices,
+};
+use events::handle_events;
+use eviction::{enforce_cache_budget, track_access, CacheUsage};
+use export::handle_export;
+use feed::handle_feed;
+use frontend::serve_frontend;
+use health::{handle_health, handle_readiness, CachePopulation, PopulationStatus};
+use highlights::{schedule_highlights, Highlights};
+#[cfg(feature = "s3")]
+use http_url::parse_http_url;
+use http_url::{parse_loopback_http_url, HttpUrl};
+use images::{
+    label_images, parse_color, refuse_hidden_paths, select_size, serve_and_cache, tag_images,
+    PendingGenerations,
+};
+use import::import_directory;
+use invites::{handle_create_invite, handle_list_invites, handle_revoke_invite, Invites};
+use limits::{limit_requests, RequestLimits};
+use listeners::parse_host;
+use log::{error, info, warn};
+use logging::{assign_request_ids, LogFormat};
+use mirror::{queue_additions, upload_queued, Mirror};
+use missing::{forget_added_images, MissingImages};
+#[cfg(feature = "notifications")]
+use notifications::{parse_notify_url, publish_additions, NotifyUrl};
+use originals::{
+    attach_filename, check_if_range, refuse_links_out_of_storage, serve_stored_original,
+};
+use playlists::{
+    forget_deleted_images, handle_create_playlist, handle_delete_playlist, handle_get_playlist,
+    handle_next, Playlists,
+};
+use prefix::{parse_base_path, strip_base_path};
+use processing::{ProcessingOptions, ResizeFilter};
+use qr::{handle_qr_code_png, handle_qr_code_svg, parse_public_url};
+use reactions::{handle_react, save_reactions, ReactionLimits};
+use read_only::refuse_changes_when_read_only;
+use recommend::{random_image, recommend_images, report_shown, SurfacedImages};
+use reconcile::handle_reconcile;
+use reload::{handle_reload, reload_on_hangup, Reloader};
+use request_log::log_requests;
+#[cfg(feature = "s3")]
+use s3::{Credentials, S3Storage};
+use secrets::{
+    handle_add_secret, handle_list_secrets, handle_remove_secret, Role, Secret, Secrets, ADMIN_NAME,
+};
+use sidecars::apply_sidecars;
+use static_export::export_static;
+use stats::handle_stats;
+use tower::{util::option_layer, ServiceBuilder};
+use tower_http::{services::ServeDir, set_header::SetResponseHeaderLayer};
+use upload::{upload_image, upload_invited_image, INVITED_UPLOAD_PATH, UPLOAD_PATH};
+use upload_codes::{UploadCodeKey, UploadCodes};
+use validation::{check_arguments, check_directories};
+use version::handle_version;
+use watcher::{WatchMode, WatchStatistics};
+use webdav::WebDavStorage;
+use websocket::handle_websocket_upgrade;
+
+pub use cache::{cache_image, CacheFormat, CacheLayout, CacheLocks, ProcessingQueue};
+pub use config::with_file_arguments;
+pub use doctor::{diagnose, Check, Diagnosis, Outcome};
+#[cfg(feature = "fast-resize")]
+pub use fast_resize::resize_lanczos3;
+pub use import::ImportSummary;
+pub use index::{Image, Indexer};
+pub use listeners::bind_listeners;
+pub use logging::initialize_logging;
+pub use qr::{ErrorCorrection, QrCode, QrError};
+pub use reconcile::{reconcile, ReconcileReport};
+pub use reload::SharedConfiguration;
+pub use sources::{Fingerprint, SourceRecords};
+pub use storage::{LocalStorage, Storage};
+pub use version::Version;
+
+mod auth;
+mod cache;
+mod capture;
+mod compression;
+mod config;
+mod connections;
+mod cors;
+mod curation;
+mod devices;
+mod doctor;
+mod events;
+mod eviction;
+mod export;
+#[cfg(feature = "fast-resize")]
+mod fast_resize;
+mod feed;
+mod frontend;
+mod health;
+mod highlights;
+mod http_client;
+mod http_url;
+mod images;
+mod import;
+mod index;
+mod invites;
+mod limits;
+mod listeners;
+mod logging;
+mod mirror;
+mod missing;
+mod msgpack;
+#[cfg(feature = "notifications")]
+mod notifications;
+mod originals;
+mod placeholder;
+mod playlists;
+mod prefix;
+mod processing;
+mod qr;
+mod reactions;
+mod read_only;
+mod recommend;
+mod reconcile;
+mod reload;
+mod request_log;
+#[cfg(feature = "s3")]
+mod s3;
+mod secrets;
+#[cfg(feature = "s3")]
+mod sha256;
+mod sidecars;
+mod sources;
+mod static_export;
+mod stats;
+mod storage;
+pub mod systemd;
+mod upload;
+mod upload_codes;
+mod validation;
+mod version;
+mod watcher;
+mod webdav;
+mod websocket;
+mod xml;
+
+/// A simple image gallery server
+#[derive(Clone, Parser)]
+#[command(args_override_self = true)]
+pub struct Arguments {
+    /// path to a YAML or JSON file of arguments, overridden by environment variables and flags
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// IP addresses to listen on, comma-separated for several, e.g. `0.0.0.0,::`
+    #[arg(long, default_value = "0.0.0.0", value_delimiter = ',', value_parser = parse_host)]
+    pub host: Vec<IpAddr>,
+    /// port to listen on
+    #[arg(long, default_value = "3000")]
+    pub port: u16,
+    /// port for the admin API and health checks instead of --port
+    #[arg(long)]
+    pub maintenance_port: Option<u16>,
+    /// IP addresses to listen on with --maintenance-port
+    #[arg(
+        long,
+        default_value = "127.0.0.1",
+        value_delimiter = ',',
+        value_parser = parse_host
+    )]
+    pub maintenance_host: Vec<IpAddr>,
+    /// path to directory where uploaded images are stored
+    #[arg(long, default_value = "storage/")]
+    pub storage: PathBuf,
+    /// path to directory where cached images are stored
+    #[arg(long, default_value = "cache/")]
+    pub cache: PathBuf,
+    /// serve the frontend from this directory instead of the built-in files
+    #[arg(long)]
+    pub frontend_dir: Option<PathBuf>,
+    /// a secret used to authenticate requests, repeat as `name=value:role` for several
+    #[arg(long, env = "MOMENTS_SECRET", hide_env_values = true)]
+    pub secret: Vec<String>,
+    /// a secret for the `/admin/` endpoints, turning --secret into a guest secret
+    #[arg(long, env = "MOMENTS_ADMIN_SECRET", hide_env_values = true)]
+    pub admin_secret: Option<String>,
+    /// path to a file with one secret per line in the format of --secret
+    #[arg(long)]
+    pub secret_file: Option<PathBuf>,
+    /// a separate secret required to download originals from `/originals/`
+    #[arg(long, env = "MOMENTS_DOWNLOAD_SECRET", hide_env_values = true)]
+    pub download_secret: Option<String>,
+    /// seconds after which the upload code shown on the kiosks changes, kiosks authenticate with
+    /// a secret of role `kiosk`, e.g. `--secret wall=<value>:kiosk`, to be sent the codes
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub upload_code_period: Option<u64>,
+    /// serve everything below this path instead of `/`, e.g. `/wall`
+    #[arg(long, default_value = "", value_parser = parse_base_path)]
+    pub base_path: String,
+    /// URL guests reach the uploader at, for the QR codes
+    #[arg(long, value_parser = parse_public_url)]
+    pub public_url: Option<String>,
+    /// honor `X-Forwarded-Prefix` of a reverse proxy when building URLs
+    #[arg(long)]
+    pub trust_forwarded_prefix: bool,
+    /// additionally serve all authenticated routes below `/<secret>/`
+    #[arg(long)]
+    pub secret_in_path: bool,
+    /// generate derivatives of existing images when first requested instead of at startup
+    #[arg(long)]
+    pub lazy_cache: bool,
+    /// Maximum size of longest edge of cached images in pixels
+    #[arg(long, default_value = "1000")]
+    pub max_cached_image_size: u32,
+    /// comma-separated list of additional sizes of the longest edge in pixels to cache
+    #[arg(long, value_delimiter = ',')]
+    pub cache_sizes: Vec<u32>,
+    /// comma-separated list of further sizes clients may request with `?w=`
+    #[arg(long, value_delimiter = ',')]
+    pub on_demand_sizes: Vec<u32>,
+    /// encoding of cached images, WebP is lossless and ignores --jpeg-image-quality
+    #[arg(long, value_enum, default_value = "jpeg")]
+    pub cache_format: CacheFormat,
+    /// JPEG image quality
+    #[arg(long, default_value = "80")]
+    pub jpeg_image_quality: u8,
+    /// filter used to scale images down
+    #[arg(long, value_enum, default_value = "lanczos3")]
+    pub resize_filter: ResizeFilter,
+    /// seconds after which processing a single image is given up
+    #[arg(long, default_value = "30")]
+    pub processing_timeout: u64,
+    /// seconds between websocket pings
+    #[arg(long, default_value = "30", value_parser = clap::value_parser!(u64).range(1..))]
+    pub websocket_ping_interval: u64,
+    /// allow websocket clients to request gzip-compressed frames
+    #[arg(long)]
+    pub websocket_compression: bool,
+    /// send responses uncompressed even to clients accepting gzip
+    #[arg(long)]
+    pub disable_compression: bool,
+    /// milliseconds during which changes are collected into a single message
+    #[arg(long, default_value = "250")]
+    pub change_batch_window: u64,
+    /// seconds each kiosk highlights an image for, 0 to leave highlighting to the kiosks
+    #[arg(long, default_value = "15")]
+    pub highlight_interval: u64,
+    /// seconds after which pinned images are recommended again
+    #[arg(long, default_value = "60", value_parser = clap::value_parser!(u64).range(1..))]
+    pub pin_interval: u64,
+    /// seconds after their upload images are recommended first
+    #[arg(long, default_value = "120")]
+    pub fresh_window: u64,
+    /// reports by different guests after which an image is hidden, 0 to never hide
+    #[arg(long, default_value = "3")]
+    pub reports_to_hide: usize,
+    /// also keep pinned and hidden flags in a `<original>.moments.json` next to originals
+    #[arg(long)]
+    pub sidecars: bool,
+    /// MQTT or ntfy URL to publish a message to for every image added
+    #[cfg(feature = "notifications")]
+    #[arg(long, value_parser = parse_notify_url)]
+    pub notify_url: Option<NotifyUrl>,
+    /// `user:password` for --notify-url
+    #[cfg(feature = "notifications")]
+    #[arg(long, env = "MOMENTS_NOTIFY_CREDENTIALS", hide_env_values = true)]
+    pub notify_credentials: Option<String>,
+    /// URL of another instance to upload every image to, on this machine as there is no TLS,
+    /// e.g. `http://localhost:8443/wall` with a TLS tunnel to the instance on port 8443
+    #[arg(long, value_parser = parse_loopback_http_url, requires = "mirror_secret")]
+    pub mirror_to: Option<HttpUrl>,
+    /// admin secret of the instance of --mirror-to
+    #[arg(long, env = "MOMENTS_MIRROR_SECRET", hide_env_values = true)]
+    pub mirror_secret: Option<String>,
+    /// WebDAV collection to keep originals in instead of --storage, polled for changes, on this
+    /// machine as there is no TLS, e.g. `http://localhost:8443/remote.php/dav/files/wall/Photos`
+    /// with a TLS tunnel to the server listening on port 8443
+    #[arg(long, value_parser = parse_loopback_http_url)]
+    pub webdav_url: Option<HttpUrl>,
+    /// user name for --webdav-url
+    #[arg(long)]
+    pub webdav_user: Option<String>,
+    /// password of --webdav-user
+    #[arg(long, env = "MOMENTS_WEBDAV_PASSWORD", hide_env_values = true)]
+    pub webdav_password: Option<String>,
+    /// S3-compatible server to keep originals in instead of --storage, polled for changes, e.g.
+    /// `http://minio.local:9000`
+    #[cfg(feature = "s3")]
+    #[arg(
+        long,
+        value_parser = parse_http_url,
+        requires_all = ["s3_bucket", "s3_access_key", "s3_secret_key"],
+        conflicts_with = "webdav_url"
+    )]
+    pub s3_endpoint: Option<HttpUrl>,
+    /// bucket at --s3-endpoint, addressed in the path
+    #[cfg(feature = "s3")]
+    #[arg(long)]
+    pub s3_bucket: Option<String>,
+    /// region requests to --s3-endpoint are signed for
+    #[cfg(feature = "s3")]
+    #[arg(long, default_value = "us-east-1")]
+    pub s3_region: String,
+    /// access key ID for --s3-endpoint
+    #[cfg(feature = "s3")]
+    #[arg(long, env = "MOMENTS_S3_ACCESS_KEY")]
+    pub s3_access_key: Option<String>,
+    /// secret access key of --s3-access-key
+    #[cfg(feature = "s3")]
+    #[arg(long, env = "MOMENTS_S3_SECRET_KEY", hide_env_values = true)]
+    pub s3_secret_key: Option<String>,
+    /// newest images in the Atom feed at `/feed.atom`, at most 500
+    #[arg(long, default_value = "50", value_parser = clap::value_parser!(u64).range(1..=500))]
+    pub feed_size: u64,
+    /// reactions each guest may send per minute to `/react/:hash`, 0 for no limit
+    #[arg(long, default_value = "30")]
+    pub reactions_per_minute: u32,
+    /// milliseconds a file appearing in storage must stay unchanged before it is indexed
+    #[arg(long, default_value = "1000", value_parser = clap::value_parser!(u64).range(1..))]
+    pub settle_time: u64,
+    /// how files added to, changed in or deleted from storage while running are noticed
+    #[arg(long, value_enum, default_value = "auto")]
+    pub watch_mode: WatchMode,
+    /// seconds between listings of storage when polling it for changes
+    #[arg(long, default_value = "10", value_parser = clap::value_parser!(u64).range(1..))]
+    pub poll_interval: u64,
+    /// number of images per message the initial index is split into
+    #[arg(long, default_value = "500")]
+    pub snapshot_chunk_size: NonZeroUsize,
+    /// evict the least recently served cache files beyond this many bytes
+    #[arg(long)]
+    pub max_cache_bytes: Option<u64>,
+    /// number of images processed concurrently, defaults to the number of CPUs
+    #[arg(long)]
+    pub cache_workers: Option<usize>,
+    /// refuse uploads and changes with 403 while keeping the gallery online
+    #[arg(long)]
+    pub read_only: bool,
+    /// format of log lines on stderr
+    #[arg(long, value_enum, default_value = "text")]
+    pub log_format: LogFormat,
+    /// origin allowed to upload from, repeat for several or `*` for any
+    #[arg(long, value_parser = parse_origin)]
+    pub cors_allowed_origin: Vec<HeaderValue>,
+    /// seconds after which requests other than uploads and streams are answered with 408
+    #[arg(long, default_value = "30", value_parser = clap::value_parser!(u64).range(1..))]
+    pub request_timeout: u64,
+    /// seconds after which uploads are answered with 408
+    #[arg(long, default_value = "300", value_parser = clap::value_parser!(u64).range(1..))]
+    pub upload_timeout: u64,
+    /// requests handled at once before further ones are answered with 503
+    #[arg(long, default_value = "128", value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_concurrent_requests: u64,
+    /// color of the image served in place of one that cannot be served
+    #[arg(long, default_value = "#808080", value_parser = parse_color)]
+    pub placeholder_color: [u8; 3],
+    /// seconds an image missing from storage is answered with 404 from memory
+    #[arg(long, default_value = "10")]
+    pub missing_image_ttl: u64,
+    /// the maximum size of a request body in bytes, which results in the maximum size an uploaded
+    /// image can have
+    #[arg(long, default_value = "16777216")]
+    pub max_request_body_size: usize,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// What to do, serving unless another command is given
+#[derive(Clone, Subcommand)]
+pub enum Command {
+    /// serve the gallery
+    Serve,
+    /// copy images from a directory into storage like uploads, then exit
+    Import(ImportArguments),
+    /// write the wall with all images into a directory for static web hosting, then exit
+    ExportStatic(ExportStaticArguments),
+    /// check what keeps the server from working and print a table of the results
+    Doctor(DoctorArguments),
+}
+
+#[derive(Clone, Args)]
+pub struct DoctorArguments {
+    /// also connect to the host of --public-url, showing it is reachable from here
+    #[arg(long)]
+    pub check_public_url: bool,
+}
+
+#[derive(Clone, Args)]
+pub struct ImportArguments {
+    /// directory with the images to import, subdirectories and hidden files are left out
+    #[arg(long)]
+    pub from: PathBuf,
+}
+
+#[derive(Clone, Args)]
+pub struct ExportStaticArguments {
+    /// directory to write the gallery to, existing files in it are overwritten
+    #[arg(long)]
+    pub out: PathBuf,
+}
+
+#[derive(Clone)]
+pub struct Configuration {
+    secrets: Arc<Secrets>,
+    secret_in_path: bool,
+    download_secret: Option<String>,
+    upload_codes: Option<UploadCodes>,
+    base_path: String,
+    trust_forwarded_prefix: bool,
+    public_url: Option<String>,
+    storage: PathBuf,
+    /// the files in `storage` unless another backend was given to [`Moments::start_with_storage`]
+    originals: Arc<dyn Storage>,
+    cache: PathBuf,
+    cache_layout: CacheLayout,
+    jpeg_image_quality: u8,
+    resize_filter: ResizeFilter,
+    placeholder_color: [u8; 3],
+    missing_image_ttl: Duration,
+    cache_workers: usize,
+    max_cache_bytes: Option<u64>,
+    websocket_ping_interval: Duration,
+    websocket_compression: bool,
+    compression: bool,
+    snapshot_chunk_size: usize,
+    change_batch_window: Duration,
+    highlight_interval: Duration,
+    pin_interval: Duration,
+    fresh_window: Duration,
+    reports_to_hide: usize,
+    sidecars: bool,
+    feed_size: usize,
+    reactions_per_minute: u32,
+    settle_time: Duration,
+    watch_mode: WatchMode,
+    poll_interval: Duration,
+    read_only: bool,
+    request_timeout: Duration,
+    upload_timeout: Duration,
+    max_concurrent_requests: usize,
+}
+
+impl Configuration {
+    /// All cached variants of the image at `path` relative to the storage directory
+    pub fn derivatives(&self, path: &Path) -> Vec<Derivative> {
+        self.cache_layout
+            .derivative_paths(path)
+            .into_iter()
+            .map(|(max_size, cache_path)| Derivative {
+                destination: self.cache.join(cache_path),
+                max_size,
+            })
+            .collect()
+    }
+
+    /// Variants of the image at `path` only generated when requested with `?w=`
+    pub fn on_demand_derivatives(&self, path: &Path) -> Vec<Derivative> {
+        self.cache_layout
+            .on_demand_paths(path)
+            .into_iter()
+            .map(|(max_size, cache_path)| Derivative {
+                destination: self.cache.join(cache_path),
+                max_size,
+            })
+            .collect()
+    }
+
+    /// Cached variants together with those generated on demand, to remove all of them
+    pub fn all_derivatives(&self, path: &Path) -> Vec<Derivative> {
+        let mut derivatives = self.derivatives(path);
+        derivatives.extend(self.on_demand_derivatives(path));
+        derivatives
+    }
+
+    pub fn processing_options(&self) -> ProcessingOptions {
+        ProcessingOptions {
+            format: self.cache_layout.format,
+            jpeg_image_quality: self.jpeg_image_quality,
+            resize_filter: self.resize_filter,
+        }
+    }
+
+    pub fn cache_settings(&self) -> CacheSettings {
+        CacheSettings {
+            layout: self.cache_layout.clone(),
+            jpeg_image_quality: self.jpeg_image_quality,
+            resize_filter: self.resize_filter,
+        }
+    }
+}
+
+/// A running instance: the configuration, the index of storage and the state shared between
+/// routes and background tasks. The binary serves [`build_router`] of it, other axum apps may
+/// nest it.
+pub struct Moments {
+    /// as started with, for settings that cannot be reloaded
+    arguments: Arguments,
+    configuration: Arc<SharedConfiguration>,
+    indexer: Arc<Indexer>,
+    locks: Arc<CacheLocks>,
+    sources: Arc<SourceRecords>,
+    queue: Arc<ProcessingQueue>,
+    generations: Arc<PendingGenerations>,
+    missing: Arc<MissingImages>,
+    highlights: Arc<Highlights>,
+    surfaced: Arc<SurfacedImages>,
+    reactions: Arc<ReactionLimits>,
+    playlists: Arc<Playlists>,
+    invites: Arc<Invites>,
+    devices: Arc<Devices>,
+    usage: Arc<CacheUsage>,
+    watch_statistics: Arc<WatchStatistics>,
+    connections: Arc<Connections>,
+    population: Arc<CachePopulation>,
+    limits: Arc<RequestLimits>,
+    reloader: Arc<Reloader>,
+    mirror: Option<Arc<Mirror>>,
+}
+
+impl Moments {
+    /// Validates the configuration, indexes storage and starts watching it as well as enforcing
+    /// the cache budget. `command_line` is parsed again with the configuration file on reloads.
+    pub async fn start(command_line: Vec<OsString>, arguments: Arguments) -> Result<Self> {
+        let storage = open_storage(&arguments);
+        Self::start_with_storage(command_line, arguments, storage).await
+    }
+
+    /// Like [`Moments::start`] with originals kept in `storage` instead of the `--storage`
+    /// directory, which is then only created. Storage other than a local directory is polled
+    /// for changes with `--watch-mode auto` or `poll`.
+    pub async fn start_with_storage(
+        command_line: Vec<OsString>,
+        arguments: Arguments,
+        storage: Arc<dyn Storage>,
+    ) -> Result<Self> {
+        let mut current = configure(arguments.clone())?;
+        current.originals = storage;
+        check_directories(&current.storage, &current.cache).await?;
+
+        let upload_code_key = UploadCodeKey::load_or_create(
+            &current
+                .cache
+                .join(INTERNAL_DIRECTORY)
+                .join("upload-code-key"),
+        )
+        .await
+        .context("failed to load the upload code key")?;
+        current.upload_codes = arguments
+            .upload_code_period
+            .map(|period| UploadCodes::new(upload_code_key, Duration::from_secs(period)));
+        let current = Arc::new(current);
+        let configuration = Arc::new(SharedConfiguration::new(current.clone()));
+
+        let sources = Arc::new(
+            SourceRecords::load(current.cache.join(INTERNAL_DIRECTORY).join("sources.json")).await,
+        );
+        let indexer = Arc::new(
+            Indexer::spawn(
+                current.originals.as_ref(),
+                current.cache_layout.clone(),
+                &sources,
+                current.fresh_window,
+            )
+            .await
+            .context("failed to index storage")?,
+        );
+        if current.sidecars {
+            apply_sidecars(&current, &indexer, &sources)
+                .await
+                .context("failed to apply sidecars")?;
+        }
+        let locks = Arc::new(CacheLocks::default());
+        let queue = Arc::new(ProcessingQueue::new(
+            current.cache_workers,
+            Duration::from_secs(arguments.processing_timeout),
+        ));
+        let usage = Arc::new(CacheUsage::default());
+        let watch_statistics = Arc::new(WatchStatistics::default());
+        let population = Arc::new(CachePopulation::default());
+        if arguments.lazy_cache {
+            population.set(PopulationStatus::Skipped);
+        }
+        let reloader = Arc::new(Reloader::start(
+            command_line,
+            arguments.clone(),
+            upload_code_key,
+            configuration.clone(),
+            indexer.clone(),
+            locks.clone(),
+            sources.clone(),
+            queue.clone(),
+            population.clone(),
+            watch_statistics.clone(),
+        ));
+        tokio::spawn(enforce_cache_budget(
+            configuration.clone(),
+            locks.clone(),
+            usage.clone(),
+        ));
+        let missing = Arc::new(MissingImages::default());
+        tokio::spawn(forget_added_images(indexer.clone(), missing.clone()));
+        let highlights = Arc::new(Highlights::new(sources.clone()));
+        tokio::spawn(schedule_highlights(
+            configuration.clone(),
+            indexer.clone(),
+            highlights.clone(),
+        ));
+        tokio::spawn(save_reactions(sources.clone()));
+        #[cfg(feature = "notifications")]
+        if let Some(url) = &arguments.notify_url {
+            tokio::spawn(publish_additions(
+                indexer.clone(),
+                url.clone(),
+                arguments.notify_credentials.clone(),
+            ));
+        }
+        let playlists = Arc::new(
+            Playlists::load(
+                current
+                    .cache
+                    .join(INTERNAL_DIRECTORY)
+                    .join("playlists.json"),
+            )
+            .await,
+        );
+        tokio::spawn(forget_deleted_images(indexer.clone(), playlists.clone()));
+        let invites = Arc::new(
+            Invites::load(current.cache.join(INTERNAL_DIRECTORY).join("invites.json")).await,
+        );
+        let devices = Arc::new(
+            Devices::load(current.cache.join(INTERNAL_DIRECTORY).join("devices.json")).await,
+        );
+        let mirror = match (&arguments.mirror_to, &arguments.mirror_secret) {
+            (Some(url), Some(secret)) => {
+                let mirror = Arc::new(
+                    Mirror::load(
+                        url.clone(),
+                        secret.clone(),
+                        current.cache.join(INTERNAL_DIRECTORY).join("mirror.json"),
+                    )
+                    .await,
+                );
+                tokio::spawn(queue_additions(indexer.clone(), mirror.clone()));
+                tokio::spawn(upload_queued(configuration.clone(), mirror.clone()));
+                Some(mirror)
+            }
+            _ => None,
+        };
+        Ok(Self {
+            arguments,
+            configuration,
+            indexer,
+            locks,
+            sources,
+            queue,
+            usage,
+            watch_statistics,
+            generations: Arc::new(PendingGenerations::default()),
+            missing,
+            highlights,
+            surfaced: Arc::new(SurfacedImages::default()),
+            reactions: Arc::new(ReactionLimits::default()),
+            playlists,
+            invites,
+            devices,
+            connections: Arc::new(Connections::default()),
+            population,
+            limits: Arc::new(RequestLimits::default()),
+            reloader,
+            mirror,
+        })
+    }
+
+    /// Generates missing derivatives and removes orphaned ones, meanwhile they are generated on
+    /// demand, nothing with `--lazy-cache`
+    pub fn populate_cache(&self) -> impl Future<Output = ()> + Send + 'static {
+        let lazy = self.arguments.lazy_cache;
+        let population = populate_cache_in_background(
+            self.configuration.load(),
+            self.indexer.clone(),
+            self.locks.clone(),
+            self.sources.clone(),
+            self.queue.clone(),
+            self.population.clone(),
+        );
+        async move {
+            if lazy {
+                info!("Generating derivatives on demand only, cache is not populated");
+            } else {
+                population.await;
+            }
+        }
+    }
+
+    /// Reloads the configuration whenever the process receives SIGHUP
+    pub fn reload_on_hangup(&self) -> impl Future<Output = ()> + Send + 'static {
+        reload_on_hangup(self.reloader.clone())
+    }
+
+    /// Asks open websockets and event streams to close, e.g. when shutting down
+    pub fn close_connections(&self) {
+        self.connections.close_all();
+    }
+
+    /// Persists what is only kept in memory, to be called after serving stopped
+    pub async fn save(&self) -> Result<()> {
+        self.sources
+            .save()
+            .await
+            .context("failed to save source records")
+    }
+
+    /// Imports the images in `directory` like uploads, see [`Command::Import`]
+    pub async fn import(&self, directory: &Path) -> Result<ImportSummary> {
+        import_directory(
+            &self.configuration.load(),
+            &self.indexer,
+            &self.sources,
+            &self.queue,
+            directory,
+        )
+        .await
+        .with_context(|| format!("failed to read {}", directory.display()))
+    }
+
+    /// Writes the wall as a static gallery into `out`, see [`Command::ExportStatic`], returns
+    /// how many images were exported
+    pub async fn export_static(&self, out: &Path) -> Result<usize> {
+        export_static(
+            &self.configuration.load(),
+            &self.locks,
+            &self.sources,
+            &self.queue,
+            &self.indexer,
+            out,
+        )
+        .await
+        .with_context(|| format!("failed to export to {}", out.display()))
+    }
+
+    pub fn configuration(&self) -> Arc<Configuration> {
+        self.configuration.load()
+    }
+
+    pub fn indexer(&self) -> &Arc<Indexer> {
+        &self.indexer
+    }
+}
+
+/// All routes of `moments` with authentication, base path and logging. Handlers expect
+/// [`axum::extract::ConnectInfo`] of the client's `SocketAddr`, e.g. from
+/// `into_make_service_with_connect_info`.
+pub fn build_router(moments: &Moments) -> Router {
+    let Moments {
+        configuration,
+        indexer,
+        locks,
+        sources,
+        queue,
+        generations,
+        missing,
+        highlights,
+        surfaced,
+        reactions,
+        playlists,
+        invites,
+        devices,
+        usage,
+        connections,
+        limits,
+        ..
+    } = moments;
+    let current = configuration.load();
+    let read_only = from_fn_with_state(configuration.clone(), refuse_changes_when_read_only);
+    let maintenance = moments.arguments.maintenance_port.is_some();
+    let compression = from_fn_with_state(configuration.clone(), compress_responses);
+    // also callable from pages of origins allowed with --cors-allowed-origin, unlike the routes
+    // only kiosks use, so CORS answers preflight requests before authentication
+    let cross_origin = Router::new()
+        .merge(
+            Router::new()
+                .route("/version", get(handle_version))
+                .route(
+                    UPLOAD_PATH,
+                    post(upload_image)
+                        .with_state((
+                            configuration.clone(),
+                            indexer.clone(),
+                            sources.clone(),
+                            queue.clone(),
+                            devices.clone(),
+                        ))
+                        .layer(DefaultBodyLimit::max(
+                            moments.arguments.max_request_body_size,
+                        ))
+                        .layer(read_only.clone()),
+                )
+                .route(
+                    "/devices",
+                    post(handle_register_device)
+                        .with_state((configuration.clone(), devices.clone()))
+                        .layer(read_only.clone()),
+                )
+                .route_layer(from_fn_with_state(configuration.clone(), require_secret))
+                // registered devices upload with their token instead of the secret
+                .route_layer(from_fn_with_state(devices.clone(), authenticate_device)),
+        )
+        // the invite replaces the secret, so relatives far away never learn it
+        .route(
+            INVITED_UPLOAD_PATH,
+            post(upload_invited_image)
+                .with_state((
+                    configuration.clone(),
+                    indexer.clone(),
+                    sources.clone(),
+                    queue.clone(),
+                    invites.clone(),
+                ))
+                .layer(DefaultBodyLimit::max(
+                    moments.arguments.max_request_body_size,
+                ))
+                .layer(read_only.clone()),
+        )
+        .merge(if maintenance {
+            Router::new()
+        } else {
+            stats_routes(moments)
+        })
+        .layer(compression.clone())
+        .layer(option_layer(cors_layer(
+            &moments.arguments.cors_allowed_origin,
+        )));
+    let generate = Router::new()
+        .route("/*path", get(serve_and_cache))
+        .with_state((
+            configuration.clone(),
+            indexer.clone(),
+            locks.clone(),
+            sources.clone(),
+            queue.clone(),
+            generations.clone(),
+            missing.clone(),
+        ));
+    let images = if moments.arguments.lazy_cache {
+        generate
+    } else {
+        // missing derivatives are generated on demand while the cache is populated
+        Router::new().fallback_service(ServeDir::new(&current.cache).fallback(generate))
+    };
+    let routes = Router::new()
+        .nest_service(
+            "/images",
+            ServiceBuilder::new()
+                // bookkeeping like the upload code key lives in the cache directory too
+                .layer(from_fn(refuse_hidden_paths))
+                .layer(from_fn_with_state(configuration.clone(), select_size))
+                .layer(from_fn_with_state(configuration.clone(), label_images))
+                .layer(SetResponseHeaderLayer::if_not_present(
+                    header::CACHE_CONTROL,
+                    // derivatives only change with their source or the cache settings, ETags
+                    // make revalidating them cheap, errors must not stick
+                    |response: &Response| {
+                        let status = response.status();
+                        (status.is_success() || status == StatusCode::NOT_MODIFIED).then(|| {
+                            HeaderValue::from_static("public, max-age=31536000, immutable")
+                        })
+                    },
+                ))
+                .layer(from_fn_with_state(
+                    (configuration.clone(), sources.clone()),
+                    tag_images,
+                ))
+                .layer(from_fn_with_state(usage.clone(), track_access))
+                .service(images),
+        )
+        .route(
+            "/index",
+            get(handle_websocket_upgrade).with_state((
+                configuration.clone(),
+                indexer.clone(),
+                connections.clone(),
+                highlights.clone(),
+            )),
+        )
+        .route(
+            "/events",
+            get(handle_events).with_state((
+                configuration.clone(),
+                indexer.clone(),
+                connections.clone(),
+            )),
+        )
+        .merge(
+            Router::new()
+                .route(
+                    "/qr.svg",
+                    get(handle_qr_code_svg).with_state(configuration.clone()),
+                )
+                .route(
+                    "/qr.png",
+                    get(handle_qr_code_png).with_state(configuration.clone()),
+                )
+                .route(
+                    "/recommend",
+                    get(recommend_images).with_state((
+                        configuration.clone(),
+                        indexer.clone(),
+                        sources.clone(),
+                        surfaced.clone(),
+                    )),
+                )
+                .route(
+                    "/report",
+                    post(handle_report)
+                        .with_state((configuration.clone(), indexer.clone(), sources.clone()))
+                        .layer(read_only.clone()),
+                )
+                .route(
+                    "/react/:hash",
+                    post(handle_react)
+                        .with_state((
+                            configuration.clone(),
+                            indexer.clone(),
+                            sources.clone(),
+                            reactions.clone(),
+                        ))
+                        .layer(read_only.clone()),
+                )
+                .route(
+                    "/playlists/:name",
+                    get(handle_get_playlist).with_state((indexer.clone(), playlists.clone())),
+                )
+                .route(
+                    "/playlists/:name/next",
+                    get(handle_next).with_state((indexer.clone(), playlists.clone())),
+                )
+                .route(
+                    "/feed.atom",
+                    get(handle_feed).with_state((configuration.clone(), indexer.clone())),
+                )
+                .route(
+                    "/random",
+                    get(random_image).with_state((configuration.clone(), indexer.clone())),
+                )
+                .route(
+                    "/shown",
+                    post(report_shown)
+                        .with_state((
+                            configuration.clone(),
+                            indexer.clone(),
+                            sources.clone(),
+                            surfaced.clone(),
+                        ))
+                        .layer(read_only.clone()),
+                )
+                // images are compressed already and websocket upgrades have no body, so only
+                // the JSON answers are
+                .layer(compression.clone()),
+        );
+    // full resolution files in storage, with content types and range requests, symbolic links
+    // out of a local directory are not followed
+    let originals = match current.originals.local_directory() {
+        Some(directory) => Router::new().nest_service(
+            "/originals",
+            ServiceBuilder::new()
+                .layer(from_fn(attach_filename))
+                .layer(from_fn_with_state(
+                    configuration.clone(),
+                    refuse_links_out_of_storage,
+                ))
+                .layer(from_fn_with_state(configuration.clone(), check_if_range))
+                .service(ServeDir::new(directory)),
+        ),
+        None => Router::new().nest_service(
+            "/originals",
+            ServiceBuilder::new()
+                .layer(from_fn(attach_filename))
+                .layer(from_fn_with_state(configuration.clone(), check_if_range))
+                .service(get(serve_stored_original).with_state(configuration.clone())),
+        ),
+    };
+    let originals = originals.route_layer(from_fn_with_state(
+        configuration.clone(),
+        require_download_secret,
+    ));
+    let app = Router::new()
+        .merge(originals)
+        .merge(cross_origin)
+        .merge(routes.route_layer(from_fn_with_state(configuration.clone(), require_secret)));
+    // only served on the maintenance port if there is one
+    let app = if maintenance {
+        app
+    } else {
+        app.merge(maintenance_routes(moments).layer(compression.clone()))
+    };
+    let no_cache = SetResponseHeaderLayer::if_not_present(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static("no-cache, no-store"),
+    );
+    let app = match &moments.arguments.frontend_dir {
+        Some(frontend_dir) => app.fallback_service(
+            ServiceBuilder::new()
+                .layer(no_cache)
+                .layer(compression)
+                .service(ServeDir::new(frontend_dir)),
+        ),
+        None => app.fallback_service(
+            ServiceBuilder::new()
+                .layer(no_cache)
+                .layer(compression)
+                .service(serve_frontend.into_service()),
+        ),
+    };
+    // prefixes have to be gone before routing, layers of the router only run after it
+    let app = ServiceBuilder::new()
+        .layer(from_fn(assign_request_ids))
+        .layer(from_fn_with_state(configuration.clone(), log_requests))
+        .layer(from_fn_with_state(configuration.clone(), strip_base_path))
+        .layer(from_fn_with_state(
+            configuration.clone(),
+            strip_secret_prefix,
+        ))
+        // after stripping prefixes, it tells routes apart by path
+        .layer(from_fn_with_state(
+            (configuration.clone(), limits.clone()),
+            limit_requests,
+        ))
+        .service(app);
+    // a fallback keeps the prefixes stripped before routing, unlike layers of the router
+    Router::new().fallback_service(app)
+}
+
+/// Builds the router for `--maintenance-port`, with the admin API, statistics and health checks
+/// taken off the public port. Admin routes still require the admin secret, by bearer token or
+/// `?token=` as the secret prefix and base path are not stripped here.
+pub fn build_maintenance_router(moments: &Moments) -> Router {
+    let configuration = &moments.configuration;
+    // not limited, health checks answer even while the public port is at its limit
+    maintenance_routes(moments)
+        .layer(from_fn_with_state(
+            configuration.clone(),
+            compress_responses,
+        ))
+        .layer(from_fn_with_state(configuration.clone(), log_requests))
+        .layer(from_fn(assign_request_ids))
+}
+
+/// Admin API, statistics and health checks, without compression
+fn maintenance_routes(moments: &Moments) -> Router {
+    let Moments {
+        configuration,
+        indexer,
+        locks,
+        sources,
+        queue,
+        playlists,
+        invites,
+        devices,
+        connections,
+        population,
+        reloader,
+        ..
+    } = moments;
+    let current = configuration.load();
+    let read_only = from_fn_with_state(configuration.clone(), refuse_changes_when_read_only);
+    // only reachable with an admin secret, 404 for everybody else
+    Router::new()
+        .route(
+            "/admin/connections",
+            get(handle_connections).with_state(connections.clone()),
+        )
+        .route(
+            "/admin/kiosks",
+            get(handle_kiosks).with_state(connections.clone()),
+        )
+        .route(
+            "/admin/reconcile",
+            post(handle_reconcile)
+                .with_state((
+                    configuration.clone(),
+                    indexer.clone(),
+                    locks.clone(),
+                    sources.clone(),
+                    queue.clone(),
+                ))
+                .layer(read_only.clone()),
+        )
+        .route(
+            "/admin/secrets",
+            get(handle_list_secrets)
+                .post(handle_add_secret)
+                .with_state(current.secrets.clone())
+                .layer(read_only.clone()),
+        )
+        .route(
+            "/admin/export.zip",
+            get(handle_export).with_state((configuration.clone(), indexer.clone())),
+        )
+        .route(
+            "/admin/images",
+            get(handle_list_images).with_state((indexer.clone(), sources.clone())),
+        )
+        .route(
+            "/admin/images/:hash",
+            get(handle_get_image).with_state((indexer.clone(), sources.clone())),
+        )
+        .route(
+            "/admin/pin/:hash",
+            post(handle_pin)
+                .delete(handle_unpin)
+                .with_state((configuration.clone(), indexer.clone(), sources.clone()))
+                .layer(read_only.clone()),
+        )
+        .route(
+            "/admin/hide/:hash",
+            post(handle_hide)
+                .delete(handle_unhide)
+                .with_state((configuration.clone(), indexer.clone(), sources.clone()))
+                .layer(read_only.clone()),
+        )
+        .route(
+            "/admin/playlists/:name",
+            put(handle_create_playlist)
+                .delete(handle_delete_playlist)
+                .with_state((indexer.clone(), playlists.clone()))
+                .layer(read_only.clone()),
+        )
+        .route(
+            "/admin/devices",
+            get(handle_list_devices).with_state(devices.clone()),
+        )
+        .route(
+            "/admin/devices/:id",
+            delete(handle_revoke_device)
+                .with_state(devices.clone())
+                .layer(read_only.clone()),
+        )
+        .route(
+            "/admin/invites",
+            get(handle_list_invites)
+                .post(handle_create_invite)
+                .with_state(invites.clone())
+                .layer(read_only.clone()),
+        )
+        .route(
+            "/admin/invites/:id",
+            delete(handle_revoke_invite)
+                .with_state(invites.clone())
+                .layer(read_only.clone()),
+        )
+        .route(
+            // changes nothing but the configuration, and is how an archived gallery thaws
+            "/admin/reload",
+            post(handle_reload).with_state(reloader.clone()),
+        )
+        .route(
+            "/admin/secrets/:name",
+            delete(handle_remove_secret)
+                .with_state(current.secrets.clone())
+                .layer(read_only.clone()),
+        )
+        .route_layer(from_fn_with_state(configuration.clone(), require_admin))
+        .merge(match moments.arguments.maintenance_port {
+            // the public port answers it with CORS headers instead
+            Some(_) => stats_routes(moments),
+            None => Router::new(),
+        })
+        .route("/healthz", get(handle_health))
+        .route(
+            "/readyz",
+            get(handle_readiness).with_state((
+                configuration.clone(),
+                indexer.clone(),
+                population.clone(),
+            )),
+        )
+}
+
+/// `/admin/stats`, only reachable with an admin secret
+fn stats_routes(moments: &Moments) -> Router {
+    let configuration = &moments.configuration;
+    Router::new()
+        .route(
+            "/admin/stats",
+            get(handle_stats).with_state((
+                configuration.clone(),
+                moments.queue.clone(),
+                moments.usage.clone(),
+                moments.watch_statistics.clone(),
+                moments.limits.clone(),
+                moments.missing.clone(),
+                moments.mirror.clone(),
+            )),
+        )
+        .route_layer(from_fn_with_state(configuration.clone(), require_admin))
+}
+
+/// Where originals are kept, the bucket of `--s3-endpoint`, the WebDAV collection of
+/// `--webdav-url` or the `--storage` directory
+fn open_storage(arguments: &Arguments) -> Arc<dyn Storage> {
+    #[cfg(feature = "s3")]
+    if let Some(endpoint) = &arguments.s3_endpoint {
+        return Arc::new(S3Storage::new(
+            endpoint.clone(),
+            arguments.s3_bucket.clone().unwrap_or_default(),
+            Credentials {
+                region: arguments.s3_region.clone(),
+                access_key: arguments.s3_access_key.clone().unwrap_or_default(),
+                secret_key: arguments.s3_secret_key.clone().unwrap_or_default(),
+            },
+        ));
+    }
+    match &arguments.webdav_url {
+        Some(url) => Arc::new(WebDavStorage::new(
+            url.clone(),
+            arguments.webdav_user.as_deref(),
+            arguments.webdav_password.as_deref(),
+        )),
+        None => Arc::new(LocalStorage::new(&arguments.storage)),
+    }
+}
+
+/// Builds the configuration from parsed arguments, reading the secret file if needed
+pub(crate) fn configure(arguments: Arguments) -> Result<Configuration> {
+    check_arguments(&arguments)?;
+    let originals = open_storage(&arguments);
+    let specifications = match (arguments.secret.is_empty(), &arguments.secret_file) {
+        (false, _) => arguments.secret,
+        (true, Some(secret_file)) => read_to_string(secret_file)
+            .context("failed to read secret file")?
+            .lines()
+            .map(str::trim_end)
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect(),
+        (true, None) => {
+            bail!("a secret is required, pass --secret, --secret-file or MOMENTS_SECRET")
+        }
+    };
+    if specifications.is_empty() {
+        bail!("the secret must not be empty");
+    }
+    // a single secret grants everything like before roles existed, unless there is an admin
+    // secret besides it
+    let bare_role = match arguments.admin_secret {
+        Some(_) => Role::Guest,
+        None => Role::Admin,
+    };
+    let secrets = specifications
+        .iter()
+        .map(|specification| Secret::parse(specification, bare_role))
+        .chain(arguments.admin_secret.map(|value| {
+            Ok(Secret {
+                name: ADMIN_NAME.to_string(),
+                value,
+                role: Role::Admin,
+            })
+        }))
+        .collect::<Result<Vec<_>, _>>()
+        .and_then(Secrets::new)
+        .context("invalid secret")?;
+    if secrets
+        .list()
+        .iter()
+        .all(|secret| secret.role != Role::Admin)
+    {
+        warn!("no admin secret given, the /admin/ endpoints cannot be used");
+    }
+    Ok(Configuration {
+        secrets: Arc::new(secrets),
+        secret_in_path: arguments.secret_in_path,
+        base_path: arguments.base_path,
+        trust_forwarded_prefix: arguments.trust_forwarded_prefix,
+        public_url: arguments.public_url,
+        download_secret: arguments
+            .download_secret
+            .filter(|secret| !secret.is_empty()),
+        // needs the key in the cache, set when starting or reloading
+        upload_codes: None,
+        originals,
+        storage: arguments.storage,
+        cache: arguments.cache,
+        cache_layout: CacheLayout {
+            max_size: arguments.max_cached_image_size,
+            sizes: arguments.cache_sizes,
+            format: arguments.cache_format,
+            on_demand_sizes: arguments.on_demand_sizes,
+        },
+        jpeg_image_quality: arguments.jpeg_image_quality,
+        placeholder_color: arguments.placeholder_color,
+        missing_image_ttl: Duration::from_secs(arguments.missing_image_ttl),
+        resize_filter: arguments.resize_filter,
+        cache_workers: arguments
+            .cache_workers
+            .unwrap_or_else(|| available_parallelism().map(NonZeroUsize::get).unwrap_or(1)),
+        max_cache_bytes: arguments.max_cache_bytes,
+        websocket_ping_interval: Duration::from_secs(arguments.websocket_ping_interval),
+        websocket_compression: arguments.websocket_compression,
+        compression: !arguments.disable_compression,
+        snapshot_chunk_size: arguments.snapshot_chunk_size.get(),
+        change_batch_window: Duration::from_millis(arguments.change_batch_window),
+        highlight_interval: Duration::from_secs(arguments.highlight_interval),
+        pin_interval: Duration::from_secs(arguments.pin_interval),
+        fresh_window: Duration::from_secs(arguments.fresh_window),
+        reports_to_hide: arguments.reports_to_hide,
+        sidecars: arguments.sidecars,
+        feed_size: arguments.feed_size as usize,
+        reactions_per_minute: arguments.reactions_per_minute,
+        settle_time: Duration::from_millis(arguments.settle_time),
+        watch_mode: arguments.watch_mode,
+        poll_interval: Duration::from_secs(arguments.poll_interval),
+        read_only: arguments.read_only,
+        request_timeout: Duration::from_secs(arguments.request_timeout),
+        upload_timeout: Duration::from_secs(arguments.upload_timeout),
+        max_concurrent_requests: arguments.max_concurrent_requests as usize,
+    })
+}
+
+pub(crate) async fn populate_cache_in_background(
+    configuration: Arc<Configuration>,
+    indexer: Arc<Indexer>,
+    locks: Arc<CacheLocks>,
+    sources: Arc<SourceRecords>,
+    queue: Arc<ProcessingQueue>,
+    population: Arc<CachePopulation>,
+) {
+    info!("Reconciling cache with storage...");
+    let images = match indexer.index_including_hidden().await {
+        Ok(images) => images,
+        Err(error) => {
+            error!("failed to reconcile cache: {error}");
+            population.set(PopulationStatus::Failed);
+            return;
+        }
+    };
+    match reconcile(&configuration, &locks, &sources, &queue, &indexer, &images).await {
+        Ok(report) => {
+            info!(
+                "Created {} derivatives, removed {} orphaned cache files",
+                report.derivatives_created.len(),
+                report.orphans_removed.len()
+            );
+            if !report.unreadable.is_empty() {
+                warn!("{} images could not be cached", report.unreadable.len());
+            }
+            population.set(PopulationStatus::Finished);
+        }
+        Err(error) => {
+            error!("failed to reconcile cache: {error}");
+            population.set(PopulationStatus::Failed);
+        }
+    }
+}
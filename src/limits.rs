@@ -0,0 +1,110 @@
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc,
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use log::warn;
+use serde::Serialize;
+use tokio::time::timeout;
+
+use crate::{reload::SharedConfiguration, upload::is_upload_path, Configuration};
+
+/// Routes whose responses last as long as the client stays connected, never timed out
+const LONG_LIVED_PATHS: [&str; 2] = ["/index", "/events"];
+
+/// Seconds clients rejected for too many requests in flight are asked to wait before retrying
+const RETRY_AFTER_SECONDS: &str = "1";
+
+/// Requests currently handled and how often the limits were hit since the start
+#[derive(Default)]
+pub struct RequestLimits {
+    in_flight: AtomicUsize,
+    rejected: AtomicU64,
+    timed_out: AtomicU64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RequestStatistics {
+    pub in_flight: usize,
+    pub max_in_flight: usize,
+    /// requests answered with 503 because too many were in flight
+    pub rejected: u64,
+    /// requests answered with 408 because they took longer than their timeout
+    pub timed_out: u64,
+    pub timeout_seconds: u64,
+    pub upload_timeout_seconds: u64,
+}
+
+impl RequestLimits {
+    pub fn statistics(&self, configuration: &Configuration) -> RequestStatistics {
+        RequestStatistics {
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            max_in_flight: configuration.max_concurrent_requests,
+            rejected: self.rejected.load(Ordering::Relaxed),
+            timed_out: self.timed_out.load(Ordering::Relaxed),
+            timeout_seconds: configuration.request_timeout.as_secs(),
+            upload_timeout_seconds: configuration.upload_timeout.as_secs(),
+        }
+    }
+}
+
+/// Counts a request as in flight until dropped
+struct InFlight<'a>(&'a AtomicUsize);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub type LimitState = (Arc<SharedConfiguration>, Arc<RequestLimits>);
+
+/// Answers requests with 503 while `--max-concurrent-requests` others are being handled, and
+/// with 408 if handling them takes longer than `--request-timeout`, or `--upload-timeout` for
+/// uploads. A request counts until its response starts, so streamed files and open websockets
+/// or event streams do not hold on to the limit, and the latter two are never timed out.
+pub async fn limit_requests(
+    State((configuration, limits)): State<LimitState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let configuration = configuration.load();
+    let in_flight = limits.in_flight.fetch_add(1, Ordering::Relaxed);
+    let _guard = InFlight(&limits.in_flight);
+    if in_flight >= configuration.max_concurrent_requests {
+        limits.rejected.fetch_add(1, Ordering::Relaxed);
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(
+                header::RETRY_AFTER,
+                HeaderValue::from_static(RETRY_AFTER_SECONDS),
+            )],
+            "too many requests in flight, try again in a moment",
+        )
+            .into_response();
+    }
+    let path = request.uri().path();
+    if LONG_LIVED_PATHS.contains(&path) {
+        return next.run(request).await;
+    }
+    let duration = if is_upload_path(path) {
+        configuration.upload_timeout
+    } else {
+        configuration.request_timeout
+    };
+    let path = path.to_string();
+    match timeout(duration, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => {
+            limits.timed_out.fetch_add(1, Ordering::Relaxed);
+            warn!(path = path.as_str(); "request timed out after {}s", duration.as_secs());
+            (StatusCode::REQUEST_TIMEOUT, "request timed out").into_response()
+        }
+    }
+}
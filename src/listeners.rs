@@ -0,0 +1,68 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use thiserror::Error;
+use tokio::net::TcpListener;
+
+/// Connections waiting to be accepted per listener before new ones are refused
+const BACKLOG: i32 = 1024;
+
+/// Parses a `--host` like `0.0.0.0`, `::` or `[::]` into an IP address
+pub fn parse_host(host: &str) -> Result<IpAddr, String> {
+    let unbracketed = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    unbracketed
+        .parse()
+        .map_err(|_| format!("expected an IP address like 0.0.0.0 or ::, got {host:?}"))
+}
+
+/// Binds a listener on `port` of every host, failing if any of them cannot be bound. IPv6
+/// listeners only accept IPv6 if IPv4 addresses are bound as well, so wildcards of both coexist
+/// on systems whose IPv6 sockets would otherwise claim the IPv4 port too.
+pub fn bind_listeners(hosts: &[IpAddr], port: u16) -> Result<Vec<TcpListener>, ListenError> {
+    let only_v6 = hosts.iter().any(IpAddr::is_ipv4);
+    let mut addresses = Vec::new();
+    for host in hosts {
+        let address = SocketAddr::new(*host, port);
+        if !addresses.contains(&address) {
+            addresses.push(address);
+        }
+    }
+    addresses
+        .into_iter()
+        .map(|address| {
+            bind(address, only_v6).map_err(|source| ListenError::Bind { address, source })
+        })
+        .collect()
+}
+
+fn bind(address: SocketAddr, only_v6: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    if address.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    // allows restarting while connections of the previous process linger in TIME_WAIT
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[derive(Debug, Error)]
+pub enum ListenError {
+    #[error("failed to bind {address}")]
+    Bind {
+        address: SocketAddr,
+        source: io::Error,
+    },
+}
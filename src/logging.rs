@@ -0,0 +1,205 @@
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::Request,
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use clap::ValueEnum;
+use env_logger::{fmt::Formatter, Env};
+use log::{
+    kv::{self, Key, Value, VisitSource, VisitValue},
+    Record,
+};
+use serde_json::{Map, Number};
+use tokio::task_local;
+
+/// Taken from proxies that already assigned an ID and returned to the client
+static REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// IDs passed in by clients longer than this are replaced with an own one
+const MAXIMUM_REQUEST_ID_LENGTH: usize = 64;
+
+task_local! {
+    /// ID of the request being handled by the current task, attached to every log line
+    static CURRENT_REQUEST_ID: String;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// human-readable lines with fields appended as `key=value`
+    Text,
+    /// one JSON object per line, e.g. for shipping logs to Loki
+    Json,
+}
+
+/// Logs to stderr in `format`, filtered by `RUST_LOG` which defaults to info
+pub fn initialize_logging(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_env(Env::default().default_filter_or("info"));
+    match format {
+        LogFormat::Text => builder.format(format_text),
+        LogFormat::Json => builder.format(format_json),
+    };
+    builder.init();
+}
+
+/// Assigns every request an ID, taken from `X-Request-Id` if a proxy set a sensible one. It is
+/// included in all log lines emitted while handling the request and returned as
+/// `X-Request-Id`, so a client's report can be matched to the logs. Work handed to other tasks,
+/// like processing an upload in the queue, logs without it.
+pub async fn assign_request_ids(request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(&REQUEST_ID)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| is_valid_request_id(id))
+        .map(str::to_string)
+        .unwrap_or_else(generate_request_id);
+    let header = HeaderValue::from_str(&id).unwrap();
+    let mut response = CURRENT_REQUEST_ID.scope(id, next.run(request)).await;
+    response.headers_mut().insert(REQUEST_ID.clone(), header);
+    response
+}
+
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAXIMUM_REQUEST_ID_LENGTH
+        && id
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"-_.".contains(&byte))
+}
+
+/// Unique within a run and unlikely to repeat across restarts, as counting starts at the time of
+/// the first request
+fn generate_request_id() -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let _ = NEXT.compare_exchange(
+        0,
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64,
+        Ordering::Relaxed,
+        Ordering::Relaxed,
+    );
+    format!("{:016x}", NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+fn current_request_id() -> Option<String> {
+    CURRENT_REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+fn format_text(formatter: &mut Formatter, record: &Record) -> io::Result<()> {
+    let style = formatter.default_level_style(record.level());
+    let mut fields = String::new();
+    if let Some(id) = current_request_id() {
+        let _ = write!(fields, " request_id={id}");
+    }
+    let mut visitor = TextFields(&mut fields);
+    let _ = record.key_values().visit(&mut visitor);
+    writeln!(
+        formatter,
+        "[{} {style}{:<5}{style:#} {}] {}{fields}",
+        formatter.timestamp(),
+        record.level(),
+        record.target(),
+        record.args(),
+    )
+}
+
+fn format_json(formatter: &mut Formatter, record: &Record) -> io::Result<()> {
+    let mut line = Map::new();
+    line.insert(
+        "timestamp".to_string(),
+        formatter.timestamp_millis().to_string().into(),
+    );
+    line.insert("level".to_string(), record.level().as_str().into());
+    line.insert("module".to_string(), record.target().into());
+    if let Some(id) = current_request_id() {
+        line.insert("request_id".to_string(), id.into());
+    }
+    line.insert("message".to_string(), record.args().to_string().into());
+    let _ = record.key_values().visit(&mut JsonFields(&mut line));
+    serde_json::to_writer(&mut *formatter, &line)?;
+    writeln!(formatter)
+}
+
+/// Appends fields as ` key=value`, quoting strings containing spaces or control characters and
+/// writing missing values as `-`
+struct TextFields<'a>(&'a mut String);
+
+impl<'kvs> VisitSource<'kvs> for TextFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let mut json = JsonValue(serde_json::Value::Null);
+        value.visit(&mut json)?;
+        let _ = match json.0 {
+            serde_json::Value::Null => write!(self.0, " {key}=-"),
+            serde_json::Value::String(text)
+                if text.is_empty()
+                    || text.contains(|c: char| c.is_whitespace() || c.is_control() || c == '"') =>
+            {
+                write!(self.0, " {key}={text:?}")
+            }
+            _ => write!(self.0, " {key}={value}"),
+        };
+        Ok(())
+    }
+}
+
+/// Inserts fields into the JSON line, numbers and booleans keep their type
+struct JsonFields<'a>(&'a mut Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let mut json = JsonValue(serde_json::Value::Null);
+        value.visit(&mut json)?;
+        self.0.insert(key.to_string(), json.0);
+        Ok(())
+    }
+}
+
+/// A field value converted to JSON, anything but numbers, booleans and missing values as string
+struct JsonValue(serde_json::Value);
+
+impl<'v> VisitValue<'v> for JsonValue {
+    fn visit_any(&mut self, value: Value) -> Result<(), kv::Error> {
+        self.0 = value.to_string().into();
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> Result<(), kv::Error> {
+        self.0 = serde_json::Value::Null;
+        Ok(())
+    }
+
+    fn visit_u64(&mut self, value: u64) -> Result<(), kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_i64(&mut self, value: i64) -> Result<(), kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_f64(&mut self, value: f64) -> Result<(), kv::Error> {
+        self.0 = Number::from_f64(value).map_or(serde_json::Value::Null, Into::into);
+        Ok(())
+    }
+
+    fn visit_bool(&mut self, value: bool) -> Result<(), kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_str(&mut self, value: &str) -> Result<(), kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+}
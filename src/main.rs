@@ -1,152 +1,134 @@
-use std::{net::SocketAddr, path::PathBuf, sync::Arc};
-
-use anyhow::{Context, Result};
-use axum::{
-    extract::DefaultBodyLimit,
-    http::{header, HeaderValue},
-    routing::{get, post},
-    Router,
+use std::{
+    env::args_os, ffi::OsString, future::IntoFuture, net::SocketAddr, path::Path, time::Duration,
 };
-use cache::cache_image;
-use clap::Parser;
-use env_logger::Env;
-use index::{collect_images, Indexer};
-use log::info;
-use tokio::{fs::create_dir_all, net::TcpListener, signal};
-use tower::ServiceBuilder;
-use tower_http::{services::ServeDir, set_header::SetResponseHeaderLayer};
-use upload::upload_image;
-use websocket::handle_websocket_upgrade;
-
-mod cache;
-mod index;
-mod upload;
-mod watcher;
-mod websocket;
 
-/// A simple image gallery server
-#[derive(Parser)]
-struct Arguments {
-    /// host to listen on
-    #[arg(long, default_value = "0.0.0.0")]
-    host: String,
-    /// port to listen on
-    #[arg(long, default_value = "3000")]
-    port: u16,
-    /// path to directory where uploaded images are stored
-    #[arg(long, default_value = "storage/")]
-    storage: PathBuf,
-    /// path to directory where cached images are stored
-    #[arg(long, default_value = "cache/")]
-    cache: PathBuf,
-    /// a secret used to authenticate requests, e.g. the name of the event
-    #[arg(long)]
-    secret: String,
-    /// Maximum size of longest edge of cached images in pixels
-    #[arg(long, default_value = "1000")]
-    max_cached_image_size: u32,
-    /// JPEG image quality
-    #[arg(long, default_value = "80")]
-    jpeg_image_quality: u8,
-    /// the maximum size of a request body in bytes, which results in the maximum size an uploaded
-    /// image can have
-    #[arg(long, default_value = "16777216")]
-    max_request_body_size: usize,
-}
+use anyhow::{bail, Context, Result};
+use axum::{extract::Request, ServiceExt};
+use clap::{CommandFactory, Parser};
+use futures_util::future::try_join_all;
+use log::{info, warn};
+use moments::{
+    bind_listeners, build_maintenance_router, build_router, diagnose, initialize_logging, systemd,
+    with_file_arguments, Arguments, Command, Moments, Version,
+};
+use tokio::{select, signal, spawn, sync::watch, time::sleep};
 
-#[derive(Clone)]
-pub struct Configuration {
-    storage: PathBuf,
-    cache: PathBuf,
-    max_cached_image_size: u32,
-    jpeg_image_quality: u8,
-}
+/// How long requests in flight may take to finish when shutting down, below the 10s Docker waits
+/// before killing the container
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(8);
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
-
-    let arguments = Arguments::parse();
-    let configuration = Arc::new(Configuration {
-        storage: arguments.storage,
-        cache: arguments.cache,
-        max_cached_image_size: arguments.max_cached_image_size,
-        jpeg_image_quality: arguments.jpeg_image_quality,
+    let command_line: Vec<_> = args_os().collect();
+    let arguments =
+        Arguments::parse_from(with_file_arguments(&Arguments::command(), &command_line)?);
+    initialize_logging(arguments.log_format);
+    info!("{}", Version::current());
+    match &arguments.command {
+        Some(Command::Import(import)) => {
+            let directory = import.from.clone();
+            return import_images(command_line, arguments, &directory).await;
+        }
+        Some(Command::ExportStatic(export)) => {
+            let out = export.out.clone();
+            let moments = Moments::start(command_line, arguments).await?;
+            let exported = moments.export_static(&out).await?;
+            moments.save().await?;
+            println!("exported {exported} images to {}", out.display());
+            return Ok(());
+        }
+        Some(Command::Doctor(doctor)) => {
+            let diagnosis = diagnose(&arguments, doctor.check_public_url).await;
+            print!("{diagnosis}");
+            let failures = diagnosis.failures();
+            if failures > 0 {
+                bail!("{failures} checks failed");
+            }
+            return Ok(());
+        }
+        Some(Command::Serve) | None => {}
+    }
+    let (hosts, port) = (arguments.host.clone(), arguments.port);
+    let maintenance = arguments
+        .maintenance_port
+        .map(|port| (arguments.maintenance_host.clone(), port));
+    let moments = Moments::start(command_line, arguments).await?;
+    let app = build_router(&moments);
+
+    // with socket activation, systemd decides where to listen
+    let mut listeners = systemd::inherited_listeners()?;
+    if listeners.is_empty() {
+        listeners = bind_listeners(&hosts, port)?;
+    }
+    for listener in &listeners {
+        info!("Serving at {}", listener.local_addr()?);
+    }
+    let mut routed: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| (listener, app.clone()))
+        .collect();
+    if let Some((hosts, port)) = maintenance {
+        let maintenance_app = build_maintenance_router(&moments);
+        for listener in bind_listeners(&hosts, port)? {
+            info!("Serving maintenance at {}", listener.local_addr()?);
+            routed.push((listener, maintenance_app.clone()));
+        }
+    }
+    let initial_population = moments.populate_cache();
+    spawn(async move {
+        initial_population.await;
+        systemd::notify("STATUS=Serving, cache populated");
     });
-
-    create_dir_all(&configuration.storage)
-        .await
-        .context("failed to create storage directory")?;
-    create_dir_all(&configuration.cache)
-        .await
-        .context("failed to create cache directory")?;
-
-    info!("Populating cache...");
-    populate_cache(&configuration)
-        .await
-        .context("failed to populate cache")?;
-
-    let indexer = Arc::new(Indexer::spawn(&configuration.storage).await?);
-
-    let app = Router::new()
-        .nest_service(
-            &format!("/{}/images", arguments.secret),
-            ServiceBuilder::new()
-                .layer(SetResponseHeaderLayer::if_not_present(
-                    header::CACHE_CONTROL,
-                    HeaderValue::from_static("public, max-age=15552000"),
-                ))
-                .service(ServeDir::new(&configuration.cache)),
+    spawn(moments.reload_on_hangup());
+    let (shutdown_sender, shutdown) = watch::channel(false);
+    let servers = routed.into_iter().map(|(listener, app)| {
+        let mut shutdown = shutdown.clone();
+        axum::serve(
+            listener,
+            ServiceExt::<Request>::into_make_service_with_connect_info::<SocketAddr>(app),
         )
-        .route(
-            &format!("/{}/index", arguments.secret),
-            get(handle_websocket_upgrade).with_state(indexer.clone()),
-        )
-        .route(
-            &format!("/{}/upload", arguments.secret),
-            post(upload_image)
-                .with_state((configuration.clone(), indexer.clone()))
-                .layer(DefaultBodyLimit::max(arguments.max_request_body_size)),
-        )
-        .fallback_service(
-            ServiceBuilder::new()
-                .layer(SetResponseHeaderLayer::if_not_present(
-                    header::CACHE_CONTROL,
-                    HeaderValue::from_static("no-cache, no-store"),
-                ))
-                .service(ServeDir::new("frontend/")),
-        );
-
-    let address: SocketAddr = format!("{}:{}", arguments.host, arguments.port)
-        .parse()
-        .context("failed to parse host and port")?;
-
-    info!("Serving at {address}");
-    let listener = TcpListener::bind(&address)
-        .await
-        .context("failed to bind listener")?;
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-        .context("failed to start server")?;
+        .with_graceful_shutdown(async move {
+            let _ = shutdown.wait_for(|shutdown| *shutdown).await;
+        })
+        .into_future()
+    });
+    // the index is ready, missing derivatives are generated on demand until populated
+    systemd::notify("READY=1\nSTATUS=Serving, populating cache");
+    // new connections are refused from here on, requests in flight like uploads may finish
+    let deadline = async {
+        shutdown_signal().await;
+        info!("Shutting down...");
+        systemd::notify("STOPPING=1");
+        shutdown_sender.send_replace(true);
+        moments.close_connections();
+        sleep(SHUTDOWN_TIMEOUT).await;
+    };
+    select! {
+        result = try_join_all(servers) => {
+            result.context("failed to start server")?;
+        }
+        _ = deadline => warn!(
+            "requests still running after {}s, shutting down anyway",
+            SHUTDOWN_TIMEOUT.as_secs()
+        ),
+    }
+    moments.save().await?;
+    info!("Stopped");
     Ok(())
 }
 
-async fn populate_cache(configuration: &Configuration) -> Result<()> {
-    let images = collect_images(&configuration.storage)
-        .await
-        .context("failed to index storage")?;
-    for image in images.values() {
-        let storage_path = configuration.storage.join(&image.path);
-        let cache_path = configuration.cache.join(&image.path);
-        cache_image(
-            &storage_path,
-            &cache_path,
-            configuration.max_cached_image_size,
-            configuration.jpeg_image_quality,
-        )
-        .await
-        .context("failed to cache image")?;
+/// Imports the images in `directory` without serving, failing if any of them failed
+async fn import_images(
+    command_line: Vec<OsString>,
+    arguments: Arguments,
+    directory: &Path,
+) -> Result<()> {
+    let moments = Moments::start(command_line, arguments).await?;
+    let summary = moments.import(directory).await?;
+    moments.save().await?;
+    println!("{summary}");
+    if summary.failed > 0 {
+        bail!("{} images failed to import", summary.failed);
     }
     Ok(())
 }
@@ -0,0 +1,331 @@
+use std::{
+    collections::VecDeque,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{create_dir_all, read},
+    io,
+    sync::{broadcast::error::RecvError, Notify},
+    time::{sleep, Duration},
+};
+
+use crate::{
+    cache::write_atomically,
+    http_client,
+    http_url::HttpUrl,
+    index::{hex_hash, Catchup, Change, ImageHash, Indexer, RevisedChange},
+    reload::SharedConfiguration,
+    upload::UPLOAD_PATH,
+    Configuration,
+};
+
+/// Wait after the first failed attempt to mirror an image, doubled after each further one
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+const MAXIMUM_BACKOFF: Duration = Duration::from_secs(300);
+
+/// An image waiting to be mirrored
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Pending {
+    #[serde(with = "hex_hash")]
+    hash: ImageHash,
+    path: PathBuf,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MirrorStatistics {
+    /// images waiting to be mirrored
+    pub pending: usize,
+    /// images mirrored since the start, including those the remote had already
+    pub mirrored: u64,
+    /// images given up on since the start because the remote refused them
+    pub failed: u64,
+    /// of the last failed attempt, cleared once one succeeds
+    pub last_error: Option<String>,
+}
+
+/// Images waiting to be uploaded to the instance of `--mirror-to`, persisted in the cache
+/// directory so none are lost across restarts. The instance is reached over plain HTTP on this
+/// machine, remote ones through a TLS tunnel, as the secret is sent along.
+pub struct Mirror {
+    url: HttpUrl,
+    secret: String,
+    file: PathBuf,
+    pending: Mutex<VecDeque<Pending>>,
+    queued: Notify,
+    mirrored: AtomicU64,
+    failed: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+impl Mirror {
+    pub async fn load(url: HttpUrl, secret: String, file: PathBuf) -> Self {
+        let pending = match read(&file).await {
+            Ok(contents) => serde_json::from_slice(&contents).unwrap_or_else(|error| {
+                warn!("ignoring corrupt mirror queue {}: {error}", file.display());
+                VecDeque::new()
+            }),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => VecDeque::new(),
+            Err(error) => {
+                warn!(
+                    "ignoring unreadable mirror queue {}: {error}",
+                    file.display()
+                );
+                VecDeque::new()
+            }
+        };
+        Self {
+            url,
+            secret,
+            file,
+            pending: Mutex::new(pending),
+            queued: Notify::new(),
+            mirrored: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            last_error: Mutex::new(None),
+        }
+    }
+
+    pub fn statistics(&self) -> MirrorStatistics {
+        MirrorStatistics {
+            pending: self.pending.lock().unwrap().len(),
+            mirrored: self.mirrored.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            last_error: self.last_error.lock().unwrap().clone(),
+        }
+    }
+
+    /// Queues the image unless it is queued already
+    fn enqueue(&self, hash: ImageHash, path: PathBuf) {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.iter().all(|image| image.hash != hash) {
+            pending.push_back(Pending { hash, path });
+            self.queued.notify_one();
+        }
+    }
+
+    /// Removes the image mirrored or given up on from the front of the queue
+    fn dequeue(&self, image: &Pending) {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.front() == Some(image) {
+            pending.pop_front();
+        }
+    }
+
+    async fn save(&self) {
+        let contents = serde_json::to_vec(&*self.pending.lock().unwrap()).unwrap();
+        let result = match self.file.parent() {
+            Some(parent) => create_dir_all(parent).await,
+            None => Ok(()),
+        };
+        if let Err(error) = match result {
+            Ok(()) => write_atomically(&self.file, contents).await,
+            Err(error) => Err(error),
+        } {
+            warn!(
+                "failed to save mirror queue {}: {error}",
+                self.file.display()
+            );
+        }
+    }
+
+    /// Uploads the original of `image` unless the remote has it already, by asking for it at
+    /// `/admin/images/:hash` first. That is 404 to secrets other than admin ones, the upload of
+    /// an image the remote has is then refused with 409.
+    async fn transfer(
+        &self,
+        configuration: &Configuration,
+        image: &Pending,
+    ) -> Result<Outcome, Failure> {
+        let contents = match configuration.originals.read(&image.path).await {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Outcome::Gone),
+            Err(error) => return Err(Failure::Retry(error)),
+        };
+        let hash = hex_hash::to_string(&image.hash);
+        let (status, _) = self
+            .exchange("HEAD", &format!("/admin/images/{hash}"), None, &[])
+            .await
+            .map_err(Failure::Retry)?;
+        if status == 200 {
+            return Ok(Outcome::Present);
+        }
+        let file_name = image
+            .path
+            .file_name()
+            .map(|name| name.to_string_lossy().replace(['"', '\r', '\n'], "_"))
+            .unwrap_or_else(|| hash.clone());
+        let content_type = mime_guess::from_path(&image.path).first_or_octet_stream();
+        let boundary = format!("moments-mirror-{hash}");
+        let mut body = format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"image\"; \
+             filename=\"{file_name}\"\r\nContent-Type: {content_type}\r\n\r\n"
+        )
+        .into_bytes();
+        body.extend(contents);
+        body.extend(format!("\r\n--{boundary}--\r\n").into_bytes());
+        let (status, reason) = self
+            .exchange(
+                "POST",
+                UPLOAD_PATH,
+                Some(&format!("multipart/form-data; boundary={boundary}")),
+                &body,
+            )
+            .await
+            .map_err(Failure::Retry)?;
+        match status {
+            200..=299 => Ok(Outcome::Uploaded),
+            // a duplicate with the same content
+            409 => Ok(Outcome::Present),
+            // refused for this image, e.g. too large or undecodable, trying again is futile
+            400 | 413 | 415 | 422 => Err(Failure::Refused(format!("{status} {reason}"))),
+            // e.g. a wrong secret, an upload code required or the remote being overloaded,
+            // which affects all images until fixed
+            _ => Err(Failure::Retry(io::Error::other(format!(
+                "remote answered with {status} {reason}"
+            )))),
+        }
+    }
+
+    /// Sends a request to the remote authenticated with the secret, returns the status code
+    /// and reason phrase of the answer
+    async fn exchange(
+        &self,
+        method: &str,
+        path: &str,
+        content_type: Option<&str>,
+        body: &[u8],
+    ) -> io::Result<(u16, String)> {
+        let authorization = format!("Bearer {}", self.secret);
+        let mut headers = vec![("Authorization", authorization.as_str())];
+        if let Some(content_type) = content_type {
+            headers.push(("Content-Type", content_type));
+        }
+        let target = format!("{}{path}", self.url.base_path);
+        let answer = http_client::send(&self.url, method, &target, &headers, body).await?;
+        Ok((answer.status, answer.reason))
+    }
+}
+
+enum Outcome {
+    Uploaded,
+    Present,
+    /// removed from storage before it was mirrored
+    Gone,
+}
+
+enum Failure {
+    Retry(io::Error),
+    Refused(String),
+}
+
+/// Queues every image added to the index, as well as all images indexed at the start or when
+/// changes were missed, as the remote skips those it has anyway
+pub async fn queue_additions(indexer: Arc<Indexer>, mirror: Arc<Mirror>) {
+    loop {
+        let subscription = match indexer.subscribe(None, None).await {
+            Ok(subscription) => subscription,
+            Err(error) => {
+                warn!("no further images are mirrored: {error}");
+                return;
+            }
+        };
+        if let Catchup::Snapshot(images) = subscription.catchup {
+            for image in images {
+                mirror.enqueue(image.hash, image.path);
+            }
+            mirror.save().await;
+        }
+        let mut changes = subscription.changes;
+        loop {
+            match changes.recv().await {
+                Ok(RevisedChange {
+                    change: Change::Addition { image },
+                    ..
+                }) => {
+                    mirror.enqueue(image.hash, image.path);
+                    mirror.save().await;
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("missed {missed} changes, queuing all images for mirroring again");
+                    break;
+                }
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+/// Uploads the queued images to the remote one after another, retrying with a backoff while it
+/// is unreachable or failing. Images it refuses are given up on.
+pub async fn upload_queued(configuration: Arc<SharedConfiguration>, mirror: Arc<Mirror>) {
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        let next = mirror.pending.lock().unwrap().front().cloned();
+        let Some(image) = next else {
+            mirror.queued.notified().await;
+            continue;
+        };
+        let path = image.path.display();
+        match mirror.transfer(&configuration.load(), &image).await {
+            Ok(outcome) => {
+                match outcome {
+                    Outcome::Uploaded => info!("mirrored {path} to {}", mirror.url.host),
+                    Outcome::Present => info!("{} has {path} already", mirror.url.host),
+                    Outcome::Gone => info!("{path} was removed before it was mirrored"),
+                }
+                if !matches!(outcome, Outcome::Gone) {
+                    mirror.mirrored.fetch_add(1, Ordering::Relaxed);
+                }
+                *mirror.last_error.lock().unwrap() = None;
+                backoff = INITIAL_BACKOFF;
+            }
+            Err(Failure::Refused(reason)) => {
+                warn!("{} refused to mirror {path}: {reason}", mirror.url.host);
+                mirror.failed.fetch_add(1, Ordering::Relaxed);
+                *mirror.last_error.lock().unwrap() = Some(format!("{path}: {reason}"));
+            }
+            Err(Failure::Retry(error)) => {
+                warn!("failed to mirror {path}, retrying in {backoff:?}: {error}");
+                *mirror.last_error.lock().unwrap() = Some(format!("{path}: {error}"));
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(MAXIMUM_BACKOFF);
+                continue;
+            }
+        }
+        mirror.dequeue(&image);
+        mirror.save().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_url::parse_http_url;
+
+    #[tokio::test]
+    async fn the_queue_survives_restarts() {
+        let directory = tempfile::tempdir().unwrap();
+        let file = directory.path().join(".moments").join("mirror.json");
+        let url = parse_http_url("http://example.org").unwrap();
+        let mirror = Mirror::load(url.clone(), "secret".to_string(), file.clone()).await;
+        mirror.enqueue([1, 2], PathBuf::from("first.jpg"));
+        mirror.enqueue([3, 4], PathBuf::from("second.jpg"));
+        mirror.enqueue([1, 2], PathBuf::from("alias of first.jpg"));
+        mirror.save().await;
+
+        let restarted = Mirror::load(url, "secret".to_string(), file).await;
+        assert_eq!(restarted.statistics().pending, 2);
+        let pending = restarted.pending.lock().unwrap().clone();
+        assert_eq!(pending[0].path, PathBuf::from("first.jpg"));
+        assert_eq!(pending[1].hash, [3, 4]);
+    }
+}
@@ -0,0 +1,110 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use log::warn;
+use serde::Serialize;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    index::{Change, Indexer, RevisedChange},
+    Configuration,
+};
+
+/// Paths remembered at most, further ones are looked up in storage every time until some expire
+const MAX_MISSING_IMAGES: usize = 1024;
+
+/// Originals recently answered with 404 by their path relative to the storage directory, so a
+/// kiosk with a stale index asking for a deleted image again and again does not look at storage
+/// and the index every time. Paths are forgotten after `--missing-image-ttl` or as soon as they
+/// are added to the index.
+#[derive(Default)]
+pub struct MissingImages {
+    paths: Mutex<HashMap<PathBuf, Instant>>,
+    hits: AtomicU64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MissingStatistics {
+    /// paths currently remembered as missing, including expired ones not looked up since
+    pub paths: usize,
+    /// requests answered with 404 from memory
+    pub hits: u64,
+    pub ttl_seconds: u64,
+}
+
+impl MissingImages {
+    /// Whether `path` was missing less than `--missing-image-ttl` ago
+    pub fn contains(&self, configuration: &Configuration, path: &Path) -> bool {
+        let mut paths = self.paths.lock().unwrap();
+        match paths.get(path) {
+            Some(since) if since.elapsed() < configuration.missing_image_ttl => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Some(_) => {
+                paths.remove(path);
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub fn insert(&self, configuration: &Configuration, path: &Path) {
+        let ttl = configuration.missing_image_ttl;
+        if ttl.is_zero() {
+            return;
+        }
+        let mut paths = self.paths.lock().unwrap();
+        if paths.len() >= MAX_MISSING_IMAGES {
+            paths.retain(|_, since| since.elapsed() < ttl);
+            if paths.len() >= MAX_MISSING_IMAGES {
+                return;
+            }
+        }
+        paths.insert(path.to_path_buf(), Instant::now());
+    }
+
+    pub fn statistics(&self, configuration: &Configuration) -> MissingStatistics {
+        MissingStatistics {
+            paths: self.paths.lock().unwrap().len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            ttl_seconds: configuration.missing_image_ttl.as_secs(),
+        }
+    }
+}
+
+/// Forgets missing paths as soon as an image is added at them, e.g. uploaded again
+pub async fn forget_added_images(indexer: Arc<Indexer>, missing: Arc<MissingImages>) {
+    let mut changes = match indexer.subscribe(Some(0), None).await {
+        Ok(subscription) => subscription.changes,
+        Err(error) => {
+            warn!("missing images are only forgotten after they expired: {error}");
+            return;
+        }
+    };
+    loop {
+        match changes.recv().await {
+            Ok(RevisedChange {
+                change: Change::Addition { image },
+                ..
+            }) => {
+                let mut paths = missing.paths.lock().unwrap();
+                paths.remove(&image.path);
+                for alias in &image.aliases {
+                    paths.remove(alias);
+                }
+            }
+            Ok(_) => {}
+            // any of the missed changes may have been an addition
+            Err(RecvError::Lagged(_)) => missing.paths.lock().unwrap().clear(),
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use serde::Serialize;
+use serde_json::Value;
+
+/// Encodes `value` as [MessagePack](https://msgpack.org) by way of [`serde_json::Value`], so it is
+/// structured exactly like its JSON counterpart
+pub fn to_vec(value: &impl Serialize) -> Result<Vec<u8>, serde_json::Error> {
+    let mut buffer = Vec::new();
+    encode(&serde_json::to_value(value)?, &mut buffer);
+    Ok(buffer)
+}
+
+fn encode(value: &Value, buffer: &mut Vec<u8>) {
+    match value {
+        Value::Null => buffer.push(0xc0),
+        Value::Bool(false) => buffer.push(0xc2),
+        Value::Bool(true) => buffer.push(0xc3),
+        Value::Number(number) => {
+            if let Some(unsigned) = number.as_u64() {
+                encode_unsigned(unsigned, buffer);
+            } else if let Some(signed) = number.as_i64() {
+                encode_signed(signed, buffer);
+            } else if let Some(float) = number.as_f64() {
+                buffer.push(0xcb);
+                buffer.extend_from_slice(&float.to_be_bytes());
+            }
+        }
+        Value::String(string) => {
+            encode_length(string.len(), 0xa0, 32, [0xd9, 0xda, 0xdb], buffer);
+            buffer.extend_from_slice(string.as_bytes());
+        }
+        Value::Array(values) => {
+            // there is no 8-bit length variant for arrays and maps, 0x00 is never selected
+            encode_length(values.len(), 0x90, 16, [0x00, 0xdc, 0xdd], buffer);
+            for value in values {
+                encode(value, buffer);
+            }
+        }
+        Value::Object(entries) => {
+            encode_length(entries.len(), 0x80, 16, [0x00, 0xde, 0xdf], buffer);
+            for (key, value) in entries {
+                encode(&Value::String(key.clone()), buffer);
+                encode(value, buffer);
+            }
+        }
+    }
+}
+
+fn encode_unsigned(value: u64, buffer: &mut Vec<u8>) {
+    if value < 0x80 {
+        buffer.push(value as u8);
+    } else if let Ok(value) = u8::try_from(value) {
+        buffer.extend_from_slice(&[0xcc, value]);
+    } else if let Ok(value) = u16::try_from(value) {
+        buffer.push(0xcd);
+        buffer.extend_from_slice(&value.to_be_bytes());
+    } else if let Ok(value) = u32::try_from(value) {
+        buffer.push(0xce);
+        buffer.extend_from_slice(&value.to_be_bytes());
+    } else {
+        buffer.push(0xcf);
+        buffer.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+/// Only called for negative values, non-negative ones are encoded as unsigned
+fn encode_signed(value: i64, buffer: &mut Vec<u8>) {
+    if value >= -32 {
+        buffer.push(value as i8 as u8);
+    } else if let Ok(value) = i8::try_from(value) {
+        buffer.extend_from_slice(&[0xd0, value as u8]);
+    } else if let Ok(value) = i16::try_from(value) {
+        buffer.push(0xd1);
+        buffer.extend_from_slice(&value.to_be_bytes());
+    } else if let Ok(value) = i32::try_from(value) {
+        buffer.push(0xd2);
+        buffer.extend_from_slice(&value.to_be_bytes());
+    } else {
+        buffer.push(0xd3);
+        buffer.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+/// Writes the header of a string, array or map, `fix_limit` is the exclusive maximum length
+/// encoded into the `fix_marker` itself, `markers` are those with 8, 16 and 32-bit lengths
+fn encode_length(
+    length: usize,
+    fix_marker: u8,
+    fix_limit: usize,
+    markers: [u8; 3],
+    buffer: &mut Vec<u8>,
+) {
+    if length < fix_limit {
+        buffer.push(fix_marker | length as u8);
+    } else if markers[0] != 0x00 && length <= u8::MAX as usize {
+        buffer.extend_from_slice(&[markers[0], length as u8]);
+    } else if let Ok(length) = u16::try_from(length) {
+        buffer.push(markers[1]);
+        buffer.extend_from_slice(&length.to_be_bytes());
+    } else {
+        buffer.push(markers[2]);
+        buffer.extend_from_slice(&(length as u32).to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use serde_json::json;
+    use time::OffsetDateTime;
+
+    use super::*;
+    use crate::index::{Change, Image};
+
+    /// Decodes the value at the start of `bytes` following the specification, returning the
+    /// bytes after it
+    fn decode(bytes: &[u8]) -> (Value, &[u8]) {
+        let (&marker, rest) = bytes.split_first().expect("a value");
+        match marker {
+            0x00..=0x7f => (marker.into(), rest),
+            0x80..=0x8f => decode_map(usize::from(marker & 0x0f), rest),
+            0x90..=0x9f => decode_array(usize::from(marker & 0x0f), rest),
+            0xa0..=0xbf => decode_string(usize::from(marker & 0x1f), rest),
+            0xc0 => (Value::Null, rest),
+            0xc2 => (false.into(), rest),
+            0xc3 => (true.into(), rest),
+            0xcb => {
+                let (float, rest) = rest.split_at(8);
+                (f64::from_be_bytes(float.try_into().unwrap()).into(), rest)
+            }
+            0xcc..=0xcf => {
+                let (unsigned, rest) = big_endian(rest, 1 << (marker - 0xcc));
+                (unsigned.into(), rest)
+            }
+            0xd0..=0xd3 => {
+                let width = 1 << (marker - 0xd0);
+                let (unsigned, rest) = big_endian(rest, width);
+                // sign extended from the most significant bit of the width
+                let shift = 64 - 8 * width;
+                (((unsigned << shift) as i64 >> shift).into(), rest)
+            }
+            0xd9..=0xdb => {
+                let (length, rest) = big_endian(rest, 1 << (marker - 0xd9));
+                decode_string(length as usize, rest)
+            }
+            0xdc | 0xdd => {
+                let (length, rest) = big_endian(rest, 2 << (marker - 0xdc));
+                decode_array(length as usize, rest)
+            }
+            0xde | 0xdf => {
+                let (length, rest) = big_endian(rest, 2 << (marker - 0xde));
+                decode_map(length as usize, rest)
+            }
+            0xe0..=0xff => (i64::from(marker as i8).into(), rest),
+            marker => panic!("unexpected marker {marker:#04x}"),
+        }
+    }
+
+    fn big_endian(bytes: &[u8], width: usize) -> (u64, &[u8]) {
+        let (value, rest) = bytes.split_at(width);
+        let value = value
+            .iter()
+            .fold(0, |value, &byte| value << 8 | u64::from(byte));
+        (value, rest)
+    }
+
+    fn decode_string(length: usize, bytes: &[u8]) -> (Value, &[u8]) {
+        let (string, rest) = bytes.split_at(length);
+        (std::str::from_utf8(string).unwrap().into(), rest)
+    }
+
+    fn decode_array(length: usize, mut bytes: &[u8]) -> (Value, &[u8]) {
+        let mut values = Vec::with_capacity(length);
+        for _ in 0..length {
+            let (value, rest) = decode(bytes);
+            values.push(value);
+            bytes = rest;
+        }
+        (Value::Array(values), bytes)
+    }
+
+    fn decode_map(length: usize, mut bytes: &[u8]) -> (Value, &[u8]) {
+        let mut entries = serde_json::Map::new();
+        for _ in 0..length {
+            let (Value::String(key), rest) = decode(bytes) else {
+                panic!("expected a string key");
+            };
+            let (value, rest) = decode(rest);
+            entries.insert(key, value);
+            bytes = rest;
+        }
+        (Value::Object(entries), bytes)
+    }
+
+    fn round_trip(value: &impl Serialize) -> Value {
+        let encoded = to_vec(value).unwrap();
+        let (decoded, rest) = decode(&encoded);
+        assert!(rest.is_empty(), "{} bytes left over", rest.len());
+        decoded
+    }
+
+    #[test]
+    fn integers_use_the_smallest_width() {
+        for (value, encoded) in [
+            (json!(0), vec![0x00]),
+            (json!(127), vec![0x7f]),
+            (json!(128), vec![0xcc, 0x80]),
+            (json!(255), vec![0xcc, 0xff]),
+            (json!(256), vec![0xcd, 0x01, 0x00]),
+            (json!(65_535), vec![0xcd, 0xff, 0xff]),
+            (json!(65_536), vec![0xce, 0x00, 0x01, 0x00, 0x00]),
+            (json!(u32::MAX), vec![0xce, 0xff, 0xff, 0xff, 0xff]),
+            (
+                json!(u64::from(u32::MAX) + 1),
+                vec![0xcf, 0, 0, 0, 1, 0, 0, 0, 0],
+            ),
+            (
+                json!(u64::MAX),
+                vec![0xcf, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            ),
+            (json!(-1), vec![0xff]),
+            (json!(-32), vec![0xe0]),
+            (json!(-33), vec![0xd0, 0xdf]),
+            (json!(-128), vec![0xd0, 0x80]),
+            (json!(-129), vec![0xd1, 0xff, 0x7f]),
+            (json!(-32_768), vec![0xd1, 0x80, 0x00]),
+            (json!(-32_769), vec![0xd2, 0xff, 0xff, 0x7f, 0xff]),
+            (json!(i32::MIN), vec![0xd2, 0x80, 0x00, 0x00, 0x00]),
+            (
+                json!(i64::from(i32::MIN) - 1),
+                vec![0xd3, 0xff, 0xff, 0xff, 0xff, 0x7f, 0xff, 0xff, 0xff],
+            ),
+            (json!(i64::MIN), vec![0xd3, 0x80, 0, 0, 0, 0, 0, 0, 0]),
+            (json!(0.5), vec![0xcb, 0x3f, 0xe0, 0, 0, 0, 0, 0, 0]),
+        ] {
+            assert_eq!(to_vec(&value).unwrap(), encoded, "{value}");
+            assert_eq!(round_trip(&value), value);
+        }
+    }
+
+    #[test]
+    fn lengths_use_the_smallest_header() {
+        let string = |length| Value::String("a".repeat(length));
+        let array = |length| Value::Array(vec![Value::Null; length]);
+        let map = |length: usize| {
+            Value::Object(
+                (0..length)
+                    .map(|index| (format!("{index:05}"), Value::Null))
+                    .collect(),
+            )
+        };
+        for (value, header) in [
+            (string(0), vec![0xa0]),
+            (string(31), vec![0xbf]),
+            (string(32), vec![0xd9, 32]),
+            (string(255), vec![0xd9, 0xff]),
+            (string(256), vec![0xda, 0x01, 0x00]),
+            (string(65_535), vec![0xda, 0xff, 0xff]),
+            (string(65_536), vec![0xdb, 0x00, 0x01, 0x00, 0x00]),
+            (array(0), vec![0x90]),
+            (array(15), vec![0x9f]),
+            (array(16), vec![0xdc, 0x00, 0x10]),
+            (array(65_535), vec![0xdc, 0xff, 0xff]),
+            (array(65_536), vec![0xdd, 0x00, 0x01, 0x00, 0x00]),
+            (map(0), vec![0x80]),
+            (map(15), vec![0x8f]),
+            (map(16), vec![0xde, 0x00, 0x10]),
+            (map(65_535), vec![0xde, 0xff, 0xff]),
+            (map(65_536), vec![0xdf, 0x00, 0x01, 0x00, 0x00]),
+        ] {
+            let encoded = to_vec(&value).unwrap();
+            assert_eq!(encoded[..header.len()], header, "{header:02x?}");
+            assert_eq!(round_trip(&value), value);
+        }
+    }
+
+    #[test]
+    fn images_and_changes_round_trip() {
+        let image: Image = serde_json::from_value(json!({
+            "hash": "0123456789abcdef0123456789abcdef",
+            "path": "20261014T120000_sunset.jpg",
+            "aliases": ["copy of sunset.jpg"],
+            "created_at": "2026-10-14T12:00:00Z",
+            "capture": { "taken_at": "2026-10-14T11:59:58+02:00", "camera_make": "Ünïcode" },
+            "cached_path": "20261014T120000_sunset.jpg",
+            "derivatives": { "320": "320/20261014T120000_sunset.jpg" },
+            "placeholder": "LEHV6nWB2yk8pyo0adR*.7kCMdnj",
+            "color": "#a0522d",
+            "pinned": true,
+            "reactions": { "❤️": 300, "👏": 2 },
+        }))
+        .unwrap();
+        let decoded: Image = serde_json::from_value(round_trip(&image)).unwrap();
+        assert_eq!(decoded, image);
+
+        let plain = Image::new(
+            [u64::MAX, 0],
+            PathBuf::from("plain.png"),
+            OffsetDateTime::UNIX_EPOCH,
+            Default::default(),
+        );
+        for change in [
+            Change::Addition {
+                image: image.clone(),
+            },
+            Change::Removal { image: plain },
+            Change::Update { image },
+        ] {
+            assert_eq!(round_trip(&change), serde_json::to_value(&change).unwrap());
+        }
+    }
+}
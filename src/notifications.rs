@@ -0,0 +1,345 @@
+use std::{process, sync::Arc};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::{info, warn};
+use serde::Serialize;
+use time::OffsetDateTime;
+use tokio::{
+    io::{self, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    spawn,
+    sync::{broadcast::error::RecvError, mpsc},
+    time::{sleep, timeout, Duration},
+};
+
+use crate::index::{hex_hash, Change, Image, ImageHash, Indexer, RevisedChange};
+
+/// Notifications waiting to be published, further ones are dropped while the target is down
+const QUEUE_SIZE: usize = 64;
+
+/// How long connecting to the target and each exchange with it may take
+const EXCHANGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Wait after the first failed attempt to publish, doubled after each further one
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+const MAXIMUM_BACKOFF: Duration = Duration::from_secs(60);
+
+const MQTT_DEFAULT_PORT: u16 = 1883;
+const HTTP_DEFAULT_PORT: u16 = 80;
+
+/// Where `--notify-url` publishes to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NotifyUrl {
+    pub protocol: NotifyProtocol,
+    pub host: String,
+    pub port: u16,
+    pub topic: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NotifyProtocol {
+    /// MQTT 3.1.1 with QoS 1, so a broken connection is noticed by the missing acknowledgement
+    Mqtt,
+    /// `POST` to the topic of an ntfy server
+    Ntfy,
+}
+
+/// Parses `mqtt://host[:port]/topic` or `http://host[:port]/topic`, there is no TLS
+pub fn parse_notify_url(url: &str) -> Result<NotifyUrl, String> {
+    let (protocol, default_port, rest) = if let Some(rest) = url.strip_prefix("mqtt://") {
+        (NotifyProtocol::Mqtt, MQTT_DEFAULT_PORT, rest)
+    } else if let Some(rest) = url.strip_prefix("http://") {
+        (NotifyProtocol::Ntfy, HTTP_DEFAULT_PORT, rest)
+    } else {
+        return Err(
+            "expected a URL like mqtt://broker:1883/topic or http://ntfy.local/topic, TLS is not \
+             supported"
+                .to_string(),
+        );
+    };
+    let (authority, topic) = rest
+        .split_once('/')
+        .filter(|(_, topic)| !topic.is_empty())
+        .ok_or_else(|| "expected a topic in the path of the URL".to_string())?;
+    if authority.contains('@') {
+        return Err("pass credentials with --notify-credentials instead of in the URL".to_string());
+    }
+    let (host, port) = match authority.rsplit_once(':') {
+        // not within the brackets of an IPv6 address
+        Some((host, port)) if !port.contains(']') => (
+            host,
+            port.parse()
+                .map_err(|_| format!("expected a port number, got {port:?}"))?,
+        ),
+        _ => (authority, default_port),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err("expected a host in the URL".to_string());
+    }
+    Ok(NotifyUrl {
+        protocol,
+        host: host.to_string(),
+        port,
+        topic: topic.to_string(),
+    })
+}
+
+/// Published for every image added to the kiosks
+#[derive(Serialize)]
+struct Notification<'a> {
+    #[serde(with = "hex_hash")]
+    hash: ImageHash,
+    path: &'a std::path::Path,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+    /// of the default derivative relative to the images route
+    cached_path: &'a std::path::Path,
+}
+
+impl<'a> Notification<'a> {
+    fn of(image: &'a Image) -> Self {
+        Self {
+            hash: image.hash,
+            path: &image.path,
+            created_at: image.created_at,
+            cached_path: &image.cached_path,
+        }
+    }
+}
+
+/// Publishes a small JSON message with hash, path and creation time of every image added to the
+/// kiosks, uploaded, found in storage or shown again, to `url`. Messages are handed to a
+/// separate task through a bounded queue, so a slow or unreachable target only ever drops
+/// notifications, it never holds up the index or uploads. That task reconnects with a backoff
+/// after failures and keeps MQTT connections open between messages.
+pub async fn publish_additions(indexer: Arc<Indexer>, url: NotifyUrl, credentials: Option<String>) {
+    let mut changes = match indexer.subscribe(Some(0), None).await {
+        Ok(subscription) => subscription.changes,
+        Err(error) => {
+            warn!("no notifications are published: {error}");
+            return;
+        }
+    };
+    let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+    spawn(publish(Publisher::new(url, credentials), receiver));
+    loop {
+        match changes.recv().await {
+            Ok(RevisedChange {
+                change: Change::Addition { image },
+                ..
+            }) => {
+                let message = serde_json::to_vec(&Notification::of(&image)).unwrap();
+                if sender.try_send(message).is_err() {
+                    warn!(
+                        "dropped the notification for {}, publishing is behind",
+                        image.path.display()
+                    );
+                }
+            }
+            Ok(_) => {}
+            Err(RecvError::Lagged(missed)) => {
+                warn!("missed {missed} changes, their notifications are not published")
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+async fn publish(mut publisher: Publisher, mut messages: mpsc::Receiver<Vec<u8>>) {
+    while let Some(message) = messages.recv().await {
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            match timeout(EXCHANGE_TIMEOUT, publisher.publish(&message)).await {
+                Ok(Ok(())) => break,
+                Ok(Err(error)) => warn!(
+                    "failed to publish to {}, retrying in {backoff:?}: {error}",
+                    publisher.url.host
+                ),
+                Err(_) => warn!(
+                    "publishing to {} timed out, retrying in {backoff:?}",
+                    publisher.url.host
+                ),
+            }
+            // reconnects on the next attempt
+            publisher.connection = None;
+            sleep(backoff).await;
+            backoff = (backoff * 2).min(MAXIMUM_BACKOFF);
+        }
+    }
+}
+
+/// Publishes messages to the target of `--notify-url`, with the MQTT connection kept
+struct Publisher {
+    url: NotifyUrl,
+    /// `user:password`
+    credentials: Option<String>,
+    connection: Option<BufReader<TcpStream>>,
+    packet_id: u16,
+}
+
+impl Publisher {
+    fn new(url: NotifyUrl, credentials: Option<String>) -> Self {
+        Self {
+            url,
+            credentials,
+            connection: None,
+            packet_id: 0,
+        }
+    }
+
+    async fn publish(&mut self, message: &[u8]) -> io::Result<()> {
+        match self.url.protocol {
+            NotifyProtocol::Mqtt => self.publish_mqtt(message).await,
+            NotifyProtocol::Ntfy => self.publish_ntfy(message).await,
+        }
+    }
+
+    async fn connect(&self) -> io::Result<BufReader<TcpStream>> {
+        let stream = TcpStream::connect((self.url.host.as_str(), self.url.port)).await?;
+        stream.set_nodelay(true)?;
+        Ok(BufReader::new(stream))
+    }
+
+    async fn publish_mqtt(&mut self, message: &[u8]) -> io::Result<()> {
+        if self.connection.is_none() {
+            let mut connection = self.connect().await?;
+            connection
+                .write_all(&mqtt_connect(self.credentials.as_deref()))
+                .await?;
+            let (kind, body) = read_mqtt_packet(&mut connection).await?;
+            match (kind >> 4, body.get(1)) {
+                // CONNACK
+                (2, Some(0)) => {}
+                (2, Some(code)) => {
+                    return Err(io::Error::other(format!(
+                        "broker refused the connection with code {code}"
+                    )))
+                }
+                _ => return Err(io::Error::other("expected CONNACK from the broker")),
+            }
+            info!("connected to MQTT broker {}", self.url.host);
+            self.connection = Some(connection);
+        }
+        let connection = self.connection.as_mut().unwrap();
+        // 0 is not a valid packet identifier
+        self.packet_id = self.packet_id.checked_add(1).unwrap_or(1);
+        connection
+            .write_all(&mqtt_publish(&self.url.topic, self.packet_id, message))
+            .await?;
+        loop {
+            let (kind, body) = read_mqtt_packet(connection).await?;
+            // PUBACK, anything else the broker might send is of no interest
+            if kind >> 4 == 4 && body[..] == self.packet_id.to_be_bytes() {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn publish_ntfy(&mut self, message: &[u8]) -> io::Result<()> {
+        let mut connection = self.connect().await?;
+        let authorization = match &self.credentials {
+            Some(credentials) => {
+                format!("Authorization: Basic {}\r\n", STANDARD.encode(credentials))
+            }
+            None => String::new(),
+        };
+        let head = format!(
+            "POST /{} HTTP/1.1\r\n\
+             Host: {}:{}\r\n\
+             Title: New image\r\n\
+             Content-Type: application/json\r\n\
+             Content-Length: {}\r\n\
+             {authorization}\
+             Connection: close\r\n\r\n",
+            self.url.topic,
+            self.url.host,
+            self.url.port,
+            message.len()
+        );
+        connection.write_all(head.as_bytes()).await?;
+        connection.write_all(message).await?;
+        let mut status_line = String::new();
+        connection.read_line(&mut status_line).await?;
+        match status_line.split(' ').nth(1) {
+            Some(status) if status.starts_with('2') => Ok(()),
+            Some(status) => Err(io::Error::other(format!(
+                "ntfy answered with status {status}"
+            ))),
+            None => Err(io::Error::other("ntfy sent no HTTP response")),
+        }
+    }
+}
+
+/// CONNECT with a clean session, no keep-alive and a client identifier unique per process
+fn mqtt_connect(credentials: Option<&str>) -> Vec<u8> {
+    let mut flags = 0x02;
+    let mut payload = mqtt_string(format!("moments-{}", process::id()).as_bytes());
+    if let Some(credentials) = credentials {
+        let (user, password) = credentials.split_once(':').unwrap_or((credentials, ""));
+        flags |= 0x80;
+        payload.extend(mqtt_string(user.as_bytes()));
+        if !password.is_empty() {
+            flags |= 0x40;
+            payload.extend(mqtt_string(password.as_bytes()));
+        }
+    }
+    let mut body = mqtt_string(b"MQTT");
+    // protocol level 4 is MQTT 3.1.1
+    body.extend([4, flags, 0, 0]);
+    body.extend(payload);
+    mqtt_packet(0x10, body)
+}
+
+/// PUBLISH with QoS 1
+fn mqtt_publish(topic: &str, packet_id: u16, message: &[u8]) -> Vec<u8> {
+    let mut body = mqtt_string(topic.as_bytes());
+    body.extend(packet_id.to_be_bytes());
+    body.extend(message);
+    mqtt_packet(0x32, body)
+}
+
+fn mqtt_string(bytes: &[u8]) -> Vec<u8> {
+    let mut string = Vec::with_capacity(2 + bytes.len());
+    string.extend((bytes.len() as u16).to_be_bytes());
+    string.extend(bytes);
+    string
+}
+
+/// Prefixes `body` with the fixed header of `kind`, its remaining length as variable length
+/// integer of seven bits per byte
+fn mqtt_packet(kind: u8, body: Vec<u8>) -> Vec<u8> {
+    let mut packet = vec![kind];
+    let mut length = body.len();
+    loop {
+        let byte = (length % 128) as u8;
+        length /= 128;
+        if length == 0 {
+            packet.push(byte);
+            break;
+        }
+        packet.push(byte | 0x80);
+    }
+    packet.extend(body);
+    packet
+}
+
+/// The first byte of the fixed header and the rest of the next packet
+async fn read_mqtt_packet(connection: &mut BufReader<TcpStream>) -> io::Result<(u8, Vec<u8>)> {
+    let kind = connection.read_u8().await?;
+    let mut length = 0;
+    for shift in (0..4).map(|index| 7 * index) {
+        let byte = connection.read_u8().await?;
+        length |= usize::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            let mut body = vec![0; length];
+            connection.read_exact(&mut body).await?;
+            return Ok((kind, body));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "malformed remaining length from the broker",
+    ))
+}
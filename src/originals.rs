@@ -0,0 +1,210 @@
+use std::{
+    path::{Component, Path},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http_range_header::parse_range_header;
+use httpdate::HttpDate;
+use log::warn;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+use tokio::io;
+
+use crate::{
+    images::{resolve_within, ServeError},
+    reload::SharedConfiguration,
+};
+
+/// Makes browsers save served originals under their file name instead of displaying them, wraps
+/// the originals service
+pub async fn attach_filename(request: Request, next: Next) -> Response {
+    let file_name = request
+        .uri()
+        .path()
+        .rsplit('/')
+        .next()
+        .map(|name| percent_decode_str(name).decode_utf8_lossy().into_owned())
+        .unwrap_or_default();
+    let mut response = next.run(request).await;
+    if !response.status().is_success() || file_name.is_empty() {
+        return response;
+    }
+    if let Some(value) = content_disposition("attachment", &file_name) {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_DISPOSITION, value);
+    }
+    response
+}
+
+/// Answers 404 for originals in a local directory that are symbolic links leading out of it,
+/// wraps the originals service as `ServeDir` follows them
+pub async fn refuse_links_out_of_storage(
+    State(configuration): State<Arc<SharedConfiguration>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let configuration = configuration.load();
+    let Some(directory) = configuration.originals.local_directory() else {
+        return next.run(request).await;
+    };
+    let path = percent_decode_str(request.uri().path().trim_start_matches('/'))
+        .decode_utf8_lossy()
+        .into_owned();
+    let path = Path::new(&path);
+    if !path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    match resolve_within(directory, &directory.join(path)).await {
+        Ok(_) => next.run(request).await,
+        Err(ServeError::NotFound) => StatusCode::NOT_FOUND.into_response(),
+        Err(error) => {
+            warn!("failed to resolve {} in storage: {error}", path.display());
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Drops the `Range` of requests whose `If-Range` does not match the original anymore, so a
+/// resumed download restarts with the whole file instead of mixing two versions of it. Ranges
+/// themselves are served by `ServeDir` or [`serve_stored_original`] and only compared against
+/// the modification time, as originals have no entity tags.
+pub async fn check_if_range(
+    State(configuration): State<Arc<SharedConfiguration>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let headers = request.headers();
+    let Some(if_range) = headers.get(header::IF_RANGE) else {
+        return next.run(request).await;
+    };
+    if !headers.contains_key(header::RANGE) {
+        return next.run(request).await;
+    }
+    let configuration = configuration.load();
+    let path = percent_decode_str(request.uri().path().trim_start_matches('/'))
+        .decode_utf8_lossy()
+        .into_owned();
+    let path = Path::new(&path);
+    let is_relative = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    let modified = if is_relative {
+        configuration
+            .originals
+            .stat(path)
+            .await
+            .ok()
+            .map(|fingerprint| HttpDate::from(SystemTime::from(fingerprint.modified)))
+    } else {
+        None
+    };
+    // dates have to match exactly, entity tags never do
+    let matches = if_range
+        .to_str()
+        .ok()
+        .and_then(|value| value.parse::<HttpDate>().ok())
+        .is_some_and(|date| Some(date) == modified);
+    if !matches {
+        request.headers_mut().remove(header::RANGE);
+    }
+    next.run(request).await
+}
+
+/// Serves originals from storage that is not a local directory, streamed and in single ranges
+/// like `ServeDir` serves local ones
+pub async fn serve_stored_original(
+    State(configuration): State<Arc<SharedConfiguration>>,
+    method: Method,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Response {
+    let configuration = configuration.load();
+    let path = percent_decode_str(uri.path().trim_start_matches('/'))
+        .decode_utf8_lossy()
+        .into_owned();
+    let path = Path::new(&path);
+    if !path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let originals = &configuration.originals;
+    let fingerprint = match originals.stat(path).await {
+        Ok(fingerprint) => fingerprint,
+        Err(error) => return original_error(path, error),
+    };
+    let size = fingerprint.size;
+    let content_type = mime_guess::from_path(path).first_or_octet_stream();
+    let last_modified = HttpDate::from(SystemTime::from(fingerprint.modified));
+    let mut response = Response::builder()
+        .header(header::CONTENT_TYPE, content_type.to_string())
+        .header(header::LAST_MODIFIED, last_modified.to_string())
+        .header(header::ACCEPT_RANGES, "bytes");
+    let ranges = headers
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| parse_range_header(value).and_then(|ranges| ranges.validate(size)));
+    let range = match ranges.as_ref().map(|ranges| ranges.as_deref()) {
+        None => 0..size,
+        Some(Ok([range])) if size > 0 => {
+            response = response.status(StatusCode::PARTIAL_CONTENT).header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{size}", range.start(), range.end()),
+            );
+            *range.start()..range.end() + 1
+        }
+        // several ranges would need a multipart answer, which `ServeDir` does not send either
+        Some(_) => {
+            return (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{size}"))],
+            )
+                .into_response()
+        }
+    };
+    let response = response.header(header::CONTENT_LENGTH, range.end - range.start);
+    if method == Method::HEAD {
+        return response.body(Body::empty()).unwrap();
+    }
+    match originals.read_range(path, range).await {
+        Ok(contents) => response.body(Body::from_stream(contents)).unwrap(),
+        Err(error) => original_error(path, error),
+    }
+}
+
+fn original_error(path: &Path, error: io::Error) -> Response {
+    if error.kind() == io::ErrorKind::NotFound {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    warn!("failed to read {} from storage: {error}", path.display());
+    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+}
+
+/// `Content-Disposition` of `disposition_type` like `inline` or `attachment` with `file_name`
+pub fn content_disposition(disposition_type: &str, file_name: &str) -> Option<HeaderValue> {
+    // plain ASCII for old clients, the exact name percent-encoded as specified in RFC 6266
+    let fallback: String = file_name
+        .chars()
+        .map(|character| match character {
+            ' '..='~' if character != '"' && character != '\\' => character,
+            _ => '_',
+        })
+        .collect();
+    let disposition = format!(
+        "{disposition_type}; filename=\"{fallback}\"; filename*=UTF-8''{}",
+        utf8_percent_encode(file_name, NON_ALPHANUMERIC)
+    );
+    HeaderValue::from_str(&disposition).ok()
+}
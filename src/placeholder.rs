@@ -0,0 +1,114 @@
+use std::f32::consts::PI;
+
+use image::{imageops::FilterType, DynamicImage, GenericImageView};
+use serde::{Deserialize, Serialize};
+
+const BASE83: &[u8; 83] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+/// Longest edge the image is reduced to before computing the placeholder, the few components of
+/// a blurhash do not need more detail
+const SAMPLE_SIZE: u32 = 32;
+
+/// What clients show in place of an image while it loads
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct Placeholder {
+    #[serde(rename = "placeholder")]
+    pub blurhash: String,
+    /// average color as `#rrggbb`
+    pub color: String,
+}
+
+impl Placeholder {
+    pub fn of(image: &DynamicImage) -> Self {
+        let (blurhash, [red, green, blue]) = blurhash(image);
+        Self {
+            blurhash,
+            color: format!("#{red:02x}{green:02x}{blue:02x}"),
+        }
+    }
+}
+
+/// Computes a [blurhash](https://blurha.sh) with 4 components along the longer edge and 3 along
+/// the shorter one, together with the average color it encodes as first component
+fn blurhash(image: &DynamicImage) -> (String, [u32; 3]) {
+    let sample = image.resize(SAMPLE_SIZE, SAMPLE_SIZE, FilterType::Triangle);
+    let (width, height) = sample.dimensions();
+    let (components_x, components_y) = if width >= height { (4, 3) } else { (3, 4) };
+    let pixels: Vec<[f32; 3]> = sample
+        .to_rgb8()
+        .pixels()
+        .map(|pixel| pixel.0.map(srgb_to_linear))
+        .collect();
+
+    let mut factors = Vec::with_capacity(components_x * components_y);
+    for j in 0..components_y {
+        for i in 0..components_x {
+            let normalization = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            let mut factor = [0.0; 3];
+            for y in 0..height as usize {
+                for x in 0..width as usize {
+                    let basis = (PI * i as f32 * x as f32 / width as f32).cos()
+                        * (PI * j as f32 * y as f32 / height as f32).cos();
+                    let pixel = pixels[y * width as usize + x];
+                    for channel in 0..3 {
+                        factor[channel] += basis * pixel[channel];
+                    }
+                }
+            }
+            let scale = normalization / (width * height) as f32;
+            factors.push(factor.map(|value| value * scale));
+        }
+    }
+
+    let mut hash = String::with_capacity(4 + 2 * factors.len());
+    let size_flag = (components_x - 1) + (components_y - 1) * 9;
+    encode_base83(size_flag as u32, 1, &mut hash);
+
+    let (dc, ac) = factors.split_first().unwrap();
+    let maximum = ac
+        .iter()
+        .flatten()
+        .fold(0.0_f32, |maximum, value| maximum.max(value.abs()));
+    let quantized_maximum = ((maximum * 166.0 - 0.5).floor()).clamp(0.0, 82.0) as u32;
+    let maximum = (quantized_maximum + 1) as f32 / 166.0;
+    encode_base83(quantized_maximum, 1, &mut hash);
+
+    let average = dc.map(linear_to_srgb);
+    let [red, green, blue] = average;
+    encode_base83((red << 16) + (green << 8) + blue, 4, &mut hash);
+    for factor in ac {
+        let [red, green, blue] = factor.map(|value| {
+            let value = (value / maximum).abs().sqrt().copysign(value);
+            (value * 9.0 + 9.5).floor().clamp(0.0, 18.0) as u32
+        });
+        encode_base83(red * 19 * 19 + green * 19 + blue, 2, &mut hash);
+    }
+    (hash, average)
+}
+
+fn encode_base83(value: u32, length: u32, hash: &mut String) {
+    for digit in (0..length).rev() {
+        let index = value / 83_u32.pow(digit) % 83;
+        hash.push(BASE83[index as usize] as char);
+    }
+}
+
+fn srgb_to_linear(value: u8) -> f32 {
+    let value = value as f32 / 255.0;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> u32 {
+    let value = value.clamp(0.0, 1.0);
+    let srgb = if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    };
+    (srgb * 255.0 + 0.5) as u32
+}
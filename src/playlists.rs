@@ -0,0 +1,330 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use time::OffsetDateTime;
+use tokio::{fs::read, io, sync::broadcast::error::RecvError};
+
+use crate::{
+    cache::write_atomically,
+    index::{hex_hash, Change, Image, Indexer, IndexerGone, RevisedChange},
+    recommend::HexHash,
+};
+
+/// Characters a playlist name may have at most
+const MAX_NAME_LENGTH: usize = 64;
+
+/// Images in a server-maintained order, stepped through by all displays together
+#[derive(Serialize, Deserialize)]
+struct Playlist {
+    images: Vec<HexHash>,
+    /// position of the image `next` answers with
+    cursor: usize,
+    /// the images were all shuffled with, `None` for a chosen subset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+}
+
+impl Playlist {
+    /// Drops images not in `known`, keeping the cursor at the image it pointed to or the one
+    /// following a removed one
+    fn retain(&mut self, known: &HashSet<HexHash>) -> bool {
+        let before_cursor = self.images[..self.cursor.min(self.images.len())]
+            .iter()
+            .filter(|hash| !known.contains(hash))
+            .count();
+        let length = self.images.len();
+        self.images.retain(|hash| known.contains(hash));
+        self.cursor -= before_cursor;
+        if self.cursor >= self.images.len() {
+            self.cursor = 0;
+        }
+        self.images.len() != length
+    }
+}
+
+/// Named playlists, persisted in the cache directory across restarts
+pub struct Playlists {
+    file: PathBuf,
+    playlists: Mutex<BTreeMap<String, Playlist>>,
+}
+
+impl Playlists {
+    pub async fn load(file: PathBuf) -> Self {
+        let playlists = match read(&file).await {
+            Ok(contents) => serde_json::from_slice(&contents).unwrap_or_else(|error| {
+                warn!("ignoring corrupt playlists {}: {error}", file.display());
+                BTreeMap::new()
+            }),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(error) => {
+                warn!("ignoring unreadable playlists {}: {error}", file.display());
+                BTreeMap::new()
+            }
+        };
+        Self {
+            file,
+            playlists: Mutex::new(playlists),
+        }
+    }
+
+    pub async fn save(&self) -> Result<(), io::Error> {
+        let contents = serde_json::to_vec(&*self.playlists.lock().unwrap())?;
+        if let Some(parent) = self.file.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        write_atomically(&self.file, contents).await
+    }
+
+    /// Drops images no longer in the index from all playlists, returns whether any changed
+    fn retain(&self, known: &HashSet<HexHash>) -> bool {
+        let mut changed = false;
+        for playlist in self.playlists.lock().unwrap().values_mut() {
+            changed |= playlist.retain(known);
+        }
+        changed
+    }
+}
+
+pub type PlaylistState = (Arc<Indexer>, Arc<Playlists>);
+
+/// Images of a new playlist, either the given ones in order or all shuffled with a seed, so
+/// the same seed gives the same order for the same images
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum PlaylistSelection {
+    Images { images: Vec<HexHash> },
+    Shuffled { seed: u64 },
+}
+
+#[derive(Serialize)]
+pub struct PlaylistView {
+    name: String,
+    /// in order, hidden ones left out
+    images: Vec<Image>,
+    /// position in `images` of the image displays show next
+    cursor: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+}
+
+/// An image of a playlist for a display to show now
+#[derive(Serialize)]
+pub struct PlaylistStep {
+    /// of the image in the playlist, starting at 0 again after the last
+    position: usize,
+    length: usize,
+    image: Image,
+}
+
+/// Creates the playlist with the name or replaces it, starting at its first image
+pub async fn handle_create_playlist(
+    State((indexer, playlists)): State<PlaylistState>,
+    Path(name): Path<String>,
+    Json(selection): Json<PlaylistSelection>,
+) -> Result<Json<PlaylistView>, PlaylistError> {
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(PlaylistError::InvalidName);
+    }
+    let (images, seed) = match selection {
+        PlaylistSelection::Images { images } => {
+            let known: HashSet<_> = indexer
+                .index_including_hidden()
+                .await?
+                .into_iter()
+                .map(|image| HexHash(image.hash))
+                .collect();
+            if let Some(HexHash(unknown)) = images.iter().find(|hash| !known.contains(hash)) {
+                return Err(PlaylistError::UnknownImage(hex_hash::to_string(unknown)));
+            }
+            (images, None)
+        }
+        PlaylistSelection::Shuffled { seed } => {
+            let mut images = indexer.index(None).await?;
+            // independent of the order the index happens to list them in
+            images.sort_by(|a, b| (a.created_at, &a.path).cmp(&(b.created_at, &b.path)));
+            let mut images: Vec<_> = images
+                .into_iter()
+                .map(|image| HexHash(image.hash))
+                .collect();
+            fastrand::Rng::with_seed(seed).shuffle(&mut images);
+            (images, Some(seed))
+        }
+    };
+    playlists.playlists.lock().unwrap().insert(
+        name.clone(),
+        Playlist {
+            images,
+            cursor: 0,
+            seed,
+            created_at: OffsetDateTime::now_utc(),
+        },
+    );
+    playlists.save().await.map_err(PlaylistError::Save)?;
+    view(&indexer, &playlists, name).await.map(Json)
+}
+
+pub async fn handle_delete_playlist(
+    State((_, playlists)): State<PlaylistState>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, PlaylistError> {
+    playlists
+        .playlists
+        .lock()
+        .unwrap()
+        .remove(&name)
+        .ok_or(PlaylistError::NotFound)?;
+    playlists.save().await.map_err(PlaylistError::Save)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn handle_get_playlist(
+    State((indexer, playlists)): State<PlaylistState>,
+    Path(name): Path<String>,
+) -> Result<Json<PlaylistView>, PlaylistError> {
+    view(&indexer, &playlists, name).await.map(Json)
+}
+
+/// Answers with the image at the shared cursor of the playlist and advances it, so displays
+/// stepping through the same playlist show one sequence together. Hidden images are skipped.
+pub async fn handle_next(
+    State((indexer, playlists)): State<PlaylistState>,
+    Path(name): Path<String>,
+) -> Result<Json<PlaylistStep>, PlaylistError> {
+    let visible: HashMap<_, _> = indexer
+        .index(None)
+        .await?
+        .into_iter()
+        .map(|image| (HexHash(image.hash), image))
+        .collect();
+    let step = {
+        let mut all = playlists.playlists.lock().unwrap();
+        let playlist = all.get_mut(&name).ok_or(PlaylistError::NotFound)?;
+        let length = playlist.images.len();
+        (0..length).find_map(|_| {
+            let position = playlist.cursor;
+            playlist.cursor = (position + 1) % length;
+            visible
+                .get(&playlist.images[position])
+                .cloned()
+                .map(|image| PlaylistStep {
+                    position,
+                    length,
+                    image,
+                })
+        })
+    };
+    playlists.save().await.map_err(PlaylistError::Save)?;
+    step.map(Json).ok_or(PlaylistError::NoImages)
+}
+
+async fn view(
+    indexer: &Indexer,
+    playlists: &Playlists,
+    name: String,
+) -> Result<PlaylistView, PlaylistError> {
+    let visible: HashMap<_, _> = indexer
+        .index(None)
+        .await?
+        .into_iter()
+        .map(|image| (HexHash(image.hash), image))
+        .collect();
+    let all = playlists.playlists.lock().unwrap();
+    let playlist = all.get(&name).ok_or(PlaylistError::NotFound)?;
+    let cursor = playlist.images[..playlist.cursor]
+        .iter()
+        .filter(|hash| visible.contains_key(hash))
+        .count();
+    Ok(PlaylistView {
+        images: playlist
+            .images
+            .iter()
+            .filter_map(|hash| visible.get(hash).cloned())
+            .collect(),
+        cursor,
+        seed: playlist.seed,
+        created_at: playlist.created_at,
+        name,
+    })
+}
+
+/// Removes images deleted from storage from all playlists, including those deleted while not
+/// running. Hidden images stay in them, displays skip them until they are shown again.
+pub async fn forget_deleted_images(indexer: Arc<Indexer>, playlists: Arc<Playlists>) {
+    let mut changes = match indexer.subscribe(Some(0), None).await {
+        Ok(subscription) => subscription.changes,
+        Err(error) => {
+            warn!("deleted images are kept in playlists: {error}");
+            return;
+        }
+    };
+    loop {
+        let Ok(images) = indexer.index_including_hidden().await else {
+            return;
+        };
+        let known = images
+            .into_iter()
+            .map(|image| HexHash(image.hash))
+            .collect();
+        if playlists.retain(&known) {
+            if let Err(error) = playlists.save().await {
+                warn!("failed to save playlists after images were deleted: {error}");
+            }
+        }
+        loop {
+            match changes.recv().await {
+                Ok(RevisedChange {
+                    change: Change::Removal { .. },
+                    ..
+                })
+                // any of the missed changes may have been a removal
+                | Err(RecvError::Lagged(_)) => break,
+                Ok(_) => {}
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum PlaylistError {
+    #[error("playlist not found")]
+    NotFound,
+    #[error("playlist names must have 1 to {MAX_NAME_LENGTH} characters")]
+    InvalidName,
+    #[error("image {0} not found")]
+    UnknownImage(String),
+    #[error("playlist has no images to show")]
+    NoImages,
+    #[error("failed to save playlists: {0}")]
+    Save(io::Error),
+    #[error(transparent)]
+    IndexerGone(#[from] IndexerGone),
+}
+
+impl IntoResponse for PlaylistError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            PlaylistError::NotFound | PlaylistError::NoImages => StatusCode::NOT_FOUND,
+            PlaylistError::InvalidName | PlaylistError::UnknownImage(_) => StatusCode::BAD_REQUEST,
+            PlaylistError::Save(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            PlaylistError::IndexerGone(_) => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
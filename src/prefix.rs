@@ -0,0 +1,86 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{HeaderName, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+};
+
+use crate::reload::SharedConfiguration;
+
+/// Set by reverse proxies that strip a path prefix before forwarding requests
+static FORWARDED_PREFIX: HeaderName = HeaderName::from_static("x-forwarded-prefix");
+
+/// Path prefix of all routes as the client sees it, combining `X-Forwarded-Prefix` and
+/// `--base-path`, empty if served at `/`. Attached to requests as extension for building URLs.
+#[derive(Clone, Debug)]
+pub struct Prefix(pub String);
+
+impl Prefix {
+    /// Absolute path of `path` relative to the root of the routes, e.g. `/wall/upload.html`
+    pub fn path(&self, path: &str) -> String {
+        format!("{}/{}", self.0, path.trim_start_matches('/'))
+    }
+}
+
+/// Normalizes `--base-path` to start with a slash and end without one, e.g. `wall/` to `/wall`
+pub fn parse_base_path(base_path: &str) -> Result<String, String> {
+    let trimmed = base_path.trim_matches('/');
+    if trimmed.is_empty() {
+        return Ok(String::new());
+    }
+    if trimmed.contains(['?', '#']) || trimmed.split('/').any(str::is_empty) {
+        return Err("expected a path like /wall".to_string());
+    }
+    Ok(format!("/{trimmed}"))
+}
+
+/// Serves all routes below `--base-path` by stripping it before routing, answers requests
+/// outside of it with 404 and redirects the base path itself to its trailing slash, so relative
+/// URLs of the frontend resolve below it
+pub async fn strip_base_path(
+    State(configuration): State<Arc<SharedConfiguration>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let configuration = configuration.load();
+    let forwarded = configuration
+        .trust_forwarded_prefix
+        .then(|| request.headers().get(&FORWARDED_PREFIX)?.to_str().ok())
+        .flatten()
+        .filter(|prefix| prefix.starts_with('/'))
+        .map(|prefix| prefix.trim_end_matches('/'))
+        .unwrap_or_default();
+    let prefix = Prefix(format!("{forwarded}{}", configuration.base_path));
+    if !configuration.base_path.is_empty() {
+        let path = request.uri().path();
+        if path == configuration.base_path {
+            let location = match request.uri().query() {
+                Some(query) => format!("{}?{query}", prefix.path("")),
+                None => prefix.path(""),
+            };
+            return Redirect::temporary(&location).into_response();
+        }
+        match path.strip_prefix(&configuration.base_path) {
+            Some(rest) if rest.starts_with('/') => {
+                let rest = rest.to_string();
+                replace_path(&mut request, &rest);
+            }
+            _ => return StatusCode::NOT_FOUND.into_response(),
+        }
+    }
+    request.extensions_mut().insert(prefix);
+    next.run(request).await
+}
+
+/// Replaces the path of the request URI, keeping its query
+pub fn replace_path(request: &mut Request, path: &str) {
+    let path_and_query = match request.uri().query() {
+        Some(query) => format!("{path}?{query}"),
+        None => path.to_string(),
+    };
+    let mut parts = request.uri().clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().unwrap());
+    *request.uri_mut() = Uri::from_parts(parts).unwrap();
+}
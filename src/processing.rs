@@ -0,0 +1,316 @@
+use std::io::Cursor;
+
+use clap::ValueEnum;
+use image::{
+    codecs::{jpeg::JpegEncoder, webp::WebPEncoder},
+    error::{UnsupportedError, UnsupportedErrorKind},
+    imageops::FilterType,
+    DynamicImage, GrayImage, ImageError, ImageFormat, ImageReader, Rgb, RgbImage,
+};
+use jpeg_decoder::PixelFormat;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::{CacheError, CacheFormat, JPEG_END_OF_IMAGE},
+    placeholder::Placeholder,
+};
+
+/// Filter used when scaling images down, trading speed for sharpness
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResizeFilter {
+    Nearest,
+    Triangle,
+    #[value(name = "catmullrom")]
+    CatmullRom,
+    Gaussian,
+    #[default]
+    Lanczos3,
+}
+
+impl From<ResizeFilter> for FilterType {
+    fn from(filter: ResizeFilter) -> Self {
+        match filter {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Gaussian => FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// Longest edge of the thumbnail placeholders are computed from
+const PLACEHOLDER_SOURCE_SIZE: u32 = 128;
+
+/// How derivatives are produced from a source image
+#[derive(Clone, Copy, Debug)]
+pub struct ProcessingOptions {
+    pub format: CacheFormat,
+    pub jpeg_image_quality: u8,
+    pub resize_filter: ResizeFilter,
+}
+
+pub struct ProcessedImage {
+    /// encoded derivatives in the order of the requested sizes
+    pub derivatives: Vec<Vec<u8>>,
+    pub placeholder: Option<Placeholder>,
+}
+
+/// Decodes `buffer` at most once and produces one encoded, upright derivative per entry of
+/// `max_sizes`, plus its placeholder if `with_placeholder` is set
+pub fn process_image(
+    buffer: Vec<u8>,
+    max_sizes: &[u32],
+    options: &ProcessingOptions,
+    with_placeholder: bool,
+) -> Result<ProcessedImage, CacheError> {
+    let orientation = extract_exif_orientation(&buffer);
+    let reader = ImageReader::new(Cursor::new(&buffer))
+        .with_guessed_format()
+        .map_err(|error| CacheError::Decode(error.into()))?;
+    let source_format = reader.format();
+    let (width, height) = reader.into_dimensions().map_err(CacheError::Decode)?;
+    let longest_edge = width.max(height);
+    // re-encoding a source that already fits would only lose quality
+    let can_pass_through = matches!(orientation, 0 | 1)
+        && options.format == CacheFormat::Jpeg
+        && source_format == Some(ImageFormat::Jpeg)
+        && buffer.ends_with(&JPEG_END_OF_IMAGE);
+    let passes_through = |max_size: u32| can_pass_through && longest_edge <= max_size;
+
+    // the pixels are decoded once, only as large as the largest derivative needs them
+    let decoded_size = max_sizes
+        .iter()
+        .filter(|&&max_size| !passes_through(max_size))
+        .map(|&max_size| max_size.min(longest_edge))
+        .chain(with_placeholder.then_some(PLACEHOLDER_SOURCE_SIZE.min(longest_edge)))
+        .max();
+    let image = match decoded_size {
+        Some(decoded_size) => {
+            Some(decode(&buffer, source_format, decoded_size).map_err(CacheError::Decode)?)
+        }
+        None => None,
+    };
+
+    let mut encoded_images = Vec::with_capacity(max_sizes.len());
+    for &max_size in max_sizes {
+        if passes_through(max_size) {
+            encoded_images.push(buffer.clone());
+            continue;
+        }
+        let image = image.as_ref().unwrap();
+        let resized_image = if image.width().max(image.height()) <= max_size {
+            image.clone()
+        } else {
+            resize(image, max_size, options.resize_filter)
+        };
+        let transformed_image =
+            apply_orientation(resized_image, orientation).map_err(CacheError::Decode)?;
+        encoded_images.push(
+            encode(
+                transformed_image,
+                options.format,
+                options.jpeg_image_quality,
+            )
+            .map_err(CacheError::Encode)?,
+        );
+    }
+    let placeholder = if with_placeholder {
+        // orienting a thumbnail is cheaper than orienting the full image
+        let thumbnail = image
+            .unwrap()
+            .thumbnail(PLACEHOLDER_SOURCE_SIZE, PLACEHOLDER_SOURCE_SIZE);
+        let thumbnail = apply_orientation(thumbnail, orientation).map_err(CacheError::Decode)?;
+        Some(Placeholder::of(&thumbnail))
+    } else {
+        None
+    };
+    Ok(ProcessedImage {
+        derivatives: encoded_images,
+        placeholder,
+    })
+}
+
+/// Decodes `buffer` with its longest edge at least `size`. JPEGs are scaled by 1/2, 1/4 or 1/8
+/// inside the decoder if that still satisfies `size`, which bounds the memory needed for large
+/// photos by the size of the derivatives instead of the size of the source.
+fn decode(
+    buffer: &[u8],
+    source_format: Option<ImageFormat>,
+    size: u32,
+) -> Result<DynamicImage, ImageError> {
+    if source_format == Some(ImageFormat::Jpeg) {
+        if let Some(image) = decode_scaled_jpeg(buffer, size) {
+            return Ok(image);
+        }
+    }
+    image::load_from_memory(buffer)
+}
+
+/// `None` if the JPEG is not decodable this way, the regular decoder then has the final word
+fn decode_scaled_jpeg(buffer: &[u8], size: u32) -> Option<DynamicImage> {
+    let mut decoder = jpeg_decoder::Decoder::new(Cursor::new(buffer));
+    decoder.read_info().ok()?;
+    let info = decoder.info()?;
+    let (width, height) = (u32::from(info.width), u32::from(info.height));
+    let longest_edge = width.max(height);
+    if size * 2 > longest_edge {
+        // no scale factor applies, both decoders would decode at full size
+        return None;
+    }
+    let requested_width = (width * size).div_ceil(longest_edge);
+    let requested_height = (height * size).div_ceil(longest_edge);
+    let (width, height) = decoder
+        .scale(requested_width as u16, requested_height as u16)
+        .ok()?;
+    let pixels = decoder.decode().ok()?;
+    let (width, height) = (u32::from(width), u32::from(height));
+    match info.pixel_format {
+        PixelFormat::L8 => GrayImage::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8),
+        PixelFormat::RGB24 => {
+            RgbImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8)
+        }
+        // left to the regular decoder, which handles their conversion
+        PixelFormat::L16 | PixelFormat::CMYK32 => None,
+    }
+}
+
+/// Scales `image` down so its longest edge is `max_size`, with the `fast-resize` feature in
+/// fixed point for Lanczos3 and 8 bits per channel
+fn resize(image: &DynamicImage, max_size: u32, filter: ResizeFilter) -> DynamicImage {
+    #[cfg(feature = "fast-resize")]
+    if filter == ResizeFilter::Lanczos3 {
+        if let Some(resized) = crate::fast_resize::resize_lanczos3(image, max_size) {
+            return resized;
+        }
+    }
+    image.resize(max_size, max_size, filter.into())
+}
+
+/// A JPEG of a single `color`, standing in for an image that cannot be served
+pub fn blank_jpeg(
+    width: u32,
+    height: u32,
+    color: [u8; 3],
+    jpeg_image_quality: u8,
+) -> Result<Vec<u8>, ImageError> {
+    let image = RgbImage::from_pixel(width, height, Rgb(color));
+    encode(
+        DynamicImage::ImageRgb8(image),
+        CacheFormat::Jpeg,
+        jpeg_image_quality,
+    )
+}
+
+fn encode(
+    image: DynamicImage,
+    format: CacheFormat,
+    jpeg_image_quality: u8,
+) -> Result<Vec<u8>, ImageError> {
+    let mut encoded_image = Vec::new();
+    match format {
+        CacheFormat::Jpeg => {
+            let encoder = JpegEncoder::new_with_quality(&mut encoded_image, jpeg_image_quality);
+            image.write_with_encoder(encoder)?;
+        }
+        CacheFormat::Webp => {
+            // the WebP encoder only accepts 8-bit color types
+            let image = if image.color().has_alpha() {
+                DynamicImage::ImageRgba8(image.into_rgba8())
+            } else {
+                DynamicImage::ImageRgb8(image.into_rgb8())
+            };
+            let encoder = WebPEncoder::new_lossless(&mut encoded_image);
+            image.write_with_encoder(encoder)?;
+        }
+    }
+    Ok(encoded_image)
+}
+
+fn apply_orientation(
+    resized_image: DynamicImage,
+    orientation: u32,
+) -> Result<DynamicImage, ImageError> {
+    let transformed_image = match orientation {
+        0 | 1 => resized_image,
+        2 => resized_image.fliph(),
+        3 => resized_image.rotate180(),
+        4 => resized_image.flipv(),
+        5 => resized_image.rotate90().fliph(),
+        6 => resized_image.rotate90(),
+        7 => resized_image.rotate270().fliph(),
+        8 => resized_image.rotate270(),
+        _ => {
+            return Err(ImageError::Unsupported(
+                UnsupportedError::from_format_and_kind(
+                    ImageFormat::Jpeg.into(),
+                    UnsupportedErrorKind::GenericFeature(format!(
+                        "unsupported exif orientation: {}",
+                        orientation
+                    )),
+                ),
+            ));
+        }
+    };
+    Ok(transformed_image)
+}
+
+fn extract_exif_orientation(buffer: &Vec<u8>) -> u32 {
+    let exifreader = exif::Reader::new();
+    exifreader
+        .read_from_container(&mut Cursor::new(buffer))
+        .ok()
+        .and_then(|exif| {
+            exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+                .and_then(|field| field.value.get_uint(0))
+        })
+        .unwrap_or(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A JPEG of smooth gradients and soft edges like a photo, `width` by `height` pixels
+    fn photo_jpeg(width: u32, height: u32) -> Vec<u8> {
+        let photo = RgbImage::from_fn(width, height, |x, y| {
+            let (x, y) = (x as f32 / width as f32, y as f32 / height as f32);
+            let face = (-((x - 0.4).powi(2) + (y - 0.45).powi(2)) * 40.0).exp();
+            let poster = ((x * 9.0).sin() * (y * 7.0).cos() * 0.5 + 0.5) * 120.0;
+            Rgb([
+                (poster + face * 120.0) as u8,
+                (80.0 + x * 100.0 + face * 90.0) as u8,
+                (200.0 - y * 150.0 + face * 40.0) as u8,
+            ])
+        });
+        let mut jpeg = Vec::new();
+        DynamicImage::ImageRgb8(photo)
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut jpeg, 90))
+            .unwrap();
+        jpeg
+    }
+
+    #[test]
+    fn jpegs_decoded_at_reduced_scale_look_like_full_ones() {
+        // scaled by 1/8 in the decoder, like a 48 MP photo shrunk to 1000 pixels but faster
+        let jpeg = photo_jpeg(2400, 1800);
+        let scaled = decode(&jpeg, Some(ImageFormat::Jpeg), 300).unwrap();
+        assert_eq!((scaled.width(), scaled.height()), (300, 225));
+        let full = image::load_from_memory(&jpeg).unwrap();
+        let full = resize(&full, 300, ResizeFilter::Lanczos3);
+        let differences: Vec<u8> = scaled
+            .as_bytes()
+            .iter()
+            .zip(full.as_bytes())
+            .map(|(a, b)| a.abs_diff(*b))
+            .collect();
+        let mean =
+            differences.iter().map(|&d| f64::from(d)).sum::<f64>() / differences.len() as f64;
+        let largest = differences.into_iter().max().unwrap();
+        // 0.58 and 3 here, 0.59 and 2 from 48 MP to 1000 pixels, 0.48 and 2 for the photo measured
+        // when the scaling was introduced
+        assert!(mean < 0.75, "mean difference {mean}");
+        assert!(largest <= 3, "difference {largest}");
+    }
+}
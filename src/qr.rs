@@ -0,0 +1,742 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use image::{codecs::png::PngEncoder, DynamicImage, GrayImage, ImageError, Luma};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{
+    auth::Authenticated,
+    prefix::Prefix,
+    reload::SharedConfiguration,
+    secrets::{Role, Secret},
+    Configuration,
+};
+
+/// Edge length of rendered QR codes in pixels unless requested otherwise
+const DEFAULT_PIXELS: u32 = 512;
+
+/// Largest edge length of rendered QR codes in pixels
+const MAXIMUM_PIXELS: u32 = 4096;
+
+/// Characters escaped in the fragment of the uploader URL
+const FRAGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'`');
+
+/// Error correction codewords per block, indexed by level and version
+const ECC_CODEWORDS_PER_BLOCK: [[u8; 41]; 4] = [
+    [
+        0, 7, 10, 15, 20, 26, 18, 20, 24, 30, 18, 20, 24, 26, 30, 22, 24, 28, 30, 28, 28, 28, 28,
+        30, 30, 26, 28, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30,
+    ],
+    [
+        0, 10, 16, 26, 18, 24, 16, 18, 22, 22, 26, 30, 22, 22, 24, 24, 28, 28, 26, 26, 26, 26, 28,
+        28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28,
+    ],
+    [
+        0, 13, 22, 18, 26, 18, 24, 18, 22, 20, 24, 28, 26, 24, 20, 30, 24, 28, 28, 26, 30, 28, 30,
+        30, 30, 30, 28, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30,
+    ],
+    [
+        0, 17, 28, 22, 16, 22, 28, 26, 26, 24, 28, 24, 28, 22, 24, 24, 30, 28, 28, 26, 28, 30, 24,
+        30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30,
+    ],
+];
+
+/// Error correction blocks, indexed by level and version
+const ERROR_CORRECTION_BLOCKS: [[u8; 41]; 4] = [
+    [
+        0, 1, 1, 1, 1, 1, 2, 2, 2, 2, 4, 4, 4, 4, 4, 6, 6, 6, 6, 7, 8, 8, 9, 9, 10, 12, 12, 12, 13,
+        14, 15, 16, 17, 18, 19, 19, 20, 21, 22, 24, 25,
+    ],
+    [
+        0, 1, 1, 1, 2, 2, 4, 4, 4, 5, 5, 5, 8, 9, 9, 10, 10, 11, 13, 14, 16, 17, 17, 18, 20, 21,
+        23, 25, 26, 28, 29, 31, 33, 35, 37, 38, 40, 43, 45, 47, 49,
+    ],
+    [
+        0, 1, 1, 2, 2, 4, 4, 6, 6, 8, 8, 8, 10, 12, 16, 12, 17, 16, 18, 21, 20, 23, 23, 25, 27, 29,
+        34, 34, 35, 38, 40, 43, 45, 48, 51, 53, 56, 59, 62, 65, 68,
+    ],
+    [
+        0, 1, 1, 2, 4, 4, 4, 5, 6, 8, 8, 11, 11, 16, 16, 18, 16, 19, 21, 25, 25, 25, 34, 30, 32,
+        35, 37, 40, 42, 45, 48, 51, 54, 57, 60, 63, 66, 70, 74, 77, 81,
+    ],
+];
+
+/// How much of a symbol may be damaged or covered while it still decodes, higher levels make
+/// larger symbols for the same data
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCorrection {
+    /// about 7%
+    Low,
+    /// about 15%
+    #[default]
+    Medium,
+    /// about 25%
+    Quartile,
+    /// about 30%
+    High,
+}
+
+impl ErrorCorrection {
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    /// The two bits identifying the level in the format information
+    fn format_bits(self) -> u32 {
+        match self {
+            ErrorCorrection::Low => 1,
+            ErrorCorrection::Medium => 0,
+            ErrorCorrection::Quartile => 3,
+            ErrorCorrection::High => 2,
+        }
+    }
+}
+
+/// A QR code symbol of dark and light modules, encoding its data in byte mode
+pub struct QrCode {
+    size: usize,
+    modules: Vec<bool>,
+    /// finder, timing and alignment patterns as well as format and version information, which
+    /// carry no data and are not masked
+    is_function: Vec<bool>,
+}
+
+impl QrCode {
+    /// Encodes `data` into the smallest symbol that fits it at the error correction level
+    pub fn encode(data: &[u8], error_correction: ErrorCorrection) -> Result<Self, QrError> {
+        let version = (1..=40)
+            .find(|&version| {
+                data_bits(data.len(), version) <= data_codewords(version, error_correction) * 8
+            })
+            .ok_or(QrError::TooLong(data.len()))?;
+
+        let mut bits = Bits::default();
+        bits.push(0b0100, 4);
+        bits.push(data.len() as u32, character_count_bits(version));
+        for &byte in data {
+            bits.push(byte.into(), 8);
+        }
+        let capacity = data_codewords(version, error_correction) * 8;
+        bits.push(0, (capacity - bits.len()).min(4));
+        bits.push(0, (8 - bits.len() % 8) % 8);
+        let mut codewords = bits.into_bytes();
+        for padding in [0xEC, 0x11].into_iter().cycle() {
+            if codewords.len() * 8 >= capacity {
+                break;
+            }
+            codewords.push(padding);
+        }
+
+        let size = version * 4 + 17;
+        let mut code = QrCode {
+            size,
+            modules: vec![false; size * size],
+            is_function: vec![false; size * size],
+        };
+        code.draw_function_patterns(version, error_correction);
+        code.draw_codewords(&add_error_correction(&codewords, version, error_correction));
+        // the mask leaving the fewest patterns that confuse scanners
+        let mask = (0..8)
+            .min_by_key(|&mask| {
+                code.apply_mask(mask);
+                code.draw_format_bits(error_correction, mask);
+                let penalty = code.penalty();
+                code.apply_mask(mask);
+                penalty
+            })
+            .unwrap();
+        code.apply_mask(mask);
+        code.draw_format_bits(error_correction, mask);
+        Ok(code)
+    }
+
+    /// Number of modules along each edge, without the quiet zone
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Whether the module in column `x` and row `y` is dark, modules outside are light
+    pub fn is_dark(&self, x: usize, y: usize) -> bool {
+        x < self.size && y < self.size && self.modules[y * self.size + x]
+    }
+
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        self.modules[y * self.size + x] = dark;
+        self.is_function[y * self.size + x] = true;
+    }
+
+    fn draw_function_patterns(&mut self, version: usize, error_correction: ErrorCorrection) {
+        let size = self.size;
+        for i in 0..size {
+            self.set_function(6, i, i % 2 == 0);
+            self.set_function(i, 6, i % 2 == 0);
+        }
+        for (x, y) in [(3, 3), (size - 4, 3), (3, size - 4)] {
+            self.draw_finder_pattern(x, y);
+        }
+        let positions = alignment_pattern_positions(version);
+        let last = positions.len().saturating_sub(1);
+        for (i, &x) in positions.iter().enumerate() {
+            for (j, &y) in positions.iter().enumerate() {
+                // the corners with finder patterns
+                if (i, j) != (0, 0) && (i, j) != (0, last) && (i, j) != (last, 0) {
+                    self.draw_alignment_pattern(x, y);
+                }
+            }
+        }
+        // reserves the format areas until the mask is chosen
+        self.draw_format_bits(error_correction, 0);
+        self.draw_version(version);
+    }
+
+    fn draw_finder_pattern(&mut self, x: usize, y: usize) {
+        for dy in -4..=4_isize {
+            for dx in -4..=4_isize {
+                let (module_x, module_y) = (x as isize + dx, y as isize + dy);
+                let inside = (0..self.size as isize).contains(&module_x)
+                    && (0..self.size as isize).contains(&module_y);
+                if inside {
+                    let distance = dx.abs().max(dy.abs());
+                    self.set_function(
+                        module_x as usize,
+                        module_y as usize,
+                        distance != 2 && distance != 4,
+                    );
+                }
+            }
+        }
+    }
+
+    fn draw_alignment_pattern(&mut self, x: usize, y: usize) {
+        for dy in -2..=2_isize {
+            for dx in -2..=2_isize {
+                self.set_function(
+                    (x as isize + dx) as usize,
+                    (y as isize + dy) as usize,
+                    dx.abs().max(dy.abs()) != 1,
+                );
+            }
+        }
+    }
+
+    fn draw_format_bits(&mut self, error_correction: ErrorCorrection, mask: u8) {
+        let data = error_correction.format_bits() << 3 | u32::from(mask);
+        let mut remainder = data;
+        for _ in 0..10 {
+            remainder = (remainder << 1) ^ ((remainder >> 9) * 0x537);
+        }
+        let bits = (data << 10 | remainder) ^ 0x5412;
+        let bit = |i: usize| (bits >> i) & 1 != 0;
+
+        let size = self.size;
+        for i in 0..=5 {
+            self.set_function(8, i, bit(i));
+        }
+        self.set_function(8, 7, bit(6));
+        self.set_function(8, 8, bit(7));
+        self.set_function(7, 8, bit(8));
+        for i in 9..15 {
+            self.set_function(14 - i, 8, bit(i));
+        }
+        for i in 0..8 {
+            self.set_function(size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set_function(8, size - 15 + i, bit(i));
+        }
+        self.set_function(8, size - 8, true);
+    }
+
+    fn draw_version(&mut self, version: usize) {
+        if version < 7 {
+            return;
+        }
+        let mut remainder = version as u32;
+        for _ in 0..12 {
+            remainder = (remainder << 1) ^ ((remainder >> 11) * 0x1F25);
+        }
+        let bits = (version as u32) << 12 | remainder;
+        for i in 0..18 {
+            let dark = (bits >> i) & 1 != 0;
+            let (a, b) = (self.size - 11 + i % 3, i / 3);
+            self.set_function(a, b, dark);
+            self.set_function(b, a, dark);
+        }
+    }
+
+    /// Places the codewords in the zigzag of two-module columns from the bottom right
+    fn draw_codewords(&mut self, codewords: &[u8]) {
+        let size = self.size;
+        let mut index = 0;
+        let mut right = size - 1;
+        loop {
+            // the vertical timing pattern is skipped
+            if right == 6 {
+                right = 5;
+            }
+            let upward = (right + 1) & 2 == 0;
+            for vertical in 0..size {
+                for column in 0..2 {
+                    let x = right - column;
+                    let y = if upward {
+                        size - 1 - vertical
+                    } else {
+                        vertical
+                    };
+                    if !self.is_function[y * size + x] && index < codewords.len() * 8 {
+                        self.modules[y * size + x] =
+                            (codewords[index / 8] >> (7 - index % 8)) & 1 != 0;
+                        index += 1;
+                    }
+                }
+            }
+            if right < 2 {
+                break;
+            }
+            right -= 2;
+        }
+    }
+
+    /// Flips the data modules selected by `mask`, applying it twice undoes it
+    fn apply_mask(&mut self, mask: u8) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                let flip = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+                let index = y * self.size + x;
+                if flip && !self.is_function[index] {
+                    self.modules[index] = !self.modules[index];
+                }
+            }
+        }
+    }
+
+    /// Penalty score of the current modules, lower is easier to scan
+    fn penalty(&self) -> usize {
+        let size = self.size;
+        let mut penalty = 0;
+        let lines = (0..size).flat_map(|line| {
+            [
+                (0..size).map(|x| self.is_dark(x, line)).collect::<Vec<_>>(),
+                (0..size).map(|y| self.is_dark(line, y)).collect::<Vec<_>>(),
+            ]
+        });
+        for line in lines {
+            // runs of five or more modules of the same color
+            let mut run = 1;
+            for i in 1..=size {
+                if i < size && line[i] == line[i - 1] {
+                    run += 1;
+                    continue;
+                }
+                if run >= 5 {
+                    penalty += run - 2;
+                }
+                run = 1;
+            }
+            // patterns looking like finder patterns, with light modules beyond the edge
+            let module = |i: isize| i >= 0 && (i as usize) < size && line[i as usize];
+            for start in -4..size as isize {
+                let core = [true, false, true, true, true, false, true]
+                    .iter()
+                    .enumerate()
+                    .all(|(offset, &dark)| module(start + offset as isize) == dark);
+                let light = |from: isize| (from..from + 4).all(|i| !module(i));
+                if core && (light(start - 4) || light(start + 7)) {
+                    penalty += 40;
+                }
+            }
+        }
+        // 2x2 blocks of the same color
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let dark = self.is_dark(x, y);
+                if dark == self.is_dark(x + 1, y)
+                    && dark == self.is_dark(x, y + 1)
+                    && dark == self.is_dark(x + 1, y + 1)
+                {
+                    penalty += 3;
+                }
+            }
+        }
+        // imbalance of dark and light modules in steps of 5%
+        let dark = self.modules.iter().filter(|&&dark| dark).count();
+        let total = size * size;
+        let deviation = (dark * 20).abs_diff(total * 10);
+        penalty += deviation.div_ceil(total).saturating_sub(1) * 10;
+        penalty
+    }
+
+    /// Renders the symbol as SVG with a quiet zone of four modules, scaled to `pixels` wide
+    pub fn to_svg(&self, pixels: u32) -> String {
+        let dimension = self.size + 8;
+        let mut path = String::new();
+        for y in 0..self.size {
+            for x in 0..self.size {
+                if self.is_dark(x, y) {
+                    path.push_str(&format!("M{},{}h1v1h-1z", x + 4, y + 4));
+                }
+            }
+        }
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{pixels}\" height=\"{pixels}\" \
+             viewBox=\"0 0 {dimension} {dimension}\" shape-rendering=\"crispEdges\">\
+             <rect width=\"100%\" height=\"100%\" fill=\"#fff\"/>\
+             <path d=\"{path}\" fill=\"#000\"/></svg>\n"
+        )
+    }
+
+    /// Renders the symbol with a quiet zone of four modules, each module `scale` pixels wide
+    pub fn to_image(&self, scale: u32) -> GrayImage {
+        let edge = (self.size as u32 + 8) * scale;
+        GrayImage::from_fn(edge, edge, |column, row| {
+            let x = (column / scale) as usize;
+            let y = (row / scale) as usize;
+            // quiet zone coordinates wrap around to outside the symbol
+            if self.is_dark(x.wrapping_sub(4), y.wrapping_sub(4)) {
+                Luma([0])
+            } else {
+                Luma([255])
+            }
+        })
+    }
+}
+
+/// Bits of the mode indicator, character count and data
+fn data_bits(length: usize, version: usize) -> usize {
+    4 + character_count_bits(version) + length * 8
+}
+
+fn character_count_bits(version: usize) -> usize {
+    if version <= 9 {
+        8
+    } else {
+        16
+    }
+}
+
+/// Modules available for codewords, excluding function patterns and format information
+fn raw_data_modules(version: usize) -> usize {
+    let mut modules = (16 * version + 128) * version + 64;
+    if version >= 2 {
+        let alignment_patterns = version / 7 + 2;
+        modules -= (25 * alignment_patterns - 10) * alignment_patterns - 55;
+        if version >= 7 {
+            modules -= 36;
+        }
+    }
+    modules
+}
+
+fn data_codewords(version: usize, error_correction: ErrorCorrection) -> usize {
+    let level = error_correction.index();
+    raw_data_modules(version) / 8
+        - usize::from(ECC_CODEWORDS_PER_BLOCK[level][version])
+            * usize::from(ERROR_CORRECTION_BLOCKS[level][version])
+}
+
+/// Centers of alignment patterns along each axis
+fn alignment_pattern_positions(version: usize) -> Vec<usize> {
+    if version == 1 {
+        return Vec::new();
+    }
+    let count = version / 7 + 2;
+    let step = (version * 8 + count * 3 + 5) / (count * 4 - 4) * 2;
+    let size = version * 4 + 17;
+    let mut positions: Vec<_> = (0..count - 1).map(|i| size - 7 - i * step).collect();
+    positions.push(6);
+    positions.reverse();
+    positions
+}
+
+/// Splits the data codewords into blocks, appends the error correction codewords of each and
+/// interleaves them
+fn add_error_correction(data: &[u8], version: usize, error_correction: ErrorCorrection) -> Vec<u8> {
+    let level = error_correction.index();
+    let blocks = usize::from(ERROR_CORRECTION_BLOCKS[level][version]);
+    let ecc_length = usize::from(ECC_CODEWORDS_PER_BLOCK[level][version]);
+    let raw_codewords = raw_data_modules(version) / 8;
+    let short_blocks = blocks - raw_codewords % blocks;
+    let short_block_length = raw_codewords / blocks;
+    let divisor = reed_solomon_divisor(ecc_length);
+
+    let mut split = Vec::with_capacity(blocks);
+    let mut offset = 0;
+    for block in 0..blocks {
+        let length = short_block_length - ecc_length + usize::from(block >= short_blocks);
+        let data = &data[offset..offset + length];
+        offset += length;
+        split.push((data, reed_solomon_remainder(data, &divisor)));
+    }
+    let mut interleaved = Vec::with_capacity(raw_codewords);
+    for i in 0..short_block_length - ecc_length + 1 {
+        for (data, _) in &split {
+            if let Some(&codeword) = data.get(i) {
+                interleaved.push(codeword);
+            }
+        }
+    }
+    for i in 0..ecc_length {
+        for (_, ecc) in &split {
+            interleaved.push(ecc[i]);
+        }
+    }
+    interleaved
+}
+
+fn reed_solomon_divisor(degree: usize) -> Vec<u8> {
+    let mut divisor = vec![0; degree];
+    divisor[degree - 1] = 1;
+    let mut root = 1;
+    for _ in 0..degree {
+        for j in 0..degree {
+            divisor[j] = gf_multiply(divisor[j], root);
+            if j + 1 < degree {
+                divisor[j] ^= divisor[j + 1];
+            }
+        }
+        root = gf_multiply(root, 0x02);
+    }
+    divisor
+}
+
+fn reed_solomon_remainder(data: &[u8], divisor: &[u8]) -> Vec<u8> {
+    let mut remainder = vec![0; divisor.len()];
+    for &byte in data {
+        let factor = byte ^ remainder.remove(0);
+        remainder.push(0);
+        for (remainder, &coefficient) in remainder.iter_mut().zip(divisor) {
+            *remainder ^= gf_multiply(coefficient, factor);
+        }
+    }
+    remainder
+}
+
+/// Product in GF(2^8) modulo x^8 + x^4 + x^3 + x^2 + 1
+fn gf_multiply(left: u8, right: u8) -> u8 {
+    let mut product: u16 = 0;
+    for i in (0..8).rev() {
+        product = (product << 1) ^ ((product >> 7) * 0x11D);
+        product ^= u16::from((right >> i) & 1) * u16::from(left);
+    }
+    product as u8
+}
+
+#[derive(Default)]
+struct Bits(Vec<bool>);
+
+impl Bits {
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn push(&mut self, value: u32, count: usize) {
+        self.0
+            .extend((0..count).rev().map(|i| (value >> i) & 1 != 0));
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.0
+            .chunks(8)
+            .map(|chunk| chunk.iter().fold(0, |byte, &bit| byte << 1 | u8::from(bit)))
+            .collect()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum QrError {
+    #[error("{0} bytes do not fit into a QR code")]
+    TooLong(usize),
+}
+
+/// Accepts `--public-url` only with scheme, e.g. `https://example.org/wall/`
+pub fn parse_public_url(url: &str) -> Result<String, String> {
+    if url.starts_with("http://") || url.starts_with("https://") {
+        Ok(url.trim_end_matches('/').to_string())
+    } else {
+        Err("expected a URL starting with http:// or https://".to_string())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct QrCodeParameters {
+    /// edge length in pixels
+    size: Option<u32>,
+    #[serde(default)]
+    error_correction: ErrorCorrection,
+    /// name of the secret to encode, defaults to the first guest secret
+    secret: Option<String>,
+}
+
+/// Renders a QR code of the uploader page URL for showing it next to the kiosk, see
+/// [`uploader_url`]
+pub async fn handle_qr_code_svg(
+    State(configuration): State<Arc<SharedConfiguration>>,
+    Extension(Authenticated(role)): Extension<Authenticated>,
+    Extension(prefix): Extension<Prefix>,
+    headers: HeaderMap,
+    Query(parameters): Query<QrCodeParameters>,
+) -> Result<Response, QrCodeError> {
+    let configuration = configuration.load();
+    let (code, pixels) = uploader_qr_code(&configuration, role, &prefix, &headers, &parameters)?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, "image/svg+xml"),
+            // contains a secret
+            (header::CACHE_CONTROL, "no-store"),
+        ],
+        code.to_svg(pixels),
+    )
+        .into_response())
+}
+
+/// Like [`handle_qr_code_svg`] but as PNG, as close to the requested size as whole pixels per
+/// module allow
+pub async fn handle_qr_code_png(
+    State(configuration): State<Arc<SharedConfiguration>>,
+    Extension(Authenticated(role)): Extension<Authenticated>,
+    Extension(prefix): Extension<Prefix>,
+    headers: HeaderMap,
+    Query(parameters): Query<QrCodeParameters>,
+) -> Result<Response, QrCodeError> {
+    let configuration = configuration.load();
+    let (code, pixels) = uploader_qr_code(&configuration, role, &prefix, &headers, &parameters)?;
+    let scale = (pixels / (code.size() as u32 + 8)).max(1);
+    let mut png = Vec::new();
+    DynamicImage::ImageLuma8(code.to_image(scale)).write_with_encoder(PngEncoder::new(&mut png))?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, "image/png"),
+            (header::CACHE_CONTROL, "no-store"),
+        ],
+        png,
+    )
+        .into_response())
+}
+
+fn uploader_qr_code(
+    configuration: &Configuration,
+    role: Role,
+    prefix: &Prefix,
+    headers: &HeaderMap,
+    parameters: &QrCodeParameters,
+) -> Result<(QrCode, u32), QrCodeError> {
+    let pixels = parameters.size.unwrap_or(DEFAULT_PIXELS);
+    if !(1..=MAXIMUM_PIXELS).contains(&pixels) {
+        return Err(QrCodeError::InvalidSize);
+    }
+    let url = uploader_url(
+        configuration,
+        role,
+        prefix,
+        headers,
+        parameters.secret.as_deref(),
+    )?;
+    Ok((
+        QrCode::encode(url.as_bytes(), parameters.error_correction)?,
+        pixels,
+    ))
+}
+
+/// URL of the uploader page with the secret in the fragment, where the page reads it from. It
+/// starts with [`base_url`], and guests only get guest secrets, see [`shared_secret`].
+fn uploader_url(
+    configuration: &Configuration,
+    role: Role,
+    prefix: &Prefix,
+    headers: &HeaderMap,
+    name: Option<&str>,
+) -> Result<String, QrCodeError> {
+    let secret = shared_secret(configuration, role, name).ok_or(QrCodeError::UnknownSecret)?;
+    let base = base_url(configuration, prefix, headers).ok_or(QrCodeError::UnknownHost)?;
+    Ok(format!(
+        "{base}/#{}",
+        utf8_percent_encode(&secret.value, FRAGMENT)
+    ))
+}
+
+/// The secret named `name` to hand on in URLs, by default the first guest secret. Guests only
+/// get guest secrets, so a kiosk never shows an admin secret unless it authenticates with one
+/// itself.
+pub fn shared_secret(
+    configuration: &Configuration,
+    role: Role,
+    name: Option<&str>,
+) -> Option<Secret> {
+    let may_see = |secret: &Secret| role == Role::Admin || secret.role == Role::Guest;
+    match name {
+        Some(name) => configuration
+            .secrets
+            .find(|secret| secret.name == name && may_see(secret)),
+        None => configuration
+            .secrets
+            .find(|secret| secret.role == Role::Guest)
+            .or_else(|| configuration.secrets.find(may_see)),
+    }
+}
+
+/// Absolute URL of the root of the routes without trailing slash, `--public-url` or the host
+/// the request was sent to with the prefix, `None` if the request lacks a host
+pub fn base_url(
+    configuration: &Configuration,
+    prefix: &Prefix,
+    headers: &HeaderMap,
+) -> Option<String> {
+    match &configuration.public_url {
+        Some(public_url) => Some(public_url.clone()),
+        None => {
+            let host = headers.get(header::HOST)?.to_str().ok()?;
+            Some(format!("http://{host}{}", prefix.0))
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum QrCodeError {
+    #[error("size must be between 1 and {MAXIMUM_PIXELS} pixels")]
+    InvalidSize,
+    #[error("no such secret")]
+    UnknownSecret,
+    #[error("request lacks a host, pass --public-url")]
+    UnknownHost,
+    #[error(transparent)]
+    Encode(#[from] QrError),
+    #[error("failed to encode PNG")]
+    Png(#[from] ImageError),
+}
+
+impl IntoResponse for QrCodeError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            QrCodeError::InvalidSize | QrCodeError::UnknownHost => StatusCode::BAD_REQUEST,
+            QrCodeError::UnknownSecret => StatusCode::NOT_FOUND,
+            QrCodeError::Encode(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            QrCodeError::Png(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
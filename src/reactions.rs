@@ -0,0 +1,146 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use axum::{
+    extract::{ConnectInfo, Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use log::warn;
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::time::{interval, Duration, MissedTickBehavior};
+
+use crate::{
+    curation::client_key,
+    index::{hex_hash, Image, Indexer, IndexerGone},
+    reload::SharedConfiguration,
+    sources::SourceRecords,
+};
+
+/// Emojis guests may react with, matching the kiosk's animations
+const EMOJIS: [&str; 5] = ["❤️", "🎉", "🍾", "🤯", "😂"];
+
+/// Window in which each guest may react `--reactions-per-minute` times
+const REACTION_WINDOW: Duration = Duration::from_secs(60);
+
+/// Guests remembered before those whose window ended are forgotten
+const MAX_REACTING_CLIENTS: usize = 4096;
+
+/// How often counted reactions are saved, a crash loses at most this much of them
+const REACTION_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Reactions of each guest in their current window, identified like reporters
+#[derive(Default)]
+pub struct ReactionLimits {
+    windows: Mutex<HashMap<String, (Instant, u32)>>,
+}
+
+impl ReactionLimits {
+    /// Counts a reaction of `client`, returns the time until it may react again if it reached
+    /// `limit`
+    fn check(&self, client: String, limit: u32) -> Result<(), Duration> {
+        if limit == 0 {
+            return Ok(());
+        }
+        let mut windows = self.windows.lock().unwrap();
+        if windows.len() >= MAX_REACTING_CLIENTS && !windows.contains_key(&client) {
+            windows.retain(|_, (started, _)| started.elapsed() < REACTION_WINDOW);
+        }
+        let (started, count) = windows.entry(client).or_insert((Instant::now(), 0));
+        if started.elapsed() >= REACTION_WINDOW {
+            *started = Instant::now();
+            *count = 0;
+        }
+        if *count >= limit {
+            return Err(REACTION_WINDOW.saturating_sub(started.elapsed()));
+        }
+        *count += 1;
+        Ok(())
+    }
+}
+
+pub type ReactionState = (
+    Arc<SharedConfiguration>,
+    Arc<Indexer>,
+    Arc<SourceRecords>,
+    Arc<ReactionLimits>,
+);
+
+#[derive(Deserialize)]
+pub struct ReactionRequest {
+    emoji: String,
+}
+
+/// Counts a guest's reaction on the image with the hash, at most `--reactions-per-minute` per
+/// client address. Kiosks learn about it as an update of the image, the counts are saved
+/// every [`REACTION_SAVE_INTERVAL`].
+pub async fn handle_react(
+    State((configuration, indexer, sources, limits)): State<ReactionState>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Path(hash): Path<String>,
+    Json(ReactionRequest { emoji }): Json<ReactionRequest>,
+) -> Result<Json<Image>, ReactionError> {
+    let configuration = configuration.load();
+    let hash = hex_hash::from_str(&hash).ok_or(ReactionError::NotFound)?;
+    if !EMOJIS.contains(&emoji.as_str()) {
+        return Err(ReactionError::UnknownEmoji(emoji));
+    }
+    limits
+        .check(client_key(address.ip()), configuration.reactions_per_minute)
+        .map_err(ReactionError::TooMany)?;
+    let image = indexer
+        .react(hash, emoji)
+        .await?
+        .ok_or(ReactionError::NotFound)?;
+    sources.set_reactions(hash, image.reactions.clone());
+    Ok(Json(image))
+}
+
+/// Saves the source records every [`REACTION_SAVE_INTERVAL`] if reactions changed them
+pub async fn save_reactions(sources: Arc<SourceRecords>) {
+    let mut ticks = interval(REACTION_SAVE_INTERVAL);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        if let Err(error) = sources.save_if_unsaved().await {
+            warn!("failed to save reactions: {error}");
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ReactionError {
+    #[error("image not found")]
+    NotFound,
+    #[error("unknown emoji {0:?}, react with one of {EMOJIS:?}")]
+    UnknownEmoji(String),
+    #[error("too many reactions, try again in {}s", .0.as_secs() + 1)]
+    TooMany(Duration),
+    #[error(transparent)]
+    IndexerGone(#[from] IndexerGone),
+}
+
+impl IntoResponse for ReactionError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            ReactionError::NotFound => StatusCode::NOT_FOUND,
+            ReactionError::UnknownEmoji(_) => StatusCode::BAD_REQUEST,
+            ReactionError::TooMany(retry_after) => {
+                return (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(header::RETRY_AFTER, (retry_after.as_secs() + 1).to_string())],
+                    self.to_string(),
+                )
+                    .into_response()
+            }
+            ReactionError::IndexerGone(_) => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
@@ -0,0 +1,33 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+
+use crate::reload::SharedConfiguration;
+
+/// Refuses requests changing anything, i.e. with a method other than GET, HEAD or OPTIONS, while
+/// `--read-only` is set. Checked per request, so a reload freezes or thaws the gallery at once,
+/// by SIGHUP or `POST /admin/reload`, which is left without this layer.
+pub async fn refuse_changes_when_read_only(
+    State(configuration): State<Arc<SharedConfiguration>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if configuration.load().read_only && !request.method().is_safe() {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "error": "read_only",
+                "message": "The gallery is archived, uploads and changes are disabled.",
+            })),
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
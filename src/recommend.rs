@@ -0,0 +1,288 @@
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    path::{Component, Path},
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use image::ImageReader;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use time::OffsetDateTime;
+use tokio::{io, task::spawn_blocking};
+
+use crate::{
+    index::{hex_hash, Image, ImageHash, Indexer, IndexerGone},
+    reload::SharedConfiguration,
+    sources::SourceRecords,
+    Configuration,
+};
+
+/// Images recommended when the kiosk does not ask for a count
+const DEFAULT_COUNT: usize = 10;
+
+/// Kiosks whose fresh images are remembered, all are forgotten beyond
+const MAX_KIOSKS: usize = 256;
+
+/// Characters escaped in the segments of image URLs
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// Fresh images recommended to each kiosk naming itself with `?kiosk=`, so they come first
+/// only once per kiosk
+#[derive(Default)]
+pub struct SurfacedImages {
+    kiosks: Mutex<HashMap<String, HashSet<ImageHash>>>,
+}
+
+impl SurfacedImages {
+    /// The fresh images of `images` recommended to `kiosk` before, forgetting those no longer
+    /// fresh
+    fn get(&self, kiosk: &str, images: &[Image]) -> HashSet<ImageHash> {
+        let fresh: HashSet<_> = images
+            .iter()
+            .filter(|image| image.fresh)
+            .map(|image| image.hash)
+            .collect();
+        let mut kiosks = self.kiosks.lock().unwrap();
+        let Some(surfaced) = kiosks.get_mut(kiosk) else {
+            return HashSet::new();
+        };
+        surfaced.retain(|hash| fresh.contains(hash));
+        surfaced.clone()
+    }
+
+    fn insert(&self, kiosk: &str, images: &[Image]) {
+        let mut kiosks = self.kiosks.lock().unwrap();
+        if kiosks.len() >= MAX_KIOSKS && !kiosks.contains_key(kiosk) {
+            kiosks.clear();
+        }
+        kiosks.entry(kiosk.to_string()).or_default().extend(
+            images
+                .iter()
+                .filter(|image| image.fresh)
+                .map(|image| image.hash),
+        );
+    }
+}
+
+pub type RecommendState = (
+    Arc<SharedConfiguration>,
+    Arc<Indexer>,
+    Arc<SourceRecords>,
+    Arc<SurfacedImages>,
+);
+
+#[derive(Deserialize)]
+pub struct RecommendParameters {
+    count: Option<usize>,
+    /// name of the asking kiosk, so each gets fresh images first once
+    kiosk: Option<String>,
+}
+
+/// Answers with up to `?count=` images to show next, in the order of [`rank_images`]. Fresh
+/// images come first once for every `?kiosk=`, or until reported shown without one.
+pub async fn recommend_images(
+    State((configuration, indexer, sources, surfaced)): State<RecommendState>,
+    Query(parameters): Query<RecommendParameters>,
+) -> Result<Json<Vec<Image>>, RecommendError> {
+    let configuration = configuration.load();
+    let images = indexer.index(None).await?;
+    let already_surfaced = match &parameters.kiosk {
+        Some(kiosk) => surfaced.get(kiosk, &images),
+        None => images
+            .iter()
+            .filter(|image| {
+                sources
+                    .last_shown(image.hash)
+                    .is_some_and(|shown| shown >= image.created_at)
+            })
+            .map(|image| image.hash)
+            .collect(),
+    };
+    let mut images = rank_images(images, &sources, &configuration, &already_surfaced);
+    images.truncate(parameters.count.unwrap_or(DEFAULT_COUNT));
+    if let Some(kiosk) = &parameters.kiosk {
+        surfaced.insert(kiosk, &images);
+    }
+    Ok(Json(images))
+}
+
+/// Orders images to show next: fresh ones not `surfaced` yet before all others, the newest
+/// first, then pinned ones not shown for `--pin-interval`, then those shown least recently and
+/// never shown ones first. Ties go to the newer image.
+pub fn rank_images(
+    images: Vec<Image>,
+    sources: &SourceRecords,
+    configuration: &Configuration,
+    surfaced: &HashSet<ImageHash>,
+) -> Vec<Image> {
+    let now = OffsetDateTime::now_utc();
+    let mut ranked: Vec<_> = images
+        .into_iter()
+        .map(|image| {
+            let last_shown = sources
+                .last_shown(image.hash)
+                .unwrap_or(OffsetDateTime::UNIX_EPOCH);
+            let fresh = image.fresh && !surfaced.contains(&image.hash);
+            let pinned_due = image.pinned && now - last_shown >= configuration.pin_interval;
+            // among fresh images only their age counts
+            let last_shown = if fresh {
+                OffsetDateTime::UNIX_EPOCH
+            } else {
+                last_shown
+            };
+            (Reverse(fresh), Reverse(pinned_due), last_shown, image)
+        })
+        .collect();
+    ranked.sort_by_key(|(fresh, pinned_due, last_shown, image)| {
+        (*fresh, *pinned_due, *last_shown, Reverse(image.created_at))
+    });
+    ranked.into_iter().map(|(_, _, _, image)| image).collect()
+}
+
+/// An image hash as hex in JSON
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct HexHash(#[serde(with = "hex_hash")] pub ImageHash);
+
+/// Images a kiosk displayed, by hash as in the index
+#[derive(Deserialize)]
+pub struct ShownImages {
+    hashes: Vec<HexHash>,
+}
+
+/// Records the reported images as shown now, for the recommendations of all kiosks. Hashes of
+/// images no longer in the index are ignored.
+pub async fn report_shown(
+    State((_, indexer, sources, _)): State<RecommendState>,
+    Json(shown): Json<ShownImages>,
+) -> Result<StatusCode, RecommendError> {
+    let now = OffsetDateTime::now_utc();
+    let hashes: HashSet<_> = shown.hashes.into_iter().map(|HexHash(hash)| hash).collect();
+    for image in indexer.index(None).await? {
+        if hashes.contains(&image.hash) {
+            sources.set_shown(image.hash, now);
+        }
+    }
+    sources.save().await.map_err(RecommendError::Save)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub type RandomState = (Arc<SharedConfiguration>, Arc<Indexer>);
+
+#[derive(Deserialize)]
+pub struct RandomParameters {
+    /// prefer newer images
+    #[serde(default)]
+    fresh: bool,
+    /// comma-separated hashes of recently shown images to avoid
+    not: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct RandomImage {
+    #[serde(flatten)]
+    image: Image,
+    /// of the default derivative, including the base path
+    url: String,
+    /// of the default derivative, unknown until it was generated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u32>,
+}
+
+/// Answers with a random image for a screensaver, more likely a newer one with `?fresh=true`,
+/// and none of those `?not=` unless there are no others
+pub async fn random_image(
+    State((configuration, indexer)): State<RandomState>,
+    Query(parameters): Query<RandomParameters>,
+) -> Result<Json<RandomImage>, RecommendError> {
+    let configuration = configuration.load();
+    let excluded = parameters
+        .not
+        .iter()
+        .flat_map(|hashes| hashes.split(','))
+        .filter(|hash| !hash.is_empty())
+        .map(|hash| {
+            hex_hash::from_str(hash).ok_or_else(|| RecommendError::InvalidHash(hash.to_string()))
+        })
+        .collect::<Result<_, _>>()?;
+    let image = indexer
+        .pick_random(parameters.fresh, excluded)
+        .await?
+        .ok_or(RecommendError::NoImages)?;
+    let derivative = configuration.cache.join(&image.cached_path);
+    let dimensions = spawn_blocking(move || {
+        ImageReader::open(derivative)?
+            .with_guessed_format()?
+            .into_dimensions()
+            .map_err(io::Error::other)
+    })
+    .await
+    .ok()
+    .and_then(Result::ok);
+    Ok(Json(RandomImage {
+        url: format!(
+            "{}/images/{}",
+            configuration.base_path,
+            encode_path(&image.cached_path)
+        ),
+        width: dimensions.map(|(width, _)| width),
+        height: dimensions.map(|(_, height)| height),
+        image,
+    }))
+}
+
+/// `path` relative to a route, with its segments percent-encoded
+pub fn encode_path(path: &Path) -> String {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(segment) => segment.to_str(),
+            _ => None,
+        })
+        .map(|segment| utf8_percent_encode(segment, PATH_SEGMENT).to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[derive(Debug, Error)]
+pub enum RecommendError {
+    #[error(transparent)]
+    IndexerGone(#[from] IndexerGone),
+    #[error("invalid image hash {0:?}")]
+    InvalidHash(String),
+    #[error("no images to pick from")]
+    NoImages,
+    #[error("failed to save when images were shown: {0}")]
+    Save(io::Error),
+}
+
+impl IntoResponse for RecommendError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            RecommendError::IndexerGone(_) => StatusCode::SERVICE_UNAVAILABLE,
+            RecommendError::InvalidHash(_) => StatusCode::BAD_REQUEST,
+            RecommendError::NoImages => StatusCode::NOT_FOUND,
+            RecommendError::Save(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
@@ -0,0 +1,388 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use log::{info, warn};
+use serde::Serialize;
+use thiserror::Error;
+use tokio::{
+    fs::{read, remove_dir, remove_dir_all, remove_file, try_exists},
+    io,
+    sync::Semaphore,
+    task::{spawn_blocking, JoinSet},
+};
+use walkdir::WalkDir;
+
+use crate::{
+    cache::{
+        cache_stored_image, is_intact, remove_derivatives, write_atomically, CacheError,
+        CacheLocks, CacheSettings, Derivative, ProcessingQueue, INTERNAL_DIRECTORY,
+    },
+    index::{Image, Indexer, IndexerGone},
+    reload::SharedConfiguration,
+    sources::SourceRecords,
+    Configuration,
+};
+
+/// Cache files younger than this are never considered orphans, an upload may be in flight
+const ORPHAN_GRACE_PERIOD: Duration = Duration::from_secs(60);
+
+/// Progress of cache population is logged every this many images
+const PROGRESS_INTERVAL: usize = 100;
+
+#[derive(Debug, Default, Serialize)]
+pub struct ReconcileReport {
+    /// cache files without an original in storage that were removed
+    pub orphans_removed: Vec<PathBuf>,
+    /// storage files that were missing a derivative in the cache
+    pub derivatives_created: Vec<PathBuf>,
+    /// storage files that could not be cached, undecodable ones are excluded from the index
+    pub unreadable: Vec<PathBuf>,
+    /// storage files among the unreadable ones whose processing took too long
+    pub timed_out: Vec<PathBuf>,
+}
+
+/// Brings the cache in line with storage: removes orphaned derivatives and caches missing ones
+pub async fn reconcile(
+    configuration: &Arc<Configuration>,
+    locks: &Arc<CacheLocks>,
+    sources: &SourceRecords,
+    queue: &Arc<ProcessingQueue>,
+    indexer: &Indexer,
+    images: &[Image],
+) -> Result<ReconcileReport, io::Error> {
+    let settings_file = configuration
+        .cache
+        .join(INTERNAL_DIRECTORY)
+        .join("settings.json");
+    let settings = configuration.cache_settings();
+    // records added while populating belong to uploads that may not be indexed yet at the end
+    let recorded = sources.recorded_paths();
+    let stale = match load_settings(&settings_file).await {
+        Some(previous) if previous != settings => {
+            info!("cache settings changed, regenerating affected derivatives");
+            if settings.encoding_changed(&previous) {
+                remove_on_demand_sizes(configuration).await?;
+            }
+            settings.stale_derivatives(&previous)
+        }
+        _ => Vec::new(),
+    };
+    let mut report = populate_cache(
+        configuration,
+        locks,
+        sources,
+        queue,
+        indexer,
+        images,
+        &stale,
+    )
+    .await?;
+    // only written once every derivative matches, an interrupted run starts over next time
+    if let Some(parent) = settings_file.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    write_atomically(&settings_file, serde_json::to_vec(&settings)?).await?;
+    remove_orphans(configuration, &mut report).await?;
+    // images were added and removed while populating
+    let indexed = indexer
+        .index_including_hidden()
+        .await
+        .map_err(io::Error::other)?;
+    sources.retain(&indexed, &recorded);
+    sources.save().await?;
+    Ok(report)
+}
+
+/// Removes all derivatives generated on demand, to be generated again with new settings when
+/// requested
+async fn remove_on_demand_sizes(configuration: &Configuration) -> Result<(), io::Error> {
+    let layout = &configuration.cache_layout;
+    for size in &layout.on_demand_sizes {
+        if layout.sizes.contains(size) || *size == layout.max_size {
+            continue;
+        }
+        match remove_dir_all(configuration.cache.join(size.to_string())).await {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Settings the cache was last populated with, `None` for new caches or caches from before
+/// settings were recorded
+async fn load_settings(file: &Path) -> Option<CacheSettings> {
+    let contents = read(file).await.ok()?;
+    serde_json::from_slice(&contents)
+        .inspect_err(|error| {
+            warn!(
+                "ignoring corrupt cache settings {}: {error}",
+                file.display()
+            )
+        })
+        .ok()
+}
+
+/// Caches all images lacking a derivative or placeholder, running up to `cache_workers` images
+/// concurrently.
+/// Derivatives flagged in `stale` were generated with different settings and are replaced.
+async fn populate_cache(
+    configuration: &Arc<Configuration>,
+    locks: &Arc<CacheLocks>,
+    sources: &SourceRecords,
+    queue: &Arc<ProcessingQueue>,
+    indexer: &Indexer,
+    images: &[Image],
+    stale: &[bool],
+) -> Result<ReconcileReport, io::Error> {
+    let mut report = ReconcileReport::default();
+    let mut missing = Vec::new();
+    for image in images {
+        let fingerprint = match configuration.originals.stat(&image.path).await {
+            Ok(fingerprint) => fingerprint,
+            Err(error) => {
+                warn!("failed to inspect {}: {error}", image.path.display());
+                report.unreadable.push(image.path.clone());
+                continue;
+            }
+        };
+        if !sources.is_current(&image.path, fingerprint, image.hash) {
+            info!(
+                "{} changed since it was cached, regenerating",
+                image.path.display()
+            );
+            remove_derivatives(&configuration.all_derivatives(&image.path)).await?;
+            sources.record(&image.path, fingerprint, image.hash);
+        }
+
+        let mut derivatives = Vec::new();
+        for (index, derivative) in configuration
+            .derivatives(&image.path)
+            .into_iter()
+            .enumerate()
+        {
+            if !try_exists(&derivative.destination).await? {
+                derivatives.push(derivative);
+            } else if stale.get(index).copied().unwrap_or(false) {
+                remove_file(&derivative.destination).await?;
+                derivatives.push(derivative);
+            } else if !is_intact(&derivative.destination, configuration.cache_layout.format).await?
+            {
+                warn!(
+                    "regenerating broken cache file {}",
+                    derivative.destination.display()
+                );
+                remove_file(&derivative.destination).await?;
+                derivatives.push(derivative);
+            }
+        }
+        // placeholders of images cached before they existed are backfilled
+        let with_placeholder = sources.placeholder(&image.path).is_none();
+        if !derivatives.is_empty() || with_placeholder {
+            missing.push((
+                image.path.clone(),
+                image.hash,
+                derivatives,
+                with_placeholder,
+            ));
+        }
+    }
+
+    let total = missing.len();
+    let workers = Arc::new(Semaphore::new(configuration.cache_workers));
+    let mut tasks = JoinSet::new();
+    for (path, hash, derivatives, with_placeholder) in missing {
+        let configuration = configuration.clone();
+        let locks = locks.clone();
+        let queue = queue.clone();
+        let workers = workers.clone();
+        tasks.spawn(async move {
+            let _permit = workers.acquire_owned().await.unwrap();
+            let _guard = locks.lock(&path).await;
+            // an on-demand request may have generated the derivatives in the meantime
+            let created = !all_exist(&derivatives).await;
+            if !created && !with_placeholder {
+                return (path, hash, Ok((false, None)));
+            }
+            let result = cache_stored_image(
+                configuration.originals.as_ref(),
+                &path,
+                &configuration.cache,
+                &derivatives,
+                &configuration.processing_options(),
+                &queue,
+                with_placeholder,
+            )
+            .await;
+            (path, hash, result.map(|placeholder| (created, placeholder)))
+        });
+    }
+
+    let mut finished = 0;
+    while let Some(task) = tasks.join_next().await {
+        let (path, hash, result) = task.unwrap();
+        match result {
+            Ok((created, placeholder)) => {
+                if let Some(placeholder) = placeholder {
+                    sources.set_placeholder(&path, placeholder.clone());
+                    // a gone indexer is already logged and only costs the placeholder
+                    let _ = indexer.set_placeholder(hash, placeholder).await;
+                }
+                if created {
+                    report.derivatives_created.push(path);
+                }
+            }
+            Err(error) => {
+                warn!(image:% = path.display(); "failed to cache: {error}");
+                if let CacheError::TimedOut { .. } = error {
+                    report.timed_out.push(path.clone());
+                }
+                // unlike I/O errors, undecodable files will not recover by themselves
+                if error.is_permanent() {
+                    info!("excluding {} from the index", path.display());
+                    let _ = indexer.remove_image(hash).await;
+                }
+                report.unreadable.push(path);
+            }
+        }
+        finished += 1;
+        if finished % PROGRESS_INTERVAL == 0 || finished == total {
+            info!("{finished}/{total} cached");
+        }
+    }
+    report.derivatives_created.sort();
+    report.unreadable.sort();
+    report.timed_out.sort();
+    Ok(report)
+}
+
+async fn all_exist(derivatives: &[Derivative]) -> bool {
+    for derivative in derivatives {
+        if !matches!(try_exists(&derivative.destination).await, Ok(true)) {
+            return false;
+        }
+    }
+    true
+}
+
+async fn remove_orphans(
+    configuration: &Configuration,
+    report: &mut ReconcileReport,
+) -> Result<(), io::Error> {
+    let cache = configuration.cache.clone();
+    let cached_paths = spawn_blocking(move || list_files(&cache)).await.unwrap()?;
+    // listed at once, storage does not need to be asked about every cache file
+    let originals: HashSet<_> = configuration
+        .originals
+        .list()
+        .await?
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect();
+    for path in cached_paths {
+        let cache_path = configuration.cache.join(&path);
+        let original_exists = configuration
+            .cache_layout
+            .original_path(&path)
+            .is_some_and(|original_path| originals.contains(&original_path));
+        if original_exists || is_recent(&cache_path).await? {
+            continue;
+        }
+        info!("removing orphaned cache file {}", path.display());
+        remove_file(&cache_path).await?;
+        report.orphans_removed.push(path);
+    }
+    remove_empty_directories(&configuration.cache).await
+}
+
+/// Removes directories left empty by removed orphans, e.g. of sizes no longer cached
+async fn remove_empty_directories(root: &Path) -> Result<(), io::Error> {
+    let root_path = root.to_path_buf();
+    let directories = spawn_blocking(move || {
+        WalkDir::new(&root_path)
+            .min_depth(1)
+            .contents_first(true)
+            .into_iter()
+            .filter_entry(|entry| entry.depth() != 1 || entry.file_name() != INTERNAL_DIRECTORY)
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_dir())
+            .map(|entry| entry.into_path())
+            .collect::<Vec<_>>()
+    })
+    .await
+    .unwrap();
+    for directory in directories {
+        // fails for directories that are not empty, which are kept
+        if remove_dir(&directory).await.is_ok() {
+            info!("removed empty cache directory {}", directory.display());
+        }
+    }
+    Ok(())
+}
+
+/// Lists all files below `root` recursively, relative to `root`
+pub fn list_files(root: &Path) -> Result<Vec<PathBuf>, io::Error> {
+    let mut paths = Vec::new();
+    let entries = WalkDir::new(root)
+        .into_iter()
+        .filter_entry(|entry| entry.depth() != 1 || entry.file_name() != INTERNAL_DIRECTORY);
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            paths.push(entry.path().strip_prefix(root).unwrap().to_path_buf());
+        }
+    }
+    Ok(paths)
+}
+
+async fn is_recent(path: &Path) -> Result<bool, io::Error> {
+    let modified = tokio::fs::metadata(path).await?.modified()?;
+    Ok(SystemTime::now()
+        .duration_since(modified)
+        .is_ok_and(|age| age < ORPHAN_GRACE_PERIOD))
+}
+
+pub type ReconcileState = (
+    Arc<SharedConfiguration>,
+    Arc<Indexer>,
+    Arc<CacheLocks>,
+    Arc<SourceRecords>,
+    Arc<ProcessingQueue>,
+);
+
+pub async fn handle_reconcile(
+    State((configuration, indexer, locks, sources, queue)): State<ReconcileState>,
+) -> Result<Json<ReconcileReport>, ReconcileError> {
+    let configuration = configuration.load();
+    let images = indexer.index_including_hidden().await?;
+    let report = reconcile(&configuration, &locks, &sources, &queue, &indexer, &images).await?;
+    Ok(Json(report))
+}
+
+#[derive(Debug, Error)]
+pub enum ReconcileError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    IndexerGone(#[from] IndexerGone),
+}
+
+impl IntoResponse for ReconcileError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            ReconcileError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ReconcileError::IndexerGone(_) => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
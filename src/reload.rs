@@ -0,0 +1,528 @@
+use std::{
+    ffi::OsString,
+    fmt::Debug,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use clap::{CommandFactory, Parser};
+use log::{info, warn};
+use serde::Serialize;
+use thiserror::Error;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    spawn,
+    sync::Mutex,
+    task::JoinHandle,
+};
+
+use crate::{
+    cache::{CacheLocks, ProcessingQueue},
+    config::{with_file_arguments, ConfigError},
+    configure,
+    health::{CachePopulation, PopulationStatus},
+    index::Indexer,
+    populate_cache_in_background,
+    secrets::Secrets,
+    sources::SourceRecords,
+    upload_codes::{UploadCodeKey, UploadCodes},
+    watcher::{watch_storage, WatchStatistics},
+    Arguments, Configuration,
+};
+
+/// The current configuration, replaced as a whole when it is reloaded. Handlers load it once
+/// per request, so a request never sees a mix of old and new settings.
+pub struct SharedConfiguration(RwLock<Arc<Configuration>>);
+
+impl SharedConfiguration {
+    pub fn new(configuration: Arc<Configuration>) -> Self {
+        Self(RwLock::new(configuration))
+    }
+
+    pub fn load(&self) -> Arc<Configuration> {
+        self.0.read().unwrap().clone()
+    }
+
+    fn store(&self, configuration: Arc<Configuration>) {
+        *self.0.write().unwrap() = configuration;
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ReloadReport {
+    /// settings that changed and were applied, with their previous and new value
+    pub applied: Vec<String>,
+    /// arguments that changed but only take effect after a restart
+    pub restart_required: Vec<String>,
+}
+
+/// Reloads the configuration file, secret file and environment and applies what can be changed
+/// while running, e.g. to rotate secrets or lower the JPEG quality without repopulating the
+/// cache after a restart
+pub struct Reloader {
+    command_line: Vec<OsString>,
+    /// arguments the server was started with, to tell which changes need a restart
+    startup: Arguments,
+    upload_code_key: UploadCodeKey,
+    configuration: Arc<SharedConfiguration>,
+    indexer: Arc<Indexer>,
+    locks: Arc<CacheLocks>,
+    sources: Arc<SourceRecords>,
+    queue: Arc<ProcessingQueue>,
+    population: Arc<CachePopulation>,
+    watch_statistics: Arc<WatchStatistics>,
+    /// also serializes reloads
+    watcher: Mutex<JoinHandle<()>>,
+}
+
+impl Reloader {
+    /// Starts watching storage with the current configuration, restarted whenever a reload
+    /// changes how
+    #[allow(clippy::too_many_arguments)]
+    pub fn start(
+        command_line: Vec<OsString>,
+        startup: Arguments,
+        upload_code_key: UploadCodeKey,
+        configuration: Arc<SharedConfiguration>,
+        indexer: Arc<Indexer>,
+        locks: Arc<CacheLocks>,
+        sources: Arc<SourceRecords>,
+        queue: Arc<ProcessingQueue>,
+        population: Arc<CachePopulation>,
+        watch_statistics: Arc<WatchStatistics>,
+    ) -> Self {
+        let watcher = spawn(watch_storage(
+            configuration.load(),
+            indexer.clone(),
+            locks.clone(),
+            sources.clone(),
+            queue.clone(),
+            watch_statistics.clone(),
+        ));
+        Self {
+            command_line,
+            startup,
+            upload_code_key,
+            configuration,
+            indexer,
+            locks,
+            sources,
+            queue,
+            population,
+            watch_statistics,
+            watcher: Mutex::new(watcher),
+        }
+    }
+
+    pub async fn reload(&self) -> Result<ReloadReport, ReloadError> {
+        let mut watcher = self.watcher.lock().await;
+        let arguments = Arguments::try_parse_from(with_file_arguments(
+            &Arguments::command(),
+            &self.command_line,
+        )?)?;
+        let restart_required = restart_required(&self.startup, &arguments);
+        let upload_code_period = arguments.upload_code_period;
+        let mut next =
+            configure(arguments).map_err(|error| ReloadError::Invalid(format!("{error:#}")))?;
+        next.upload_codes = upload_code_period
+            .map(|period| UploadCodes::new(self.upload_code_key, Duration::from_secs(period)));
+        let current = self.configuration.load();
+        // cannot change while running, kept until a restart
+        next.storage.clone_from(&current.storage);
+        next.originals = current.originals.clone();
+        next.cache.clone_from(&current.cache);
+        next.cache_layout
+            .sizes
+            .clone_from(&current.cache_layout.sizes);
+        next.cache_layout.format = current.cache_layout.format;
+        next.cache_layout
+            .on_demand_sizes
+            .clone_from(&current.cache_layout.on_demand_sizes);
+        next.cache_workers = current.cache_workers;
+
+        let applied = applied_changes(&current, &next);
+        // handlers of /admin/secrets keep the same list, with the changes they made
+        current.secrets.reload(&next.secrets);
+        next.secrets = current.secrets.clone();
+        let next = Arc::new(next);
+        self.configuration.store(next.clone());
+
+        for change in &applied {
+            info!("reloaded {change}");
+        }
+        for argument in &restart_required {
+            warn!("--{argument} changed, restart to apply it");
+        }
+        if applied.is_empty() && restart_required.is_empty() {
+            info!("reloaded configuration without changes");
+        }
+
+        if next.cache_settings() != current.cache_settings() {
+            info!("cache settings changed, regenerating derivatives in the background");
+            self.population.set(PopulationStatus::Running);
+            spawn(populate_cache_in_background(
+                next.clone(),
+                self.indexer.clone(),
+                self.locks.clone(),
+                self.sources.clone(),
+                self.queue.clone(),
+                self.population.clone(),
+            ));
+        }
+        if next.fresh_window != current.fresh_window {
+            // a gone indexer is logged and answers nothing anymore anyway
+            let _ = self.indexer.set_fresh_window(next.fresh_window).await;
+        }
+        let watch_changed = next.watch_mode != current.watch_mode
+            || next.poll_interval != current.poll_interval
+            || next.settle_time != current.settle_time;
+        if watch_changed {
+            // storage is listed again on start, files added meanwhile are not missed
+            watcher.abort();
+            *watcher = spawn(watch_storage(
+                next,
+                self.indexer.clone(),
+                self.locks.clone(),
+                self.sources.clone(),
+                self.queue.clone(),
+                self.watch_statistics.clone(),
+            ));
+        }
+        Ok(ReloadReport {
+            applied,
+            restart_required,
+        })
+    }
+}
+
+/// Reloads whenever the process receives SIGHUP
+pub async fn reload_on_hangup(reloader: Arc<Reloader>) {
+    let mut hangups = signal(SignalKind::hangup()).expect("failed to install signal handler");
+    while hangups.recv().await.is_some() {
+        info!("Reloading configuration...");
+        if let Err(error) = reloader.reload().await {
+            warn!("failed to reload configuration, keeping the current one: {error}");
+        }
+    }
+}
+
+pub async fn handle_reload(
+    State(reloader): State<Arc<Reloader>>,
+) -> Result<Json<ReloadReport>, ReloadError> {
+    Ok(Json(reloader.reload().await?))
+}
+
+fn restart_required(startup: &Arguments, arguments: &Arguments) -> Vec<String> {
+    let mut changed = Vec::new();
+    let mut compare = |name: &str, differs: bool| {
+        if differs {
+            changed.push(name.to_string());
+        }
+    };
+    compare("host", startup.host != arguments.host);
+    compare("port", startup.port != arguments.port);
+    compare(
+        "maintenance-host",
+        startup.maintenance_host != arguments.maintenance_host,
+    );
+    compare(
+        "maintenance-port",
+        startup.maintenance_port != arguments.maintenance_port,
+    );
+    compare("storage", startup.storage != arguments.storage);
+    compare("webdav-url", startup.webdav_url != arguments.webdav_url);
+    compare("webdav-user", startup.webdav_user != arguments.webdav_user);
+    compare(
+        "webdav-password",
+        startup.webdav_password != arguments.webdav_password,
+    );
+    #[cfg(feature = "s3")]
+    {
+        compare("s3-endpoint", startup.s3_endpoint != arguments.s3_endpoint);
+        compare("s3-bucket", startup.s3_bucket != arguments.s3_bucket);
+        compare("s3-region", startup.s3_region != arguments.s3_region);
+        compare(
+            "s3-access-key",
+            startup.s3_access_key != arguments.s3_access_key,
+        );
+        compare(
+            "s3-secret-key",
+            startup.s3_secret_key != arguments.s3_secret_key,
+        );
+    }
+    compare("cache", startup.cache != arguments.cache);
+    compare(
+        "frontend-dir",
+        startup.frontend_dir != arguments.frontend_dir,
+    );
+    compare("cache-sizes", startup.cache_sizes != arguments.cache_sizes);
+    compare(
+        "on-demand-sizes",
+        startup.on_demand_sizes != arguments.on_demand_sizes,
+    );
+    compare(
+        "cache-format",
+        startup.cache_format != arguments.cache_format,
+    );
+    compare(
+        "processing-timeout",
+        startup.processing_timeout != arguments.processing_timeout,
+    );
+    compare("lazy-cache", startup.lazy_cache != arguments.lazy_cache);
+    compare(
+        "cache-workers",
+        startup.cache_workers != arguments.cache_workers,
+    );
+    compare(
+        "cors-allowed-origin",
+        startup.cors_allowed_origin != arguments.cors_allowed_origin,
+    );
+    compare("log-format", startup.log_format != arguments.log_format);
+    #[cfg(feature = "notifications")]
+    {
+        compare("notify-url", startup.notify_url != arguments.notify_url);
+        compare(
+            "notify-credentials",
+            startup.notify_credentials != arguments.notify_credentials,
+        );
+    }
+    compare("mirror-to", startup.mirror_to != arguments.mirror_to);
+    compare(
+        "mirror-secret",
+        startup.mirror_secret != arguments.mirror_secret,
+    );
+    compare(
+        "max-request-body-size",
+        startup.max_request_body_size != arguments.max_request_body_size,
+    );
+    changed
+}
+
+/// Changes from `current` to `next` as `name: previous -> new`, without revealing secrets
+fn applied_changes(current: &Configuration, next: &Configuration) -> Vec<String> {
+    let mut changes = Vec::new();
+    let names = |secrets: &Secrets| {
+        secrets
+            .list()
+            .into_iter()
+            .map(|secret| format!("{} ({:?})", secret.name, secret.role))
+            .collect::<Vec<_>>()
+    };
+    if names(&current.secrets) != names(&next.secrets)
+        || current.secrets.values() != next.secrets.values()
+    {
+        push_change(
+            &mut changes,
+            "secrets",
+            &names(&current.secrets),
+            &names(&next.secrets),
+        );
+    }
+    if current.download_secret != next.download_secret {
+        changes.push("download secret changed".to_string());
+    }
+    push_change(
+        &mut changes,
+        "secret_in_path",
+        &current.secret_in_path,
+        &next.secret_in_path,
+    );
+    push_change(
+        &mut changes,
+        "base_path",
+        &current.base_path,
+        &next.base_path,
+    );
+    push_change(
+        &mut changes,
+        "trust_forwarded_prefix",
+        &current.trust_forwarded_prefix,
+        &next.trust_forwarded_prefix,
+    );
+    push_change(
+        &mut changes,
+        "public_url",
+        &current.public_url,
+        &next.public_url,
+    );
+    push_change(
+        &mut changes,
+        "max_cached_image_size",
+        &current.cache_layout.max_size,
+        &next.cache_layout.max_size,
+    );
+    push_change(
+        &mut changes,
+        "jpeg_image_quality",
+        &current.jpeg_image_quality,
+        &next.jpeg_image_quality,
+    );
+    push_change(
+        &mut changes,
+        "resize_filter",
+        &current.resize_filter,
+        &next.resize_filter,
+    );
+    push_change(
+        &mut changes,
+        "max_cache_bytes",
+        &current.max_cache_bytes,
+        &next.max_cache_bytes,
+    );
+    push_change(
+        &mut changes,
+        "websocket_ping_interval",
+        &current.websocket_ping_interval,
+        &next.websocket_ping_interval,
+    );
+    push_change(
+        &mut changes,
+        "websocket_compression",
+        &current.websocket_compression,
+        &next.websocket_compression,
+    );
+    push_change(
+        &mut changes,
+        "compression",
+        &current.compression,
+        &next.compression,
+    );
+    push_change(
+        &mut changes,
+        "snapshot_chunk_size",
+        &current.snapshot_chunk_size,
+        &next.snapshot_chunk_size,
+    );
+    push_change(
+        &mut changes,
+        "change_batch_window",
+        &current.change_batch_window,
+        &next.change_batch_window,
+    );
+    push_change(
+        &mut changes,
+        "settle_time",
+        &current.settle_time,
+        &next.settle_time,
+    );
+    push_change(
+        &mut changes,
+        "watch_mode",
+        &current.watch_mode,
+        &next.watch_mode,
+    );
+    push_change(
+        &mut changes,
+        "poll_interval",
+        &current.poll_interval,
+        &next.poll_interval,
+    );
+    push_change(
+        &mut changes,
+        "read_only",
+        &current.read_only,
+        &next.read_only,
+    );
+    push_change(
+        &mut changes,
+        "request_timeout",
+        &current.request_timeout,
+        &next.request_timeout,
+    );
+    push_change(
+        &mut changes,
+        "upload_timeout",
+        &current.upload_timeout,
+        &next.upload_timeout,
+    );
+    push_change(
+        &mut changes,
+        "placeholder_color",
+        &current.placeholder_color,
+        &next.placeholder_color,
+    );
+    push_change(
+        &mut changes,
+        "highlight_interval",
+        &current.highlight_interval,
+        &next.highlight_interval,
+    );
+    push_change(
+        &mut changes,
+        "pin_interval",
+        &current.pin_interval,
+        &next.pin_interval,
+    );
+    push_change(
+        &mut changes,
+        "upload_codes",
+        &current.upload_codes,
+        &next.upload_codes,
+    );
+    push_change(
+        &mut changes,
+        "fresh_window",
+        &current.fresh_window,
+        &next.fresh_window,
+    );
+    push_change(
+        &mut changes,
+        "reports_to_hide",
+        &current.reports_to_hide,
+        &next.reports_to_hide,
+    );
+    push_change(&mut changes, "sidecars", &current.sidecars, &next.sidecars);
+    push_change(
+        &mut changes,
+        "feed_size",
+        &current.feed_size,
+        &next.feed_size,
+    );
+    push_change(
+        &mut changes,
+        "reactions_per_minute",
+        &current.reactions_per_minute,
+        &next.reactions_per_minute,
+    );
+    push_change(
+        &mut changes,
+        "missing_image_ttl",
+        &current.missing_image_ttl,
+        &next.missing_image_ttl,
+    );
+    push_change(
+        &mut changes,
+        "max_concurrent_requests",
+        &current.max_concurrent_requests,
+        &next.max_concurrent_requests,
+    );
+    changes
+}
+
+fn push_change<T: PartialEq + Debug>(changes: &mut Vec<String>, name: &str, previous: &T, new: &T) {
+    if previous != new {
+        changes.push(format!("{name}: {previous:?} -> {new:?}"));
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ReloadError {
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error("invalid arguments: {0}")]
+    Arguments(#[from] clap::Error),
+    #[error("invalid configuration: {0}")]
+    Invalid(String),
+}
+
+impl IntoResponse for ReloadError {
+    fn into_response(self) -> Response {
+        (StatusCode::UNPROCESSABLE_ENTITY, self.to_string()).into_response()
+    }
+}
@@ -0,0 +1,139 @@
+use std::{sync::Arc, time::Instant};
+
+use axum::{
+    body::HttpBody,
+    extract::{Request, State},
+    http::{header, HeaderMap, Uri},
+    middleware::Next,
+    response::Response,
+};
+use log::{info, warn};
+use percent_encoding::percent_decode_str;
+
+use crate::reload::SharedConfiguration;
+
+/// Placeholder logged instead of secrets and tokens
+const REDACTED: &str = "<redacted>";
+
+/// Query parameters whose values are always redacted, they grant access on their own
+const SECRET_PARAMETERS: [&str; 2] = ["token", "invite"];
+
+/// Logs method, path, status, latency and body sizes of every request. Secrets are redacted from
+/// logged paths, whether they are passed as `?token=` or as path prefix, as are invites.
+pub async fn log_requests(
+    State(configuration): State<Arc<SharedConfiguration>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let configuration = configuration.load();
+    let method = request.method().clone();
+    let mut secrets = configuration.secrets.values();
+    secrets.extend(configuration.download_secret.clone());
+    let path = redact(request.uri(), &secrets);
+    let request_bytes = content_length(request.headers());
+    let start = Instant::now();
+    let response = next.run(request).await;
+    let status = response.status();
+    // streamed bodies like served files without a known length are logged as -
+    let response_bytes = response
+        .body()
+        .size_hint()
+        .exact()
+        .or_else(|| content_length(response.headers()));
+    let duration_ms = start.elapsed().as_millis() as u64;
+    let status = status.as_u16();
+    macro_rules! log_request {
+        ($level:ident) => {
+            $level!(
+                method:% = method,
+                path = path.as_str(),
+                status,
+                duration_ms,
+                request_bytes,
+                response_bytes;
+                "handled request"
+            )
+        };
+    }
+    if status >= 500 {
+        log_request!(warn);
+    } else {
+        log_request!(info);
+    }
+    response
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// Path and query of `uri` with path segments and query values equal to one of the secrets
+/// replaced, as well as all values of `token` and `invite` parameters. Text merely containing a
+/// secret is kept, short secrets would mangle unrelated paths otherwise.
+fn redact(uri: &Uri, secrets: &[String]) -> String {
+    let is_secret = |encoded: &str| {
+        let decoded = percent_decode_str(encoded).decode_utf8_lossy();
+        secrets.iter().any(|secret| decoded == secret.as_str())
+    };
+    let path = uri
+        .path()
+        .split('/')
+        .map(|segment| {
+            if is_secret(segment) {
+                REDACTED
+            } else {
+                segment
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+    match uri.query() {
+        Some(query) => {
+            let query = query
+                .split('&')
+                .map(|pair| match pair.split_once('=') {
+                    // form encoding writes spaces as +
+                    Some((name, value))
+                        if SECRET_PARAMETERS
+                            .contains(&percent_decode_str(name).decode_utf8_lossy().as_ref())
+                            || is_secret(&value.replace('+', " ")) =>
+                    {
+                        format!("{name}={REDACTED}")
+                    }
+                    _ => pair.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join("&");
+            format!("{path}?{query}")
+        }
+        None => path,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_whole_segments_and_values_are_redacted() {
+        let secrets = ["a1".to_string()];
+        let redacted = |uri: &str| redact(&uri.parse().unwrap(), &secrets);
+        assert_eq!(
+            redacted("/a1/images/data1.png"),
+            "/<redacted>/images/data1.png"
+        );
+        assert_eq!(
+            redacted("/images/data1.png?w=a1&other=a1b"),
+            "/images/data1.png?w=<redacted>&other=a1b"
+        );
+        assert_eq!(
+            redacted("/upload?invite=b2&token=c3"),
+            "/upload?invite=<redacted>&token=<redacted>"
+        );
+    }
+}
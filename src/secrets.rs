@@ -0,0 +1,312 @@
+use std::{
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::auth::constant_time_eq;
+
+/// Name of a secret given as bare value without name and role
+const DEFAULT_NAME: &str = "default";
+
+/// Name of the secret given with `--admin-secret`
+pub const ADMIN_NAME: &str = "admin";
+
+/// What a request authenticated with a secret may do
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// view the gallery and upload images
+    Guest,
+    /// a display on the wall, additionally shown the upload codes with `--upload-code-period`
+    Kiosk,
+    /// additionally use the `/admin/` endpoints, e.g. to rotate secrets
+    Admin,
+}
+
+impl FromStr for Role {
+    type Err = SecretError;
+
+    fn from_str(role: &str) -> Result<Self, Self::Err> {
+        match role {
+            "guest" => Ok(Role::Guest),
+            "kiosk" => Ok(Role::Kiosk),
+            "admin" => Ok(Role::Admin),
+            role => Err(SecretError::UnknownRole(role.to_string())),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Secret {
+    pub name: String,
+    pub value: String,
+    pub role: Role,
+}
+
+impl Secret {
+    /// Parses `name=value:role`, anything else is taken as the value of a secret named
+    /// `default` with the `bare_role`
+    pub fn parse(specification: &str, bare_role: Role) -> Result<Self, SecretError> {
+        let structured = specification.split_once('=').and_then(|(name, rest)| {
+            let (value, role) = rest.rsplit_once(':')?;
+            Some((name, value, role.parse().ok()?))
+        });
+        let secret = match structured {
+            Some((name, value, role)) => Secret {
+                name: name.to_string(),
+                value: value.to_string(),
+                role,
+            },
+            None => Secret {
+                name: DEFAULT_NAME.to_string(),
+                value: specification.to_string(),
+                role: bare_role,
+            },
+        };
+        secret.validate()?;
+        Ok(secret)
+    }
+
+    fn validate(&self) -> Result<(), SecretError> {
+        if self.name.is_empty() {
+            return Err(SecretError::EmptyName);
+        }
+        if self.value.is_empty() {
+            return Err(SecretError::EmptyValue(self.name.clone()));
+        }
+        Ok(())
+    }
+}
+
+/// Name and role of a secret, its value is never listed
+#[derive(Debug, Serialize)]
+pub struct SecretSummary {
+    pub name: String,
+    pub role: Role,
+}
+
+/// Secrets requests may authenticate with, changeable while running to rotate a leaked one
+#[derive(Debug)]
+pub struct Secrets(RwLock<Listed>);
+
+#[derive(Debug, Default)]
+struct Listed {
+    secrets: Vec<Secret>,
+    /// names of the secrets added while running, kept when the configuration is reloaded
+    added: Vec<String>,
+    /// configured secrets removed while running, left out when the configuration is reloaded
+    removed: Vec<Secret>,
+}
+
+impl Secrets {
+    pub fn new(secrets: Vec<Secret>) -> Result<Self, SecretError> {
+        let mut listed = Listed::default();
+        for secret in secrets {
+            insert(&mut listed.secrets, secret)?;
+        }
+        Ok(Self(RwLock::new(listed)))
+    }
+
+    /// Role of the secret with value `token`, compared in constant time against every secret
+    pub fn role(&self, token: &str) -> Option<Role> {
+        self.0
+            .read()
+            .unwrap()
+            .secrets
+            .iter()
+            .fold(None, |role, secret| {
+                if constant_time_eq(token.as_bytes(), secret.value.as_bytes()) {
+                    Some(secret.role)
+                } else {
+                    role
+                }
+            })
+    }
+
+    /// Values of all secrets, e.g. to redact them from logs
+    pub fn values(&self) -> Vec<String> {
+        self.0
+            .read()
+            .unwrap()
+            .secrets
+            .iter()
+            .map(|secret| secret.value.clone())
+            .collect()
+    }
+
+    /// The first secret matching `predicate`
+    pub fn find(&self, predicate: impl Fn(&Secret) -> bool) -> Option<Secret> {
+        self.0
+            .read()
+            .unwrap()
+            .secrets
+            .iter()
+            .find(|secret| predicate(secret))
+            .cloned()
+    }
+
+    pub fn list(&self) -> Vec<SecretSummary> {
+        self.0
+            .read()
+            .unwrap()
+            .secrets
+            .iter()
+            .map(|secret| SecretSummary {
+                name: secret.name.clone(),
+                role: secret.role,
+            })
+            .collect()
+    }
+
+    /// Adds a secret while running, which lasts until a restart
+    pub fn add(&self, secret: Secret) -> Result<(), SecretError> {
+        let mut listed = self.0.write().unwrap();
+        let name = secret.name.clone();
+        insert(&mut listed.secrets, secret)?;
+        listed.added.push(name);
+        Ok(())
+    }
+
+    /// Replaces the configured secrets with those of `configured` when the configuration is
+    /// reloaded. Changes made while running last until a restart: secrets added while running
+    /// are kept and take precedence over configured ones with the same name or value, configured
+    /// ones removed while running stay removed.
+    pub fn reload(&self, configured: &Secrets) {
+        let configured = configured.0.read().unwrap().secrets.clone();
+        let mut listed = self.0.write().unwrap();
+        let Listed {
+            secrets,
+            added,
+            removed,
+        } = &mut *listed;
+        secrets.retain(|secret| added.contains(&secret.name));
+        for secret in configured {
+            if removed
+                .iter()
+                .any(|gone| gone.name == secret.name && gone.value == secret.value)
+            {
+                info!(
+                    "secret {:?} was removed while running, restart to restore it",
+                    secret.name
+                );
+                continue;
+            }
+            let name = secret.name.clone();
+            if let Err(error) = insert(secrets, secret) {
+                warn!("ignoring configured secret {name:?} until a restart: {error}");
+            }
+        }
+    }
+
+    /// Removes the secret called `name` unless it is the last admin secret, which would leave no
+    /// way to add another one
+    pub fn remove(&self, name: &str) -> Result<Secret, SecretError> {
+        let mut listed = self.0.write().unwrap();
+        let Listed {
+            secrets,
+            added,
+            removed,
+        } = &mut *listed;
+        let Some(position) = secrets.iter().position(|secret| secret.name == name) else {
+            return Err(SecretError::UnknownName(name.to_string()));
+        };
+        let is_last_admin = secrets[position].role == Role::Admin
+            && secrets
+                .iter()
+                .filter(|secret| secret.role == Role::Admin)
+                .count()
+                == 1;
+        if is_last_admin {
+            return Err(SecretError::LastAdmin(name.to_string()));
+        }
+        let secret = secrets.remove(position);
+        if added.contains(&secret.name) {
+            added.retain(|added| *added != secret.name);
+        } else {
+            removed.push(secret.clone());
+        }
+        Ok(secret)
+    }
+}
+
+/// Adds `secret` to `secrets` unless its name or value is taken already
+fn insert(secrets: &mut Vec<Secret>, secret: Secret) -> Result<(), SecretError> {
+    secret.validate()?;
+    if secrets.iter().any(|existing| existing.name == secret.name) {
+        return Err(SecretError::DuplicateName(secret.name));
+    }
+    if secrets
+        .iter()
+        .any(|existing| existing.value == secret.value)
+    {
+        return Err(SecretError::DuplicateValue(secret.name));
+    }
+    secrets.push(secret);
+    Ok(())
+}
+
+pub async fn handle_list_secrets(State(secrets): State<Arc<Secrets>>) -> Json<Vec<SecretSummary>> {
+    Json(secrets.list())
+}
+
+/// Adds a secret, e.g. a new guest secret before removing a leaked one. Changes last until the
+/// server restarts, the configuration needs to be updated as well.
+pub async fn handle_add_secret(
+    State(secrets): State<Arc<Secrets>>,
+    Json(secret): Json<Secret>,
+) -> Result<StatusCode, SecretError> {
+    let (name, role) = (secret.name.clone(), secret.role);
+    secrets.add(secret)?;
+    info!("added {role:?} secret {name:?}");
+    Ok(StatusCode::CREATED)
+}
+
+pub async fn handle_remove_secret(
+    State(secrets): State<Arc<Secrets>>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, SecretError> {
+    let secret = secrets.remove(&name)?;
+    info!("removed {:?} secret {name:?}", secret.role);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Error)]
+pub enum SecretError {
+    #[error("unknown role `{0}`, expected guest or admin")]
+    UnknownRole(String),
+    #[error("secret names must not be empty")]
+    EmptyName,
+    #[error("secret {0:?} must not be empty")]
+    EmptyValue(String),
+    #[error("a secret named {0:?} exists already")]
+    DuplicateName(String),
+    #[error("secret {0:?} has the same value as another one")]
+    DuplicateValue(String),
+    #[error("no secret named {0:?}")]
+    UnknownName(String),
+    #[error("secret {0:?} is the last admin secret")]
+    LastAdmin(String),
+}
+
+impl IntoResponse for SecretError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            SecretError::UnknownName(_) => StatusCode::NOT_FOUND,
+            SecretError::DuplicateName(_)
+            | SecretError::DuplicateValue(_)
+            | SecretError::LastAdmin(_) => StatusCode::CONFLICT,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
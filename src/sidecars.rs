@@ -0,0 +1,156 @@
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::io;
+
+use crate::{
+    index::{Image, ImageFlag, Indexer},
+    sources::SourceRecords,
+    storage::Storage,
+    Configuration,
+};
+
+/// Appended to the file name of an original for its sidecar, e.g. `photo.jpg.moments.json`
+const SIDECAR_SUFFIX: &str = ".moments.json";
+
+/// What organizers set for an image, kept next to its original with `--sidecars`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Sidecar {
+    #[serde(default)]
+    pinned: bool,
+    #[serde(default)]
+    hidden: bool,
+}
+
+impl Sidecar {
+    fn of(image: &Image) -> Self {
+        Self {
+            pinned: image.pinned,
+            hidden: image.hidden,
+        }
+    }
+}
+
+pub fn is_sidecar(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.ends_with(SIDECAR_SUFFIX))
+}
+
+/// The original a sidecar belongs to
+pub fn original_of(sidecar: &Path) -> Option<PathBuf> {
+    let name = sidecar
+        .file_name()?
+        .to_str()?
+        .strip_suffix(SIDECAR_SUFFIX)?;
+    (!name.is_empty()).then(|| sidecar.with_file_name(name))
+}
+
+fn sidecar_of(original: &Path) -> PathBuf {
+    let mut name = original.as_os_str().to_os_string();
+    name.push(SIDECAR_SUFFIX);
+    PathBuf::from(name)
+}
+
+/// Writes the sidecar of `image` after an organizer changed it, nothing without `--sidecars`
+pub async fn save_sidecar(configuration: &Configuration, image: &Image) -> Result<(), io::Error> {
+    if !configuration.sidecars {
+        return Ok(());
+    }
+    let contents = serde_json::to_vec_pretty(&Sidecar::of(image))?;
+    configuration
+        .originals
+        .write(&sidecar_of(&image.path), contents)
+        .await
+}
+
+/// The sidecar of the original at `path`, `None` if it has none or it cannot be read, which
+/// leaves the central store in charge
+async fn load_sidecar(storage: &dyn Storage, path: &Path) -> Option<Sidecar> {
+    let sidecar = sidecar_of(path);
+    match storage.read(&sidecar).await {
+        Ok(contents) => serde_json::from_slice(&contents)
+            .inspect_err(|error| warn!("ignoring corrupt sidecar {}: {error}", sidecar.display()))
+            .ok(),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => None,
+        Err(error) => {
+            warn!("ignoring unreadable sidecar {}: {error}", sidecar.display());
+            None
+        }
+    }
+}
+
+/// Applies the sidecars of all indexed images over the central store, at startup
+pub async fn apply_sidecars(
+    configuration: &Configuration,
+    indexer: &Indexer,
+    sources: &SourceRecords,
+) -> Result<(), io::Error> {
+    let images = indexer
+        .index_including_hidden()
+        .await
+        .map_err(io::Error::other)?;
+    let mut changed = false;
+    for image in &images {
+        changed |= apply(configuration, indexer, sources, image).await?;
+    }
+    if changed {
+        sources.save().await?;
+    }
+    Ok(())
+}
+
+/// Applies the sidecar of the indexed image at `path`, e.g. when either of them appeared or
+/// changed in storage
+pub async fn apply_sidecar(
+    configuration: &Configuration,
+    indexer: &Indexer,
+    sources: &SourceRecords,
+    path: &Path,
+) -> Result<(), io::Error> {
+    let images = indexer
+        .index_including_hidden()
+        .await
+        .map_err(io::Error::other)?;
+    let Some(image) = images.iter().find(|image| image.path == path) else {
+        return Ok(());
+    };
+    if apply(configuration, indexer, sources, image).await? {
+        sources.save().await?;
+    }
+    Ok(())
+}
+
+/// Sets the flags of `image` that differ in its sidecar, in the source records and the index,
+/// returns whether there were any
+async fn apply(
+    configuration: &Configuration,
+    indexer: &Indexer,
+    sources: &SourceRecords,
+    image: &Image,
+) -> Result<bool, io::Error> {
+    let Some(sidecar) = load_sidecar(configuration.originals.as_ref(), &image.path).await else {
+        return Ok(false);
+    };
+    let current = Sidecar::of(image);
+    let mut changed = false;
+    for (flag, value, current) in [
+        (ImageFlag::Pinned, sidecar.pinned, current.pinned),
+        (ImageFlag::Hidden, sidecar.hidden, current.hidden),
+    ] {
+        if value != current {
+            // not recorded before it was cached, the sidecar applies again at the next start
+            sources.set_flag(image.hash, flag, value);
+            indexer
+                .set_flag(image.hash, flag, value)
+                .await
+                .map_err(io::Error::other)?;
+            changed = true;
+        }
+    }
+    if changed {
+        info!("applied the sidecar of {}", image.path.display());
+    }
+    Ok(changed)
+}
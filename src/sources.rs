@@ -0,0 +1,537 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tokio::{
+    fs::{metadata, read},
+    io,
+};
+
+use crate::{
+    cache::write_atomically,
+    index::{hex_hash, Image, ImageFlag, ImageHash},
+    placeholder::Placeholder,
+};
+
+/// Size and modification time of a source image, cheap to obtain without reading the file
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub size: u64,
+    #[serde(with = "time::serde::rfc3339")]
+    pub modified: OffsetDateTime,
+}
+
+impl Fingerprint {
+    pub async fn of(path: impl AsRef<Path>) -> Result<Self, io::Error> {
+        let metadata = metadata(path).await?;
+        Ok(Self {
+            size: metadata.len(),
+            modified: OffsetDateTime::from(metadata.modified()?),
+        })
+    }
+}
+
+/// The state of a source image at the time its derivatives were generated
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct SourceRecord {
+    #[serde(flatten)]
+    fingerprint: Fingerprint,
+    #[serde(with = "hex_hash")]
+    hash: ImageHash,
+    #[serde(flatten)]
+    placeholder: Option<Placeholder>,
+}
+
+/// What kiosks, organizers and guests recorded about an image, kept by content so it stays with
+/// the image when another of its paths becomes canonical
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+struct ImageDetails {
+    /// when a kiosk last reported showing the image, for recommending the others first
+    #[serde(
+        default,
+        with = "time::serde::rfc3339::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    last_shown: Option<OffsetDateTime>,
+    /// kept prominent on the kiosks by an organizer
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pinned: bool,
+    /// kept from the kiosks by an organizer
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    hidden: bool,
+    /// guests asking to remove the image, at most one per reporter
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    reports: Vec<Report>,
+    /// how often guests reacted with each emoji
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    reactions: BTreeMap<String, u64>,
+}
+
+/// A guest asking to remove an image
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Report {
+    /// client address, IPv6 ones reduced to their /64 network
+    pub reporter: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub reported_at: OffsetDateTime,
+}
+
+impl SourceRecord {
+    fn new(fingerprint: Fingerprint, hash: ImageHash) -> Self {
+        Self {
+            fingerprint,
+            hash,
+            placeholder: None,
+        }
+    }
+}
+
+impl ImageDetails {
+    fn flag(&mut self, flag: ImageFlag) -> &mut bool {
+        match flag {
+            ImageFlag::Pinned => &mut self.pinned,
+            ImageFlag::Hidden => &mut self.hidden,
+        }
+    }
+}
+
+/// Source records by path and the details of their images by hash, as persisted
+#[derive(Default, Serialize, Deserialize)]
+struct Records {
+    sources: HashMap<PathBuf, SourceRecord>,
+    #[serde(default, with = "details_by_hash")]
+    images: HashMap<ImageHash, ImageDetails>,
+}
+
+impl Records {
+    /// Whether a source with this content is recorded, details of others are not kept
+    fn is_recorded(&self, hash: ImageHash) -> bool {
+        self.sources.values().any(|record| record.hash == hash)
+    }
+
+    /// Details of a recorded image, `None` for unknown images
+    fn details_mut(&mut self, hash: ImageHash) -> Option<&mut ImageDetails> {
+        if !self.is_recorded(hash) {
+            return None;
+        }
+        Some(self.images.entry(hash).or_default())
+    }
+}
+
+/// Records as saved before details were kept by content, each with those of its path
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredRecords {
+    Current(Records),
+    ByPath(HashMap<PathBuf, LegacyRecord>),
+}
+
+#[derive(Deserialize)]
+struct LegacyRecord {
+    #[serde(flatten)]
+    source: SourceRecord,
+    #[serde(flatten)]
+    details: ImageDetails,
+}
+
+impl From<StoredRecords> for Records {
+    fn from(stored: StoredRecords) -> Self {
+        match stored {
+            StoredRecords::Current(records) => records,
+            StoredRecords::ByPath(legacy) => {
+                let mut records = Records::default();
+                // sorted, so the smallest path wins like the canonical one in the index
+                let mut legacy: Vec<_> = legacy.into_iter().collect();
+                legacy.sort_by(|(a, _), (b, _)| a.cmp(b));
+                for (path, LegacyRecord { source, details }) in legacy {
+                    if details != ImageDetails::default() {
+                        records.images.entry(source.hash).or_insert(details);
+                    }
+                    records.sources.insert(path, source);
+                }
+                records
+            }
+        }
+    }
+}
+
+/// Serializes details as a map by hexadecimal hash, JSON keys must be strings
+mod details_by_hash {
+    use std::collections::HashMap;
+
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    use super::ImageDetails;
+    use crate::index::{hex_hash, ImageHash};
+
+    pub fn serialize<S: Serializer>(
+        images: &HashMap<ImageHash, ImageDetails>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(
+            images
+                .iter()
+                .map(|(hash, details)| (hex_hash::to_string(hash), details)),
+        )
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<ImageHash, ImageDetails>, D::Error> {
+        HashMap::<String, ImageDetails>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(hash, details)| {
+                hex_hash::from_str(&hash)
+                    .map(|hash| (hash, details))
+                    .ok_or_else(|| D::Error::custom("invalid image hash"))
+            })
+            .collect()
+    }
+}
+
+/// Remembers which source content the cached derivatives were generated from, and when images
+/// were last shown and how organizers flagged them, persisted as JSON to detect replaced originals
+/// and keep recommendations fair across restarts
+pub struct SourceRecords {
+    file: PathBuf,
+    records: Mutex<Records>,
+    /// set by changes only saved periodically, see [`Self::save_if_unsaved`]
+    unsaved: AtomicBool,
+}
+
+impl SourceRecords {
+    /// Loads the records from `file`, starting empty if it is missing or unreadable
+    pub async fn load(file: PathBuf) -> Self {
+        let records = match read(&file).await {
+            Ok(contents) => serde_json::from_slice::<StoredRecords>(&contents)
+                .map(Records::from)
+                .unwrap_or_else(|error| {
+                    warn!(
+                        "ignoring corrupt source records {}: {error}",
+                        file.display()
+                    );
+                    Records::default()
+                }),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Records::default(),
+            Err(error) => {
+                warn!(
+                    "ignoring unreadable source records {}: {error}",
+                    file.display()
+                );
+                Records::default()
+            }
+        };
+        Self {
+            file,
+            records: Mutex::new(records),
+            unsaved: AtomicBool::new(false),
+        }
+    }
+
+    pub async fn save(&self) -> Result<(), io::Error> {
+        self.unsaved.store(false, Ordering::Relaxed);
+        let contents = serde_json::to_vec(&*self.records.lock().unwrap())?;
+        if let Some(parent) = self.file.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        write_atomically(&self.file, contents).await
+    }
+
+    /// Whether derivatives of `path` were generated from a source with this fingerprint
+    pub fn matches(&self, path: &Path, fingerprint: Fingerprint) -> bool {
+        self.records
+            .lock()
+            .unwrap()
+            .sources
+            .get(path)
+            .is_some_and(|record| record.fingerprint == fingerprint)
+    }
+
+    /// Hash of the recorded content of `path`
+    pub fn hash(&self, path: &Path) -> Option<ImageHash> {
+        self.records
+            .lock()
+            .unwrap()
+            .sources
+            .get(path)
+            .map(|record| record.hash)
+    }
+
+    /// Hash of the recorded content of `path` if the source still has this fingerprint
+    pub fn matching_hash(&self, path: &Path, fingerprint: Fingerprint) -> Option<ImageHash> {
+        self.records
+            .lock()
+            .unwrap()
+            .sources
+            .get(path)
+            .filter(|record| record.fingerprint == fingerprint)
+            .map(|record| record.hash)
+    }
+
+    /// Whether derivatives of `path` are still valid for a source with `hash`. Sources that
+    /// were only touched and caches from before records existed are adopted as valid.
+    pub fn is_current(&self, path: &Path, fingerprint: Fingerprint, hash: ImageHash) -> bool {
+        let sources = &mut self.records.lock().unwrap().sources;
+        match sources.get_mut(path) {
+            Some(record) if record.hash != hash => false,
+            Some(record) => {
+                record.fingerprint = fingerprint;
+                true
+            }
+            None => {
+                sources.insert(path.to_path_buf(), SourceRecord::new(fingerprint, hash));
+                true
+            }
+        }
+    }
+
+    /// Records new source content, forgetting the placeholder of the previous content
+    pub fn record(&self, path: &Path, fingerprint: Fingerprint, hash: ImageHash) {
+        self.records
+            .lock()
+            .unwrap()
+            .sources
+            .insert(path.to_path_buf(), SourceRecord::new(fingerprint, hash));
+    }
+
+    /// Records a new fingerprint for the same content, keeping the placeholder
+    pub fn touch(&self, path: &Path, fingerprint: Fingerprint, hash: ImageHash) {
+        let sources = &mut self.records.lock().unwrap().sources;
+        match sources.get_mut(path) {
+            Some(record) if record.hash == hash => record.fingerprint = fingerprint,
+            _ => {
+                sources.insert(path.to_path_buf(), SourceRecord::new(fingerprint, hash));
+            }
+        }
+    }
+
+    pub fn placeholder(&self, path: &Path) -> Option<Placeholder> {
+        self.records
+            .lock()
+            .unwrap()
+            .sources
+            .get(path)
+            .and_then(|record| record.placeholder.clone())
+    }
+
+    /// Stores the placeholder of a recorded source, unknown sources are ignored
+    pub fn set_placeholder(&self, path: &Path, placeholder: Placeholder) {
+        if let Some(record) = self.records.lock().unwrap().sources.get_mut(path) {
+            record.placeholder = Some(placeholder);
+        }
+    }
+
+    pub fn last_shown(&self, hash: ImageHash) -> Option<OffsetDateTime> {
+        self.records
+            .lock()
+            .unwrap()
+            .images
+            .get(&hash)
+            .and_then(|details| details.last_shown)
+    }
+
+    /// Stores when a recorded image was last shown, unknown images are ignored
+    pub fn set_shown(&self, hash: ImageHash, at: OffsetDateTime) {
+        if let Some(details) = self.records.lock().unwrap().details_mut(hash) {
+            details.last_shown = Some(at);
+        }
+    }
+
+    pub fn flag(&self, hash: ImageHash, flag: ImageFlag) -> bool {
+        self.records
+            .lock()
+            .unwrap()
+            .images
+            .get_mut(&hash)
+            .is_some_and(|details| *details.flag(flag))
+    }
+
+    /// Sets `flag` of a recorded image, returns whether it is recorded
+    pub fn set_flag(&self, hash: ImageHash, flag: ImageFlag, value: bool) -> bool {
+        match self.records.lock().unwrap().details_mut(hash) {
+            Some(details) => {
+                *details.flag(flag) = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn reports(&self, hash: ImageHash) -> Vec<Report> {
+        self.records
+            .lock()
+            .unwrap()
+            .images
+            .get(&hash)
+            .map(|details| details.reports.clone())
+            .unwrap_or_default()
+    }
+
+    /// Adds a report on a recorded image unless its reporter reported it already, returns the
+    /// number of reports or `None` if the image is not recorded
+    pub fn add_report(&self, hash: ImageHash, report: Report) -> Option<usize> {
+        let mut records = self.records.lock().unwrap();
+        let reports = &mut records.details_mut(hash)?.reports;
+        if !reports
+            .iter()
+            .any(|existing| existing.reporter == report.reporter)
+        {
+            reports.push(report);
+        }
+        Some(reports.len())
+    }
+
+    pub fn reactions(&self, hash: ImageHash) -> BTreeMap<String, u64> {
+        self.records
+            .lock()
+            .unwrap()
+            .images
+            .get(&hash)
+            .map(|details| details.reactions.clone())
+            .unwrap_or_default()
+    }
+
+    /// Stores the reaction counts of a recorded image until the next periodic save, unknown
+    /// images are ignored
+    pub fn set_reactions(&self, hash: ImageHash, reactions: BTreeMap<String, u64>) {
+        if let Some(details) = self.records.lock().unwrap().details_mut(hash) {
+            details.reactions = reactions;
+            self.unsaved.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Saves changes that are not worth a save each, e.g. reactions
+    pub async fn save_if_unsaved(&self) -> Result<(), io::Error> {
+        if self.unsaved.load(Ordering::Relaxed) {
+            self.save().await?;
+        }
+        Ok(())
+    }
+
+    /// Drops the record of a source removed from storage, the details of its image stay for
+    /// the other paths with the same content
+    pub fn forget(&self, path: &Path) {
+        self.records.lock().unwrap().sources.remove(path);
+    }
+
+    /// Drops the details of an image whose last path was removed from storage
+    pub fn forget_image(&self, hash: ImageHash) {
+        self.records.lock().unwrap().images.remove(&hash);
+    }
+
+    /// Paths with a record, taken before indexing to tell stale records from ones added since
+    /// in [`Self::retain`]
+    pub fn recorded_paths(&self) -> HashSet<PathBuf> {
+        self.records
+            .lock()
+            .unwrap()
+            .sources
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    /// Drops the records among `recorded` of sources that are no longer among the indexed
+    /// `images`, and the details of images no source has anymore. Records added after
+    /// `recorded` was taken stay, uploads record their source before indexing it.
+    pub fn retain(&self, images: &[Image], recorded: &HashSet<PathBuf>) {
+        let paths: HashSet<&Path> = images
+            .iter()
+            .flat_map(|image| [&image.path].into_iter().chain(&image.aliases))
+            .map(PathBuf::as_path)
+            .collect();
+        let mut records = self.records.lock().unwrap();
+        records
+            .sources
+            .retain(|path, _| paths.contains(path.as_path()) || !recorded.contains(path.as_path()));
+        let hashes: HashSet<ImageHash> =
+            records.sources.values().map(|record| record.hash).collect();
+        records.images.retain(|hash, _| hashes.contains(hash));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn details_recorded_by_path_are_kept_by_content() {
+        let directory = tempfile::tempdir().unwrap();
+        let file = directory.path().join("sources.json");
+        let hash = "000000000000000100000000000000ff";
+        std::fs::write(
+            &file,
+            serde_json::json!({
+                "forwarded.jpg": {
+                    "size": 1,
+                    "modified": "2024-05-01T12:00:00Z",
+                    "hash": hash,
+                    "hidden": true,
+                    "reactions": { "🎉": 2 },
+                },
+                "canonical.jpg": {
+                    "size": 1,
+                    "modified": "2024-05-01T12:00:00Z",
+                    "hash": hash,
+                    "placeholder": "LKO2?U%2Tw=w]~RBVZRi};RPxuwH",
+                    "color": "#806040",
+                },
+            })
+            .to_string(),
+        )
+        .unwrap();
+
+        let records = SourceRecords::load(file.clone()).await;
+        let hash = hex_hash::from_str(hash).unwrap();
+        assert!(records.flag(hash, ImageFlag::Hidden));
+        assert_eq!(records.reactions(hash)["🎉"], 2);
+        assert!(records.placeholder(Path::new("canonical.jpg")).is_some());
+        // the details stay with the remaining path
+        records.forget(Path::new("forwarded.jpg"));
+        records.save().await.unwrap();
+        let records = SourceRecords::load(file).await;
+        assert!(records.flag(hash, ImageFlag::Hidden));
+        assert_eq!(records.hash(Path::new("canonical.jpg")), Some(hash));
+        assert_eq!(records.hash(Path::new("forwarded.jpg")), None);
+    }
+
+    #[tokio::test]
+    async fn records_added_while_indexing_are_retained() {
+        let directory = tempfile::tempdir().unwrap();
+        let records = SourceRecords::load(directory.path().join("sources.json")).await;
+        let fingerprint = Fingerprint {
+            size: 1,
+            modified: OffsetDateTime::UNIX_EPOCH,
+        };
+        records.record(Path::new("indexed.jpg"), fingerprint, [1, 1]);
+        records.record(Path::new("removed.jpg"), fingerprint, [2, 2]);
+        records.set_flag([2, 2], ImageFlag::Pinned, true);
+        let recorded = records.recorded_paths();
+        let indexed = Image::new(
+            [1, 1],
+            PathBuf::from("indexed.jpg"),
+            OffsetDateTime::UNIX_EPOCH,
+            Default::default(),
+        );
+        // an upload recorded but not yet indexed
+        records.record(Path::new("uploaded.jpg"), fingerprint, [3, 3]);
+        records.set_flag([3, 3], ImageFlag::Pinned, true);
+
+        records.retain(&[indexed], &recorded);
+        assert_eq!(records.hash(Path::new("indexed.jpg")), Some([1, 1]));
+        assert_eq!(records.hash(Path::new("removed.jpg")), None);
+        assert!(!records.flag([2, 2], ImageFlag::Pinned));
+        assert_eq!(records.hash(Path::new("uploaded.jpg")), Some([3, 3]));
+        assert!(records.flag([3, 3], ImageFlag::Pinned));
+    }
+}
@@ -0,0 +1,75 @@
+use std::{path::Path, sync::Arc};
+
+use log::warn;
+use tokio::{
+    fs::{copy, create_dir_all, write},
+    io,
+};
+
+use crate::{
+    cache::{CacheLocks, ProcessingQueue},
+    frontend::embedded_file,
+    index::Indexer,
+    reconcile::reconcile,
+    sources::SourceRecords,
+    Configuration,
+};
+
+/// Meta tag telling `kiosk.js` to load the images from the index file next to it instead of
+/// connecting to a server
+const STATIC_INDEX_MARKER: &str = r#"<meta name="moments-static-index" content="index.json" />"#;
+
+/// Frontend files the exported wall needs besides its page
+const WALL_FILES: [&str; 3] = ["kiosk.css", "kiosk.js", "favicon.png"];
+
+/// Writes a gallery into `out` that any web server can serve as it is: the kiosk wall as
+/// `index.html`, the images it shows as `index.json` in the order of the live index and their
+/// cached derivatives below `images/`. Missing derivatives are generated first, images that
+/// still lack one are left out. Returns how many images were exported.
+pub async fn export_static(
+    configuration: &Arc<Configuration>,
+    locks: &Arc<CacheLocks>,
+    sources: &SourceRecords,
+    queue: &Arc<ProcessingQueue>,
+    indexer: &Indexer,
+    out: &Path,
+) -> Result<usize, io::Error> {
+    let images = indexer.index(None).await.map_err(io::Error::other)?;
+    reconcile(configuration, locks, sources, queue, indexer, &images).await?;
+    // caching may have added placeholders
+    let images = indexer.index(None).await.map_err(io::Error::other)?;
+
+    create_dir_all(out).await?;
+    let mut exported = Vec::with_capacity(images.len());
+    'images: for image in images {
+        for cached_path in [&image.cached_path]
+            .into_iter()
+            .chain(image.derivatives.values())
+        {
+            let destination = out.join("images").join(cached_path);
+            if let Some(parent) = destination.parent() {
+                create_dir_all(parent).await?;
+            }
+            if let Err(error) = copy(configuration.cache.join(cached_path), &destination).await {
+                warn!(
+                    "leaving {} out of the export: {error}",
+                    image.path.display()
+                );
+                continue 'images;
+            }
+        }
+        exported.push(image);
+    }
+    write(out.join("index.json"), serde_json::to_vec(&exported)?).await?;
+
+    let page = String::from_utf8_lossy(embedded_file("kiosk.html").unwrap_or_default()).replacen(
+        "</head>",
+        &format!("  {STATIC_INDEX_MARKER}\n  </head>"),
+        1,
+    );
+    write(out.join("index.html"), page).await?;
+    for file in WALL_FILES {
+        write(out.join(file), embedded_file(file).unwrap_or_default()).await?;
+    }
+    Ok(exported.len())
+}
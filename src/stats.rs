@@ -0,0 +1,60 @@
+use std::sync::Arc;
+
+use axum::{extract::State, Json};
+use serde::Serialize;
+
+use crate::{
+    cache::{ProcessingQueue, ProcessingStatistics},
+    eviction::CacheUsage,
+    limits::{RequestLimits, RequestStatistics},
+    mirror::{Mirror, MirrorStatistics},
+    missing::{MissingImages, MissingStatistics},
+    reload::SharedConfiguration,
+    watcher::{WatchProgress, WatchStatistics},
+};
+
+#[derive(Debug, Serialize)]
+pub struct Statistics {
+    pub processing: ProcessingStatistics,
+    pub cache: CacheStatistics,
+    pub watcher: WatchProgress,
+    pub requests: RequestStatistics,
+    pub missing_images: MissingStatistics,
+    /// only with `--mirror-to`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mirror: Option<MirrorStatistics>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CacheStatistics {
+    /// size of the cache as of the last periodic measurement
+    pub bytes: u64,
+    pub max_bytes: Option<u64>,
+}
+
+pub type StatsState = (
+    Arc<SharedConfiguration>,
+    Arc<ProcessingQueue>,
+    Arc<CacheUsage>,
+    Arc<WatchStatistics>,
+    Arc<RequestLimits>,
+    Arc<MissingImages>,
+    Option<Arc<Mirror>>,
+);
+
+pub async fn handle_stats(
+    State((configuration, queue, usage, watcher, limits, missing, mirror)): State<StatsState>,
+) -> Json<Statistics> {
+    let configuration = configuration.load();
+    Json(Statistics {
+        processing: queue.statistics(),
+        cache: CacheStatistics {
+            bytes: usage.bytes(),
+            max_bytes: configuration.max_cache_bytes,
+        },
+        watcher: watcher.progress(),
+        requests: limits.statistics(&configuration),
+        missing_images: missing.statistics(&configuration),
+        mirror: mirror.map(|mirror| mirror.statistics()),
+    })
+}
@@ -0,0 +1,187 @@
+use std::{
+    io::SeekFrom,
+    ops::Range,
+    path::{Component, Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures_util::{
+    future::ready,
+    stream::{self, BoxStream},
+    StreamExt,
+};
+use log::warn;
+use time::OffsetDateTime;
+use tokio::{
+    fs::{metadata, read, read_dir, remove_file, File},
+    io::{self, AsyncReadExt, AsyncSeekExt},
+};
+use tokio_util::io::ReaderStream;
+
+use crate::{
+    cache::write_atomically,
+    index::{hash_contents, ImageHash},
+    sources::Fingerprint,
+};
+
+/// Contents of a file as they arrive, so large ones are not held in memory
+pub type ByteStream = BoxStream<'static, io::Result<Bytes>>;
+
+/// Where originals are kept, a flat namespace of files by their path relative to it. Everything
+/// derived from them, the cache and the internal state, stays in the local cache directory.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// All files with their fingerprints, in no particular order
+    async fn list(&self) -> io::Result<Vec<(PathBuf, Fingerprint)>>;
+
+    /// Fails with [`io::ErrorKind::NotFound`] for missing files
+    async fn stat(&self, path: &Path) -> io::Result<Fingerprint>;
+
+    async fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    /// The bytes in `range` of the file, which ends within it, streamed unless they can only be
+    /// read at once
+    async fn read_range(&self, path: &Path, range: Range<u64>) -> io::Result<ByteStream> {
+        let contents = self.read(path).await?;
+        let part = contents
+            .get(range.start as usize..range.end as usize)
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        Ok(stream::once(ready(Ok(Bytes::copy_from_slice(part)))).boxed())
+    }
+
+    /// Content hash of the file, from contents read at once unless they can be streamed
+    async fn hash(&self, path: &Path) -> io::Result<ImageHash> {
+        Ok(hash_contents(self.read(path).await?).await)
+    }
+
+    /// Creates the file or replaces its contents, readers never observe it partially written
+    async fn write(&self, path: &Path, contents: Vec<u8>) -> io::Result<()>;
+
+    async fn delete(&self, path: &Path) -> io::Result<()>;
+
+    async fn exists(&self, path: &Path) -> io::Result<bool> {
+        match self.stat(path).await {
+            Ok(_) => Ok(true),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// The directory the files are in if they are on the local file system, so they can be
+    /// watched for changes and served with range requests
+    fn local_directory(&self) -> Option<&Path> {
+        None
+    }
+}
+
+/// Files directly in a local directory, the default
+pub struct LocalStorage {
+    directory: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+        }
+    }
+
+    /// Refuses paths leaving the directory or reaching into subdirectories, storage is flat
+    fn resolve(&self, path: &Path) -> io::Result<PathBuf> {
+        match path.components().collect::<Vec<_>>()[..] {
+            [Component::Normal(_)] => Ok(self.directory.join(path)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a file name in storage", path.display()),
+            )),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn list(&self) -> io::Result<Vec<(PathBuf, Fingerprint)>> {
+        let mut files = Vec::new();
+        let mut entries = read_dir(&self.directory).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            // following symbolic links to files, unlike the metadata of the entry
+            let fingerprint = match metadata(entry.path()).await {
+                Ok(metadata) if !metadata.is_file() => continue,
+                Ok(metadata) => fingerprint(&metadata),
+                Err(error) => Err(error),
+            };
+            match fingerprint {
+                Ok(fingerprint) => files.push((PathBuf::from(entry.file_name()), fingerprint)),
+                // deleted since reading the directory, or a dangling symbolic link
+                Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+                Err(error) => warn!("skipping {}: {error}", entry.path().display()),
+            }
+        }
+        Ok(files)
+    }
+
+    async fn stat(&self, path: &Path) -> io::Result<Fingerprint> {
+        let metadata = metadata(self.resolve(path)?).await?;
+        if !metadata.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is not a file", path.display()),
+            ));
+        }
+        fingerprint(&metadata)
+    }
+
+    async fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        read(self.resolve(path)?).await
+    }
+
+    async fn read_range(&self, path: &Path, range: Range<u64>) -> io::Result<ByteStream> {
+        let mut file = File::open(self.resolve(path)?).await?;
+        file.seek(SeekFrom::Start(range.start)).await?;
+        Ok(ReaderStream::new(file.take(range.end - range.start)).boxed())
+    }
+
+    async fn write(&self, path: &Path, contents: Vec<u8>) -> io::Result<()> {
+        write_atomically(&self.resolve(path)?, contents).await
+    }
+
+    async fn delete(&self, path: &Path) -> io::Result<()> {
+        remove_file(self.resolve(path)?).await
+    }
+
+    fn local_directory(&self) -> Option<&Path> {
+        Some(&self.directory)
+    }
+}
+
+fn fingerprint(metadata: &std::fs::Metadata) -> io::Result<Fingerprint> {
+    Ok(Fingerprint {
+        size: metadata.len(),
+        modified: OffsetDateTime::from(metadata.modified()?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn entries_failing_to_be_inspected_are_skipped() {
+        let directory = tempfile::tempdir().unwrap();
+        std::fs::write(directory.path().join("photo.png"), b"png").unwrap();
+        std::fs::create_dir(directory.path().join("subdirectory")).unwrap();
+        let link = |target: &str, name: &str| {
+            std::os::unix::fs::symlink(directory.path().join(target), directory.path().join(name))
+                .unwrap()
+        };
+        link("missing.png", "dangling.png");
+        // a loop of links fails with an error other than not found
+        link("second.png", "first.png");
+        link("first.png", "second.png");
+
+        let files = LocalStorage::new(directory.path()).list().await.unwrap();
+        let names: Vec<_> = files.into_iter().map(|(path, _)| path).collect();
+        assert_eq!(names, [PathBuf::from("photo.png")]);
+    }
+}
@@ -0,0 +1,77 @@
+use std::{
+    env::{self, VarError},
+    io,
+    os::{fd::FromRawFd, unix::net::UnixDatagram},
+    process,
+};
+
+use log::warn;
+use socket2::Socket;
+use thiserror::Error;
+use tokio::net::TcpListener;
+
+/// File descriptor of the first socket passed by systemd, the others follow it
+const FIRST_INHERITED_DESCRIPTOR: i32 = 3;
+
+/// Listeners passed by systemd socket activation via `LISTEN_FDS`, none if the process was not
+/// started that way, e.g. because `LISTEN_PID` names another process that passed its
+/// environment on
+pub fn inherited_listeners() -> Result<Vec<TcpListener>, ActivationError> {
+    let count = match env::var("LISTEN_FDS") {
+        Ok(count) => count,
+        Err(VarError::NotPresent) => return Ok(Vec::new()),
+        Err(VarError::NotUnicode(_)) => return Err(ActivationError::InvalidCount),
+    };
+    let is_for_us = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == process::id());
+    if !is_for_us {
+        return Ok(Vec::new());
+    }
+    let count: i32 = count.parse().map_err(|_| ActivationError::InvalidCount)?;
+    (FIRST_INHERITED_DESCRIPTOR..FIRST_INHERITED_DESCRIPTOR + count)
+        .map(|descriptor| {
+            listener(descriptor)
+                .map_err(|source| ActivationError::Descriptor { descriptor, source })
+        })
+        .collect()
+}
+
+fn listener(descriptor: i32) -> io::Result<TcpListener> {
+    // systemd passes ownership of the descriptors, nothing else in this process uses them
+    let socket = unsafe { Socket::from_raw_fd(descriptor) };
+    // fails for anything but a bound socket, e.g. if the unit does not configure ListenStream=
+    socket.local_addr()?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Tells systemd about the state of the service, e.g. `READY=1` for units with `Type=notify`.
+/// Does nothing if not started by systemd or it does not expect notifications.
+pub fn notify(state: &str) {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let result = UnixDatagram::unbound().and_then(|socket| {
+        // sockets in the abstract namespace have no path in the file system
+        #[cfg(target_os = "linux")]
+        if let Some(name) = path.as_encoded_bytes().strip_prefix(b"@") {
+            use std::os::linux::net::SocketAddrExt;
+            let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            return socket.send_to_addr(state.as_bytes(), &address);
+        }
+        socket.send_to(state.as_bytes(), &path)
+    });
+    if let Err(error) = result {
+        warn!("failed to notify systemd of {state}: {error}");
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ActivationError {
+    #[error("LISTEN_FDS is not a number of file descriptors")]
+    InvalidCount,
+    #[error("inherited file descriptor {descriptor} is not a listening socket")]
+    Descriptor { descriptor: i32, source: io::Error },
+}
@@ -1,72 +1,300 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{Instant, SystemTime},
+};
 
 use axum::{
-    extract::State,
-    http::StatusCode,
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
     response::{IntoResponse, Response},
+    Extension,
 };
 use axum_typed_multipart::{FieldData, TryFromMultipart, TypedMultipart};
-use image::ImageError;
+use log::{info, warn};
+use serde::Deserialize;
 use tempfile::NamedTempFile;
 use thiserror::Error;
 use time::{format_description::parse, OffsetDateTime};
-use tokio::fs::copy;
+use tokio::fs::read;
 
 use crate::{
-    cache::cache_image,
-    index::{hash_file, Image, IndexError, Indexer},
+    auth::Authenticated,
+    cache::{cache_image, remove_derivatives, CacheError, ProcessingQueue},
+    devices::{AuthenticatedDevice, Devices},
+    index::{hex_hash, inspect_file, Image, ImageHash, IndexError, Indexer},
+    invites::Invites,
+    reload::SharedConfiguration,
+    secrets::Role,
+    sources::SourceRecords,
+    upload_codes::{UPLOAD_PASS_COOKIE, UPLOAD_PASS_LIFETIME},
     Configuration,
 };
 
+/// Route receiving image uploads with the secret or a device token
+pub const UPLOAD_PATH: &str = "/upload";
+
+/// Route receiving image uploads with an invite instead
+pub const INVITED_UPLOAD_PATH: &str = "/upload/invited";
+
+/// Whether `path` receives uploads, which may take long over a weak connection
+pub fn is_upload_path(path: &str) -> bool {
+    path == UPLOAD_PATH || path == INVITED_UPLOAD_PATH
+}
+
 #[derive(TryFromMultipart)]
 pub struct UploadImageRequest {
     #[form_data(limit = "unlimited")]
     image: FieldData<NamedTempFile>,
+    /// shown on the kiosks, required with `--upload-code-period` unless the uploader has a pass
+    code: Option<String>,
+}
+
+pub type UploadState = (
+    Arc<SharedConfiguration>,
+    Arc<Indexer>,
+    Arc<SourceRecords>,
+    Arc<ProcessingQueue>,
+    Arc<Devices>,
+);
+
+pub type InvitedUploadState = (
+    Arc<SharedConfiguration>,
+    Arc<Indexer>,
+    Arc<SourceRecords>,
+    Arc<ProcessingQueue>,
+    Arc<Invites>,
+);
+
+#[derive(Deserialize)]
+pub struct InviteParameters {
+    invite: String,
 }
 
+/// Stores an uploaded image. With `--upload-code-period` guests have to include the code shown
+/// on the kiosks, which gets them a pass cookie for further uploads, unless they upload from a
+/// registered device.
 pub async fn upload_image(
-    State((configuration, indexer)): State<(Arc<Configuration>, Arc<Indexer>)>,
-    TypedMultipart(UploadImageRequest { image }): TypedMultipart<UploadImageRequest>,
+    State((configuration, indexer, sources, queue, devices)): State<UploadState>,
+    Extension(Authenticated(role)): Extension<Authenticated>,
+    device: Option<Extension<AuthenticatedDevice>>,
+    headers: HeaderMap,
+    TypedMultipart(UploadImageRequest { image, code }): TypedMultipart<UploadImageRequest>,
+) -> Result<Response, UploadError> {
+    let configuration = configuration.load();
+    let pass = match &device {
+        // the device proved to be at the event when it was registered
+        Some(_) => None,
+        None => authorize_upload(&configuration, role, &headers, code.as_deref())?,
+    };
+    let uploader = device.as_ref().map(|Extension(device)| device.to_string());
+    receive_upload(
+        &configuration,
+        &indexer,
+        &sources,
+        &queue,
+        image,
+        uploader.as_deref(),
+    )
+    .await?;
+    if let Some(Extension(device)) = device {
+        devices.record_upload(&device.id);
+        if let Err(error) = devices.save().await {
+            warn!("failed to save the upload of device {}: {error}", device.id);
+        }
+    }
+    Ok(match pass {
+        Some(pass) => {
+            let cookie = format!(
+                "{UPLOAD_PASS_COOKIE}={pass}; Max-Age={}; Path={}/; HttpOnly; SameSite=Strict",
+                UPLOAD_PASS_LIFETIME.as_secs(),
+                configuration.base_path
+            );
+            [(header::SET_COOKIE, cookie)].into_response()
+        }
+        None => ().into_response(),
+    })
+}
+
+/// Stores an image uploaded with `?invite=` instead of a secret, taking one use of the invite.
+/// Failed uploads give it back, so a duplicate does not use up a relative's only upload.
+pub async fn upload_invited_image(
+    State((configuration, indexer, sources, queue, invites)): State<InvitedUploadState>,
+    Query(InviteParameters { invite }): Query<InviteParameters>,
+    TypedMultipart(UploadImageRequest { image, .. }): TypedMultipart<UploadImageRequest>,
+) -> Result<(), UploadError> {
+    let configuration = configuration.load();
+    let id = invites.consume(&invite).ok_or(UploadError::InvalidInvite)?;
+    let uploader = format!("invite {id}");
+    let result = receive_upload(
+        &configuration,
+        &indexer,
+        &sources,
+        &queue,
+        image,
+        Some(&uploader),
+    )
+    .await;
+    if result.is_err() {
+        invites.refund(&id);
+    }
+    if let Err(error) = invites.save().await {
+        warn!("failed to save the use of invite {id}: {error}");
+    }
+    result
+}
+
+/// Stores an uploaded image under the time of upload and its file name, logging the outcome
+/// with the device or invite it was uploaded with
+async fn receive_upload(
+    configuration: &Configuration,
+    indexer: &Indexer,
+    sources: &SourceRecords,
+    queue: &ProcessingQueue,
+    image: FieldData<NamedTempFile>,
+    uploader: Option<&str>,
 ) -> Result<(), UploadError> {
+    let now = OffsetDateTime::now_utc();
+    let file_name = stored_file_name(now, image.metadata.file_name.as_deref());
+    let start = Instant::now();
+    let result = store_upload(
+        configuration,
+        indexer,
+        sources,
+        queue,
+        image.contents.path(),
+        &file_name,
+        now,
+    )
+    .await;
+    let duration_ms = start.elapsed().as_millis() as u64;
+    // quoting escapes control characters clients may have put into file names
+    match &result {
+        Ok(hash) => info!(
+            image = file_name.as_str(),
+            hash = hex_hash::to_string(hash).as_str(),
+            uploader,
+            duration_ms;
+            "stored upload"
+        ),
+        Err(error) => warn!(
+            image = file_name.as_str(),
+            uploader,
+            duration_ms;
+            "rejected upload: {error}"
+        ),
+    }
+    result.map(|_| ())
+}
+
+/// Checks the upload code or pass if codes are required, admins need neither. Returns a new
+/// pass for an uploader who entered a correct code.
+pub fn authorize_upload(
+    configuration: &Configuration,
+    role: Role,
+    headers: &HeaderMap,
+    code: Option<&str>,
+) -> Result<Option<String>, UploadError> {
+    let Some(codes) = &configuration.upload_codes else {
+        return Ok(None);
+    };
+    let now = SystemTime::now();
+    let has_pass = headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .any(|(name, value)| name == UPLOAD_PASS_COOKIE && codes.check_pass(value, now));
+    if role == Role::Admin || has_pass {
+        return Ok(None);
+    }
+    match code {
+        Some(code) if codes.verify(code, now) => Ok(Some(codes.issue_pass(now))),
+        _ => Err(UploadError::CodeRequired),
+    }
+}
+
+/// Name in storage of an image uploaded or imported `now`, the last part of its original name
+/// prefixed with the time so names rarely collide. Clients choose the original name, so
+/// directories in it, with either separator, and `.` or `..` are dropped.
+pub fn stored_file_name(now: OffsetDateTime, original: Option<&str>) -> String {
     let format = parse("[year][month][day]T[hour][minute][second]Z").unwrap();
-    let timestamp = OffsetDateTime::now_utc().format(&format).unwrap();
-    let file_name = image
-        .metadata
-        .file_name
+    let timestamp = now.format(&format).unwrap();
+    original
+        .and_then(|file_name| file_name.rsplit(['/', '\\']).next())
+        .filter(|file_name| !matches!(*file_name, "" | "." | ".."))
         .map(|file_name| format!("{timestamp}_{file_name}"))
-        .unwrap_or(timestamp);
-    let storage_path = configuration.storage.join(&file_name);
-    let cache_path = configuration.cache.join(&file_name);
-    let uploaded_image = &image.contents.path();
-
-    cache_image(
-        &uploaded_image,
-        &cache_path,
-        configuration.max_cached_image_size,
-        configuration.jpeg_image_quality,
+        .unwrap_or(timestamp)
+}
+
+/// Generates the derivatives of `uploaded_image`, copies it into storage as `file_name` and
+/// indexes it last, so kiosks never learn of an image that failed to be stored. A duplicate is
+/// removed from storage and the cache again.
+pub async fn store_upload(
+    configuration: &Configuration,
+    indexer: &Indexer,
+    sources: &SourceRecords,
+    queue: &ProcessingQueue,
+    uploaded_image: &Path,
+    file_name: &str,
+    now: OffsetDateTime,
+) -> Result<ImageHash, UploadError> {
+    // all sizes are generated before acknowledging so clients never request a missing derivative
+    let placeholder = cache_image(
+        uploaded_image,
+        &configuration.cache,
+        &configuration.derivatives(Path::new(file_name)),
+        &configuration.processing_options(),
+        queue,
+        true,
     )
     .await?;
+    let (hash, capture) = inspect_file(uploaded_image).await?;
+
+    let path = Path::new(file_name);
+    let originals = &configuration.originals;
+    originals.write(path, read(uploaded_image).await?).await?;
+    // recorded before indexing, so the watcher recognizes the file as this upload
+    sources.record(path, originals.stat(path).await?, hash);
+    if let Some(placeholder) = placeholder.clone() {
+        sources.set_placeholder(path, placeholder);
+    }
 
-    let hash = hash_file(uploaded_image).await?;
-    indexer
-        .add_image(
-            hash,
-            Image {
-                path: PathBuf::from(file_name),
-            },
-        )
-        .await?;
-
-    copy(uploaded_image, &storage_path)
-        .await
-        .map_err(ImageError::from)?;
-    Ok(())
+    let mut indexed_image = Image::new(hash, path.to_path_buf(), now, capture);
+    indexed_image.placeholder = placeholder;
+    match indexer.add_image(hash, indexed_image).await {
+        // the same content uploaded under the same name in the same second
+        Err(IndexError::Duplicate { path: existing }) if existing == path => {
+            return Err(IndexError::Duplicate { path: existing }.into())
+        }
+        Err(error) => {
+            sources.forget(path);
+            if let Err(error) = originals.delete(path).await {
+                warn!("failed to remove {} from storage: {error}", path.display());
+            }
+            if let Err(error) = remove_derivatives(&configuration.all_derivatives(path)).await {
+                warn!(
+                    "failed to remove derivatives of {}: {error}",
+                    path.display()
+                );
+            }
+            return Err(error.into());
+        }
+        Ok(()) => {}
+    }
+    sources.save().await?;
+    Ok(hash)
 }
 
 #[derive(Debug, Error)]
 pub enum UploadError {
+    #[error("upload code missing or wrong, enter the one shown on the screens")]
+    CodeRequired,
+    #[error("invite unknown, used up or expired")]
+    InvalidInvite,
     #[error(transparent)]
-    Image(#[from] ImageError),
+    Cache(#[from] CacheError),
     #[error(transparent)]
     Io(#[from] std::io::Error),
     #[error(transparent)]
@@ -75,6 +303,13 @@ pub enum UploadError {
 
 impl IntoResponse for UploadError {
     fn into_response(self) -> Response {
-        (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response()
+        let status = match &self {
+            UploadError::CodeRequired | UploadError::InvalidInvite => StatusCode::FORBIDDEN,
+            UploadError::Index(IndexError::Duplicate { .. }) => StatusCode::CONFLICT,
+            UploadError::Cache(error) if error.is_permanent() => StatusCode::UNPROCESSABLE_ENTITY,
+            UploadError::Index(IndexError::Gone(_)) => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, self.to_string()).into_response()
     }
 }
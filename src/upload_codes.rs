@@ -0,0 +1,305 @@
+use std::{
+    fmt::{self, Debug, Formatter},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+use sha1::{Digest, Sha1};
+use tokio::{
+    fs::{create_dir_all, read},
+    io,
+};
+
+use crate::{auth::constant_time_eq, cache::write_atomically};
+
+/// Digits of an upload code
+const CODE_DIGITS: u32 = 6;
+
+/// How long guests who entered a code once may upload without entering another one
+pub const UPLOAD_PASS_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Cookie carrying the upload pass
+pub const UPLOAD_PASS_COOKIE: &str = "moments_upload_pass";
+
+/// Length of the blocks SHA-1 hashes, which HMAC pads its key to
+const BLOCK_SIZE: usize = 64;
+
+/// Random bytes of the key of the upload codes, the length of a SHA-1 digest
+const KEY_LENGTH: usize = 20;
+
+/// Short codes changing every `period`, shown on the kiosks and required for uploads, so only
+/// guests at the event can upload even if the secret leaked. Codes are TOTP (RFC 6238) with
+/// a random [`UploadCodeKey`] kept in the cache, so they survive restarts.
+#[derive(Clone, PartialEq, Eq)]
+pub struct UploadCodes {
+    key: [u8; KEY_LENGTH],
+    period: Duration,
+}
+
+impl UploadCodes {
+    pub fn new(key: UploadCodeKey, period: Duration) -> Self {
+        Self { key: key.0, period }
+    }
+
+    /// The code at `now` and until when it is shown
+    pub fn current(&self, now: SystemTime) -> (String, SystemTime) {
+        let step = self.step(now);
+        let valid_until = UNIX_EPOCH + self.period * (step + 1) as u32;
+        (self.code(step), valid_until)
+    }
+
+    /// Whether `code` is the one at `now` or a step before or after, for clocks that differ a
+    /// little and guests typing while it changes
+    pub fn verify(&self, code: &str, now: SystemTime) -> bool {
+        let step = self.step(now);
+        [step.saturating_sub(1), step, step + 1]
+            .into_iter()
+            .any(|step| constant_time_eq(code.trim().as_bytes(), self.code(step).as_bytes()))
+    }
+
+    /// A pass letting the holder upload for [`UPLOAD_PASS_LIFETIME`] without a code
+    pub fn issue_pass(&self, now: SystemTime) -> String {
+        let expires = (now + UPLOAD_PASS_LIFETIME)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        format!("{expires}.{}", self.pass_signature(expires))
+    }
+
+    /// Whether `pass` was issued by [`Self::issue_pass`] with this key and did not expire
+    pub fn check_pass(&self, pass: &str, now: SystemTime) -> bool {
+        let Some((expires, signature)) = pass.split_once('.') else {
+            return false;
+        };
+        let Ok(expires) = expires.parse::<u64>() else {
+            return false;
+        };
+        let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        expires > now
+            && constant_time_eq(
+                signature.as_bytes(),
+                self.pass_signature(expires).as_bytes(),
+            )
+    }
+
+    fn step(&self, now: SystemTime) -> u64 {
+        now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / self.period.as_secs()
+    }
+
+    /// HOTP (RFC 4226) of the step
+    fn code(&self, step: u64) -> String {
+        let mac = hmac_sha1(&self.key, &step.to_be_bytes());
+        let offset = (mac[19] & 0x0f) as usize;
+        let truncated =
+            u32::from_be_bytes(mac[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+        format!(
+            "{:0width$}",
+            truncated % 10u32.pow(CODE_DIGITS),
+            width = CODE_DIGITS as usize
+        )
+    }
+
+    fn pass_signature(&self, expires: u64) -> String {
+        hmac_sha1(&self.key, format!("upload pass {expires}").as_bytes())
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+
+/// Leaves out the key, so reloads can log changes
+impl Debug for UploadCodes {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> fmt::Result {
+        formatter
+            .debug_struct("UploadCodes")
+            .field("period", &self.period)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Key of the upload codes, created once at random, so no secret, leaked or not, tells the codes
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct UploadCodeKey([u8; KEY_LENGTH]);
+
+impl UploadCodeKey {
+    /// Reads the key from `file`, creating it if there is none or it is corrupt
+    pub async fn load_or_create(file: &Path) -> Result<Self, io::Error> {
+        match read(file).await {
+            Ok(contents) => match contents.try_into() {
+                Ok(key) => return Ok(Self(key)),
+                Err(_) => warn!("replacing corrupt upload code key {}", file.display()),
+            },
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+            Err(error) => return Err(error),
+        }
+        let mut key = [0; KEY_LENGTH];
+        getrandom::getrandom(&mut key).map_err(|error| io::Error::other(error.to_string()))?;
+        if let Some(parent) = file.parent() {
+            create_dir_all(parent).await?;
+        }
+        write_atomically(file, key.to_vec()).await?;
+        Ok(Self(key))
+    }
+}
+
+/// HMAC (RFC 2104) with SHA-1, keys longer than a block are hashed first
+fn hmac_sha1(key: &[u8], message: &[u8]) -> [u8; 20] {
+    let mut padded = [0; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        padded[..20].copy_from_slice(&Sha1::digest(key));
+    } else {
+        padded[..key.len()].copy_from_slice(key);
+    }
+    let inner = Sha1::new()
+        .chain_update(padded.map(|byte| byte ^ 0x36))
+        .chain_update(message)
+        .finalize();
+    Sha1::new()
+        .chain_update(padded.map(|byte| byte ^ 0x5c))
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    /// The key of the test vectors of RFC 4226 and, for SHA-1, RFC 6238
+    fn rfc_codes() -> UploadCodes {
+        UploadCodes {
+            key: *b"12345678901234567890",
+            period: Duration::from_secs(30),
+        }
+    }
+
+    #[test]
+    fn hmac_sha1_matches_rfc_2202() {
+        let vectors: [(Vec<u8>, Vec<u8>, &str); 7] = [
+            (
+                vec![0x0b; 20],
+                b"Hi There".to_vec(),
+                "b617318655057264e28bc0b6fb378c8ef146be00",
+            ),
+            (
+                b"Jefe".to_vec(),
+                b"what do ya want for nothing?".to_vec(),
+                "effcdf6ae5eb2fa2d27416d5f184df9c259a7c79",
+            ),
+            (
+                vec![0xaa; 20],
+                vec![0xdd; 50],
+                "125d7342b9ac11cd91a39af48aa17b4f63f175d3",
+            ),
+            (
+                (1..=25).collect(),
+                vec![0xcd; 50],
+                "4c9007f4026250c6bc8414f9bf50c86c2d7235da",
+            ),
+            (
+                vec![0x0c; 20],
+                b"Test With Truncation".to_vec(),
+                "4c1a03424b55e07fe7f27be1d58bb9324a9a5a04",
+            ),
+            (
+                vec![0xaa; 80],
+                b"Test Using Larger Than Block-Size Key - Hash Key First".to_vec(),
+                "aa4ae5e15272d00e95705637ce8a3b55ed402112",
+            ),
+            (
+                vec![0xaa; 80],
+                b"Test Using Larger Than Block-Size Key and Larger Than One Block-Size Data"
+                    .to_vec(),
+                "e8e99d0f45237d786d6bbaa7965c7808bbff1a91",
+            ),
+        ];
+        for (key, message, digest) in vectors {
+            assert_eq!(hex(&hmac_sha1(&key, &message)), digest);
+        }
+    }
+
+    #[test]
+    fn codes_match_hotp_of_rfc_4226() {
+        let codes = rfc_codes();
+        let expected = [
+            "755224", "287082", "359152", "969429", "338314", "254676", "287922", "162583",
+            "399871", "520489",
+        ];
+        for (counter, code) in expected.into_iter().enumerate() {
+            assert_eq!(codes.code(counter as u64), code);
+        }
+    }
+
+    #[test]
+    fn codes_match_totp_of_rfc_6238() {
+        let codes = rfc_codes();
+        // the last six of the eight digits in the RFC
+        for (seconds, code) in [
+            (59, "287082"),
+            (1_111_111_109, "081804"),
+            (1_111_111_111, "050471"),
+            (1_234_567_890, "005924"),
+            (2_000_000_000, "279037"),
+            (20_000_000_000, "353130"),
+        ] {
+            let now = UNIX_EPOCH + Duration::from_secs(seconds);
+            assert_eq!(codes.current(now).0, code, "{seconds}");
+            assert!(codes.verify(code, now));
+        }
+    }
+
+    #[tokio::test]
+    async fn codes_are_derived_from_a_random_key_kept_in_the_cache() {
+        let directory = tempfile::tempdir().unwrap();
+        let file = directory.path().join(".moments").join("upload-code-key");
+        let key = UploadCodeKey::load_or_create(&file).await.unwrap();
+        assert!(UploadCodeKey::load_or_create(&file).await.unwrap() == key);
+
+        // another instance with the same secrets but its own cache
+        let other_directory = tempfile::tempdir().unwrap();
+        let other_key = UploadCodeKey::load_or_create(&other_directory.path().join("key"))
+            .await
+            .unwrap();
+        let period = Duration::from_secs(60);
+        let now = SystemTime::now();
+        let codes = UploadCodes::new(key, period);
+        let other_codes = UploadCodes::new(other_key, period);
+        let differing = (0..32)
+            .map(|step| now + period * step)
+            .filter(|&at| codes.current(at).0 != other_codes.current(at).0)
+            .count();
+        // codes of two keys collide with a chance of one in a million
+        assert!(differing >= 31, "{differing}");
+
+        std::fs::write(&file, b"too short").unwrap();
+        let replaced = UploadCodeKey::load_or_create(&file).await.unwrap();
+        assert!(replaced != key);
+        assert_eq!(std::fs::read(&file).unwrap(), replaced.0);
+    }
+
+    #[test]
+    fn codes_are_accepted_one_step_early_or_late() {
+        let codes = rfc_codes();
+        let now = UNIX_EPOCH + Duration::from_secs(1_111_111_111);
+        let (code, valid_until) = codes.current(now);
+        assert_eq!(valid_until, UNIX_EPOCH + Duration::from_secs(1_111_111_140));
+        // the code of the step starting at 1111111110, before the next step ends
+        let start = 1_111_111_110;
+        for (seconds, accepted) in [
+            (start - 31, false),
+            (start - 30, true),
+            (start + 59, true),
+            (start + 60, false),
+        ] {
+            let shifted = UNIX_EPOCH + Duration::from_secs(seconds);
+            assert_eq!(codes.verify(&code, shifted), accepted, "{seconds}");
+        }
+        assert!(codes.verify(&format!(" {code} "), now));
+        assert!(!codes.verify("000000", now));
+    }
+}
@@ -0,0 +1,295 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    io,
+    path::{absolute, Component, Path, PathBuf},
+};
+
+use thiserror::Error;
+use tokio::fs::create_dir_all;
+
+use crate::{health::check_writable, Arguments};
+
+/// Something wrong with the arguments that would only surface later as weird behavior
+#[derive(Debug, Error)]
+pub enum Problem {
+    #[error("--jpeg-image-quality must be between 1 and 100, got {0}")]
+    JpegImageQuality(u8),
+    #[error("--{0} must be greater than 0")]
+    Zero(&'static str),
+    #[error("--storage and --cache must be different directories, both are {}", .0.display())]
+    SameDirectory(PathBuf),
+    #[error(
+        "--cache {} is inside --storage {}, cached images would be indexed as uploads",
+        cache.display(),
+        storage.display()
+    )]
+    CacheInsideStorage { cache: PathBuf, storage: PathBuf },
+    #[error(
+        "--storage {} is inside --cache {}, originals would be removed as orphaned cache files",
+        storage.display(),
+        cache.display()
+    )]
+    StorageInsideCache { storage: PathBuf, cache: PathBuf },
+    #[error("{name} directory {} is not writable: {source}", path.display())]
+    Unwritable {
+        name: &'static str,
+        path: PathBuf,
+        source: io::Error,
+    },
+}
+
+/// All problems found, so they can be fixed at once instead of one restart each
+#[derive(Debug)]
+pub struct Problems(pub Vec<Problem>);
+
+impl Display for Problems {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        write!(formatter, "invalid configuration:")?;
+        for problem in &self.0 {
+            write!(formatter, "\n  - {problem}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Problems {}
+
+/// Checks value ranges and that storage and cache do not overlap
+pub fn check_arguments(arguments: &Arguments) -> Result<(), Problems> {
+    let mut problems = Vec::new();
+    if !(1..=100).contains(&arguments.jpeg_image_quality) {
+        problems.push(Problem::JpegImageQuality(arguments.jpeg_image_quality));
+    }
+    let zeros = [
+        (
+            "max-cached-image-size",
+            arguments.max_cached_image_size == 0,
+        ),
+        ("cache-sizes", arguments.cache_sizes.contains(&0)),
+        ("on-demand-sizes", arguments.on_demand_sizes.contains(&0)),
+        ("cache-workers", arguments.cache_workers == Some(0)),
+        ("processing-timeout", arguments.processing_timeout == 0),
+        (
+            "max-request-body-size",
+            arguments.max_request_body_size == 0,
+        ),
+    ];
+    for (name, is_zero) in zeros {
+        if is_zero {
+            problems.push(Problem::Zero(name));
+        }
+    }
+    let storage = resolve(&arguments.storage);
+    let cache = resolve(&arguments.cache);
+    if storage == cache {
+        problems.push(Problem::SameDirectory(storage));
+    } else if cache.starts_with(&storage) {
+        problems.push(Problem::CacheInsideStorage { cache, storage });
+    } else if storage.starts_with(&cache) {
+        problems.push(Problem::StorageInsideCache { storage, cache });
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(Problems(problems))
+    }
+}
+
+/// Creates storage and cache if they are missing and checks both are writable
+pub async fn check_directories(storage: &Path, cache: &Path) -> Result<(), Problems> {
+    let mut problems = Vec::new();
+    for (name, path) in [("storage", storage), ("cache", cache)] {
+        let result = match create_dir_all(path).await {
+            Ok(()) => check_writable(path.to_path_buf()).await,
+            Err(error) => Err(error),
+        };
+        if let Err(source) = result {
+            problems.push(Problem::Unwritable {
+                name,
+                path: path.to_path_buf(),
+                source,
+            });
+        }
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(Problems(problems))
+    }
+}
+
+/// Absolute path without `.` and `..`, with symbolic links resolved as far as it exists, so
+/// directories not created yet compare correctly to existing ones
+fn resolve(path: &Path) -> PathBuf {
+    let absolute = absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut normalized = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    for existing in normalized.ancestors() {
+        if let Ok(canonical) = existing.canonicalize() {
+            return canonical.join(normalized.strip_prefix(existing).unwrap());
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    /// Problems found in `arguments` after `--storage` and `--cache` in a fresh directory
+    fn problems(storage: &str, cache: &str, arguments: &[&str]) -> Vec<Problem> {
+        let directory = tempfile::tempdir().unwrap();
+        let storage = directory.path().join(storage);
+        let cache = directory.path().join(cache);
+        let arguments = Arguments::try_parse_from(
+            [
+                "moments".as_ref(),
+                "--storage".as_ref(),
+                storage.as_os_str(),
+            ]
+            .into_iter()
+            .chain(["--cache".as_ref(), cache.as_os_str()])
+            .chain(arguments.iter().map(|argument| argument.as_ref())),
+        )
+        .unwrap();
+        match check_arguments(&arguments) {
+            Ok(()) => Vec::new(),
+            Err(problems) => problems.0,
+        }
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert!(problems("storage", "cache", &[]).is_empty());
+    }
+
+    #[test]
+    fn jpeg_quality_of_zero_is_rejected() {
+        let problems = problems("storage", "cache", &["--jpeg-image-quality", "0"]);
+        assert!(matches!(problems[..], [Problem::JpegImageQuality(0)]));
+    }
+
+    #[test]
+    fn jpeg_quality_above_100_is_rejected() {
+        let problems = problems("storage", "cache", &["--jpeg-image-quality", "250"]);
+        assert!(matches!(problems[..], [Problem::JpegImageQuality(250)]));
+    }
+
+    #[test]
+    fn zero_sizes_counts_and_timeouts_are_rejected() {
+        for (flag, value) in [
+            ("max-cached-image-size", "0"),
+            ("cache-sizes", "200,0"),
+            ("on-demand-sizes", "0"),
+            ("cache-workers", "0"),
+            ("processing-timeout", "0"),
+            ("max-request-body-size", "0"),
+        ] {
+            let problems = problems("storage", "cache", &[&format!("--{flag}"), value]);
+            assert!(
+                matches!(problems[..], [Problem::Zero(name)] if name == flag),
+                "{flag}: {problems:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn configuring_reports_the_problems() {
+        let arguments = Arguments::try_parse_from([
+            "moments",
+            "--secret",
+            "secret",
+            "--jpeg-image-quality",
+            "0",
+        ])
+        .unwrap();
+        let Err(error) = crate::configure(arguments) else {
+            panic!("configured with invalid arguments");
+        };
+        assert!(format!("{error:#}").contains("--jpeg-image-quality must be between 1 and 100"));
+    }
+
+    #[test]
+    fn all_problems_are_reported_at_once() {
+        let problems = problems(
+            "storage",
+            "cache",
+            &["--jpeg-image-quality", "0", "--processing-timeout", "0"],
+        );
+        assert_eq!(problems.len(), 2);
+    }
+
+    #[test]
+    fn same_storage_and_cache_are_rejected() {
+        let problems = problems("images", "images/./", &[]);
+        assert!(matches!(problems[..], [Problem::SameDirectory(_)]));
+    }
+
+    #[test]
+    fn cache_inside_storage_is_rejected() {
+        let problems = problems("storage", "storage/cache", &[]);
+        assert!(matches!(problems[..], [Problem::CacheInsideStorage { .. }]));
+    }
+
+    #[test]
+    fn storage_inside_cache_is_rejected() {
+        let problems = problems("cache/../cache/storage", "cache", &[]);
+        assert!(matches!(problems[..], [Problem::StorageInsideCache { .. }]));
+    }
+
+    #[test]
+    fn directories_linked_into_each_other_are_rejected() {
+        let directory = tempfile::tempdir().unwrap();
+        let storage = directory.path().join("storage");
+        std::fs::create_dir(&storage).unwrap();
+        std::os::unix::fs::symlink(storage.join("cache"), directory.path().join("link")).unwrap();
+        std::fs::create_dir(storage.join("cache")).unwrap();
+        let arguments = Arguments::try_parse_from([
+            "moments".as_ref(),
+            "--storage".as_ref(),
+            storage.as_os_str(),
+            "--cache".as_ref(),
+            directory.path().join("link").as_os_str(),
+        ])
+        .unwrap();
+        let problems = check_arguments(&arguments).unwrap_err().0;
+        assert!(matches!(problems[..], [Problem::CacheInsideStorage { .. }]));
+    }
+
+    #[tokio::test]
+    async fn unwritable_directories_are_rejected() {
+        let directory = tempfile::tempdir().unwrap();
+        // also fails for root, unlike directories without write permission
+        let file = directory.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        let problems = check_directories(&file.join("storage"), &directory.path().join("cache"))
+            .await
+            .unwrap_err()
+            .0;
+        assert!(matches!(
+            problems[..],
+            [Problem::Unwritable {
+                name: "storage",
+                ..
+            }]
+        ));
+        let problems = check_directories(&directory.path().join("storage"), &file)
+            .await
+            .unwrap_err()
+            .0;
+        assert!(matches!(
+            problems[..],
+            [Problem::Unwritable { name: "cache", .. }]
+        ));
+    }
+}
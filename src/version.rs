@@ -0,0 +1,61 @@
+use std::fmt::{self, Display, Formatter};
+
+use axum::Json;
+use serde::Serialize;
+use time::{
+    format_description::well_known::{Rfc2822, Rfc3339},
+    OffsetDateTime,
+};
+
+mod built_info {
+    include!(concat!(env!("OUT_DIR"), "/built.rs"));
+}
+
+/// Which build is running, embedded by the build script
+#[derive(Clone, Debug, Serialize)]
+pub struct Version {
+    pub version: &'static str,
+    /// abbreviated hash, suffixed with `-dirty` for uncommitted changes, `unknown` outside of a
+    /// repository
+    pub commit: &'static str,
+    #[serde(with = "time::serde::rfc3339")]
+    pub built_at: OffsetDateTime,
+    pub features: Vec<String>,
+}
+
+impl Version {
+    pub fn current() -> Self {
+        let built_at = OffsetDateTime::parse(built_info::BUILT_TIME_UTC, &Rfc2822)
+            .unwrap_or(OffsetDateTime::UNIX_EPOCH);
+        Self {
+            version: built_info::PKG_VERSION,
+            commit: env!("MOMENTS_GIT_COMMIT"),
+            built_at,
+            // Cargo spells the features of `CARGO_FEATURE_*` with underscores
+            features: built_info::FEATURES_LOWERCASE
+                .iter()
+                .map(|feature| feature.replace('_', "-"))
+                .collect(),
+        }
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        write!(
+            formatter,
+            "moments {} (commit {}, built {}",
+            self.version,
+            self.commit,
+            self.built_at.format(&Rfc3339).map_err(|_| fmt::Error)?
+        )?;
+        if !self.features.is_empty() {
+            write!(formatter, ", features {}", self.features.join(","))?;
+        }
+        write!(formatter, ")")
+    }
+}
+
+pub async fn handle_version() -> Json<Version> {
+    Json(Version::current())
+}
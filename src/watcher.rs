@@ -1,2 +1,639 @@
+use std::{
+    collections::{BinaryHeap, HashMap, HashSet},
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
+use clap::ValueEnum;
+use log::{info, warn};
+use notify_debouncer_mini::{
+    new_debouncer,
+    notify::{self, RecommendedWatcher, RecursiveMode},
+    DebounceEventResult, Debouncer,
+};
+use serde::Serialize;
+use thiserror::Error;
+use tokio::{
+    fs::write,
+    io, select,
+    sync::mpsc,
+    task::JoinSet,
+    time::{interval, sleep, timeout},
+};
 
+use crate::{
+    cache::{
+        cache_stored_image, remove_derivatives, CacheError, CacheLocks, ProcessingQueue,
+        INTERNAL_DIRECTORY,
+    },
+    index::{inspect, Image, ImageHash, IndexError, Indexer, IndexerGone},
+    sidecars::{apply_sidecar, is_sidecar, original_of},
+    sources::{Fingerprint, SourceRecords},
+    storage::Storage,
+    Configuration,
+};
+
+/// Extensions of files that are still being written by common tools
+const TEMPORARY_EXTENSIONS: &[&str] = &["crdownload", "part", "partial", "swp", "tmp"];
+
+/// Hidden file written to storage to find out whether events arrive for it
+const PROBE_FILE: &str = ".moments-probe";
+
+/// How long after the settle time the event for the probe may take
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How changes in storage are noticed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum WatchMode {
+    /// file system events, or polling if none arrive, e.g. on FUSE mounts like SSHFS
+    Auto,
+    /// file system events only, inotify on Linux
+    Inotify,
+    /// listing storage every `poll_interval`
+    Poll,
+    /// files added while running are only indexed after a restart
+    Off,
+}
+
+/// Progress of the watcher through files that changed in storage, shared with the stats endpoint
+#[derive(Default)]
+pub struct WatchStatistics {
+    queued: AtomicUsize,
+    active: AtomicUsize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WatchProgress {
+    /// changed files waiting to be cached and indexed, newest first
+    pub queued: usize,
+    /// files being settled, cached and indexed
+    pub active: usize,
+}
+
+impl WatchStatistics {
+    fn update(&self, queued: usize, active: usize) {
+        self.queued.store(queued, Ordering::Relaxed);
+        self.active.store(active, Ordering::Relaxed);
+    }
+
+    pub fn progress(&self) -> WatchProgress {
+        WatchProgress {
+            queued: self.queued.load(Ordering::Relaxed),
+            active: self.active.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Caches and indexes files that appear in storage while running, e.g. copied by rsync, so they
+/// reach kiosks without a restart. Files deleted from storage are removed, files changing their
+/// content are replaced by a removal and an addition, and renames are a removal followed by an
+/// addition with the same hash.
+pub async fn watch_storage(
+    configuration: Arc<Configuration>,
+    indexer: Arc<Indexer>,
+    locks: Arc<CacheLocks>,
+    sources: Arc<SourceRecords>,
+    queue: Arc<ProcessingQueue>,
+    statistics: Arc<WatchStatistics>,
+) {
+    let mut mode = configuration.watch_mode;
+    if configuration.originals.local_directory().is_none() {
+        match mode {
+            WatchMode::Off | WatchMode::Poll => {}
+            WatchMode::Auto => mode = WatchMode::Poll,
+            WatchMode::Inotify => {
+                warn!("not watching storage, only local directories have events, poll instead");
+                return;
+            }
+        }
+    }
+    let (event_sender, mut events) = mpsc::unbounded_channel();
+    // events stop once the debouncer is dropped
+    let debouncer = match mode {
+        WatchMode::Off => {
+            info!("not watching storage, new images are indexed after a restart");
+            return;
+        }
+        WatchMode::Poll => None,
+        WatchMode::Auto | WatchMode::Inotify => {
+            match watch_events(&configuration, event_sender.clone()) {
+                Ok(debouncer) => Some(debouncer),
+                Err(error) if mode == WatchMode::Auto => {
+                    warn!("cannot watch storage, polling it instead: {error}");
+                    None
+                }
+                Err(error) => {
+                    warn!("not watching storage: {error}");
+                    return;
+                }
+            }
+        }
+    };
+    let debouncer = match debouncer {
+        Some(debouncer) if mode == WatchMode::Auto => {
+            match probe_events(&configuration, &mut events, &event_sender).await {
+                Ok(true) => Some(debouncer),
+                Ok(false) => {
+                    warn!("no events arrive for storage, e.g. on a network mount, polling it");
+                    None
+                }
+                Err(error) => {
+                    warn!("cannot check whether storage events arrive, relying on them: {error}");
+                    Some(debouncer)
+                }
+            }
+        }
+        debouncer => debouncer,
+    };
+    drop(event_sender);
+    let polling = debouncer.is_none();
+    let mut poll = interval(configuration.poll_interval);
+    if polling {
+        info!(
+            "listing storage every {}s for changes",
+            configuration.poll_interval.as_secs()
+        );
+    } else {
+        info!("watching storage for changes");
+    }
+
+    // hashes of the paths last seen in storage
+    let mut known = match known_paths(&indexer).await {
+        Ok(known) => known,
+        Err(error) => {
+            warn!("not watching storage: {error}");
+            return;
+        }
+    };
+    // fingerprints of undecodable files, retried once they change
+    let mut failed = HashMap::new();
+    // fingerprints of the sidecars last applied, with --sidecars
+    let mut sidecars = HashMap::new();
+    // changed files by modification time, the newest are most likely to be on screen soon
+    let mut backlog = BinaryHeap::new();
+    let mut waiting = HashSet::new();
+    // paths being processed and those that changed again meanwhile
+    let mut busy = HashSet::new();
+    let mut recheck = HashSet::new();
+    let mut tasks = JoinSet::new();
+    // also picks up files added between indexing and watching
+    let mut pending = list_storage(configuration.originals.as_ref()).await;
+    loop {
+        for path in pending.drain(..) {
+            if is_sidecar(&path) {
+                if configuration.sidecars {
+                    sync_sidecar(&configuration, &indexer, &sources, &mut sidecars, &path).await;
+                }
+                continue;
+            }
+            if is_ignored(&path) || waiting.contains(&path) {
+                continue;
+            }
+            if busy.contains(&path) {
+                recheck.insert(path);
+                continue;
+            }
+            // vanished again, its removal follows
+            let Ok(fingerprint) = configuration.originals.stat(&path).await else {
+                continue;
+            };
+            if failed.get(&path) == Some(&fingerprint) {
+                continue;
+            }
+            // unchanged since it was cached, or recorded by an upload before indexing it
+            if let Some(hash) = sources.matching_hash(&path, fingerprint) {
+                known.insert(path, hash);
+                continue;
+            }
+            waiting.insert(path.clone());
+            backlog.push((fingerprint.modified, path));
+        }
+        // derivatives are generated by the processing queue, more tasks would only make the
+        // newest files wait behind older ones
+        while tasks.len() < configuration.cache_workers {
+            let Some((_, path)) = backlog.pop() else {
+                break;
+            };
+            // removed from storage while waiting
+            if !waiting.remove(&path) {
+                continue;
+            }
+            busy.insert(path.clone());
+            let previous = known.get(&path).copied();
+            let configuration = configuration.clone();
+            let indexer = indexer.clone();
+            let locks = locks.clone();
+            let sources = sources.clone();
+            let queue = queue.clone();
+            tasks.spawn(async move {
+                let result = sync_file(
+                    &configuration,
+                    &indexer,
+                    &locks,
+                    &sources,
+                    &queue,
+                    &path,
+                    previous,
+                )
+                .await;
+                (path, result)
+            });
+        }
+        statistics.update(waiting.len(), tasks.len());
+        select! {
+            Some(path) = events.recv() => {
+                // notify reports paths below the watched directory, storage is flat
+                if let Some(file_name) = path.file_name() {
+                    let path = PathBuf::from(file_name);
+                    if configuration.originals.exists(&path).await.unwrap_or(false) {
+                        pending.push(path);
+                    } else {
+                        sidecars.remove(&path);
+                        failed.remove(&path);
+                        waiting.remove(&path);
+                        if known.remove(&path).is_some() {
+                            // before the settle time of a renamed file's addition elapses
+                            remove_deleted(&configuration, &indexer, &locks, &sources, &path)
+                                .await;
+                        }
+                    }
+                }
+            }
+            Some(task) = tasks.join_next() => {
+                let (path, result) = task.unwrap();
+                busy.remove(&path);
+                match result {
+                    Ok(hash) => {
+                        known.insert(path.clone(), hash);
+                    }
+                    Err(WatchError::Cache(error)) if error.is_permanent() => {
+                        warn!("not indexing {} from storage: {error}", path.display());
+                        known.remove(&path);
+                        if let Ok(fingerprint) = configuration.originals.stat(&path).await {
+                            failed.insert(path.clone(), fingerprint);
+                        }
+                    }
+                    Err(error) => {
+                        // retried with the next event or listing
+                        warn!("failed to index {} from storage: {error}", path.display());
+                    }
+                }
+                if recheck.remove(&path) {
+                    pending.push(path);
+                }
+            }
+            _ = poll.tick(), if polling => {
+                let listed = list_storage(configuration.originals.as_ref()).await;
+                let existing: HashSet<_> = listed.iter().cloned().collect();
+                failed.retain(|path, _| existing.contains(path));
+                waiting.retain(|path| existing.contains(path));
+                let vanished: Vec<_> = known
+                    .keys()
+                    .filter(|path| !existing.contains(*path))
+                    .cloned()
+                    .collect();
+                for path in vanished {
+                    known.remove(&path);
+                    remove_deleted(&configuration, &indexer, &locks, &sources, &path).await;
+                }
+                // unchanged files only cost a lookup of their fingerprint
+                pending = listed;
+            }
+        }
+    }
+}
+
+fn watch_events(
+    configuration: &Configuration,
+    sender: mpsc::UnboundedSender<PathBuf>,
+) -> Result<Debouncer<RecommendedWatcher>, notify::Error> {
+    let mut debouncer = new_debouncer(
+        configuration.settle_time,
+        move |result: DebounceEventResult| match result {
+            Ok(batch) => {
+                for event in batch {
+                    let _ = sender.send(event.path);
+                }
+            }
+            Err(error) => warn!("failed to watch storage: {error}"),
+        },
+    )?;
+    debouncer
+        .watcher()
+        .watch(&configuration.storage, RecursiveMode::NonRecursive)?;
+    Ok(debouncer)
+}
+
+/// Writes a probe file to storage and waits for its event, FUSE mounts accept watches but
+/// never report changes. Other events arriving meanwhile are queued again. Read-only galleries
+/// write nothing to storage and rely on events.
+async fn probe_events(
+    configuration: &Configuration,
+    events: &mut mpsc::UnboundedReceiver<PathBuf>,
+    sender: &mpsc::UnboundedSender<PathBuf>,
+) -> Result<bool, io::Error> {
+    if configuration.read_only {
+        return Ok(true);
+    }
+    let probe = ProbeFile(configuration.storage.join(PROBE_FILE));
+    write(&probe.0, []).await?;
+    let mut others = Vec::new();
+    let arrived = timeout(configuration.settle_time + PROBE_TIMEOUT, async {
+        while let Some(path) = events.recv().await {
+            if path.file_name() == Some(OsStr::new(PROBE_FILE)) {
+                return;
+            }
+            others.push(path);
+        }
+    })
+    .await
+    .is_ok();
+    drop(probe);
+    for path in others {
+        let _ = sender.send(path);
+    }
+    Ok(arrived)
+}
+
+/// Removes the probe file when dropped, also if probing failed or was cancelled
+struct ProbeFile(PathBuf);
+
+impl Drop for ProbeFile {
+    fn drop(&mut self) {
+        match std::fs::remove_file(&self.0) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => {
+                warn!("failed to remove {}: {error}", self.0.display())
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Hashes of all indexed images by path, including aliases
+async fn known_paths(indexer: &Indexer) -> Result<HashMap<PathBuf, ImageHash>, IndexerGone> {
+    Ok(indexer
+        .index_including_hidden()
+        .await?
+        .into_iter()
+        .flat_map(|image| {
+            let hash = image.hash;
+            [image.path]
+                .into_iter()
+                .chain(image.aliases)
+                .map(move |path| (path, hash))
+        })
+        .collect())
+}
+
+/// Files in storage, relative to it
+async fn list_storage(storage: &dyn Storage) -> Vec<PathBuf> {
+    match storage.list().await {
+        Ok(files) => files.into_iter().map(|(path, _)| path).collect(),
+        Err(error) => {
+            warn!("failed to list storage: {error}");
+            Vec::new()
+        }
+    }
+}
+
+/// Hidden and temporary files, e.g. those rsync writes before renaming them into place
+fn is_ignored(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return true;
+    };
+    name.starts_with('.')
+        || name.ends_with('~')
+        || name == INTERNAL_DIRECTORY
+        || path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| {
+                TEMPORARY_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str())
+            })
+}
+
+/// Indexes a new or changed file once it settled, returns the hash of its content
+async fn sync_file(
+    configuration: &Configuration,
+    indexer: &Indexer,
+    locks: &Arc<CacheLocks>,
+    sources: &SourceRecords,
+    queue: &ProcessingQueue,
+    path: &Path,
+    previous: Option<ImageHash>,
+) -> Result<ImageHash, WatchError> {
+    let originals = configuration.originals.as_ref();
+    let fingerprint = originals.stat(path).await?;
+    let fingerprint =
+        settled_fingerprint(originals, path, fingerprint, configuration.settle_time).await?;
+    // written by an upload, which records it before indexing
+    if let Some(hash) = sources.matching_hash(path, fingerprint) {
+        return Ok(hash);
+    }
+
+    let (hash, capture) = inspect(originals.read(path).await?).await;
+    if let Some(previous) = previous {
+        if previous == hash {
+            // only touched, e.g. copied again with the same content
+            sources.touch(path, fingerprint, hash);
+            sources.save().await?;
+            return Ok(hash);
+        }
+        info!("{} changed in storage, replacing it", path.display());
+        remove_file(configuration, indexer, locks, sources, path).await?;
+    }
+    let placeholder = {
+        let _guard = locks.lock(path).await;
+        cache_stored_image(
+            originals,
+            path,
+            &configuration.cache,
+            &configuration.derivatives(path),
+            &configuration.processing_options(),
+            queue,
+            true,
+        )
+        .await?
+    };
+    let mut image = Image::new(hash, path.to_path_buf(), fingerprint.modified, capture);
+    image.placeholder = placeholder.clone();
+    match indexer.add_image(hash, image).await {
+        Err(IndexError::Duplicate { path: existing }) => {
+            info!(
+                "{} in storage has the same content as {}, indexing it as alias",
+                path.display(),
+                existing.display()
+            );
+            indexer
+                .add_alias(hash, path.to_path_buf())
+                .await
+                .map_err(IndexError::from)?;
+        }
+        result => {
+            result?;
+            info!("indexed {} from storage", path.display());
+        }
+    }
+    sources.record(path, fingerprint, hash);
+    if let Some(placeholder) = placeholder {
+        sources.set_placeholder(path, placeholder);
+    }
+    sources.save().await?;
+    // the sidecar may have been copied into storage before its original
+    if configuration.sidecars {
+        apply_sidecar(configuration, indexer, sources, path).await?;
+    }
+    Ok(hash)
+}
+
+/// Applies a sidecar that appeared or changed since it was last seen
+async fn sync_sidecar(
+    configuration: &Configuration,
+    indexer: &Indexer,
+    sources: &SourceRecords,
+    seen: &mut HashMap<PathBuf, Fingerprint>,
+    path: &Path,
+) {
+    let Ok(fingerprint) = configuration.originals.stat(path).await else {
+        seen.remove(path);
+        return;
+    };
+    if seen.insert(path.to_path_buf(), fingerprint) == Some(fingerprint) {
+        return;
+    }
+    let Some(original) = original_of(path) else {
+        return;
+    };
+    if let Err(error) = apply_sidecar(configuration, indexer, sources, &original).await {
+        warn!("failed to apply sidecar {}: {error}", path.display());
+    }
+}
+
+async fn remove_deleted(
+    configuration: &Configuration,
+    indexer: &Indexer,
+    locks: &Arc<CacheLocks>,
+    sources: &SourceRecords,
+    path: &Path,
+) {
+    match remove_file(configuration, indexer, locks, sources, path).await {
+        Ok(true) => info!("removed {} deleted from storage", path.display()),
+        Ok(false) => {}
+        Err(error) => warn!(
+            "failed to remove {} deleted from storage: {error}",
+            path.display()
+        ),
+    }
+}
+
+/// Removes a path from the index and its derivatives from the cache, returns whether an image
+/// was removed rather than an alias
+pub async fn remove_file(
+    configuration: &Configuration,
+    indexer: &Indexer,
+    locks: &Arc<CacheLocks>,
+    sources: &SourceRecords,
+    path: &Path,
+) -> Result<bool, WatchError> {
+    let removed = indexer
+        .remove_path(path.to_path_buf())
+        .await
+        .map_err(IndexError::from)?;
+    {
+        let _guard = locks.lock(path).await;
+        remove_derivatives(&configuration.all_derivatives(path)).await?;
+    }
+    sources.forget(path);
+    // with a remaining alias the image stays, and with it what was recorded about it
+    if let Some(removed) = removed.as_ref().filter(|image| image.aliases.is_empty()) {
+        sources.forget_image(removed.hash);
+    }
+    sources.save().await?;
+    Ok(removed.is_some())
+}
+
+/// Waits until the file kept its fingerprint for `settle_time`, writers may still be copying it
+async fn settled_fingerprint(
+    storage: &dyn Storage,
+    path: &Path,
+    mut fingerprint: Fingerprint,
+    settle_time: Duration,
+) -> Result<Fingerprint, io::Error> {
+    loop {
+        sleep(settle_time).await;
+        let current = storage.stat(path).await?;
+        if current == fingerprint {
+            return Ok(current);
+        }
+        fingerprint = current;
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum WatchError {
+    #[error(transparent)]
+    Cache(#[from] CacheError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Index(#[from] IndexError),
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::{configure, Arguments};
+
+    fn configuration(storage: &Path, arguments: &[&str]) -> Configuration {
+        let arguments = Arguments::try_parse_from(
+            ["moments".as_ref(), "--secret".as_ref(), "secret".as_ref()]
+                .into_iter()
+                .chain(["--storage".as_ref(), storage.as_os_str()])
+                .chain(arguments.iter().map(|argument| argument.as_ref())),
+        )
+        .unwrap();
+        configure(arguments).unwrap()
+    }
+
+    #[tokio::test]
+    async fn probe_file_is_removed_when_probing_is_cancelled() {
+        let storage = tempfile::tempdir().unwrap();
+        let configuration = configuration(storage.path(), &[]);
+        let (sender, mut events) = mpsc::unbounded_channel();
+        let probing = probe_events(&configuration, &mut events, &sender);
+        assert!(timeout(Duration::from_millis(100), probing).await.is_err());
+        assert!(!storage.path().join(PROBE_FILE).exists());
+    }
+
+    #[tokio::test]
+    async fn probe_file_is_removed_after_its_event() {
+        let storage = tempfile::tempdir().unwrap();
+        let configuration = configuration(storage.path(), &[]);
+        let (sender, mut events) = mpsc::unbounded_channel();
+        sender.send(PathBuf::from("other.png")).unwrap();
+        sender.send(storage.path().join(PROBE_FILE)).unwrap();
+        let arrived = probe_events(&configuration, &mut events, &sender).await;
+        assert!(arrived.unwrap());
+        assert!(!storage.path().join(PROBE_FILE).exists());
+        // queued again for the watcher
+        assert_eq!(events.try_recv().unwrap(), Path::new("other.png"));
+    }
+
+    #[tokio::test]
+    async fn read_only_storage_is_not_probed() {
+        let storage = tempfile::tempdir().unwrap();
+        let configuration = configuration(storage.path(), &["--read-only"]);
+        let (sender, mut events) = mpsc::unbounded_channel();
+        let probing = probe_events(&configuration, &mut events, &sender);
+        let arrived = timeout(Duration::from_millis(100), probing).await.unwrap();
+        assert!(arrived.unwrap());
+        assert_eq!(std::fs::read_dir(storage.path()).unwrap().count(), 0);
+    }
+}
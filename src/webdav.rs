@@ -0,0 +1,322 @@
+use std::{
+    ops::Range,
+    path::{Component, Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures_util::{stream, StreamExt};
+use highway::HighwayHash;
+use log::warn;
+use percent_encoding::{percent_decode_str, utf8_percent_encode};
+use time::OffsetDateTime;
+use tokio::io;
+
+use crate::{
+    http_client::{self, retrying, Answer},
+    http_url::{HttpUrl, PATH_SEGMENT},
+    index::{content_hasher, ImageHash},
+    sources::Fingerprint,
+    storage::{ByteStream, Storage},
+    xml::{elements, text},
+};
+
+/// Asks for only the properties a [`Fingerprint`] is made of
+const PROPFIND: &str = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+    <d:propfind xmlns:d=\"DAV:\"><d:prop>\
+    <d:resourcetype/><d:getcontentlength/><d:getlastmodified/>\
+    </d:prop></d:propfind>";
+
+/// Files directly in a WebDAV collection, e.g. a Nextcloud share, reached over plain HTTP on
+/// this machine without FUSE, remote shares through a TLS tunnel. There is no local directory to
+/// watch, so it is always polled for changes.
+pub struct WebDavStorage {
+    url: HttpUrl,
+    authorization: Option<String>,
+}
+
+impl WebDavStorage {
+    /// Authenticates with HTTP basic authentication if a `user` is given
+    pub fn new(url: HttpUrl, user: Option<&str>, password: Option<&str>) -> Self {
+        let authorization = user.map(|user| {
+            let credentials = format!("{user}:{}", password.unwrap_or_default());
+            format!("Basic {}", STANDARD.encode(credentials))
+        });
+        Self { url, authorization }
+    }
+
+    /// Request target of a file, refusing paths leaving the collection or reaching into
+    /// subcollections like [`crate::LocalStorage`] does
+    fn target(&self, path: &Path) -> io::Result<String> {
+        match path.components().collect::<Vec<_>>()[..] {
+            [Component::Normal(name)] => Ok(format!(
+                "{}/{}",
+                self.url.base_path,
+                utf8_percent_encode(&name.to_string_lossy(), PATH_SEGMENT)
+            )),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a file name in storage", path.display()),
+            )),
+        }
+    }
+
+    /// The file name an `href` of a PROPFIND answer refers to, [`None`] for the collection
+    /// itself and anything not directly in it
+    fn file_name(&self, href: &str) -> Option<PathBuf> {
+        // either an absolute path or a full URL
+        let path = match href.split_once("://") {
+            Some((_, rest)) => rest.find('/').map_or("/", |start| &rest[start..]),
+            None => href,
+        };
+        let path = percent_decode_str(path).decode_utf8_lossy();
+        let base_path = percent_decode_str(&self.url.base_path).decode_utf8_lossy();
+        let name = path.strip_prefix(base_path.as_ref())?.trim_matches('/');
+        if name.is_empty() || name.contains('/') {
+            return None;
+        }
+        Some(PathBuf::from(name))
+    }
+
+    /// Sends a single request, authenticated if a user was given
+    async fn send(
+        &self,
+        method: &str,
+        target: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> io::Result<Answer> {
+        let mut headers = headers.to_vec();
+        if let Some(authorization) = &self.authorization {
+            headers.push(("Authorization", authorization));
+        }
+        http_client::send(&self.url, method, target, &headers, body).await
+    }
+}
+
+#[async_trait]
+impl Storage for WebDavStorage {
+    async fn list(&self) -> io::Result<Vec<(PathBuf, Fingerprint)>> {
+        let target = format!("{}/", self.url.base_path);
+        let responses = retrying("list storage on WebDAV", || async {
+            let headers = [
+                ("Depth", "1"),
+                ("Content-Type", "application/xml; charset=utf-8"),
+            ];
+            let answer = self
+                .send("PROPFIND", &target, &headers, PROPFIND.as_bytes())
+                .await?
+                .expect(&[207])?;
+            Ok(parse_multistatus(&answer.text().await?))
+        })
+        .await?;
+        let mut files = Vec::new();
+        for response in responses {
+            let Some(path) = self.file_name(&response.href) else {
+                continue;
+            };
+            if response.collection {
+                continue;
+            }
+            match response.fingerprint() {
+                Some(fingerprint) => files.push((path, fingerprint)),
+                None => warn!(
+                    "WebDAV server lists {} without size or date",
+                    path.display()
+                ),
+            }
+        }
+        Ok(files)
+    }
+
+    async fn stat(&self, path: &Path) -> io::Result<Fingerprint> {
+        let target = self.target(path)?;
+        let responses = retrying("stat a file on WebDAV", || async {
+            let headers = [
+                ("Depth", "0"),
+                ("Content-Type", "application/xml; charset=utf-8"),
+            ];
+            let answer = self
+                .send("PROPFIND", &target, &headers, PROPFIND.as_bytes())
+                .await?
+                .expect(&[207])?;
+            Ok(parse_multistatus(&answer.text().await?))
+        })
+        .await?;
+        let response = responses
+            .into_iter()
+            .find(|response| !response.collection)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} is not a file", path.display()),
+                )
+            })?;
+        response.fingerprint().ok_or_else(|| {
+            io::Error::other(format!(
+                "WebDAV server has no size or date of {}",
+                path.display()
+            ))
+        })
+    }
+
+    async fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let target = self.target(path)?;
+        retrying("read a file on WebDAV", || async {
+            let answer = self.send("GET", &target, &[], &[]).await?.expect(&[200])?;
+            answer.bytes().await
+        })
+        .await
+    }
+
+    async fn read_range(&self, path: &Path, range: Range<u64>) -> io::Result<ByteStream> {
+        if range.is_empty() {
+            return Ok(stream::empty().boxed());
+        }
+        let target = self.target(path)?;
+        let bytes = format!("bytes={}-{}", range.start, range.end - 1);
+        let answer = retrying("read a file on WebDAV", || async {
+            self.send("GET", &target, &[("Range", &bytes)], &[])
+                .await?
+                .expect(&[200, 206])
+        })
+        .await?;
+        Ok(answer.range(range))
+    }
+
+    async fn hash(&self, path: &Path) -> io::Result<ImageHash> {
+        let target = self.target(path)?;
+        retrying("hash a file on WebDAV", || async {
+            let mut answer = self.send("GET", &target, &[], &[]).await?.expect(&[200])?;
+            let mut hasher = content_hasher();
+            while let Some(part) = answer.part().await? {
+                hasher.append(&part);
+            }
+            Ok(hasher.finalize128())
+        })
+        .await
+    }
+
+    /// Nextcloud and most other servers write into a temporary file and move it into place
+    /// once the upload is complete, so readers never observe it partially written
+    async fn write(&self, path: &Path, contents: Vec<u8>) -> io::Result<()> {
+        let target = self.target(path)?;
+        retrying("write a file on WebDAV", || async {
+            let headers = [("Content-Type", "application/octet-stream")];
+            self.send("PUT", &target, &headers, &contents)
+                .await?
+                .expect(&[200, 201, 204])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete(&self, path: &Path) -> io::Result<()> {
+        let target = self.target(path)?;
+        retrying("delete a file on WebDAV", || async {
+            self.send("DELETE", &target, &[], &[])
+                .await?
+                .expect(&[200, 204])?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+/// A resource in the answer to a PROPFIND
+#[derive(Debug, PartialEq)]
+struct Resource {
+    href: String,
+    collection: bool,
+    length: Option<u64>,
+    modified: Option<OffsetDateTime>,
+}
+
+impl Resource {
+    fn fingerprint(&self) -> Option<Fingerprint> {
+        Some(Fingerprint {
+            size: self.length?,
+            modified: self.modified?,
+        })
+    }
+}
+
+/// The resources of a `207 Multi-Status` answer, without an XML parser as only a few
+/// properties are needed. Namespace prefixes are ignored, servers pick different ones.
+fn parse_multistatus(xml: &str) -> Vec<Resource> {
+    elements(xml, "response")
+        .into_iter()
+        .filter_map(|response| {
+            let text = |name| text(response, name);
+            Some(Resource {
+                href: text("href")?,
+                collection: elements(response, "resourcetype")
+                    .into_iter()
+                    .any(|resource_type| !elements(resource_type, "collection").is_empty()),
+                length: text("getcontentlength").and_then(|length| length.parse().ok()),
+                modified: text("getlastmodified")
+                    .and_then(|modified| httpdate::parse_http_date(&modified).ok())
+                    .map(OffsetDateTime::from),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_url::parse_http_url;
+
+    #[test]
+    fn nextcloud_listings_are_parsed() {
+        let xml = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:" xmlns:s="http://sabredav.org/ns" xmlns:oc="http://owncloud.org/ns">
+ <d:response>
+  <d:href>/remote.php/dav/files/wall/Party%20Photos/</d:href>
+  <d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype>
+   <d:getlastmodified>Tue, 13 Oct 2026 18:00:00 GMT</d:getlastmodified></d:prop>
+   <d:status>HTTP/1.1 200 OK</d:status></d:propstat>
+  <d:propstat><d:prop><d:getcontentlength/></d:prop>
+   <d:status>HTTP/1.1 404 Not Found</d:status></d:propstat>
+ </d:response>
+ <d:response>
+  <d:href>/remote.php/dav/files/wall/Party%20Photos/Tom%20&amp;%20Jerry.jpg</d:href>
+  <d:propstat><d:prop><d:resourcetype/>
+   <d:getcontentlength>1234</d:getcontentlength>
+   <d:getlastmodified>Wed, 14 Oct 2026 18:31:00 GMT</d:getlastmodified></d:prop>
+   <d:status>HTTP/1.1 200 OK</d:status></d:propstat>
+ </d:response>
+</d:multistatus>"#;
+        let resources = parse_multistatus(xml);
+        assert_eq!(resources.len(), 2);
+        assert!(resources[0].collection);
+        assert_eq!(resources[0].length, None);
+        assert_eq!(
+            resources[1],
+            Resource {
+                href: "/remote.php/dav/files/wall/Party%20Photos/Tom%20&%20Jerry.jpg".to_string(),
+                collection: false,
+                length: Some(1234),
+                modified: Some(OffsetDateTime::from_unix_timestamp(1_792_002_660).unwrap()),
+            }
+        );
+
+        let url =
+            parse_http_url("http://cloud.local/remote.php/dav/files/wall/Party%20Photos").unwrap();
+        let storage = WebDavStorage::new(url, None, None);
+        assert_eq!(storage.file_name(&resources[0].href), None);
+        assert_eq!(
+            storage.file_name(&resources[1].href),
+            Some(PathBuf::from("Tom & Jerry.jpg"))
+        );
+        assert_eq!(
+            storage.file_name("http://cloud.local/remote.php/dav/files/wall/Party%20Photos/a.jpg"),
+            Some(PathBuf::from("a.jpg"))
+        );
+        assert_eq!(
+            storage.target(Path::new("Tom & Jerry.jpg")).unwrap(),
+            "/remote.php/dav/files/wall/Party%20Photos/Tom%20%26%20Jerry.jpg"
+        );
+        assert!(storage.target(Path::new("../escaped.jpg")).is_err());
+    }
+}
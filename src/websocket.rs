@@ -1,31 +1,613 @@
-use std::sync::Arc;
+use std::{io::Write, net::SocketAddr, sync::Arc, time::SystemTime};
 
 use axum::{
     extract::{
-        ws::{Message, WebSocket},
-        State, WebSocketUpgrade,
+        ws::{close_code, CloseFrame, Message, WebSocket},
+        ConnectInfo, Query, State, WebSocketUpgrade,
     },
+    http::{header, HeaderMap, StatusCode},
     response::IntoResponse,
+    Extension,
 };
+use flate2::write::GzEncoder;
+use futures_util::{stream::SplitSink, SinkExt, StreamExt};
+use log::info;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tokio::{
+    select,
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc::{self, error::TrySendError},
+    },
+    task::spawn,
+    time::{interval_at, sleep_until, timeout, Duration, Instant},
+};
+
+use crate::{
+    auth::Authenticated,
+    connections::{ClientRole, ConnectionGuard, Connections},
+    highlights::{Highlight, HighlightReceiver, Highlights},
+    index::{Catchup, Change, Image, Indexer, RevisedChange},
+    msgpack,
+    reload::SharedConfiguration,
+    secrets::Role,
+    upload_codes::UploadCodes,
+    Configuration,
+};
+
+/// Messages queued for a peer beyond which it counts as fallen behind
+const OUTBOUND_QUEUE_SIZE: usize = 64;
+
+/// Subprotocol websocket clients request to receive MessagePack instead of JSON
+pub const MESSAGE_PACK_PROTOCOL: &str = "moments.msgpack";
+
+/// Newest protocol version, see [`ServerMessage`] and [`ClientMessage`]
+const LATEST_PROTOCOL: u32 = 2;
+
+/// Characters a client identity in a hello may have at most
+const MAX_CLIENT_ID_LENGTH: usize = 64;
+
+/// How long queued messages and the close frame may take to reach a peer when shutting down
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Deserialize)]
+pub struct IndexParameters {
+    /// only send the newest N images in the initial index, later additions are always sent
+    recent_limit: Option<usize>,
+    /// `1` (default) sends the bare index followed by bare changes and ignores client messages,
+    /// `2` wraps everything in [`ServerMessage`] envelopes and sends the index in chunks
+    protocol: Option<u32>,
+    /// revision of the last message received before reconnecting, protocol 2 clients then only
+    /// get the changes after it if they are still remembered
+    since: Option<u64>,
+    /// `gzip` requests messages as gzip-compressed JSON in binary frames, honored only with
+    /// `--websocket-compression`
+    compression: Option<Compression>,
+    /// `true` makes a protocol 2 client a kiosk taking part in [`ServerMessage::Highlight`]s,
+    /// like a [`ClientMessage::Hello`] as kiosk but without a stable identity
+    #[serde(default)]
+    highlights: bool,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Gzip,
+}
+
+/// Messages sent to clients of protocol version 2
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage<'a> {
+    /// part of the index, sent first and in response to [`ClientMessage::Resync`], parts are
+    /// numbered from 1 to `of`
+    Snapshot {
+        images: &'a [Image],
+        part: usize,
+        of: usize,
+    },
+    /// the snapshot of the index at `revision` is complete, changes after it follow
+    SnapshotComplete { revision: u64 },
+    /// a change to the index resulting in `revision`
+    Change { change: &'a Change, revision: u64 },
+    /// changes in a burst shorter than `--change-batch-window`, each a [`Self::Change`] in order
+    Changes { items: Vec<ServerMessage<'a>> },
+    /// an image for this kiosk to show prominently, no other kiosk highlights it at the same
+    /// time, to be confirmed with [`ClientMessage::HighlightShown`] once shown
+    Highlight(&'a Highlight),
+    /// the code guests currently have to enter to upload, sent to clients authenticated with a
+    /// kiosk or admin secret whenever it changes with `--upload-code-period`
+    UploadCode {
+        code: String,
+        #[serde(with = "time::serde::rfc3339")]
+        valid_until: OffsetDateTime,
+    },
+}
+
+impl<'a> ServerMessage<'a> {
+    /// Splits the index at `revision` into snapshot parts of at most `chunk_size` images,
+    /// followed by [`Self::SnapshotComplete`]
+    pub fn snapshot(images: &'a [Image], chunk_size: usize, revision: u64) -> Vec<Self> {
+        let mut chunks: Vec<_> = images.chunks(chunk_size).collect();
+        if chunks.is_empty() {
+            chunks.push(&[]);
+        }
+        let of = chunks.len();
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(index, images)| Self::Snapshot {
+                images,
+                part: index + 1,
+                of,
+            })
+            .chain([Self::SnapshotComplete { revision }])
+            .collect()
+    }
+
+    pub fn change(change: &'a RevisedChange) -> Self {
+        Self::Change {
+            change: &change.change,
+            revision: change.revision,
+        }
+    }
+}
 
-use crate::index::Indexer;
+/// Messages accepted from clients of protocol version 2
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// requests a fresh snapshot, e.g. after the client noticed it missed changes
+    Resync,
+    /// the highlight `id` was actually shown, so its image counts as shown for all kiosks
+    HighlightShown { id: u64 },
+    /// introduces the client with an `id` that stays the same across reconnects, kiosks then
+    /// take part in highlights with their turn and regions kept
+    Hello { role: ClientRole, id: String },
+}
+
+pub type WebsocketState = (
+    Arc<SharedConfiguration>,
+    Arc<Indexer>,
+    Arc<Connections>,
+    Arc<Highlights>,
+);
 
 pub async fn handle_websocket_upgrade(
     upgrade: WebSocketUpgrade,
-    State(indexer): State<Arc<Indexer>>,
+    State((configuration, indexer, connections, highlights)): State<WebsocketState>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Extension(Authenticated(role)): Extension<Authenticated>,
+    Query(parameters): Query<IndexParameters>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
-    upgrade.on_upgrade(move |socket| handle_websocket(socket, indexer))
+    let configuration = configuration.load();
+    let protocol = parameters.protocol.unwrap_or(1);
+    if !(1..=LATEST_PROTOCOL).contains(&protocol) {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("unsupported protocol version {protocol}, latest is {LATEST_PROTOCOL}"),
+        )
+            .into_response();
+    }
+    // older protocols have no revisions to resume from, nor highlights
+    let since = parameters.since.filter(|_| protocol >= 2);
+    let receiver = (parameters.highlights && protocol >= 2).then(|| highlights.register());
+    // guests must not see the code without being at the event
+    let upload_codes = (protocol >= 2 && matches!(role, Role::Kiosk | Role::Admin))
+        .then(|| configuration.upload_codes.clone())
+        .flatten();
+    let compression = parameters
+        .compression
+        .filter(|_| configuration.websocket_compression);
+    let format = if headers
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|protocol| protocol.trim() == MESSAGE_PACK_PROTOCOL)
+    {
+        Format::MessagePack
+    } else {
+        Format::Json
+    };
+    // confirms the subprotocol if requested, clients not requesting it get none
+    let upgrade = upgrade.protocols([MESSAGE_PACK_PROTOCOL]);
+    upgrade.on_upgrade(move |socket| {
+        handle_websocket(
+            socket,
+            connections.register(address),
+            indexer,
+            highlights,
+            receiver,
+            upload_codes,
+            parameters.recent_limit,
+            since,
+            Encoding {
+                protocol,
+                format,
+                compression,
+            },
+            configuration,
+        )
+    })
+}
+
+/// How messages are put into frames for one connection
+#[derive(Clone, Copy)]
+pub struct Encoding {
+    pub protocol: u32,
+    pub format: Format,
+    pub compression: Option<Compression>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// text frames, or binary ones if compressed
+    Json,
+    /// binary frames, selected with the [`MESSAGE_PACK_PROTOCOL`] subprotocol
+    MessagePack,
+}
+
+impl Encoding {
+    fn message(&self, message: &impl Serialize) -> Message {
+        let bytes = match self.format {
+            Format::Json => serde_json::to_vec(message),
+            Format::MessagePack => msgpack::to_vec(message),
+        }
+        .unwrap();
+        match (self.compression, self.format) {
+            (Some(Compression::Gzip), _) => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&bytes).unwrap();
+                Message::Binary(encoder.finish().unwrap())
+            }
+            (None, Format::Json) => Message::Text(String::from_utf8(bytes).unwrap()),
+            (None, Format::MessagePack) => Message::Binary(bytes),
+        }
+    }
+
+    fn change(&self, change: &RevisedChange) -> Message {
+        if self.protocol >= 2 {
+            self.message(&ServerMessage::change(change))
+        } else {
+            self.message(&change.change)
+        }
+    }
+}
+
+/// Sends the index, or only the changes after revision `since` if possible, followed by all
+/// changes, pinging the peer every `websocket_ping_interval` and
+/// closing the connection once a ping went unanswered until the next one.
+/// Changes following another within `--change-batch-window` are collected and sent together
+/// once the window passed.
+/// Messages are queued for a separate writer so a slow peer cannot hold up the handler. Peers
+/// whose queue overflows or whose subscription lags skip the changes and catch up once their
+/// queue has room again, protocol version 1 peers with the changes in their format or the whole
+/// index as on connecting.
+/// Kiosks taking part in highlights, with `?highlights=true` or after their hello, are sent
+/// theirs as they are scheduled, kiosk and admin clients the upload codes as they change.
+/// When the server shuts down, peers receive a close frame with code 1001 (going away).
+#[allow(clippy::too_many_arguments)]
+pub async fn handle_websocket(
+    socket: WebSocket,
+    connection: ConnectionGuard,
+    indexer: Arc<Indexer>,
+    registry: Arc<Highlights>,
+    mut highlights: Option<HighlightReceiver>,
+    upload_codes: Option<UploadCodes>,
+    recent_limit: Option<usize>,
+    mut since: Option<u64>,
+    encoding: Encoding,
+    configuration: Arc<Configuration>,
+) {
+    let protocol = encoding.protocol;
+    let chunk_size = configuration.snapshot_chunk_size;
+    let ping_interval = configuration.websocket_ping_interval;
+    let batch_window = configuration.change_batch_window;
+    let address = connection.address();
+    let session = connection.session();
+    let closing = connection.closing();
+    tokio::pin!(closing);
+    let (sink, mut stream) = socket.split();
+    let (outbound, queue) = mpsc::channel(OUTBOUND_QUEUE_SIZE);
+    let mut writer = spawn(write_messages(sink, queue, connection));
+
+    let Some((mut updates, mut revision)) = catch_up(
+        &outbound,
+        &indexer,
+        recent_limit,
+        since,
+        encoding,
+        chunk_size,
+    )
+    .await
+    else {
+        writer.abort();
+        return;
+    };
+    let mut ping = interval_at(Instant::now() + ping_interval, ping_interval);
+    let mut awaiting_pong = false;
+    // set while changes are skipped until the peer can catch up from `since`
+    let mut stalled = false;
+    // changes not yet queued and until when further ones are added to them
+    let mut batch = Vec::new();
+    let mut batch_until = None;
+    let mut shutting_down = false;
+    let mut next_code = Instant::now();
+    loop {
+        let mut flush = false;
+        select! {
+            message = stream.next() => match message {
+                Some(Ok(Message::Pong(_))) => awaiting_pong = false,
+                Some(Ok(Message::Text(text))) if protocol >= 2 => {
+                    match serde_json::from_str(&text) {
+                        Ok(ClientMessage::Resync) => {
+                            since = None;
+                            stalled = true;
+                        }
+                        Ok(ClientMessage::HighlightShown { id }) => {
+                            if let Some(highlights) = &highlights {
+                                highlights.acknowledge(id);
+                            }
+                        }
+                        Ok(ClientMessage::Hello { id, .. })
+                            if id.is_empty() || id.chars().count() > MAX_CLIENT_ID_LENGTH =>
+                        {
+                            info!("ignoring hello of {address} with an invalid id");
+                        }
+                        Ok(ClientMessage::Hello { role, id }) => {
+                            session.identify(role, id.clone());
+                            if role == ClientRole::Kiosk {
+                                highlights
+                                    .get_or_insert_with(|| registry.register())
+                                    .identify(id);
+                            }
+                        }
+                        Err(error) => info!("ignoring unexpected websocket message: {error}"),
+                    }
+                }
+                // pings are answered by the websocket implementation while receiving
+                Some(Ok(Message::Ping(_) | Message::Text(_) | Message::Binary(_))) => {}
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+            },
+            update = updates.recv(), if !stalled => {
+                let change = match update {
+                    Ok(change) => change,
+                    Err(RecvError::Lagged(_)) => {
+                        info!("websocket client {address} lagged behind, catching it up later");
+                        since = Some(revision);
+                        stalled = true;
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                batch.push(change);
+                // the first change after a quiet period goes out immediately and opens a window
+                // collecting the following ones
+                if batch_until.is_none() {
+                    flush = true;
+                    if protocol >= 2 && !batch_window.is_zero() {
+                        batch_until = Some(Instant::now() + batch_window);
+                    }
+                }
+            },
+            _ = sleep_until(batch_until.unwrap_or_else(Instant::now)), if batch_until.is_some() => {
+                if batch.is_empty() {
+                    batch_until = None;
+                } else {
+                    flush = true;
+                    batch_until = Some(Instant::now() + batch_window);
+                }
+            },
+            Some(highlight) = recv_highlight(&mut highlights), if highlights.is_some() => {
+                // a kiosk too far behind for it has to do without this highlight
+                let message = encoding.message(&ServerMessage::Highlight(&highlight));
+                if let Err(TrySendError::Closed(_)) = outbound.try_send(message) {
+                    break;
+                }
+            },
+            _ = sleep_until(next_code), if upload_codes.is_some() => {
+                let Some(codes) = &upload_codes else {
+                    continue;
+                };
+                let now = SystemTime::now();
+                let (code, valid_until) = codes.current(now);
+                next_code = Instant::now() + valid_until.duration_since(now).unwrap_or_default();
+                let message = encoding.message(&ServerMessage::UploadCode {
+                    code,
+                    valid_until: valid_until.into(),
+                });
+                if let Err(TrySendError::Closed(_)) = outbound.try_send(message) {
+                    break;
+                }
+            },
+            permit = outbound.reserve(), if stalled => {
+                drop(permit);
+                batch.clear();
+                batch_until = None;
+                // resubscribing discards changes the catch-up already contains
+                let resubscribed =
+                    catch_up(&outbound, &indexer, recent_limit, since, encoding, chunk_size).await;
+                match resubscribed {
+                    Some(resubscribed) => (updates, revision) = resubscribed,
+                    None => break,
+                }
+                stalled = false;
+            },
+            _ = ping.tick() => {
+                if awaiting_pong {
+                    info!("closing websocket connection to {address}, peer did not answer ping");
+                    break;
+                }
+                // a peer not draining its queue does not answer either
+                awaiting_pong = true;
+                if let Err(TrySendError::Closed(_)) = outbound.try_send(Message::Ping(Vec::new())) {
+                    break;
+                }
+            },
+            _ = outbound.closed() => break,
+            _ = &mut closing => {
+                let _ = outbound.try_send(Message::Close(Some(CloseFrame {
+                    code: close_code::AWAY,
+                    reason: "server shutting down".into(),
+                })));
+                shutting_down = true;
+                break;
+            },
+        }
+        if !flush || stalled {
+            continue;
+        }
+        let message = match batch.as_slice() {
+            [change] => encoding.change(change),
+            changes => encoding.message(&ServerMessage::Changes {
+                items: changes.iter().map(ServerMessage::change).collect(),
+            }),
+        };
+        match outbound.try_send(message) {
+            Ok(()) => revision = batch.last().unwrap().revision,
+            Err(TrySendError::Full(_)) => {
+                info!("websocket client {address} fell behind, catching it up later");
+                since = Some(revision);
+                stalled = true;
+            }
+            Err(TrySendError::Closed(_)) => break,
+        }
+        batch.clear();
+    }
+    if shutting_down {
+        // the writer finishes with the close frame once the queue is dropped
+        drop(outbound);
+        if timeout(CLOSE_TIMEOUT, &mut writer).await.is_err() {
+            writer.abort();
+        }
+    } else {
+        writer.abort();
+    }
 }
 
-pub async fn handle_websocket(mut socket: WebSocket, indexer: Arc<Indexer>) {
-    let mut updates = indexer.change_receiver.resubscribe();
-    let index = indexer.index().await;
-    let message = Message::Text(serde_json::to_string(&index).unwrap());
-    socket.send(message).await.unwrap();
-    while let Ok(update) = updates.recv().await {
-        let message = Message::Text(serde_json::to_string(&update).unwrap());
-        if socket.send(message).await.is_err() {
+async fn recv_highlight(highlights: &mut Option<HighlightReceiver>) -> Option<Highlight> {
+    highlights.as_mut()?.recv().await
+}
+
+/// Sends queued messages until the queue or the peer is gone
+async fn write_messages(
+    mut sink: SplitSink<WebSocket, Message>,
+    mut queue: mpsc::Receiver<Message>,
+    connection: ConnectionGuard,
+) {
+    while let Some(message) = queue.recv().await {
+        if sink.send(message).await.is_err() {
             break;
         }
+        connection.message_sent();
+    }
+}
+
+/// Subscribes to changes and queues the index they apply to, or the changes after `since` if
+/// they are still remembered. Returns the subscription and the revision the peer is at, `None`
+/// if the indexer or the peer is gone.
+async fn catch_up(
+    outbound: &mpsc::Sender<Message>,
+    indexer: &Indexer,
+    recent_limit: Option<usize>,
+    since: Option<u64>,
+    encoding: Encoding,
+    chunk_size: usize,
+) -> Option<(broadcast::Receiver<RevisedChange>, u64)> {
+    let subscription = indexer.subscribe(recent_limit, since).await.ok()?;
+    // changes queue up in the subscription until the catch-up is complete
+    match &subscription.catchup {
+        Catchup::Snapshot(images) if encoding.protocol < 2 => {
+            outbound.send(encoding.message(images)).await.ok()?;
+        }
+        Catchup::Snapshot(images) => {
+            for message in ServerMessage::snapshot(images, chunk_size, subscription.revision) {
+                outbound.send(encoding.message(&message)).await.ok()?;
+            }
+        }
+        Catchup::Changes(changes) => {
+            for change in changes {
+                outbound.send(encoding.change(change)).await.ok()?;
+            }
+        }
+    }
+    Some((subscription.changes, subscription.revision))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, io::Read, path::PathBuf};
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+    use crate::{capture::CaptureMetadata, placeholder::Placeholder};
+
+    /// An index like that of a party, photos of a few phones uploaded over an evening
+    fn party_index(count: u32) -> Vec<Image> {
+        let mut noise = 0x2545_f491_u32;
+        let mut random = move || {
+            noise ^= noise << 13;
+            noise ^= noise >> 17;
+            noise ^= noise << 5;
+            noise
+        };
+        let blurhash_digits =
+            b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+        let start = OffsetDateTime::from_unix_timestamp(1_792_000_000).unwrap();
+        (0..count)
+            .map(|index| {
+                let created_at = start + time::Duration::seconds(i64::from(index) * 97);
+                let name = format!("{}_IMG_{}.jpg", created_at.unix_timestamp(), 4000 + index);
+                let (make, model) =
+                    [("Apple", "iPhone 15"), ("Google", "Pixel 8")][index as usize % 2];
+                let blurhash = (0..28)
+                    .map(|_| blurhash_digits[random() as usize % blurhash_digits.len()] as char)
+                    .collect();
+                Image {
+                    hash: [
+                        u64::from(random()) << 32 | u64::from(random()),
+                        u64::from(random()),
+                    ],
+                    path: PathBuf::from(&name),
+                    aliases: Vec::new(),
+                    created_at,
+                    capture: CaptureMetadata {
+                        taken_at: Some(format!(
+                            "2026-10-13T21:{:02}:{:02}",
+                            index / 60,
+                            index % 60
+                        )),
+                        camera_make: Some(make.to_string()),
+                        camera_model: Some(model.to_string()),
+                    },
+                    cached_path: PathBuf::from(&name),
+                    derivatives: BTreeMap::from([(400, PathBuf::from("400").join(&name))]),
+                    placeholder: Some(Placeholder {
+                        blurhash,
+                        color: format!("#{:06x}", random() % 0x100_0000),
+                    }),
+                    pinned: false,
+                    hidden: false,
+                    reactions: BTreeMap::new(),
+                    fresh: false,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn compressed_snapshots_are_a_fifth_of_the_size() {
+        let images = party_index(72);
+        let [snapshot, _] = &ServerMessage::snapshot(&images, images.len(), 72)[..] else {
+            panic!("the index is not in one part");
+        };
+        let encoding = |compression| Encoding {
+            protocol: 2,
+            format: Format::Json,
+            compression,
+        };
+        let Message::Text(json) = encoding(None).message(snapshot) else {
+            panic!("uncompressed JSON is not sent as text");
+        };
+        let Message::Binary(compressed) = encoding(Some(Compression::Gzip)).message(snapshot)
+        else {
+            panic!("compressed JSON is not sent as binary");
+        };
+        let mut decompressed = String::new();
+        GzDecoder::new(&compressed[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, json);
+        // 27 kB take 5.8 kB, the hashes and placeholders barely compress
+        assert!(
+            compressed.len() * 100 < json.len() * 22,
+            "{} bytes compressed to {}",
+            json.len(),
+            compressed.len()
+        );
     }
 }
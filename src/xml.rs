@@ -0,0 +1,62 @@
+/// Contents of all elements with the local `name` in `xml`, not nested within each other. Just
+/// enough XML for the answers of WebDAV and S3 servers, namespace prefixes are ignored as
+/// servers pick different ones.
+pub fn elements<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let mut found = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let end = rest
+            .find(|character: char| character.is_whitespace() || "/>".contains(character))
+            .unwrap_or(rest.len());
+        // closing tags, processing instructions and comments have no matching name
+        let tag = &rest[..end];
+        if tag.rsplit(':').next() != Some(name) {
+            continue;
+        }
+        let Some(close) = rest.find('>') else {
+            break;
+        };
+        if rest[..close].ends_with('/') {
+            found.push("");
+            rest = &rest[close + 1..];
+            continue;
+        }
+        let content = &rest[close + 1..];
+        let closing = format!("</{tag}");
+        let Some(length) = content.find(&closing) else {
+            break;
+        };
+        found.push(&content[..length]);
+        rest = &content[length + closing.len()..];
+    }
+    found
+}
+
+/// The text of the first element with the local `name` in `xml` that is not empty
+pub fn text(xml: &str, name: &str) -> Option<String> {
+    elements(xml, name)
+        .into_iter()
+        .map(|content| unescape(content.trim()))
+        .find(|text| !text.is_empty())
+}
+
+pub fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn elements_are_found_with_any_prefix() {
+        let xml = "<D:a x='1'>one</D:a><ab>no</ab><a/><a>two</a>";
+        assert_eq!(elements(xml, "a"), ["one", "", "two"]);
+        assert_eq!(elements("<a>unterminated", "a"), Vec::<&str>::new());
+    }
+}
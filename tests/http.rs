@@ -0,0 +1,4476 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    future::IntoFuture,
+    io::{self, Cursor},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::SystemTime,
+};
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{connect_info::MockConnectInfo, Request},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Router,
+};
+use clap::{CommandFactory, Parser};
+use futures_util::{SinkExt, StreamExt};
+use image::{ImageFormat, Rgb, RgbImage};
+use moments::{
+    build_maintenance_router, build_router, diagnose, with_file_arguments, Arguments,
+    ErrorCorrection, Fingerprint, Image, ImportSummary, Moments, Outcome, QrCode, QrError, Storage,
+};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+use serde_json::Value;
+use tempfile::TempDir;
+use time::OffsetDateTime;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tower::ServiceExt;
+
+const SECRET: &str = "test-secret";
+
+const BOUNDARY: &str = "moments-test-boundary";
+
+/// A started instance on fresh storage and cache directories, removed when dropped
+struct TestServer {
+    directory: TempDir,
+    command_line: Vec<OsString>,
+    moments: Moments,
+    router: Router,
+}
+
+impl TestServer {
+    async fn start() -> Self {
+        Self::start_with(|_| {}).await
+    }
+
+    /// Starts after `prepare` filled the storage directory
+    async fn start_with(prepare: impl FnOnce(&Path)) -> Self {
+        Self::start_with_arguments(prepare, &[]).await
+    }
+
+    /// Starts after `prepare` filled the storage directory, with `extra` arguments appended
+    async fn start_with_arguments(prepare: impl FnOnce(&Path), extra: &[&str]) -> Self {
+        Self::start_in(prepare, extra, None).await
+    }
+
+    /// Starts with originals in `storage` instead of the storage directory
+    async fn start_with_storage(storage: Arc<dyn Storage>) -> Self {
+        Self::start_in(|_| {}, &[], Some(storage)).await
+    }
+
+    async fn start_in(
+        prepare: impl FnOnce(&Path),
+        extra: &[&str],
+        originals: Option<Arc<dyn Storage>>,
+    ) -> Self {
+        let directory = tempfile::tempdir().unwrap();
+        let storage = directory.path().join("storage");
+        let cache = directory.path().join("cache");
+        std::fs::create_dir_all(&storage).unwrap();
+        prepare(&storage);
+        // kept for reloads, which parse the command line again
+        let command_line: Vec<OsString> = [
+            "moments".as_ref(),
+            "--secret".as_ref(),
+            SECRET.as_ref(),
+            "--storage".as_ref(),
+            storage.as_os_str(),
+            "--cache".as_ref(),
+            cache.as_os_str(),
+        ]
+        .into_iter()
+        .chain(extra.iter().map(|argument| argument.as_ref()))
+        .map(OsString::from)
+        .collect();
+        let arguments = Arguments::parse_from(
+            with_file_arguments(&Arguments::command(), &command_line).unwrap(),
+        );
+        let moments = match originals {
+            Some(originals) => {
+                Moments::start_with_storage(command_line.clone(), arguments, originals).await
+            }
+            None => Moments::start(command_line.clone(), arguments).await,
+        }
+        .unwrap();
+        Self::serve(directory, command_line, moments)
+    }
+
+    fn serve(directory: TempDir, command_line: Vec<OsString>, moments: Moments) -> Self {
+        let router =
+            build_router(&moments).layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4242))));
+        Self {
+            directory,
+            command_line,
+            moments,
+            router,
+        }
+    }
+
+    /// Saves and starts again with the same directories and arguments, like after a reboot
+    async fn restart(self) -> Self {
+        self.moments.save().await.unwrap();
+        let Self {
+            directory,
+            command_line,
+            ..
+        } = self;
+        let arguments = Arguments::parse_from(
+            with_file_arguments(&Arguments::command(), &command_line).unwrap(),
+        );
+        let moments = Moments::start(command_line.clone(), arguments)
+            .await
+            .unwrap();
+        Self::serve(directory, command_line, moments)
+    }
+
+    async fn send(&self, request: Request) -> Response {
+        self.router.clone().oneshot(request).await.unwrap()
+    }
+
+    async fn statistics(&self) -> Value {
+        let response = self.send(authenticated_get("/admin/stats")).await;
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    /// How many images were decoded since the start, from the statistics
+    async fn processed(&self) -> u64 {
+        self.statistics().await["processing"]["processed"]
+            .as_u64()
+            .unwrap()
+    }
+
+    async fn upload(&self, file_name: &str, contents: Vec<u8>) -> Response {
+        self.upload_with(file_name, contents, None, Request::post("/upload"))
+            .await
+    }
+
+    /// Uploads with an upload `code` if given and the headers of `request`, authenticated with
+    /// [`SECRET`] unless it has an `Authorization` header
+    async fn upload_with(
+        &self,
+        file_name: &str,
+        contents: Vec<u8>,
+        code: Option<&str>,
+        mut request: axum::http::request::Builder,
+    ) -> Response {
+        let body = upload_body(file_name, contents, code);
+        if !request
+            .headers_ref()
+            .is_some_and(|headers| headers.contains_key(header::AUTHORIZATION))
+        {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {SECRET}"));
+        }
+        self.send(
+            request
+                .header(
+                    header::CONTENT_TYPE,
+                    format!("multipart/form-data; boundary={BOUNDARY}"),
+                )
+                .body(Body::from(body))
+                .unwrap(),
+        )
+        .await
+    }
+}
+
+/// The multipart form uploading `contents` as `file_name`, with an upload code if given
+fn upload_body(file_name: &str, contents: Vec<u8>, code: Option<&str>) -> Vec<u8> {
+    let mut body = Vec::new();
+    if let Some(code) = code {
+        body.extend(
+            format!(
+                "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"code\"\r\n\r\n\
+                 {code}\r\n"
+            )
+            .into_bytes(),
+        );
+    }
+    body.extend(
+        format!(
+            "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"image\"; \
+             filename=\"{file_name}\"\r\nContent-Type: image/png\r\n\r\n"
+        )
+        .into_bytes(),
+    );
+    body.extend(contents);
+    body.extend(format!("\r\n--{BOUNDARY}--\r\n").into_bytes());
+    body
+}
+
+/// Originals kept in memory, standing in for storage that is not a local directory
+#[derive(Default)]
+struct MemoryStorage {
+    files: Mutex<BTreeMap<PathBuf, (Vec<u8>, OffsetDateTime)>>,
+    /// writes fail while set, like a full disk
+    unwritable: AtomicBool,
+}
+
+impl MemoryStorage {
+    fn fingerprint((contents, modified): &(Vec<u8>, OffsetDateTime)) -> Fingerprint {
+        Fingerprint {
+            size: contents.len() as u64,
+            modified: *modified,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for MemoryStorage {
+    async fn list(&self) -> io::Result<Vec<(PathBuf, Fingerprint)>> {
+        let files = self.files.lock().unwrap();
+        Ok(files
+            .iter()
+            .map(|(path, file)| (path.clone(), Self::fingerprint(file)))
+            .collect())
+    }
+
+    async fn stat(&self, path: &Path) -> io::Result<Fingerprint> {
+        let files = self.files.lock().unwrap();
+        files
+            .get(path)
+            .map(Self::fingerprint)
+            .ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+
+    async fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let files = self.files.lock().unwrap();
+        files
+            .get(path)
+            .map(|(contents, _)| contents.clone())
+            .ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+
+    async fn write(&self, path: &Path, contents: Vec<u8>) -> io::Result<()> {
+        if self.unwritable.load(Ordering::Relaxed) {
+            return Err(io::Error::other("storage is full"));
+        }
+        let modified = OffsetDateTime::now_utc();
+        self.files
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), (contents, modified));
+        Ok(())
+    }
+
+    async fn delete(&self, path: &Path) -> io::Result<()> {
+        self.files
+            .lock()
+            .unwrap()
+            .remove(path)
+            .map(drop)
+            .ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+}
+
+/// A PNG of a gradient, `seed` makes the content and thereby the hash unique
+fn png(seed: u8) -> Vec<u8> {
+    let image = RgbImage::from_fn(64, 48, |x, y| Rgb([x as u8 * 4, y as u8 * 5, seed]));
+    let mut encoded = Cursor::new(Vec::new());
+    image.write_to(&mut encoded, ImageFormat::Png).unwrap();
+    encoded.into_inner()
+}
+
+/// A JPEG of 64×48 pixels, red on the left and blue on the right, telling viewers with its EXIF
+/// `orientation` how to rotate it
+fn jpeg_with_orientation(orientation: u16) -> Vec<u8> {
+    let image = RgbImage::from_fn(64, 48, |x, _| match x < 32 {
+        true => Rgb([255, 0, 0]),
+        false => Rgb([0, 0, 255]),
+    });
+    let mut encoded = Cursor::new(Vec::new());
+    image.write_to(&mut encoded, ImageFormat::Jpeg).unwrap();
+    let encoded = encoded.into_inner();
+    // big-endian TIFF structure with a single IFD entry of type SHORT
+    let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01".to_vec();
+    exif.extend(orientation.to_be_bytes());
+    exif.extend([0; 6]);
+    let mut jpeg = encoded[..2].to_vec();
+    jpeg.extend([0xff, 0xe1]);
+    jpeg.extend((exif.len() as u16 + 2).to_be_bytes());
+    jpeg.extend(exif);
+    jpeg.extend(&encoded[2..]);
+    jpeg
+}
+
+fn authenticated_get(path: &str) -> Request {
+    Request::get(path)
+        .header(header::AUTHORIZATION, format!("Bearer {SECRET}"))
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Keeps log records of all tests as `message key=value...`
+struct CapturingLogger(Mutex<Vec<String>>);
+
+impl log::Log for CapturingLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        struct Pairs<'a>(&'a mut String);
+
+        impl<'kvs> log::kv::VisitSource<'kvs> for Pairs<'_> {
+            fn visit_pair(
+                &mut self,
+                key: log::kv::Key<'kvs>,
+                value: log::kv::Value<'kvs>,
+            ) -> Result<(), log::kv::Error> {
+                self.0.push_str(&format!(" {key}={value}"));
+                Ok(())
+            }
+        }
+
+        let mut line = record.args().to_string();
+        record.key_values().visit(&mut Pairs(&mut line)).unwrap();
+        self.0.lock().unwrap().push(line);
+    }
+
+    fn flush(&self) {}
+}
+
+/// The records logged since the first call, by any test running concurrently as well
+fn captured_logs() -> &'static Mutex<Vec<String>> {
+    static LOGGER: OnceLock<&'static CapturingLogger> = OnceLock::new();
+    let logger = LOGGER.get_or_init(|| {
+        let logger = Box::leak(Box::new(CapturingLogger(Mutex::new(Vec::new()))));
+        log::set_logger(logger).unwrap();
+        log::set_max_level(log::LevelFilter::Info);
+        logger
+    });
+    &logger.0
+}
+
+/// The next message sent over the websocket, which has to be text
+async fn next_json<S>(socket: &mut S) -> Value
+where
+    S: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+{
+    let Some(Ok(Message::Text(message))) = socket.next().await else {
+        panic!("expected a text message");
+    };
+    serde_json::from_str(&message).unwrap()
+}
+
+/// The next highlight sent over the websocket, `None` if there is none within `wait`
+async fn next_highlight<S>(socket: &mut S, wait: std::time::Duration) -> Option<Value>
+where
+    S: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+{
+    tokio::time::timeout(wait, async {
+        loop {
+            let Some(Ok(Message::Text(message))) = socket.next().await else {
+                panic!("expected messages until a highlight");
+            };
+            let message: Value = serde_json::from_str(&message).unwrap();
+            if message["type"] == "highlight" {
+                return message;
+            }
+        }
+    })
+    .await
+    .ok()
+}
+
+/// A PNG of noise, which barely compresses, `width` by `height` pixels
+fn noise_png(width: u32, height: u32) -> Vec<u8> {
+    let mut state = 0x2545_f491_u32;
+    let image = RgbImage::from_fn(width, height, |_, _| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        let [red, green, blue, _] = state.to_le_bytes();
+        Rgb([red, green, blue])
+    });
+    let mut encoded = Cursor::new(Vec::new());
+    image.write_to(&mut encoded, ImageFormat::Png).unwrap();
+    encoded.into_inner()
+}
+
+#[tokio::test]
+async fn requests_without_secret_are_rejected() {
+    let server = TestServer::start().await;
+    let response = server
+        .send(Request::get("/version").body(Body::empty()).unwrap())
+        .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = server.send(authenticated_get("/version")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn uploaded_image_is_indexed_and_served() {
+    let server = TestServer::start().await;
+    let response = server.upload("party.png", png(1)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let images = server.moments.indexer().index(None).await.unwrap();
+    assert_eq!(images.len(), 1);
+    let image = &images[0];
+    assert!(image.path.to_str().unwrap().ends_with("_party.png"));
+    assert!(server
+        .directory
+        .path()
+        .join("storage")
+        .join(&image.path)
+        .is_file());
+
+    let response = server
+        .send(authenticated_get(&format!(
+            "/images/{}",
+            image.cached_path.display()
+        )))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "image/jpeg");
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert!(image::load_from_memory(&body).is_ok());
+}
+
+#[tokio::test]
+async fn duplicate_upload_is_rejected() {
+    let server = TestServer::start().await;
+    let response = server.upload("first.png", png(2)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = server.upload("second.png", png(2)).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    assert_eq!(server.moments.indexer().index(None).await.unwrap().len(), 1);
+    // neither in storage nor as derivatives in the cache
+    for directory in ["storage", "cache"] {
+        let stored: Vec<_> = std::fs::read_dir(server.directory.path().join(directory))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .filter(|name| name.to_str().unwrap().ends_with(".png"))
+            .collect();
+        assert_eq!(stored.len(), 1, "{stored:?}");
+    }
+}
+
+#[test]
+fn handlers_answer_503_once_the_indexer_is_gone() {
+    // the indexer task ends with the runtime it was spawned on, the server outlives it
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let server = runtime.block_on(TestServer::start_with(|storage| {
+        std::fs::write(storage.join("stranded.png"), png(59)).unwrap();
+    }));
+    runtime.shutdown_timeout(std::time::Duration::from_secs(1));
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        for path in ["/recommend", "/random", "/admin/images"] {
+            let response = server.send(authenticated_get(path)).await;
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE, "{path}");
+        }
+        let response = server.upload("late.png", png(60)).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    });
+}
+
+#[tokio::test]
+async fn websocket_sends_snapshot_of_storage() {
+    let server = TestServer::start_with(|storage| {
+        std::fs::write(storage.join("existing.png"), png(3)).unwrap();
+    })
+    .await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let app = build_router(&server.moments);
+    tokio::spawn(async move {
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .unwrap();
+    });
+
+    let (mut socket, _) = connect_async(format!("ws://{address}/index?token={SECRET}"))
+        .await
+        .unwrap();
+    let Some(Ok(Message::Text(snapshot))) = socket.next().await else {
+        panic!("expected the index as first message");
+    };
+    let snapshot: Value = serde_json::from_str(&snapshot).unwrap();
+    let images = snapshot.as_array().unwrap();
+    assert_eq!(images.len(), 1);
+    assert_eq!(images[0]["path"], "existing.png");
+}
+
+#[tokio::test]
+async fn legacy_websockets_survive_bursts_of_changes() {
+    const REACTIONS: u64 = 20_000;
+    let server = TestServer::start_with(|storage| {
+        std::fs::write(storage.join("existing.png"), png(3)).unwrap();
+    })
+    .await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let app = build_router(&server.moments);
+    tokio::spawn(async move {
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .unwrap();
+    });
+    let (mut socket, _) = connect_async(format!("ws://{address}/index?token={SECRET}"))
+        .await
+        .unwrap();
+    let Some(Ok(Message::Text(_))) = socket.next().await else {
+        panic!("expected the index as first message");
+    };
+
+    // far more changes than fit into the queue and the subscription while the peer reads none
+    let indexer = server.moments.indexer();
+    let hash = indexer.index(None).await.unwrap()[0].hash;
+    for _ in 0..REACTIONS {
+        indexer.react(hash, "🎉".to_string()).await.unwrap();
+    }
+    let reactions = |image: &Value| image["reactions"]["🎉"].as_u64().unwrap_or_default();
+    loop {
+        let message = tokio::time::timeout(std::time::Duration::from_secs(10), socket.next())
+            .await
+            .expect("the connection stays open");
+        let Some(Ok(Message::Text(message))) = message else {
+            panic!("expected changes or the index, got {message:?}");
+        };
+        let message: Value = serde_json::from_str(&message).unwrap();
+        // caught up with the changes in between or the whole index again
+        let count = match message.as_array() {
+            Some(images) => reactions(&images[0]),
+            None => reactions(&message["Update"]["image"]),
+        };
+        if count == REACTIONS {
+            break;
+        }
+    }
+}
+
+#[tokio::test]
+async fn uploads_during_websocket_setup_arrive_exactly_once() {
+    let server = TestServer::start().await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let app = build_router(&server.moments);
+    tokio::spawn(async move {
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .unwrap();
+    });
+
+    let url = format!("ws://{address}/index?token={SECRET}&protocol=2");
+    let names: Vec<_> = (62..72)
+        .map(|seed| format!("concurrent-{seed}.png"))
+        .collect();
+    let uploads = async {
+        for (seed, name) in (62..72).zip(&names) {
+            let response = server.upload(name, png(seed)).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+    };
+    // each connects at another point of the uploads, before, between or after them
+    let connections = futures_util::future::join_all((0..10).map(|index| {
+        let url = url.clone();
+        async move {
+            tokio::time::sleep(std::time::Duration::from_millis(index * 2)).await;
+            connect_async(url).await.unwrap().0
+        }
+    }));
+    let ((), sockets) = tokio::join!(uploads, connections);
+
+    for mut socket in sockets {
+        let mut received = BTreeMap::<String, usize>::new();
+        // a little longer than all of them take to arrive, to also catch duplicates
+        let mut deadline = std::time::Duration::from_secs(5);
+        while let Ok(Some(Ok(Message::Text(message)))) =
+            tokio::time::timeout(deadline, socket.next()).await
+        {
+            let message: Value = serde_json::from_str(&message).unwrap();
+            let images = match message["type"].as_str().unwrap() {
+                "snapshot" => message["images"].as_array().unwrap().clone(),
+                "change" => vec![message["change"]["Addition"]["image"].clone()],
+                "changes" => message["items"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|item| item["change"]["Addition"]["image"].clone())
+                    .collect(),
+                _ => Vec::new(),
+            };
+            for image in images {
+                // without the upload time in front
+                let path = image["path"].as_str().unwrap();
+                let name = path.rsplit('_').next().unwrap().to_string();
+                *received.entry(name).or_default() += 1;
+            }
+            if received.len() == names.len() {
+                deadline = std::time::Duration::from_millis(100);
+            }
+        }
+        let expected: BTreeMap<_, _> = names.iter().map(|name| (name.clone(), 1)).collect();
+        assert_eq!(received, expected);
+    }
+}
+
+#[tokio::test]
+async fn reconnecting_kiosks_get_a_snapshot_if_their_revision_is_forgotten() {
+    let server = TestServer::start_with(|storage| {
+        std::fs::write(storage.join("resumed.png"), png(72)).unwrap();
+    })
+    .await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let app = build_router(&server.moments);
+    tokio::spawn(async move {
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .unwrap();
+    });
+    let first_messages = |since: u64, count: usize| {
+        let url = format!("ws://{address}/index?token={SECRET}&protocol=2&since={since}");
+        async move {
+            let (mut socket, _) = connect_async(url).await.unwrap();
+            let mut messages = Vec::new();
+            while messages.len() < count {
+                let Some(Ok(Message::Text(message))) = socket.next().await else {
+                    panic!("expected {count} messages");
+                };
+                messages.push(serde_json::from_str::<Value>(&message).unwrap());
+            }
+            messages
+        }
+    };
+
+    // revisions of an earlier run are never remembered
+    let messages = first_messages(1, 2).await;
+    assert_eq!(messages[0]["type"], "snapshot");
+    assert_eq!(messages[0]["images"][0]["path"], "resumed.png");
+    assert_eq!(messages[1]["type"], "snapshot_complete");
+    let revision = messages[1]["revision"].as_u64().unwrap();
+
+    let response = server.upload("missed.png", png(73)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let messages = first_messages(revision, 1).await;
+    assert_eq!(messages[0]["type"], "change");
+    assert_eq!(messages[0]["revision"], revision + 1);
+    let path = messages[0]["change"]["Addition"]["image"]["path"].as_str();
+    assert!(path.unwrap().ends_with("missed.png"));
+}
+
+#[tokio::test]
+async fn enveloped_snapshots_come_in_parts_and_are_sent_again_on_resync() {
+    let server = TestServer::start_with_arguments(
+        |storage| {
+            std::fs::write(storage.join("first.png"), png(76)).unwrap();
+            std::fs::write(storage.join("second.png"), png(77)).unwrap();
+        },
+        &["--snapshot-chunk-size", "1"],
+    )
+    .await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let app = build_router(&server.moments);
+    tokio::spawn(async move {
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .unwrap();
+    });
+    let url = format!("ws://{address}/index?token={SECRET}&protocol=2");
+    let (mut socket, _) = connect_async(url).await.unwrap();
+    let mut snapshot = Vec::new();
+    for part in 1..=2 {
+        let message = next_json(&mut socket).await;
+        assert_eq!(message["type"], "snapshot");
+        assert_eq!(
+            (message["part"].as_u64(), message["of"].as_u64()),
+            (Some(part), Some(2))
+        );
+        assert_eq!(message["images"].as_array().unwrap().len(), 1);
+        snapshot.push(message["images"][0]["path"].as_str().unwrap().to_string());
+    }
+    snapshot.sort();
+    assert_eq!(snapshot, ["first.png", "second.png"]);
+    let message = next_json(&mut socket).await;
+    assert_eq!(message["type"], "snapshot_complete");
+    let revision = message["revision"].as_u64().unwrap();
+
+    let response = server.upload("third.png", png(78)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let message = next_json(&mut socket).await;
+    assert_eq!(message["type"], "change");
+    assert_eq!(message["revision"], revision + 1);
+    let path = message["change"]["Addition"]["image"]["path"].as_str();
+    assert!(path.unwrap().ends_with("third.png"));
+
+    // a client unsure of its state asks for everything again
+    let resync = serde_json::json!({ "type": "resync" }).to_string();
+    socket.send(Message::Text(resync)).await.unwrap();
+    for part in 1..=3 {
+        let message = next_json(&mut socket).await;
+        assert_eq!(message["type"], "snapshot");
+        assert_eq!(
+            (message["part"].as_u64(), message["of"].as_u64()),
+            (Some(part), Some(3))
+        );
+    }
+    let message = next_json(&mut socket).await;
+    assert_eq!(message["type"], "snapshot_complete");
+    assert_eq!(message["revision"], revision + 1);
+}
+
+#[tokio::test]
+async fn shutting_down_finishes_uploads_and_closes_websockets() {
+    let server = TestServer::start().await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let (shutdown_sender, mut shutdown) = tokio::sync::watch::channel(false);
+    let serving = tokio::spawn(
+        axum::serve(
+            listener,
+            build_router(&server.moments).into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(async move {
+            let _ = shutdown.wait_for(|shutdown| *shutdown).await;
+        })
+        .into_future(),
+    );
+    let (mut socket, _) = connect_async(format!("ws://{address}/index?token={SECRET}"))
+        .await
+        .unwrap();
+    socket.next().await.unwrap().unwrap();
+    let body = upload_body("interrupted.png", png(80), None);
+    let (first_half, second_half) = body.split_at(body.len() / 2);
+    let mut upload = TcpStream::connect(address).await.unwrap();
+    let head = format!(
+        "POST /upload HTTP/1.1\r\nHost: {address}\r\nAuthorization: Bearer {SECRET}\r\n\
+         Content-Type: multipart/form-data; boundary={BOUNDARY}\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    upload.write_all(head.as_bytes()).await.unwrap();
+    upload.write_all(first_half).await.unwrap();
+    // sent only once the upload is being received
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    shutdown_sender.send_replace(true);
+    server.moments.close_connections();
+    let Some(Ok(Message::Close(Some(frame)))) = socket.next().await else {
+        panic!("expected a close frame");
+    };
+    assert_eq!(u16::from(frame.code), 1001);
+    let mut refused = false;
+    for _ in 0..50 {
+        if TcpStream::connect(address).await.is_err() {
+            refused = true;
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    assert!(refused, "new connections are still accepted");
+
+    upload.write_all(second_half).await.unwrap();
+    let mut response = String::new();
+    upload.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    tokio::time::timeout(std::time::Duration::from_secs(5), serving)
+        .await
+        .expect("serving goes on after the upload finished")
+        .unwrap()
+        .unwrap();
+    let images = server.moments.indexer().index(None).await.unwrap();
+    assert!(images[0]
+        .path
+        .to_str()
+        .unwrap()
+        .ends_with("interrupted.png"));
+}
+
+#[tokio::test]
+async fn admin_routes_only_exist_for_the_admin_secret() {
+    const ADMIN_SECRET: &str = "admin-secret";
+    let server = TestServer::start_with_arguments(
+        |storage| std::fs::write(storage.join("moderated.png"), png(75)).unwrap(),
+        &["--admin-secret", ADMIN_SECRET, "--secret-in-path"],
+    )
+    .await;
+    let send = |method: &str, path: String, bearer: Option<&str>| {
+        let mut request = Request::builder().method(method).uri(path);
+        if let Some(bearer) = bearer {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {bearer}"));
+        }
+        server.send(request.body(Body::empty()).unwrap())
+    };
+
+    for (secret, admin) in [(SECRET, false), (ADMIN_SECRET, true), ("guessed", false)] {
+        for (method, path) in [
+            ("GET", "/admin/stats"),
+            ("GET", "/admin/images"),
+            ("GET", "/admin/connections"),
+            ("POST", "/admin/reconcile"),
+        ] {
+            let expected = match admin {
+                true => StatusCode::OK,
+                // as if they did not exist, also for guests knowing a secret
+                false => StatusCode::NOT_FOUND,
+            };
+            let response = send(method, path.to_string(), Some(secret)).await;
+            assert_eq!(response.status(), expected, "{method} {path} with {secret}");
+            let response = send(method, format!("/{secret}{path}"), None).await;
+            assert_eq!(response.status(), expected, "{method} /{secret}{path}");
+        }
+
+        // guest routes take both secrets
+        let expected = match secret {
+            "guessed" => StatusCode::UNAUTHORIZED,
+            _ => StatusCode::OK,
+        };
+        let response = send("GET", "/recommend".to_string(), Some(secret)).await;
+        assert_eq!(response.status(), expected, "/recommend with {secret}");
+        if secret != "guessed" {
+            let response = send("GET", format!("/{secret}/recommend"), None).await;
+            assert_eq!(response.status(), StatusCode::OK, "/{secret}/recommend");
+        }
+    }
+    let response = send("GET", "/admin/stats".to_string(), None).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn secrets_and_quality_change_without_a_restart() {
+    let configuration = tempfile::tempdir().unwrap();
+    let config = configuration.path().join("moments.json");
+    std::fs::write(&config, "{}").unwrap();
+    let server =
+        TestServer::start_with_arguments(|_| {}, &["--config", config.to_str().unwrap()]).await;
+    let server = &server;
+    let send = |method: &str, path: &str, bearer: &str, body: Option<Value>| {
+        let request = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(header::AUTHORIZATION, format!("Bearer {bearer}"));
+        let request = match body {
+            Some(body) => request
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string())),
+            None => request.body(Body::empty()),
+        };
+        server.send(request.unwrap())
+    };
+    let cached_size = |name: &'static str, image: Vec<u8>| async move {
+        let response = server.upload(name, image).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let images = server.moments.indexer().index(None).await.unwrap();
+        let image = images
+            .iter()
+            .find(|image| image.path.to_str().unwrap().ends_with(name))
+            .unwrap();
+        let path = format!("/images/{}", image.cached_path.display());
+        let response = server.send(authenticated_get(&path)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap()
+            .len()
+    };
+
+    // a guest secret handed out for the evening and a second admin
+    for (name, value, role) in [
+        ("evening", "guest-secret", "guest"),
+        ("helper", "helper-secret", "admin"),
+    ] {
+        let secret = serde_json::json!({ "name": name, "value": value, "role": role });
+        let response = send("POST", "/admin/secrets", SECRET, Some(secret)).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+    for (path, secret, expected) in [
+        ("/recommend", "guest-secret", StatusCode::OK),
+        ("/admin/stats", "guest-secret", StatusCode::NOT_FOUND),
+        ("/admin/stats", "helper-secret", StatusCode::OK),
+    ] {
+        let response = send("GET", path, secret, None).await;
+        assert_eq!(response.status(), expected, "{path} with {secret}");
+    }
+    // leaked, rotated away
+    let response = send("DELETE", "/admin/secrets/evening", "helper-secret", None).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = send("GET", "/recommend", "guest-secret", None).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = send("DELETE", "/admin/secrets/helper", SECRET, None).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = send("GET", "/admin/stats", "helper-secret", None).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let before = cached_size("before.png", noise_png(64, 48)).await;
+    std::fs::write(&config, r#"{"jpeg_image_quality": 30}"#).unwrap();
+    let response = send("POST", "/admin/reload", SECRET, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let report: Value = serde_json::from_slice(&body).unwrap();
+    assert!(report["restart_required"].as_array().unwrap().is_empty());
+    let applied = report["applied"].as_array().unwrap();
+    assert!(applied
+        .iter()
+        .any(|change| change.as_str().unwrap().contains("jpeg_image_quality")));
+    let after = cached_size("after.png", noise_png(48, 64)).await;
+    assert!(
+        after * 2 < before,
+        "{after} bytes at quality 30, {before} at 80"
+    );
+}
+
+#[tokio::test]
+async fn secrets_changed_at_runtime_survive_reloads() {
+    let configuration = tempfile::tempdir().unwrap();
+    let config = configuration.path().join("moments.json");
+    std::fs::write(&config, "{}").unwrap();
+    let server =
+        TestServer::start_with_arguments(|_| {}, &["--config", config.to_str().unwrap()]).await;
+    let send = |method: &str, path: &str, bearer: &str, body: Option<Value>| {
+        let request = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(header::AUTHORIZATION, format!("Bearer {bearer}"));
+        let request = match body {
+            Some(body) => request
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string())),
+            None => request.body(Body::empty()),
+        };
+        server.send(request.unwrap())
+    };
+    for (name, value, role) in [
+        ("evening", "guest-secret", "guest"),
+        ("helper", "helper-secret", "admin"),
+    ] {
+        let secret = serde_json::json!({ "name": name, "value": value, "role": role });
+        let response = send("POST", "/admin/secrets", SECRET, Some(secret)).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+    // the configured secret leaked
+    let response = send("DELETE", "/admin/secrets/default", "helper-secret", None).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let response = send("POST", "/admin/reload", "helper-secret", None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    for (path, secret, expected) in [
+        ("/recommend", "guest-secret", StatusCode::OK),
+        ("/admin/stats", "helper-secret", StatusCode::OK),
+        ("/recommend", SECRET, StatusCode::UNAUTHORIZED),
+    ] {
+        let response = send("GET", path, secret, None).await;
+        assert_eq!(response.status(), expected, "{path} with {secret}");
+    }
+}
+
+#[tokio::test]
+async fn maintenance_port_takes_admin_and_health_routes() {
+    let server = TestServer::start_with_arguments(|_| {}, &["--maintenance-port", "0"]).await;
+    for path in ["/healthz", "/admin/stats", "/admin/connections"] {
+        let response = server.send(authenticated_get(path)).await;
+        assert_eq!(
+            response.status(),
+            StatusCode::NOT_FOUND,
+            "{path} on public port"
+        );
+    }
+    let response = server.send(authenticated_get("/version")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let maintenance = build_maintenance_router(&server.moments);
+    for path in ["/healthz", "/admin/stats", "/admin/connections"] {
+        let response = maintenance
+            .clone()
+            .oneshot(authenticated_get(path))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.status(),
+            StatusCode::OK,
+            "{path} on maintenance port"
+        );
+    }
+    let response = maintenance
+        .oneshot(authenticated_get("/version"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn uploaded_file_names_are_reduced_to_their_last_part() {
+    let server = TestServer::start().await;
+    for (seed, file_name) in [
+        (7, "x/../../escaped.png"),
+        (8, "..\\..\\windows.png"),
+        (9, "/etc/passwd"),
+        (10, ".."),
+    ] {
+        let response = server.upload(file_name, png(seed)).await;
+        assert_eq!(response.status(), StatusCode::OK, "{file_name}");
+    }
+
+    let mut names: Vec<_> = server
+        .moments
+        .indexer()
+        .index(None)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|image| {
+            let name = image.path.to_str().unwrap().to_string();
+            assert_eq!(image.path.components().count(), 1, "{name}");
+            name.split_once('_').map(|(_, name)| name.to_string())
+        })
+        .collect();
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            None,
+            Some("escaped.png".to_string()),
+            Some("passwd".to_string()),
+            Some("windows.png".to_string())
+        ]
+    );
+}
+
+#[tokio::test]
+async fn secrets_are_redacted_from_logged_paths() {
+    let logs = captured_logs();
+    let server = TestServer::start().await;
+    for path in [
+        format!("/redaction/{SECRET}/version"),
+        format!("/redaction/version?token={SECRET}"),
+        "/redaction/version?token=guessed".to_string(),
+        format!("/redaction/version?other={SECRET}"),
+        "/redaction/upload/invited?invite=guessed".to_string(),
+    ] {
+        server
+            .send(Request::get(path).body(Body::empty()).unwrap())
+            .await;
+    }
+
+    let logged: Vec<_> = logs
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|line| line.contains("/redaction/"))
+        .cloned()
+        .collect();
+    assert_eq!(logged.len(), 5, "{logged:?}");
+    for line in &logged {
+        assert!(!line.contains(SECRET), "{line}");
+        assert!(!line.contains("guessed"), "{line}");
+        assert!(line.contains("<redacted>"), "{line}");
+    }
+}
+
+#[tokio::test]
+async fn image_paths_cannot_escape_the_directories() {
+    let server = TestServer::start_with(|storage| {
+        std::fs::write(storage.join("inside.png"), png(4)).unwrap();
+    })
+    .await;
+    let outside = server.directory.path().join("outside.png");
+    std::fs::write(&outside, png(5)).unwrap();
+    let storage = server.directory.path().join("storage");
+    std::os::unix::fs::symlink(&outside, storage.join("link.png")).unwrap();
+
+    for path in [
+        "/images/..%2f..%2fetc%2fpasswd",
+        "/images/%2e%2e%2fstorage%2finside.png",
+        "/images/..%2Foutside.png",
+        "/images/%2Fetc%2Fpasswd",
+        "/images/link.png",
+    ] {
+        let response = server.send(authenticated_get(path)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "{path}");
+    }
+    let response = server.send(authenticated_get("/images/inside.png")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn bookkeeping_in_the_cache_is_not_served() {
+    let server = TestServer::start_with_arguments(
+        |storage| std::fs::write(storage.join("inside.png"), png(4)).unwrap(),
+        &["--upload-code-period", "60"],
+    )
+    .await;
+    let key = server
+        .directory
+        .path()
+        .join("cache/.moments/upload-code-key");
+    assert!(key.exists());
+
+    for path in [
+        "/images/.moments/upload-code-key",
+        "/images/.moments/sources.json",
+        "/images/%2emoments/upload-code-key",
+        "/images/.moments/upload-code-key?w=400",
+    ] {
+        let response = server.send(authenticated_get(path)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "{path}");
+    }
+    let response = server
+        .send(
+            Request::get(format!("/images/.moments/upload-code-key?token={SECRET}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn uploaded_file_names_cannot_escape_the_cache() {
+    let server = TestServer::start().await;
+    server.upload("x/../../escaped.png", png(6)).await;
+
+    let mut entries: Vec<_> = std::fs::read_dir(server.directory.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    entries.sort();
+    assert_eq!(entries, ["cache", "storage"]);
+    for entry in std::fs::read_dir(server.directory.path().join("cache")).unwrap() {
+        let entry = entry.unwrap();
+        let name = entry.file_name().into_string().unwrap();
+        assert!(
+            !entry.file_type().unwrap().is_dir()
+                || name == ".moments"
+                || name.parse::<u32>().is_ok(),
+            "{name}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn large_derivative_is_streamed() {
+    let server = TestServer::start_with_arguments(
+        |storage| std::fs::write(storage.join("large.png"), noise_png(2400, 1600)).unwrap(),
+        &[
+            "--max-cached-image-size",
+            "2400",
+            "--jpeg-image-quality",
+            "100",
+        ],
+    )
+    .await;
+    let response = server.send(authenticated_get("/images/large.png")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let length: usize = response.headers()[header::CONTENT_LENGTH]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(length > 2 * 1024 * 1024, "derivative of {length} bytes");
+
+    let mut chunks = response.into_body().into_data_stream();
+    let (mut received, mut largest_chunk) = (0, 0);
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.unwrap();
+        received += chunk.len();
+        largest_chunk = largest_chunk.max(chunk.len());
+    }
+    assert_eq!(received, length);
+    assert!(
+        largest_chunk < length / 16,
+        "chunks of up to {largest_chunk} bytes"
+    );
+}
+
+#[tokio::test]
+async fn lazy_cache_generates_derivatives_on_first_request() {
+    let server = TestServer::start_with_arguments(
+        |storage| std::fs::write(storage.join("new.png"), png(6)).unwrap(),
+        &["--lazy-cache"],
+    )
+    .await;
+    let derivative = server.directory.path().join("cache").join("new.png");
+    assert!(!derivative.exists());
+
+    let responses = futures_util::future::join_all(
+        (0..20).map(|_| server.send(authenticated_get("/images/new.png"))),
+    )
+    .await;
+    let mut bodies = Vec::new();
+    for response in responses {
+        assert_eq!(response.status(), StatusCode::OK);
+        bodies.push(to_bytes(response.into_body(), usize::MAX).await.unwrap());
+    }
+    assert!(bodies.windows(2).all(|pair| pair[0] == pair[1]));
+    assert_eq!(std::fs::read(&derivative).unwrap(), bodies[0]);
+}
+
+#[tokio::test]
+async fn sizes_are_generated_on_demand_from_the_allowed_ones() {
+    let server = TestServer::start_with_arguments(
+        |storage| std::fs::write(storage.join("sized.png"), png(7)).unwrap(),
+        &["--on-demand-sizes", "16"],
+    )
+    .await;
+    let variant = server.directory.path().join("cache/16/sized.png");
+    assert!(!variant.exists());
+
+    let response = server
+        .send(authenticated_get("/images/sized.png?w=16"))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers()[header::CACHE_CONTROL]
+        .to_str()
+        .unwrap()
+        .contains("immutable"));
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let image = image::load_from_memory(&body).unwrap();
+    assert_eq!((image.width(), image.height()), (16, 12));
+    assert!(variant.is_file());
+
+    let response = server.send(authenticated_get("/images/sized.png")).await;
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let image = image::load_from_memory(&body).unwrap();
+    assert_eq!((image.width(), image.height()), (64, 48));
+
+    let response = server
+        .send(authenticated_get("/images/sized.png?w=17"))
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert!(!server.directory.path().join("cache/17").exists());
+}
+
+#[tokio::test]
+async fn images_generated_on_demand_are_rotated_by_their_orientation() {
+    let server = TestServer::start_with_arguments(
+        |storage| std::fs::write(storage.join("portrait.jpg"), jpeg_with_orientation(6)).unwrap(),
+        &["--lazy-cache", "--on-demand-sizes", "16"],
+    )
+    .await;
+    for (uri, size) in [
+        ("/images/portrait.jpg", (48, 64)),
+        ("/images/portrait.jpg?w=16", (12, 16)),
+    ] {
+        let response = server.send(authenticated_get(uri)).await;
+        assert_eq!(response.status(), StatusCode::OK, "{uri}");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let image = image::load_from_memory(&body).unwrap().into_rgb8();
+        assert_eq!(image.dimensions(), size, "{uri}");
+        // turned clockwise, the left half is on top
+        let (width, height) = size;
+        let Rgb([red, _, blue]) = *image.get_pixel(width / 2, height / 8);
+        assert!(red > 200 && blue < 60, "{uri}");
+        let Rgb([red, _, blue]) = *image.get_pixel(width / 2, height - height / 8);
+        assert!(red < 60 && blue > 200, "{uri}");
+    }
+}
+
+#[tokio::test]
+async fn small_jpegs_are_cached_without_reencoding() {
+    let upright = jpeg_with_orientation(1);
+    let stored = upright.clone();
+    let server = TestServer::start_with_arguments(
+        |storage| {
+            std::fs::write(storage.join("upright.jpg"), stored).unwrap();
+            std::fs::write(storage.join("sideways.jpg"), jpeg_with_orientation(6)).unwrap();
+        },
+        &["--lazy-cache"],
+    )
+    .await;
+    let response = server.send(authenticated_get("/images/upright.jpg")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, upright);
+    let derivative = server.directory.path().join("cache/upright.jpg");
+    assert_eq!(std::fs::read(derivative).unwrap(), upright);
+
+    // only turned upright, at the size of the source
+    let response = server.send(authenticated_get("/images/sideways.jpg")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_ne!(body, jpeg_with_orientation(6));
+    let image = image::load_from_memory(&body).unwrap().into_rgb8();
+    assert_eq!(image.dimensions(), (48, 64));
+    let Rgb([red, _, blue]) = *image.get_pixel(24, 8);
+    assert!(red > 200 && blue < 60);
+}
+
+#[tokio::test]
+async fn generated_images_answer_conditional_requests() {
+    let server = TestServer::start_with_arguments(
+        |storage| std::fs::write(storage.join("revalidated.png"), png(8)).unwrap(),
+        &["--lazy-cache"],
+    )
+    .await;
+    let response = server
+        .send(authenticated_get("/images/revalidated.png"))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let entity_tag = response.headers()[header::ETAG].clone();
+    let last_modified = response.headers()[header::LAST_MODIFIED].clone();
+    let length = response.headers()[header::CONTENT_LENGTH].clone();
+
+    let conditional = |name, value| {
+        Request::get("/images/revalidated.png")
+            .header(header::AUTHORIZATION, format!("Bearer {SECRET}"))
+            .header(name, value)
+            .body(Body::empty())
+            .unwrap()
+    };
+    for request in [
+        conditional(header::IF_NONE_MATCH, entity_tag.clone()),
+        conditional(header::IF_MODIFIED_SINCE, last_modified.clone()),
+    ] {
+        let response = server.send(request).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], entity_tag);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(body.is_empty());
+    }
+    let response = server
+        .send(conditional(
+            header::IF_NONE_MATCH,
+            "\"other\"".parse().unwrap(),
+        ))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = server
+        .send(
+            Request::head("/images/revalidated.png")
+                .header(header::AUTHORIZATION, format!("Bearer {SECRET}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::ETAG], entity_tag);
+    assert_eq!(response.headers()[header::LAST_MODIFIED], last_modified);
+    assert_eq!(response.headers()[header::CONTENT_LENGTH], length);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert!(body.is_empty());
+}
+
+#[tokio::test]
+async fn cached_images_are_immutable_and_revalidated_without_a_body() {
+    let server = TestServer::start_with(|storage| {
+        std::fs::write(storage.join("thumbnail.png"), png(81)).unwrap();
+    })
+    .await;
+    let response = server
+        .send(authenticated_get("/images/thumbnail.png"))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()[header::CACHE_CONTROL],
+        "public, max-age=31536000, immutable"
+    );
+    let entity_tag = response.headers()[header::ETAG].clone();
+    assert!(
+        !entity_tag.to_str().unwrap().starts_with("W/"),
+        "{entity_tag:?}"
+    );
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert!(!body.is_empty());
+
+    // what a kiosk sends on a page refresh
+    let response = server
+        .send(
+            Request::get("/images/thumbnail.png")
+                .header(header::AUTHORIZATION, format!("Bearer {SECRET}"))
+                .header(header::IF_NONE_MATCH, entity_tag.clone())
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(response.headers()[header::ETAG], entity_tag);
+    assert_eq!(
+        response.headers()[header::CACHE_CONTROL],
+        "public, max-age=31536000, immutable"
+    );
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert!(body.is_empty());
+
+    // errors must not be cached for a year
+    let response = server.send(authenticated_get("/images/missing.png")).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert!(response.headers().get(header::CACHE_CONTROL).is_none());
+}
+
+#[tokio::test]
+async fn concurrent_cache_misses_process_the_image_once() {
+    // with a single worker, the broken image waits until all requests for it arrived
+    let server = TestServer::start_with_arguments(
+        |storage| {
+            let image = noise_png(1200, 900);
+            std::fs::write(storage.join("popular.png"), &image).unwrap();
+            std::fs::write(storage.join("broken.png"), &image[..image.len() / 2]).unwrap();
+        },
+        &["--cache-workers", "1", "--max-cached-image-size", "200"],
+    )
+    .await;
+    let processed = server.processed().await;
+    let requests = (0..100).map(|index| {
+        let path = match index % 2 {
+            0 => "/images/popular.png",
+            _ => "/images/broken.png",
+        };
+        server.send(authenticated_get(path))
+    });
+    let responses = futures_util::future::join_all(requests).await;
+    for (index, response) in responses.into_iter().enumerate() {
+        assert_eq!(response.status(), StatusCode::OK);
+        // each waiter for the broken image got the failure and answers with a placeholder
+        let cache_control = &response.headers()[header::CACHE_CONTROL];
+        assert_eq!(cache_control == "no-store", index % 2 == 1);
+    }
+    assert_eq!(server.processed().await, processed + 2);
+}
+
+#[tokio::test]
+async fn broken_and_vanished_images_are_replaced_by_a_placeholder() {
+    let server = TestServer::start_with_arguments(
+        |storage| {
+            let image = png(10);
+            std::fs::write(storage.join("broken.png"), &image[..image.len() / 2]).unwrap();
+            std::fs::write(storage.join("vanished.png"), png(11)).unwrap();
+        },
+        // deletions are only noticed when serving
+        &["--watch-mode", "off", "--placeholder-color", "#2060a0"],
+    )
+    .await;
+    std::fs::remove_file(server.directory.path().join("storage/vanished.png")).unwrap();
+
+    for path in ["/images/broken.png", "/images/vanished.png"] {
+        let response = server.send(authenticated_get(path)).await;
+        assert_eq!(response.status(), StatusCode::OK, "{path}");
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "no-store");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let image = image::load_from_memory(&body).unwrap().into_rgb8();
+        let Rgb([red, green, blue]) = *image.get_pixel(0, 0);
+        assert!(red.abs_diff(0x20) < 8 && green.abs_diff(0x60) < 8 && blue.abs_diff(0xa0) < 8);
+        if path == "/images/broken.png" {
+            // the header survived, so the placeholder has the size of the derivative
+            assert_eq!(image.dimensions(), (64, 48));
+        }
+    }
+
+    // the vanished image is taken out of the index, afterwards it is unknown
+    for _ in 0..100 {
+        let images = server.moments.indexer().index(None).await.unwrap();
+        if images.len() == 1 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    let images = server.moments.indexer().index(None).await.unwrap();
+    assert_eq!(images.len(), 1);
+    let response = server.send(authenticated_get("/images/vanished.png")).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = server.send(authenticated_get("/images/unknown.png")).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn truncated_derivatives_are_regenerated() {
+    let server = TestServer::start_with(|storage| {
+        std::fs::write(storage.join("truncated.png"), png(61)).unwrap();
+    })
+    .await;
+    let reconcile = || async {
+        let request = Request::post("/admin/reconcile")
+            .header(header::AUTHORIZATION, format!("Bearer {SECRET}"))
+            .body(Body::empty())
+            .unwrap();
+        let response = server.send(request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice::<Value>(&body).unwrap()["derivatives_created"].clone()
+    };
+    // caching at startup may still be running
+    reconcile().await;
+    let derivative = server.directory.path().join("cache/truncated.png");
+    let complete = std::fs::read(&derivative).unwrap();
+    std::fs::write(&derivative, &complete[..complete.len() / 2]).unwrap();
+
+    assert_eq!(reconcile().await, serde_json::json!(["truncated.png"]));
+    assert_eq!(std::fs::read(&derivative).unwrap(), complete);
+}
+
+#[tokio::test]
+async fn derivatives_are_regenerated_when_their_original_changes() {
+    let server = TestServer::start_with_arguments(
+        |storage| std::fs::write(storage.join("recropped.png"), png(62)).unwrap(),
+        &["--watch-mode", "inotify", "--settle-time", "50"],
+    )
+    .await;
+    // caching at startup may still be running
+    let request = Request::post("/admin/reconcile")
+        .header(header::AUTHORIZATION, format!("Bearer {SECRET}"))
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(server.send(request).await.status(), StatusCode::OK);
+    let original = server.directory.path().join("storage/recropped.png");
+    let derivative = server.directory.path().join("cache/recropped.png");
+    let cached = std::fs::read(&derivative).unwrap();
+    let processed = server.processed().await;
+    let mut subscription = server
+        .moments
+        .indexer()
+        .subscribe(Some(0), None)
+        .await
+        .unwrap();
+
+    // touched, e.g. synced again without changes, is not worth encoding again
+    std::fs::File::options()
+        .write(true)
+        .open(&original)
+        .unwrap()
+        .set_modified(SystemTime::now() + std::time::Duration::from_secs(3600))
+        .unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    assert!(subscription.changes.try_recv().is_err());
+    assert_eq!(server.processed().await, processed);
+    assert_eq!(std::fs::read(&derivative).unwrap(), cached);
+
+    std::fs::write(&original, png(200)).unwrap();
+    let image = loop {
+        let change = subscription.changes.recv().await.unwrap();
+        let change = serde_json::to_value(&change.change).unwrap();
+        if let Some(image) = change.get("Addition") {
+            break image["image"].clone();
+        }
+    };
+    let response = server
+        .send(authenticated_get(&format!(
+            "/images/{}",
+            image["cached_path"].as_str().unwrap()
+        )))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let Rgb([_, _, blue]) = *image::load_from_memory(&body)
+        .unwrap()
+        .into_rgb8()
+        .get_pixel(10, 10);
+    assert!(blue.abs_diff(200) < 10, "blue of {blue}");
+}
+
+#[tokio::test]
+async fn derivatives_named_png_are_served_as_jpeg() {
+    let server = TestServer::start_with(|storage| {
+        std::fs::write(storage.join("Party Time.png"), png(12)).unwrap();
+    })
+    .await;
+    // generated on the first request, from the cache on the second
+    for _ in 0..2 {
+        let response = server
+            .send(authenticated_get("/images/Party%20Time.png"))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/jpeg");
+        assert_eq!(
+            response.headers()[header::CONTENT_DISPOSITION],
+            "inline; filename=\"Party Time.jpg\"; filename*=UTF-8''Party%20Time%2Ejpg"
+        );
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(
+            image::guess_format(&body).unwrap(),
+            ImageFormat::Jpeg,
+            "the cached file keeps the name of the original"
+        );
+    }
+    assert!(server
+        .directory
+        .path()
+        .join("cache/Party Time.png")
+        .is_file());
+}
+
+#[tokio::test]
+async fn unwritable_cache_is_answered_with_an_error() {
+    // a file where the directory of a size belongs, which even root cannot write into, unlike a
+    // read-only directory
+    let server = TestServer::start_with_arguments(
+        |storage| std::fs::write(storage.join("blocked.png"), png(13)).unwrap(),
+        &["--cache-sizes", "32"],
+    )
+    .await;
+    std::fs::write(server.directory.path().join("cache/32"), b"in the way").unwrap();
+
+    for _ in 0..2 {
+        let response = server.send(authenticated_get("/images/blocked.png")).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        // neither cached by browsers nor replaced by a placeholder
+        assert!(!response.headers().contains_key(header::CACHE_CONTROL));
+    }
+    let response = server.send(authenticated_get("/healthz")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn originals_are_served_in_ranges() {
+    let original = png(9);
+    let length = original.len();
+    let server = TestServer::start_with(|storage| {
+        std::fs::write(storage.join("ranged.png"), &original).unwrap()
+    })
+    .await;
+    let ranged = |range: &str, if_range: Option<&str>| {
+        let mut request = Request::get("/originals/ranged.png")
+            .header(header::AUTHORIZATION, format!("Bearer {SECRET}"))
+            .header(header::RANGE, range);
+        if let Some(if_range) = if_range {
+            request = request.header(header::IF_RANGE, if_range);
+        }
+        request.body(Body::empty()).unwrap()
+    };
+
+    let response = server.send(ranged("bytes=10-", None)).await;
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        response.headers()[header::CONTENT_RANGE],
+        format!("bytes 10-{}/{length}", length - 1)
+    );
+    let last_modified = response.headers()[header::LAST_MODIFIED].clone();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, original[10..]);
+
+    let response = server.send(ranged("bytes=-10", None)).await;
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        response.headers()[header::CONTENT_RANGE],
+        format!("bytes {}-{}/{length}", length - 10, length - 1)
+    );
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, original[length - 10..]);
+
+    let response = server
+        .send(ranged(&format!("bytes={}-", length + 100), None))
+        .await;
+    assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(
+        response.headers()[header::CONTENT_RANGE],
+        format!("bytes */{length}")
+    );
+
+    let response = server
+        .send(ranged("bytes=0-9", Some(last_modified.to_str().unwrap())))
+        .await;
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, original[..10]);
+
+    for if_range in ["Thu, 01 Jan 1970 00:00:00 GMT", "\"some-tag\""] {
+        let response = server.send(ranged("bytes=0-9", Some(if_range))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, original);
+    }
+}
+
+#[tokio::test]
+async fn originals_cannot_escape_the_storage() {
+    let server = TestServer::start_with(|storage| {
+        std::fs::write(storage.join("inside.png"), png(4)).unwrap();
+        std::fs::create_dir(storage.join("album")).unwrap();
+    })
+    .await;
+    let outside = server.directory.path().join("outside.png");
+    std::fs::write(&outside, png(5)).unwrap();
+    let storage = server.directory.path().join("storage");
+    std::os::unix::fs::symlink(&outside, storage.join("link.png")).unwrap();
+    std::os::unix::fs::symlink(server.directory.path(), storage.join("album/up")).unwrap();
+    std::os::unix::fs::symlink(storage.join("inside.png"), storage.join("album/inside.png"))
+        .unwrap();
+
+    for path in [
+        "/originals/link.png",
+        "/originals/album/up/outside.png",
+        "/originals/album%2Fup%2Foutside.png",
+        "/originals/..%2Foutside.png",
+    ] {
+        let response = server.send(authenticated_get(path)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "{path}");
+    }
+    // links staying inside are followed
+    for path in ["/originals/inside.png", "/originals/album/inside.png"] {
+        let response = server.send(authenticated_get(path)).await;
+        assert_eq!(response.status(), StatusCode::OK, "{path}");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, png(4));
+    }
+}
+
+#[tokio::test]
+async fn missing_images_are_remembered_until_added() {
+    let server = TestServer::start_with_arguments(|_| {}, &["--missing-image-ttl", "3600"]).await;
+    for _ in 0..3 {
+        let response = server.send(authenticated_get("/images/later.png")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+    let statistics = server.statistics().await;
+    assert_eq!(statistics["missing_images"]["hits"], 2);
+    assert_eq!(statistics["missing_images"]["paths"], 1);
+
+    std::fs::write(server.directory.path().join("storage/later.png"), png(13)).unwrap();
+    for _ in 0..500 {
+        let images = server.moments.indexer().index(None).await.unwrap();
+        if !images.is_empty() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    let response = server.send(authenticated_get("/images/later.png")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn least_recently_shown_images_are_recommended() {
+    let server = TestServer::start_with(|storage| {
+        for (seed, name) in [(20, "first.png"), (21, "second.png"), (22, "third.png")] {
+            std::fs::write(storage.join(name), png(seed)).unwrap();
+        }
+    })
+    .await;
+    for name in ["first.png", "second.png", "third.png"] {
+        let response = server
+            .send(authenticated_get(&format!("/images/{name}")))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let server = &server;
+    let recommend = |count| async move {
+        let response = server
+            .send(authenticated_get(&format!("/recommend?count={count}")))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let images: Vec<Value> = serde_json::from_slice(&body).unwrap();
+        images
+            .into_iter()
+            .map(|image| image["hash"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+    let report = |hashes: Vec<String>| async move {
+        let response = server
+            .send(
+                Request::post("/shown")
+                    .header(header::AUTHORIZATION, format!("Bearer {SECRET}"))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(
+                        serde_json::json!({ "hashes": hashes }).to_string(),
+                    ))
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    };
+
+    let shown = recommend(2).await;
+    assert_eq!(shown.len(), 2);
+    report(shown.clone()).await;
+    let next = recommend(1).await;
+    assert_eq!(next.len(), 1);
+    assert!(!shown.contains(&next[0]));
+    report(next.clone()).await;
+    let all = recommend(10).await;
+    assert_eq!(all.len(), 3);
+    assert_eq!(all[2], next[0]);
+
+    let sources =
+        std::fs::read_to_string(server.directory.path().join("cache/.moments/sources.json"))
+            .unwrap();
+    assert_eq!(sources.matches("last_shown").count(), 3);
+}
+
+#[tokio::test]
+async fn random_images_avoid_recently_shown_ones() {
+    let server = TestServer::start_with(|storage| {
+        for (seed, name) in [(23, "one.png"), (24, "two.png"), (25, "three #3.png")] {
+            std::fs::write(storage.join(name), png(seed)).unwrap();
+        }
+    })
+    .await;
+    let random = |query: String| {
+        let server = &server;
+        async move {
+            let response = server
+                .send(authenticated_get(&format!("/random{query}")))
+                .await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            serde_json::from_slice::<Value>(&body).unwrap()
+        }
+    };
+    let images = server.moments.indexer().index(None).await.unwrap();
+    let hash = |name: &str| {
+        let image = images
+            .iter()
+            .find(|image| image.path == Path::new(name))
+            .unwrap();
+        serde_json::to_value(image).unwrap()["hash"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    };
+
+    let not = format!("?not={},{}", hash("one.png"), hash("two.png"));
+    for _ in 0..10 {
+        let image = random(not.clone()).await;
+        assert_eq!(image["path"], "three #3.png");
+        assert_eq!(image["url"], "/images/three%20%233.png");
+        // not generated yet
+        assert!(image.get("width").is_none());
+    }
+    let response = server
+        .send(authenticated_get("/images/three%20%233.png"))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let image = random(not).await;
+    assert_eq!(
+        (image["width"].clone(), image["height"].clone()),
+        (64.into(), 48.into())
+    );
+
+    // with all of them excluded one is repeated
+    let all = format!(
+        "?fresh=true&not={},{},{}",
+        hash("one.png"),
+        hash("two.png"),
+        hash("three #3.png")
+    );
+    let image = random(all).await;
+    assert!(image["path"].is_string());
+
+    let response = server.send(authenticated_get("/random?not=nonsense")).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn kiosks_highlight_different_images_in_turns() {
+    let server = TestServer::start_with_arguments(
+        |storage| {
+            for (seed, name) in [(26, "red.png"), (27, "green.png"), (28, "blue.png")] {
+                std::fs::write(storage.join(name), png(seed)).unwrap();
+            }
+        },
+        &["--highlight-interval", "1"],
+    )
+    .await;
+    for name in ["red.png", "green.png", "blue.png"] {
+        let response = server
+            .send(authenticated_get(&format!("/images/{name}")))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let app = build_router(&server.moments);
+    tokio::spawn(async move {
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .unwrap();
+    });
+
+    let url = format!("ws://{address}/index?token={SECRET}&protocol=2&highlights=true");
+    let (mut first, _) = connect_async(&url).await.unwrap();
+    let (mut second, _) = connect_async(&url).await.unwrap();
+    let mut highlights = Vec::new();
+    for socket in [&mut first, &mut second] {
+        let highlight = next_highlight(socket, std::time::Duration::from_secs(5)).await;
+        highlights.push(highlight.unwrap());
+    }
+    assert_ne!(
+        highlights[0]["image"]["hash"],
+        highlights[1]["image"]["hash"]
+    );
+    assert_ne!(highlights[0]["starts_at"], highlights[1]["starts_at"]);
+    assert_ne!(highlights[0]["region"], highlights[1]["region"]);
+    assert_eq!(highlights[0]["duration_ms"], 1000);
+
+    let acknowledgement = serde_json::json!({
+        "type": "highlight_shown",
+        "id": highlights[0]["id"],
+    });
+    first
+        .send(Message::Text(acknowledgement.to_string()))
+        .await
+        .unwrap();
+    let sources = server.directory.path().join("cache/.moments/sources.json");
+    for _ in 0..100 {
+        let contents = std::fs::read_to_string(&sources).unwrap_or_default();
+        if contents.contains("last_shown") {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    let contents = std::fs::read_to_string(&sources).unwrap();
+    assert_eq!(contents.matches("last_shown").count(), 1);
+}
+
+#[tokio::test]
+async fn pinned_images_are_recommended_regularly() {
+    let server = TestServer::start_with_arguments(
+        |storage| {
+            std::fs::write(storage.join("crowd.png"), png(29)).unwrap();
+            std::fs::write(storage.join("group.png"), png(30)).unwrap();
+        },
+        &["--pin-interval", "1", "--fresh-window", "0"],
+    )
+    .await;
+    for name in ["crowd.png", "group.png"] {
+        let response = server
+            .send(authenticated_get(&format!("/images/{name}")))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let images = server.moments.indexer().index(None).await.unwrap();
+    let hash = |name: &str| {
+        let image = images
+            .iter()
+            .find(|image| image.path == Path::new(name))
+            .unwrap();
+        serde_json::to_value(image).unwrap()["hash"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    };
+    let (crowd, group) = (hash("crowd.png"), hash("group.png"));
+    let recommended = || async {
+        let response = server.send(authenticated_get("/recommend?count=1")).await;
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let images: Vec<Value> = serde_json::from_slice(&body).unwrap();
+        images[0]["hash"].as_str().unwrap().to_string()
+    };
+    let pin = |method: &str, hash: &str| {
+        Request::builder()
+            .method(method)
+            .uri(format!("/admin/pin/{hash}"))
+            .header(header::AUTHORIZATION, format!("Bearer {SECRET}"))
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let mut subscription = server
+        .moments
+        .indexer()
+        .subscribe(Some(0), None)
+        .await
+        .unwrap();
+    let response = server.send(pin("POST", &group)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let image: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(image["pinned"], true);
+    let change = subscription.changes.recv().await.unwrap();
+    let change = serde_json::to_value(&change.change).unwrap();
+    assert_eq!(change["Update"]["image"]["hash"], group.as_str());
+    assert_eq!(change["Update"]["image"]["pinned"], true);
+    let sources =
+        std::fs::read_to_string(server.directory.path().join("cache/.moments/sources.json"))
+            .unwrap();
+    assert_eq!(sources.matches("\"pinned\":true").count(), 1);
+
+    assert_eq!(recommended().await, group);
+    let response = server
+        .send(
+            Request::post("/shown")
+                .header(header::AUTHORIZATION, format!("Bearer {SECRET}"))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    serde_json::json!({ "hashes": [group] }).to_string(),
+                ))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(recommended().await, crowd);
+    // due again after the pin interval, although the other image was never shown
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    assert_eq!(recommended().await, group);
+
+    let response = server.send(pin("DELETE", &group)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(recommended().await, crowd);
+    let response = server
+        .send(pin("POST", "00000000000000000000000000000000"))
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn archived_gallery_refuses_changes() {
+    let server = TestServer::start_with_arguments(
+        |storage| std::fs::write(storage.join("archived.png"), png(55)).unwrap(),
+        &["--read-only"],
+    )
+    .await;
+    let image = indexed(&server, "archived.png").await;
+    let hash = serde_json::to_value(&image).unwrap()["hash"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    for (method, uri) in [
+        ("POST", format!("/admin/pin/{hash}")),
+        ("DELETE", format!("/admin/pin/{hash}")),
+        ("POST", format!("/admin/hide/{hash}")),
+        ("DELETE", format!("/admin/hide/{hash}")),
+        ("PUT", "/admin/playlists/archive".to_string()),
+        ("DELETE", "/admin/playlists/archive".to_string()),
+        ("POST", "/admin/invites".to_string()),
+        ("DELETE", "/admin/invites/unknown".to_string()),
+        ("DELETE", "/admin/devices/unknown".to_string()),
+        ("POST", "/admin/reconcile".to_string()),
+        ("POST", format!("/react/{hash}")),
+        ("POST", "/report".to_string()),
+        ("POST", "/shown".to_string()),
+    ] {
+        let response = server
+            .send(
+                Request::builder()
+                    .method(method)
+                    .uri(&uri)
+                    .header(header::AUTHORIZATION, format!("Bearer {SECRET}"))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from("{}"))
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN, "{method} {uri}");
+    }
+    let image = indexed(&server, "archived.png").await;
+    assert!(!image.pinned);
+    assert!(!image.hidden);
+    let response = server.send(authenticated_get("/playlists/archive")).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn archived_galleries_thaw_on_reload() {
+    let configuration = tempfile::tempdir().unwrap();
+    let config = configuration.path().join("moments.json");
+    std::fs::write(&config, r#"{"read_only": true}"#).unwrap();
+    let server =
+        TestServer::start_with_arguments(|_| {}, &["--config", config.to_str().unwrap()]).await;
+    let response = server.upload("late.png", png(82)).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    std::fs::write(&config, "{}").unwrap();
+    let response = server
+        .send(
+            Request::post("/admin/reload")
+                .header(header::AUTHORIZATION, format!("Bearer {SECRET}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = server.upload("late.png", png(82)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn hidden_images_are_kept_from_the_kiosks() {
+    let server = TestServer::start_with(|storage| {
+        std::fs::write(storage.join("blinked.png"), png(31)).unwrap();
+        std::fs::write(storage.join("smiling.png"), png(32)).unwrap();
+    })
+    .await;
+    for name in ["blinked.png", "smiling.png"] {
+        let response = server
+            .send(authenticated_get(&format!("/images/{name}")))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let images = server.moments.indexer().index(None).await.unwrap();
+    let blinked = images
+        .iter()
+        .find(|image| image.path == Path::new("blinked.png"))
+        .unwrap();
+    let hash = serde_json::to_value(blinked).unwrap()["hash"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let hide = |method: &str| {
+        Request::builder()
+            .method(method)
+            .uri(format!("/admin/hide/{hash}"))
+            .header(header::AUTHORIZATION, format!("Bearer {SECRET}"))
+            .body(Body::empty())
+            .unwrap()
+    };
+    let paths = |response: Response| async {
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let images: Vec<Value> = serde_json::from_slice(&body).unwrap();
+        let mut paths: Vec<_> = images
+            .iter()
+            .map(|image| image["path"].as_str().unwrap().to_string())
+            .collect();
+        paths.sort();
+        paths
+    };
+
+    let mut subscription = server
+        .moments
+        .indexer()
+        .subscribe(Some(0), None)
+        .await
+        .unwrap();
+    let response = server.send(hide("POST")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let change = subscription.changes.recv().await.unwrap();
+    let change = serde_json::to_value(&change.change).unwrap();
+    assert_eq!(change["Removal"]["image"]["path"], "blinked.png");
+
+    let images = server.moments.indexer().index(None).await.unwrap();
+    assert_eq!(images.len(), 1);
+    let response = server.send(authenticated_get("/recommend")).await;
+    assert_eq!(paths(response).await, ["smiling.png"]);
+    for _ in 0..10 {
+        let response = server.send(authenticated_get("/random")).await;
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let image: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(image["path"], "smiling.png");
+    }
+    let response = server.send(authenticated_get("/admin/images")).await;
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let listed: Vec<Value> = serde_json::from_slice(&body).unwrap();
+    assert_eq!(listed.len(), 2);
+    assert!(listed
+        .iter()
+        .any(|image| image["path"] == "blinked.png" && image["hidden"] == true));
+    // still known, so it cannot sneak back in as an upload
+    let response = server.upload("again.png", png(31)).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let sources =
+        std::fs::read_to_string(server.directory.path().join("cache/.moments/sources.json"))
+            .unwrap();
+    assert_eq!(sources.matches("\"hidden\":true").count(), 1);
+
+    let response = server.send(hide("DELETE")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let change = subscription.changes.recv().await.unwrap();
+    let change = serde_json::to_value(&change.change).unwrap();
+    assert_eq!(change["Addition"]["image"]["path"], "blinked.png");
+    let response = server.send(authenticated_get("/recommend")).await;
+    assert_eq!(paths(response).await, ["blinked.png", "smiling.png"]);
+}
+
+#[tokio::test]
+async fn identical_files_are_indexed_as_aliases() {
+    let server = TestServer::start_with_arguments(
+        |storage| {
+            for name in ["forwarded.png", "original.png", "another.png"] {
+                std::fs::write(storage.join(name), png(56)).unwrap();
+            }
+        },
+        // paths are removed by the test instead of the watcher
+        &["--watch-mode", "off"],
+    )
+    .await;
+    let response = server.send(authenticated_get("/admin/images")).await;
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let images: Vec<Value> = serde_json::from_slice(&body).unwrap();
+    assert_eq!(images.len(), 1);
+    assert_eq!(images[0]["path"], "another.png");
+    assert_eq!(
+        images[0]["aliases"],
+        serde_json::json!(["forwarded.png", "original.png"])
+    );
+
+    let indexer = server.moments.indexer();
+    let mut subscription = indexer.subscribe(Some(0), None).await.unwrap();
+    let removed = indexer.remove_path("original.png".into()).await.unwrap();
+    assert_eq!(removed, None);
+    let change = subscription.changes.recv().await.unwrap();
+    let change = serde_json::to_value(&change.change).unwrap();
+    assert_eq!(change["Update"]["image"]["path"], "another.png");
+    assert_eq!(
+        change["Update"]["image"]["aliases"],
+        serde_json::json!(["forwarded.png"])
+    );
+
+    // the remaining alias takes the place of the canonical path
+    let removed = indexer.remove_path("another.png".into()).await.unwrap();
+    assert_eq!(removed.unwrap().path, Path::new("another.png"));
+    let images = indexer.index(None).await.unwrap();
+    assert_eq!(images.len(), 1);
+    assert_eq!(images[0].path, Path::new("forwarded.png"));
+    assert!(images[0].aliases.is_empty());
+    let response = server
+        .send(authenticated_get("/images/forwarded.png"))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn deleting_the_canonical_path_promotes_an_alias() {
+    let server = TestServer::start_with_arguments(
+        |storage| {
+            for name in ["canonical.png", "forwarded.png"] {
+                std::fs::write(storage.join(name), png(57)).unwrap();
+            }
+        },
+        &["--watch-mode", "inotify"],
+    )
+    .await;
+    let mut subscription = server
+        .moments
+        .indexer()
+        .subscribe(Some(0), None)
+        .await
+        .unwrap();
+    std::fs::remove_file(server.directory.path().join("storage/canonical.png")).unwrap();
+
+    // an update in place, kiosks neither lose the image nor show it again as new
+    let change = subscription.changes.recv().await.unwrap();
+    let change = serde_json::to_value(&change.change).unwrap();
+    assert_eq!(change["Update"]["image"]["path"], "forwarded.png");
+    assert!(change["Update"]["image"].get("aliases").is_none());
+    assert_eq!(change["Update"]["image"]["cached_path"], "forwarded.png");
+    let response = server
+        .send(authenticated_get("/images/forwarded.png"))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn hidden_images_stay_hidden_when_an_alias_becomes_canonical() {
+    let server = TestServer::start_with_arguments(
+        |storage| {
+            for name in ["canonical.png", "forwarded.png", "resent.png"] {
+                std::fs::write(storage.join(name), png(59)).unwrap();
+            }
+        },
+        &["--watch-mode", "inotify"],
+    )
+    .await;
+    let response = server
+        .send(authenticated_get("/images/canonical.png"))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let images = server.moments.indexer().index(None).await.unwrap();
+    let hash = serde_json::to_value(&images[0]).unwrap()["hash"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let response = server
+        .send(
+            Request::post(format!("/admin/hide/{hash}"))
+                .header(header::AUTHORIZATION, format!("Bearer {SECRET}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let canonical_path = || async {
+        let images = server.moments.indexer().index_including_hidden().await;
+        images.unwrap()[0].path.clone()
+    };
+
+    // while running
+    std::fs::remove_file(server.directory.path().join("storage/canonical.png")).unwrap();
+    for _ in 0..100 {
+        if canonical_path().await == Path::new("forwarded.png") {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(canonical_path().await, Path::new("forwarded.png"));
+    assert!(server
+        .moments
+        .indexer()
+        .index(None)
+        .await
+        .unwrap()
+        .is_empty());
+
+    // and while stopped
+    std::fs::remove_file(server.directory.path().join("storage/forwarded.png")).unwrap();
+    let server = server.restart().await;
+    let images = server
+        .moments
+        .indexer()
+        .index_including_hidden()
+        .await
+        .unwrap();
+    assert_eq!(images.len(), 1);
+    assert_eq!(images[0].path, Path::new("resent.png"));
+    assert!(images[0].hidden);
+    assert!(server
+        .moments
+        .indexer()
+        .index(None)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn identical_files_added_while_running_are_indexed_as_aliases() {
+    let server = TestServer::start_with_arguments(
+        |storage| std::fs::write(storage.join("original.png"), png(58)).unwrap(),
+        &["--watch-mode", "inotify", "--settle-time", "50"],
+    )
+    .await;
+    let mut subscription = server
+        .moments
+        .indexer()
+        .subscribe(Some(0), None)
+        .await
+        .unwrap();
+    let storage = server.directory.path().join("storage");
+    std::fs::write(storage.join("photo.png"), png(58)).unwrap();
+    let change = subscription.changes.recv().await.unwrap();
+    let change = serde_json::to_value(&change.change).unwrap();
+    assert_eq!(change["Update"]["image"]["path"], "original.png");
+    assert_eq!(
+        change["Update"]["image"]["aliases"],
+        serde_json::json!(["photo.png"])
+    );
+
+    // a smaller path becomes canonical
+    std::fs::write(storage.join("again.png"), png(58)).unwrap();
+    let change = subscription.changes.recv().await.unwrap();
+    let change = serde_json::to_value(&change.change).unwrap();
+    assert_eq!(change["Update"]["image"]["path"], "again.png");
+    assert_eq!(change["Update"]["image"]["cached_path"], "again.png");
+    assert_eq!(
+        change["Update"]["image"]["aliases"],
+        serde_json::json!(["original.png", "photo.png"])
+    );
+    let images = server.moments.indexer().index(None).await.unwrap();
+    assert_eq!(images.len(), 1);
+    let response = server.send(authenticated_get("/images/again.png")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn files_still_being_written_are_indexed_once_they_settled() {
+    let server = TestServer::start_with_arguments(
+        |_| {},
+        &["--watch-mode", "inotify", "--settle-time", "400"],
+    )
+    .await;
+    let mut subscription = server
+        .moments
+        .indexer()
+        .subscribe(Some(0), None)
+        .await
+        .unwrap();
+    let contents = png(79);
+    let mut file =
+        std::fs::File::create(server.directory.path().join("storage/copied.png")).unwrap();
+    // a slow copy pausing for less than the settle time between parts
+    for part in contents.chunks(contents.len().div_ceil(5)) {
+        std::io::Write::write_all(&mut file, part).unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(150)).await;
+        assert!(subscription.changes.try_recv().is_err());
+    }
+    drop(file);
+    assert!(server
+        .moments
+        .indexer()
+        .index(None)
+        .await
+        .unwrap()
+        .is_empty());
+
+    let change = subscription.changes.recv().await.unwrap();
+    let change = serde_json::to_value(&change.change).unwrap();
+    assert_eq!(change["Addition"]["image"]["path"], "copied.png");
+    let response = server.send(authenticated_get("/images/copied.png")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn images_reported_by_enough_guests_are_hidden() {
+    let server = TestServer::start_with_arguments(
+        |storage| std::fs::write(storage.join("awkward.png"), png(33)).unwrap(),
+        &["--reports-to-hide", "3"],
+    )
+    .await;
+    let response = server.send(authenticated_get("/images/awkward.png")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let images = server.moments.indexer().index(None).await.unwrap();
+    let hash = serde_json::to_value(&images[0]).unwrap()["hash"].clone();
+    let report = |client: &str, hash: &Value, reason: Option<&str>| {
+        let router = build_router(&server.moments)
+            .layer(MockConnectInfo(client.parse::<SocketAddr>().unwrap()));
+        let request = Request::post("/report")
+            .header(header::AUTHORIZATION, format!("Bearer {SECRET}"))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::json!({ "hash": hash, "reason": reason }).to_string(),
+            ))
+            .unwrap();
+        async move { router.oneshot(request).await.unwrap().status() }
+    };
+    let reports = || async {
+        let response = server.send(authenticated_get("/admin/images")).await;
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let images: Vec<Value> = serde_json::from_slice(&body).unwrap();
+        images[0]["reports"].clone()
+    };
+
+    for (client, reason) in [
+        ("10.0.0.1:1000", Some("eyes closed")),
+        ("10.0.0.1:2000", Some("again")),
+        ("[2001:db8::1]:1000", None),
+        ("[2001:db8::2]:1000", Some("same network")),
+    ] {
+        assert_eq!(report(client, &hash, reason).await, StatusCode::NO_CONTENT);
+    }
+    let summary = reports().await;
+    assert_eq!(summary["count"], 2);
+    assert_eq!(summary["reasons"], serde_json::json!(["eyes closed"]));
+    assert_eq!(server.moments.indexer().index(None).await.unwrap().len(), 1);
+
+    assert_eq!(
+        report("10.0.0.2:1000", &hash, None).await,
+        StatusCode::NO_CONTENT
+    );
+    assert_eq!(reports().await["count"], 3);
+    assert!(server
+        .moments
+        .indexer()
+        .index(None)
+        .await
+        .unwrap()
+        .is_empty());
+
+    let unknown = Value::from("0123456789abcdef0123456789abcdef");
+    assert_eq!(
+        report("10.0.0.3:1000", &unknown, None).await,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn reports_on_images_not_cached_yet_are_not_found() {
+    let server = TestServer::start_with_arguments(
+        |storage| std::fs::write(storage.join("awkward.png"), png(33)).unwrap(),
+        &["--lazy-cache"],
+    )
+    .await;
+    let image = indexed(&server, "awkward.png").await;
+    let request = Request::post("/report")
+        .header(header::AUTHORIZATION, format!("Bearer {SECRET}"))
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            serde_json::json!({ "hash": serde_json::to_value(&image).unwrap()["hash"] })
+                .to_string(),
+        ))
+        .unwrap();
+    assert_eq!(server.send(request).await.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn reactions_are_counted_and_limited_per_guest() {
+    let server = TestServer::start_with_arguments(
+        |storage| std::fs::write(storage.join("cheering.png"), png(34)).unwrap(),
+        &["--reactions-per-minute", "3"],
+    )
+    .await;
+    let response = server.send(authenticated_get("/images/cheering.png")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let images = server.moments.indexer().index(None).await.unwrap();
+    let hash = serde_json::to_value(&images[0]).unwrap()["hash"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let react = |emoji: &str| {
+        Request::post(format!("/react/{hash}"))
+            .header(header::AUTHORIZATION, format!("Bearer {SECRET}"))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::json!({ "emoji": emoji }).to_string(),
+            ))
+            .unwrap()
+    };
+    let mut subscription = server
+        .moments
+        .indexer()
+        .subscribe(Some(0), None)
+        .await
+        .unwrap();
+
+    let response = server.send(react("💩")).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    for emoji in ["❤️", "🎉", "❤️"] {
+        let response = server.send(react(emoji)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    for expected in [1, 1, 2] {
+        let change = subscription.changes.recv().await.unwrap();
+        let change = serde_json::to_value(&change.change).unwrap();
+        assert_eq!(change["Update"]["image"]["reactions"]["❤️"], expected);
+    }
+    let response = server.send(react("❤️")).await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(response.headers().contains_key(header::RETRY_AFTER));
+
+    let images = server.moments.indexer().index(None).await.unwrap();
+    let reactions = &serde_json::to_value(&images[0]).unwrap()["reactions"];
+    assert_eq!(reactions, &serde_json::json!({ "❤️": 2, "🎉": 1 }));
+    server.moments.save().await.unwrap();
+    let sources =
+        std::fs::read_to_string(server.directory.path().join("cache/.moments/sources.json"))
+            .unwrap();
+    assert!(sources.contains(r#""reactions":{"❤️":2,"🎉":1}"#));
+}
+
+#[tokio::test]
+async fn displays_step_through_playlists_together() {
+    let server = TestServer::start_with_arguments(
+        |storage| {
+            for (seed, name) in [(35, "first.png"), (36, "second.png"), (37, "third.png")] {
+                std::fs::write(storage.join(name), png(seed)).unwrap();
+            }
+        },
+        &["--watch-mode", "inotify"],
+    )
+    .await;
+    let images = server.moments.indexer().index(None).await.unwrap();
+    let hash = |name: &str| {
+        let image = images
+            .iter()
+            .find(|image| image.path == Path::new(name))
+            .unwrap();
+        serde_json::to_value(image).unwrap()["hash"].clone()
+    };
+    let create = |name: &str, selection: Value| {
+        Request::put(format!("/admin/playlists/{name}"))
+            .header(header::AUTHORIZATION, format!("Bearer {SECRET}"))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(selection.to_string()))
+            .unwrap()
+    };
+    let json = |response: Response| async {
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice::<Value>(&body).unwrap()
+    };
+    let next = || async {
+        let response = server.send(authenticated_get("/playlists/reel/next")).await;
+        json(response).await["image"]["path"].clone()
+    };
+
+    let selection = serde_json::json!({ "images": [hash("third.png"), hash("first.png")] });
+    json(server.send(create("reel", selection)).await).await;
+    // every call advances the shared cursor, whichever display makes it
+    for expected in ["third.png", "first.png", "third.png"] {
+        assert_eq!(next().await, expected);
+    }
+    let unknown = serde_json::json!({ "images": ["0123456789abcdef0123456789abcdef"] });
+    let response = server.send(create("reel", unknown)).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let mut orders = Vec::new();
+    for name in ["all", "again"] {
+        let selection = serde_json::json!({ "seed": 7 });
+        let playlist = json(server.send(create(name, selection)).await).await;
+        let images = playlist["images"].as_array().unwrap().clone();
+        let order: Vec<_> = images.iter().map(|image| image["path"].clone()).collect();
+        assert_eq!(order.len(), 3);
+        orders.push(order);
+    }
+    assert_eq!(orders[0], orders[1]);
+
+    let mut subscription = server
+        .moments
+        .indexer()
+        .subscribe(Some(0), None)
+        .await
+        .unwrap();
+    std::fs::remove_file(server.directory.path().join("storage/first.png")).unwrap();
+    loop {
+        let change = subscription.changes.recv().await.unwrap();
+        if serde_json::to_value(&change.change).unwrap()["Removal"].is_object() {
+            break;
+        }
+    }
+    // the cursor pointed at the deleted image and moves on to the one after it
+    for _ in 0..50 {
+        let response = server.send(authenticated_get("/playlists/reel")).await;
+        if json(response).await["images"].as_array().unwrap().len() == 1 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(next().await, "third.png");
+    assert_eq!(next().await, "third.png");
+    let playlists = server
+        .directory
+        .path()
+        .join("cache/.moments/playlists.json");
+    let playlists = std::fs::read_to_string(playlists).unwrap();
+    assert!(!playlists.contains(hash("first.png").as_str().unwrap()));
+}
+
+#[tokio::test]
+async fn fresh_uploads_are_recommended_first_once_per_kiosk() {
+    let server = TestServer::start_with_arguments(
+        |storage| {
+            let path = storage.join("earlier.png");
+            std::fs::write(&path, png(38)).unwrap();
+            let an_hour_ago = SystemTime::now() - std::time::Duration::from_secs(3600);
+            let file = std::fs::File::options().write(true).open(path).unwrap();
+            file.set_modified(an_hour_ago).unwrap();
+        },
+        &["--fresh-window", "1"],
+    )
+    .await;
+    let mut subscription = server
+        .moments
+        .indexer()
+        .subscribe(Some(0), None)
+        .await
+        .unwrap();
+    let response = server.upload("just now.png", png(39)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let change = subscription.changes.recv().await.unwrap();
+    let change = serde_json::to_value(&change.change).unwrap();
+    assert_eq!(change["Addition"]["image"]["fresh"], true);
+    let server = &server;
+    let recommended = |kiosk: &'static str| async move {
+        let response = server
+            .send(authenticated_get(&format!(
+                "/recommend?count=1&kiosk={kiosk}"
+            )))
+            .await;
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let images: Vec<Value> = serde_json::from_slice(&body).unwrap();
+        images[0].clone()
+    };
+
+    let first = recommended("left").await;
+    assert_eq!(first["fresh"], true);
+    let response = server
+        .send(
+            Request::post("/shown")
+                .header(header::AUTHORIZATION, format!("Bearer {SECRET}"))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    serde_json::json!({ "hashes": [first["hash"]] }).to_string(),
+                ))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    // back in the rotation for this kiosk, but still first for another one
+    assert_eq!(recommended("left").await["path"], "earlier.png");
+    assert_eq!(recommended("right").await["path"], first["path"]);
+
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    let listed = recommended("center").await;
+    assert_eq!(listed["path"], "earlier.png");
+    let images = server.moments.indexer().index(None).await.unwrap();
+    assert!(images
+        .iter()
+        .all(|image| serde_json::to_value(image).unwrap().get("fresh").is_none()));
+}
+
+#[tokio::test]
+async fn kiosks_introduce_themselves_over_the_websocket() {
+    let server = TestServer::start_with_arguments(
+        |storage| {
+            for (seed, name) in [(40, "foam.png"), (41, "sand.png")] {
+                std::fs::write(storage.join(name), png(seed)).unwrap();
+            }
+        },
+        &["--highlight-interval", "1"],
+    )
+    .await;
+    for name in ["foam.png", "sand.png"] {
+        let response = server
+            .send(authenticated_get(&format!("/images/{name}")))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let app = build_router(&server.moments);
+    tokio::spawn(async move {
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .unwrap();
+    });
+    let url = format!("ws://{address}/index?token={SECRET}&protocol=2");
+    let mut kiosks = Vec::new();
+    for id in ["foyer", "bar"] {
+        let (mut socket, _) = connect_async(&url).await.unwrap();
+        let hello = serde_json::json!({ "type": "hello", "role": "kiosk", "id": id });
+        socket.send(Message::Text(hello.to_string())).await.unwrap();
+        kiosks.push(socket);
+    }
+    let (mut viewer, _) = connect_async(&url).await.unwrap();
+
+    let mut listed = Value::Null;
+    for _ in 0..100 {
+        let response = server.send(authenticated_get("/admin/kiosks")).await;
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        listed = serde_json::from_slice(&body).unwrap();
+        if listed.as_array().unwrap().len() == 2 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    let ids: Vec<_> = listed
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|kiosk| kiosk["id"].clone())
+        .collect();
+    assert_eq!(ids, ["foyer", "bar"]);
+    let response = server.send(authenticated_get("/admin/connections")).await;
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let connections: Vec<Value> = serde_json::from_slice(&body).unwrap();
+    assert_eq!(connections.len(), 3);
+    assert_eq!(
+        connections
+            .iter()
+            .filter(|connection| connection["role"] == "kiosk")
+            .count(),
+        2
+    );
+
+    let wait = std::time::Duration::from_secs(5);
+    let mut hashes = Vec::new();
+    for socket in &mut kiosks {
+        let highlight = next_highlight(socket, wait).await.unwrap();
+        hashes.push(highlight["image"]["hash"].clone());
+    }
+    assert_ne!(hashes[0], hashes[1]);
+    // without a hello it stays a plain client
+    let wait = std::time::Duration::from_millis(1500);
+    assert!(next_highlight(&mut viewer, wait).await.is_none());
+}
+
+#[tokio::test]
+async fn uploads_require_the_code_shown_on_the_kiosks() {
+    const ADMIN_SECRET: &str = "admin-secret";
+    const KIOSK_SECRET: &str = "kiosk-secret";
+    let server = TestServer::start_with_arguments(
+        |_| {},
+        &[
+            "--admin-secret",
+            ADMIN_SECRET,
+            "--secret",
+            &format!("wall={KIOSK_SECRET}:kiosk"),
+            "--upload-code-period",
+            "60",
+        ],
+    )
+    .await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let app = build_router(&server.moments);
+    tokio::spawn(async move {
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .unwrap();
+    });
+    let upload_code = |token: &str| {
+        let url = format!("ws://{address}/index?token={token}&protocol=2");
+        async move {
+            let (mut socket, _) = connect_async(&url).await.unwrap();
+            tokio::time::timeout(std::time::Duration::from_millis(500), async {
+                loop {
+                    let Some(Ok(Message::Text(message))) = socket.next().await else {
+                        panic!("expected messages until an upload code");
+                    };
+                    let message: Value = serde_json::from_str(&message).unwrap();
+                    if message["type"] == "upload_code" {
+                        return message["code"].as_str().unwrap().to_string();
+                    }
+                }
+            })
+            .await
+            .ok()
+        }
+    };
+
+    assert!(upload_code(SECRET).await.is_none());
+    let code = upload_code(KIOSK_SECRET).await.unwrap();
+    assert_eq!(code.len(), 6);
+    assert!(upload_code(ADMIN_SECRET).await.is_some());
+    // kiosks show the code, they cannot upload without it like guests
+    let request =
+        Request::post("/upload").header(header::AUTHORIZATION, format!("Bearer {KIOSK_SECRET}"));
+    let response = server
+        .upload_with("sunset.png", png(42), None, request)
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = server
+        .upload_with("sunset.png", png(42), None, Request::post("/upload"))
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let wrong = if code == "000000" { "111111" } else { "000000" };
+    let response = server
+        .upload_with("sunset.png", png(42), Some(wrong), Request::post("/upload"))
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = server
+        .upload_with("sunset.png", png(42), Some(&code), Request::post("/upload"))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    // the same secrets do not tell the codes of another instance, which has its own key
+    let other = TestServer::start_with_arguments(
+        |_| {},
+        &["--admin-secret", ADMIN_SECRET, "--upload-code-period", "60"],
+    )
+    .await;
+    let elsewhere = other
+        .upload_with("sunset.png", png(42), Some(&code), Request::post("/upload"))
+        .await;
+    assert_eq!(elsewhere.status(), StatusCode::FORBIDDEN);
+    let cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+    assert!(cookie.contains("HttpOnly"));
+    let pass = cookie.split(';').next().unwrap().to_string();
+    // the pass replaces the code for further uploads
+    let request = Request::post("/upload").header(header::COOKIE, &pass);
+    let response = server
+        .upload_with("sunrise.png", png(43), None, request)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let forged = format!("{}0", &pass[..pass.len() - 1]);
+    let forged = if forged == pass {
+        format!("{}1", &pass[..pass.len() - 1])
+    } else {
+        forged
+    };
+    let request = Request::post("/upload").header(header::COOKIE, forged);
+    let response = server.upload_with("noon.png", png(44), None, request).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let request =
+        Request::post("/upload").header(header::AUTHORIZATION, format!("Bearer {ADMIN_SECRET}"));
+    let response = server.upload_with("noon.png", png(44), None, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn invites_allow_a_limited_number_of_uploads_without_the_secret() {
+    let server = TestServer::start().await;
+    let admin = |request: axum::http::request::Builder, body: Value| {
+        request
+            .header(header::AUTHORIZATION, format!("Bearer {SECRET}"))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+    let mint = |body: Value| async {
+        let response = server
+            .send(admin(Request::post("/admin/invites"), body))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice::<Value>(&body).unwrap()
+    };
+    let upload = |token: String, seed: u8| {
+        // the invite replaces the secret, a wrong one does not matter
+        let request = Request::post(format!("/upload/invited?invite={token}"))
+            .header(header::AUTHORIZATION, "Bearer wrong");
+        server.upload_with("relative.png", png(seed), None, request)
+    };
+
+    let invite = mint(serde_json::json!({ "uses": 2, "label": "grandma" })).await;
+    let token = invite["token"].as_str().unwrap().to_string();
+    assert_eq!(invite["uses_left"], 2);
+    assert_eq!(upload(token.clone(), 50).await.status(), StatusCode::OK);
+    // failed uploads do not use up the invite
+    assert_eq!(
+        upload(token.clone(), 50).await.status(),
+        StatusCode::CONFLICT
+    );
+    assert_eq!(upload(token.clone(), 51).await.status(), StatusCode::OK);
+    assert_eq!(
+        upload(token.clone(), 52).await.status(),
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        upload("unknown".to_string(), 52).await.status(),
+        StatusCode::FORBIDDEN
+    );
+
+    let response = server.send(authenticated_get("/admin/invites")).await;
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let listed: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(listed[0]["id"], invite["id"]);
+    assert_eq!(listed[0]["label"], "grandma");
+    assert_eq!(listed[0]["uses_left"], 0);
+    assert!(listed[0].get("token").is_none());
+    let invites = server.directory.path().join("cache/.moments/invites.json");
+    assert!(!std::fs::read_to_string(invites).unwrap().contains(&token));
+
+    let revoked = mint(serde_json::json!({})).await;
+    let id = revoked["id"].as_str().unwrap();
+    let response = server
+        .send(admin(
+            Request::delete(format!("/admin/invites/{id}")),
+            Value::Null,
+        ))
+        .await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let token = revoked["token"].as_str().unwrap().to_string();
+    assert_eq!(upload(token, 53).await.status(), StatusCode::FORBIDDEN);
+    let response = server
+        .send(admin(
+            Request::post("/admin/invites"),
+            serde_json::json!({ "uses": 0 }),
+        ))
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn uploads_with_invites_get_the_upload_timeout() {
+    let server = TestServer::start_with_arguments(|_| {}, &["--request-timeout", "1"]).await;
+    let response = server
+        .send(
+            Request::post("/admin/invites")
+                .header(header::AUTHORIZATION, format!("Bearer {SECRET}"))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from("{}"))
+                .unwrap(),
+        )
+        .await;
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let token = serde_json::from_slice::<Value>(&body).unwrap()["token"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    // a slow connection sending the image for longer than --request-timeout
+    let head = format!(
+        "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"image\"; \
+         filename=\"slow.png\"\r\nContent-Type: image/png\r\n\r\n"
+    );
+    let tail = format!("\r\n--{BOUNDARY}--\r\n");
+    let chunks = [head.into_bytes(), png(54), tail.into_bytes()];
+    let body = futures_util::stream::iter(chunks).then(|chunk| async {
+        tokio::time::sleep(std::time::Duration::from_millis(600)).await;
+        Ok::<_, io::Error>(chunk)
+    });
+    let response = server
+        .send(
+            Request::post(format!("/upload/invited?invite={token}"))
+                .header(
+                    header::CONTENT_TYPE,
+                    format!("multipart/form-data; boundary={BOUNDARY}"),
+                )
+                .body(Body::from_stream(body))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn registered_devices_upload_with_their_token_until_revoked() {
+    let server = TestServer::start().await;
+    let register = |authorization: &str| {
+        Request::post("/devices")
+            .header(header::AUTHORIZATION, authorization)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"nickname": "Anna's phone"}"#))
+            .unwrap()
+    };
+    let response = server.send(register("Bearer wrong")).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = server.send(register(&format!("Bearer {SECRET}"))).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let device: Value = serde_json::from_slice(&body).unwrap();
+    let token = device["token"].as_str().unwrap();
+    let upload = |authorization: String, seed: u8| {
+        let request = Request::post("/upload")
+            .header("x-device-token", token)
+            .header(header::AUTHORIZATION, authorization);
+        server.upload_with("phone.png", png(seed), None, request)
+    };
+
+    // the token replaces the secret
+    let response = upload("Bearer wrong".to_string(), 60).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = server.send(authenticated_get("/admin/devices")).await;
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let listed: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(listed[0]["id"], device["id"]);
+    assert_eq!(listed[0]["nickname"], "Anna's phone");
+    assert_eq!(listed[0]["uploads"], 1);
+    assert!(listed[0].get("token").is_none());
+
+    let id = device["id"].as_str().unwrap();
+    let response = server
+        .send(
+            Request::delete(format!("/admin/devices/{id}"))
+                .header(header::AUTHORIZATION, format!("Bearer {SECRET}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    // a revoked device is rejected even with the secret, everybody else keeps using it
+    let response = upload(format!("Bearer {SECRET}"), 61).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        server.upload("other.png", png(61)).await.status(),
+        StatusCode::OK
+    );
+}
+
+/// Checks that `uri` answers with `original` whole and in single ranges, also for storage that
+/// is not a local directory
+async fn assert_served_in_ranges(server: &TestServer, uri: &str, original: &[u8]) {
+    let length = original.len();
+    let ranged = |range: &str, if_range: Option<&str>| {
+        let mut request = Request::get(uri)
+            .header(header::AUTHORIZATION, format!("Bearer {SECRET}"))
+            .header(header::RANGE, range);
+        if let Some(if_range) = if_range {
+            request = request.header(header::IF_RANGE, if_range);
+        }
+        request.body(Body::empty()).unwrap()
+    };
+
+    let response = server.send(authenticated_get(uri)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::ACCEPT_RANGES], "bytes");
+    assert_eq!(
+        response.headers()[header::CONTENT_LENGTH],
+        length.to_string()
+    );
+    let last_modified = response.headers()[header::LAST_MODIFIED].clone();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, original);
+
+    for (range, start, end) in [
+        ("bytes=10-", 10, length),
+        ("bytes=-10", length - 10, length),
+        ("bytes=5-14", 5, 15),
+    ] {
+        let response = server.send(ranged(range, None)).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT, "{range}");
+        assert_eq!(
+            response.headers()[header::CONTENT_RANGE],
+            format!("bytes {start}-{}/{length}", end - 1)
+        );
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, original[start..end], "{range}");
+    }
+
+    for range in [
+        format!("bytes={}-", length + 100),
+        "bytes=0-1,5-6".to_string(),
+    ] {
+        let response = server.send(ranged(&range, None)).await;
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(
+            response.headers()[header::CONTENT_RANGE],
+            format!("bytes */{length}")
+        );
+    }
+
+    let response = server
+        .send(ranged("bytes=0-9", Some(last_modified.to_str().unwrap())))
+        .await;
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, original[..10]);
+    let response = server
+        .send(ranged("bytes=0-9", Some("Thu, 01 Jan 1970 00:00:00 GMT")))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, original);
+}
+
+#[tokio::test]
+async fn originals_are_kept_in_the_given_storage() {
+    let storage = Arc::new(MemoryStorage::default());
+    storage
+        .write(Path::new("existing.png"), png(40))
+        .await
+        .unwrap();
+    let server = TestServer::start_with_storage(storage.clone()).await;
+    let images = server.moments.indexer().index(None).await.unwrap();
+    assert_eq!(images.len(), 1);
+    assert_eq!(images[0].path, Path::new("existing.png"));
+
+    let response = server.upload("remote.png", png(41)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let images = server.moments.indexer().index(None).await.unwrap();
+    assert_eq!(images.len(), 2);
+    let uploaded = images
+        .iter()
+        .find(|image| image.path.to_str().unwrap().ends_with("_remote.png"))
+        .unwrap();
+    assert_eq!(storage.read(&uploaded.path).await.unwrap(), png(41));
+    let storage_directory = server.directory.path().join("storage");
+    assert!(!storage_directory.join(&uploaded.path).exists());
+
+    let response = server
+        .send(authenticated_get(&format!(
+            "/images/{}",
+            uploaded.cached_path.display()
+        )))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert!(image::load_from_memory(&body).is_ok());
+
+    let response = server
+        .send(authenticated_get(&format!(
+            "/originals/{}",
+            uploaded.path.display()
+        )))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+    assert!(response.headers()[header::CONTENT_DISPOSITION]
+        .to_str()
+        .unwrap()
+        .starts_with("attachment"));
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, png(41));
+    assert_served_in_ranges(
+        &server,
+        &format!("/originals/{}", uploaded.path.display()),
+        &png(41),
+    )
+    .await;
+
+    let response = server
+        .send(authenticated_get("/originals/missing.png"))
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+/// Serves `storage` in the collection `/dav/` like a WebDAV server for user `wall` with password
+/// `password`. The first `unavailable` requests are answered with 503.
+async fn serve_webdav(storage: Arc<MemoryStorage>, unavailable: usize) -> SocketAddr {
+    let unavailable = Arc::new(AtomicUsize::new(unavailable));
+    let app = Router::new().fallback(move |request: Request| {
+        let storage = storage.clone();
+        let unavailable = unavailable.clone();
+        async move {
+            if unavailable
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                    left.checked_sub(1)
+                })
+                .is_ok()
+            {
+                return StatusCode::SERVICE_UNAVAILABLE.into_response();
+            }
+            let authorization = request.headers().get(header::AUTHORIZATION);
+            // base64 of wall:password
+            if authorization.is_none_or(|value| value != "Basic d2FsbDpwYXNzd29yZA==") {
+                return StatusCode::UNAUTHORIZED.into_response();
+            }
+            let path = request.uri().path().to_string();
+            let Some(name) = path.strip_prefix("/dav/") else {
+                return StatusCode::NOT_FOUND.into_response();
+            };
+            let name = PathBuf::from(percent_decode_str(name).decode_utf8().unwrap().as_ref());
+            let method = request.method().as_str().to_string();
+            let propfind_entry = |name: &Path, fingerprint: Fingerprint| {
+                let href = utf8_percent_encode(name.to_str().unwrap(), NON_ALPHANUMERIC);
+                let modified = httpdate::fmt_http_date(fingerprint.modified.into());
+                format!(
+                    "<d:response><d:href>/dav/{href}</d:href><d:propstat><d:prop>\
+                     <d:resourcetype/><d:getcontentlength>{}</d:getcontentlength>\
+                     <d:getlastmodified>{modified}</d:getlastmodified></d:prop>\
+                     <d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response>",
+                    fingerprint.size
+                )
+            };
+            let multistatus = |responses: String| {
+                let xml = format!(
+                    "<?xml version=\"1.0\"?><d:multistatus xmlns:d=\"DAV:\">{responses}\
+                     </d:multistatus>"
+                );
+                (StatusCode::MULTI_STATUS, xml).into_response()
+            };
+            match (method.as_str(), name.as_os_str().is_empty()) {
+                ("PROPFIND", true) => {
+                    let mut responses = "<d:response><d:href>/dav/</d:href><d:propstat>\
+                        <d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop>\
+                        <d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response>"
+                        .to_string();
+                    for (name, fingerprint) in storage.list().await.unwrap() {
+                        responses.push_str(&propfind_entry(&name, fingerprint));
+                    }
+                    multistatus(responses)
+                }
+                ("PROPFIND", false) => match storage.stat(&name).await {
+                    Ok(fingerprint) => multistatus(propfind_entry(&name, fingerprint)),
+                    Err(_) => StatusCode::NOT_FOUND.into_response(),
+                },
+                ("GET", false) => match storage.read(&name).await {
+                    // streamed in two chunks with chunked transfer encoding
+                    Ok(contents) => {
+                        let (first, second) = contents.split_at(contents.len() / 2);
+                        let parts = [first.to_vec(), second.to_vec()].map(Ok::<_, io::Error>);
+                        Body::from_stream(futures_util::stream::iter(parts)).into_response()
+                    }
+                    Err(_) => StatusCode::NOT_FOUND.into_response(),
+                },
+                ("PUT", false) => {
+                    let contents = to_bytes(request.into_body(), usize::MAX).await.unwrap();
+                    storage.write(&name, contents.to_vec()).await.unwrap();
+                    StatusCode::CREATED.into_response()
+                }
+                ("DELETE", false) => match storage.delete(&name).await {
+                    Ok(()) => StatusCode::NO_CONTENT.into_response(),
+                    Err(_) => StatusCode::NOT_FOUND.into_response(),
+                },
+                _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+            }
+        }
+    });
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    address
+}
+
+#[tokio::test]
+async fn originals_are_kept_on_a_webdav_share() {
+    let storage = Arc::new(MemoryStorage::default());
+    storage
+        .write(Path::new("party&photo.png"), png(62))
+        .await
+        .unwrap();
+    // retried after the first answer
+    let address = serve_webdav(storage.clone(), 1).await;
+    let url = format!("http://{address}/dav");
+    let server = TestServer::start_with_arguments(
+        |_| {},
+        &[
+            "--webdav-url",
+            &url,
+            "--webdav-user",
+            "wall",
+            "--webdav-password",
+            "password",
+            "--poll-interval",
+            "1",
+            "--settle-time",
+            "50",
+        ],
+    )
+    .await;
+    let images = server.moments.indexer().index(None).await.unwrap();
+    assert_eq!(images.len(), 1);
+    assert_eq!(images[0].path, Path::new("party&photo.png"));
+    let response = server
+        .send(authenticated_get(&format!(
+            "/images/{}",
+            images[0].cached_path.display()
+        )))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    // the share answers ranges with the whole file
+    assert_served_in_ranges(&server, "/originals/party%26photo.png", &png(62)).await;
+
+    let response = server.upload("new.png", png(63)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let uploaded = storage
+        .list()
+        .await
+        .unwrap()
+        .into_iter()
+        .find(|(path, _)| path.to_str().unwrap().ends_with("_new.png"))
+        .unwrap()
+        .0;
+    assert_eq!(storage.read(&uploaded).await.unwrap(), png(63));
+    assert!(!server
+        .directory
+        .path()
+        .join("storage")
+        .join(&uploaded)
+        .exists());
+
+    // noticed by polling, there is nothing to watch
+    storage
+        .write(Path::new("added.png"), png(64))
+        .await
+        .unwrap();
+    storage.delete(Path::new("party&photo.png")).await.unwrap();
+    let mut paths = Vec::new();
+    for _ in 0..100 {
+        let images = server.moments.indexer().index(None).await.unwrap();
+        paths = images.into_iter().map(|image| image.path).collect();
+        paths.sort();
+        if paths.len() == 2 && paths[1] == Path::new("added.png") {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert_eq!(paths, [uploaded, PathBuf::from("added.png")]);
+}
+
+/// Serves `storage` as the bucket `photos` like an S3 server, listing one object per page.
+/// Signatures are not checked, only that requests are signed with the access key `minio`.
+#[cfg(feature = "s3")]
+async fn serve_s3(storage: Arc<MemoryStorage>) -> SocketAddr {
+    use time::format_description::well_known::Rfc3339;
+
+    let app = Router::new().fallback(move |request: Request| {
+        let storage = storage.clone();
+        async move {
+            let signed = request
+                .headers()
+                .get(header::AUTHORIZATION)
+                .is_some_and(|value| {
+                    value
+                        .to_str()
+                        .unwrap()
+                        .starts_with("AWS4-HMAC-SHA256 Credential=minio/")
+                })
+                && request.headers().contains_key("x-amz-date");
+            if !signed {
+                return StatusCode::FORBIDDEN.into_response();
+            }
+            let method = request.method().as_str().to_string();
+            let path = request.uri().path().to_string();
+            if path == "/photos" && method == "GET" {
+                let query = request.uri().query().unwrap_or_default();
+                assert!(query.contains("list-type=2"), "{query}");
+                let start: usize = query
+                    .split('&')
+                    .find_map(|pair| pair.strip_prefix("continuation-token="))
+                    .map_or(0, |token| token.parse().unwrap());
+                let files = storage.list().await.unwrap();
+                let contents: String = files
+                    .get(start)
+                    .map(|(name, fingerprint)| {
+                        format!(
+                            "<Contents><Key>{}</Key><LastModified>{}</LastModified>\
+                             <Size>{}</Size></Contents>",
+                            name.display().to_string().replace('&', "&amp;"),
+                            fingerprint.modified.format(&Rfc3339).unwrap(),
+                            fingerprint.size
+                        )
+                    })
+                    .unwrap_or_default();
+                let truncated = start + 1 < files.len();
+                let xml = format!(
+                    "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+                     <ListBucketResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
+                     <Name>photos</Name>{contents}<IsTruncated>{truncated}</IsTruncated>\
+                     <NextContinuationToken>{}</NextContinuationToken></ListBucketResult>",
+                    start + 1
+                );
+                return xml.into_response();
+            }
+            let Some(key) = path.strip_prefix("/photos/") else {
+                return StatusCode::NOT_FOUND.into_response();
+            };
+            let key = PathBuf::from(percent_decode_str(key).decode_utf8().unwrap().as_ref());
+            match method.as_str() {
+                "HEAD" => match storage.stat(&key).await {
+                    Ok(fingerprint) => {
+                        let modified = httpdate::fmt_http_date(fingerprint.modified.into());
+                        Response::builder()
+                            .header(header::CONTENT_LENGTH, fingerprint.size)
+                            .header(header::LAST_MODIFIED, modified)
+                            .body(Body::empty())
+                            .unwrap()
+                    }
+                    Err(_) => StatusCode::NOT_FOUND.into_response(),
+                },
+                "GET" => match storage.read(&key).await {
+                    // only the `bytes=<first>-<last>` ranges moments asks for
+                    Ok(contents) => match request
+                        .headers()
+                        .get(header::RANGE)
+                        .and_then(|range| range.to_str().unwrap().strip_prefix("bytes="))
+                        .and_then(|range| range.split_once('-'))
+                    {
+                        Some((first, last)) => {
+                            let (first, last): (usize, usize) =
+                                (first.parse().unwrap(), last.parse().unwrap());
+                            let range = format!("bytes {first}-{last}/{}", contents.len());
+                            (
+                                StatusCode::PARTIAL_CONTENT,
+                                [(header::CONTENT_RANGE, range)],
+                                contents[first..=last].to_vec(),
+                            )
+                                .into_response()
+                        }
+                        None => contents.into_response(),
+                    },
+                    Err(_) => StatusCode::NOT_FOUND.into_response(),
+                },
+                "PUT" => {
+                    let contents = to_bytes(request.into_body(), usize::MAX).await.unwrap();
+                    storage.write(&key, contents.to_vec()).await.unwrap();
+                    StatusCode::OK.into_response()
+                }
+                "DELETE" => {
+                    let _ = storage.delete(&key).await;
+                    StatusCode::NO_CONTENT.into_response()
+                }
+                _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+            }
+        }
+    });
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    address
+}
+
+#[cfg(feature = "s3")]
+#[tokio::test]
+async fn originals_are_kept_in_an_s3_bucket() {
+    let storage = Arc::new(MemoryStorage::default());
+    for (name, seed) in [("first.png", 65), ("second&third.png", 66)] {
+        storage.write(Path::new(name), png(seed)).await.unwrap();
+    }
+    let address = serve_s3(storage.clone()).await;
+    let endpoint = format!("http://{address}");
+    let server = TestServer::start_with_arguments(
+        |_| {},
+        &[
+            "--s3-endpoint",
+            &endpoint,
+            "--s3-bucket",
+            "photos",
+            "--s3-access-key",
+            "minio",
+            "--s3-secret-key",
+            "minio-secret",
+            "--poll-interval",
+            "1",
+            "--settle-time",
+            "50",
+        ],
+    )
+    .await;
+    let mut paths: Vec<_> = server
+        .moments
+        .indexer()
+        .index(None)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|image| image.path)
+        .collect();
+    paths.sort();
+    assert_eq!(paths, ["first.png", "second&third.png"].map(PathBuf::from));
+    assert_served_in_ranges(&server, "/originals/second%26third.png", &png(66)).await;
+
+    let response = server.upload("new.png", png(67)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let uploaded = storage
+        .list()
+        .await
+        .unwrap()
+        .into_iter()
+        .find(|(path, _)| path.to_str().unwrap().ends_with("_new.png"))
+        .unwrap()
+        .0;
+    assert_eq!(storage.read(&uploaded).await.unwrap(), png(67));
+
+    // noticed by polling, there is nothing to watch
+    storage
+        .write(Path::new("added.png"), png(68))
+        .await
+        .unwrap();
+    let mut added = false;
+    for _ in 0..100 {
+        let images = server.moments.indexer().index(None).await.unwrap();
+        if images
+            .iter()
+            .any(|image| image.path == Path::new("added.png"))
+        {
+            added = true;
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert!(added, "added.png was not indexed");
+}
+
+/// Names and contents of the entries of a ZIP archive of stored entries, from the local headers
+fn zip_entries(archive: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let u16_at = |offset: usize| u16::from_le_bytes([archive[offset], archive[offset + 1]]);
+    let u32_at =
+        |offset: usize| u32::from_le_bytes(archive[offset..offset + 4].try_into().unwrap());
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while u32_at(offset) == 0x0403_4b50 {
+        assert_eq!(u16_at(offset + 8), 0, "entries are stored");
+        let size = u32_at(offset + 18) as usize;
+        let name_length = u16_at(offset + 26) as usize;
+        let extra_length = u16_at(offset + 28) as usize;
+        let name_start = offset + 30;
+        let data_start = name_start + name_length + extra_length;
+        let name = String::from_utf8(archive[name_start..name_start + name_length].to_vec());
+        let contents = archive[data_start..data_start + size].to_vec();
+        assert_eq!(crc32fast::hash(&contents), u32_at(offset + 14));
+        entries.push((name.unwrap(), contents));
+        offset = data_start + size;
+    }
+    let end = archive.len() - 22;
+    assert_eq!(u32_at(end), 0x0605_4b50);
+    assert_eq!(u16_at(end + 10) as usize, entries.len());
+    entries
+}
+
+#[tokio::test]
+async fn uploads_failing_to_be_stored_are_not_indexed() {
+    let storage = Arc::new(MemoryStorage::default());
+    storage.unwritable.store(true, Ordering::Relaxed);
+    let server = TestServer::start_with_storage(storage.clone()).await;
+    let mut changes = server
+        .moments
+        .indexer()
+        .subscribe(Some(0), None)
+        .await
+        .unwrap()
+        .changes;
+
+    let response = server.upload("lost.png", png(42)).await;
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(server
+        .moments
+        .indexer()
+        .index(None)
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(changes.try_recv().is_err());
+
+    // nothing is left behind that would make a retry a duplicate
+    storage.unwritable.store(false, Ordering::Relaxed);
+    let response = server.upload("lost.png", png(42)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(server.moments.indexer().index(None).await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn originals_are_exported_as_a_zip_archive() {
+    let server = TestServer::start_with(|storage| {
+        std::fs::write(storage.join("first.png"), png(50)).unwrap();
+        std::fs::write(storage.join("second.png"), png(51)).unwrap();
+    })
+    .await;
+    let response = server
+        .send(
+            Request::get("/admin/export.zip")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = server.send(authenticated_get("/admin/export.zip")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "application/zip");
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let entries = zip_entries(&body);
+    let names: Vec<_> = entries.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, ["first.png", "second.png", "metadata.json"]);
+    assert_eq!(entries[0].1, png(50));
+    assert_eq!(entries[1].1, png(51));
+    let metadata: Value = serde_json::from_slice(&entries[2].1).unwrap();
+    assert_eq!(metadata.as_array().unwrap().len(), 2);
+    assert_eq!(metadata[0]["path"], "first.png");
+
+    let response = server
+        .send(authenticated_get(
+            "/admin/export.zip?from=2999-01-01T00:00:00Z",
+        ))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let entries = zip_entries(&body);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].1, b"[]");
+}
+
+#[tokio::test]
+async fn directories_are_imported_like_uploads() {
+    let server = TestServer::start_with(|storage| {
+        std::fs::write(storage.join("existing.png"), png(60)).unwrap();
+    })
+    .await;
+    let import = tempfile::tempdir().unwrap();
+    std::fs::write(import.path().join("again.png"), png(60)).unwrap();
+    std::fs::write(import.path().join("new.png"), png(61)).unwrap();
+    std::fs::write(import.path().join("notes.txt"), "no image").unwrap();
+    std::fs::write(import.path().join(".DS_Store"), "hidden").unwrap();
+    std::fs::create_dir(import.path().join("nested")).unwrap();
+
+    let summary = server.moments.import(import.path()).await.unwrap();
+    assert_eq!(
+        summary,
+        ImportSummary {
+            imported: 1,
+            duplicates: 1,
+            failed: 1,
+        }
+    );
+    let images = server.moments.indexer().index(None).await.unwrap();
+    assert_eq!(images.len(), 2);
+    let imported = images
+        .iter()
+        .find(|image| image.path.to_str().unwrap().ends_with("_new.png"))
+        .unwrap();
+    let storage = server.directory.path().join("storage");
+    assert_eq!(
+        std::fs::read(storage.join(&imported.path)).unwrap(),
+        png(61)
+    );
+    let cache = server.directory.path().join("cache");
+    assert!(cache.join(&imported.cached_path).is_file());
+    assert!(imported.placeholder.is_some());
+}
+
+#[tokio::test]
+async fn the_wall_is_exported_for_static_hosting() {
+    let server = TestServer::start_with(|storage| {
+        std::fs::write(storage.join("shown.png"), png(70)).unwrap();
+        std::fs::write(storage.join("hidden.png"), png(71)).unwrap();
+    })
+    .await;
+    let images = server.moments.indexer().index(None).await.unwrap();
+    let hidden = images
+        .iter()
+        .find(|image| image.path == Path::new("hidden.png"))
+        .unwrap();
+    // only cached images can be hidden
+    let response = server
+        .send(authenticated_get(&format!(
+            "/images/{}",
+            hidden.cached_path.display()
+        )))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let hash = serde_json::to_value(hidden).unwrap()["hash"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let response = server
+        .send(
+            Request::post(format!("/admin/hide/{hash}"))
+                .header(header::AUTHORIZATION, format!("Bearer {SECRET}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let out = tempfile::tempdir().unwrap();
+    let exported = server.moments.export_static(out.path()).await.unwrap();
+    assert_eq!(exported, 1);
+    let index: Value =
+        serde_json::from_slice(&std::fs::read(out.path().join("index.json")).unwrap()).unwrap();
+    let index = index.as_array().unwrap();
+    assert_eq!(index.len(), 1);
+    assert_eq!(index[0]["path"], "shown.png");
+    let cached_path = index[0]["cached_path"].as_str().unwrap();
+    let derivative = std::fs::read(out.path().join("images").join(cached_path)).unwrap();
+    assert!(image::load_from_memory(&derivative).is_ok());
+    let page = std::fs::read_to_string(out.path().join("index.html")).unwrap();
+    assert!(page.contains(r#"<meta name="moments-static-index" content="index.json" />"#));
+    for file in ["kiosk.js", "kiosk.css", "favicon.png"] {
+        assert!(out.path().join(file).is_file());
+    }
+}
+
+#[tokio::test]
+async fn sidecars_next_to_originals_carry_the_flags() {
+    let server = TestServer::start_with_arguments(
+        |storage| {
+            std::fs::write(storage.join("tagged.png"), png(80)).unwrap();
+            std::fs::write(
+                storage.join("tagged.png.moments.json"),
+                r#"{"pinned":true}"#,
+            )
+            .unwrap();
+            std::fs::write(storage.join("plain.png"), png(81)).unwrap();
+        },
+        &["--sidecars", "--watch-mode", "poll", "--poll-interval", "1"],
+    )
+    .await;
+    let storage = server.directory.path().join("storage");
+    let images = server
+        .moments
+        .indexer()
+        .index_including_hidden()
+        .await
+        .unwrap();
+    assert_eq!(images.len(), 2, "sidecars are not indexed as images");
+    assert!(indexed(&server, "tagged.png").await.pinned);
+    assert!(!indexed(&server, "plain.png").await.pinned);
+
+    let plain = indexed(&server, "plain.png").await;
+    let response = server
+        .send(authenticated_get(&format!(
+            "/images/{}",
+            plain.cached_path.display()
+        )))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let hash = serde_json::to_value(&plain).unwrap()["hash"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let response = server
+        .send(
+            Request::post(format!("/admin/hide/{hash}"))
+                .header(header::AUTHORIZATION, format!("Bearer {SECRET}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let sidecar: Value =
+        serde_json::from_slice(&std::fs::read(storage.join("plain.png.moments.json")).unwrap())
+            .unwrap();
+    assert_eq!(sidecar["hidden"], true);
+
+    // edited by another tool, the sidecar wins
+    std::fs::write(
+        storage.join("tagged.png.moments.json"),
+        r#"{"hidden":true}"#,
+    )
+    .unwrap();
+    let mut applied = false;
+    for _ in 0..50 {
+        let tagged = indexed(&server, "tagged.png").await;
+        if tagged.hidden && !tagged.pinned {
+            applied = true;
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert!(applied, "edited sidecar was not picked up");
+}
+
+/// The indexed image at `path`, hidden or not
+async fn indexed(server: &TestServer, path: &str) -> Image {
+    server
+        .moments
+        .indexer()
+        .index_including_hidden()
+        .await
+        .unwrap()
+        .into_iter()
+        .find(|image| image.path == Path::new(path))
+        .unwrap()
+}
+
+#[tokio::test]
+async fn newest_images_are_offered_as_atom_feed() {
+    let server = TestServer::start_with_arguments(
+        |storage| {
+            for (name, seed, year) in [("old.png", 90, 2020), ("b&w.png", 91, 2021)] {
+                std::fs::write(storage.join(name), png(seed)).unwrap();
+                let modified = OffsetDateTime::now_utc().replace_year(year).unwrap();
+                std::fs::File::options()
+                    .write(true)
+                    .open(storage.join(name))
+                    .unwrap()
+                    .set_modified(modified.into())
+                    .unwrap();
+            }
+        },
+        &[
+            "--public-url",
+            "https://example.org/wall",
+            "--feed-size",
+            "2",
+        ],
+    )
+    .await;
+    let response = server.upload("party.png", png(92)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = server.send(authenticated_get("/feed.atom")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()[header::CONTENT_TYPE],
+        "application/atom+xml; charset=utf-8"
+    );
+    let last_modified = response.headers()[header::LAST_MODIFIED].clone();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let feed = String::from_utf8(body.to_vec()).unwrap();
+    assert_eq!(feed.matches("<entry>").count(), 2, "capped by --feed-size");
+    let uploaded = server.moments.indexer().index(None).await.unwrap();
+    let uploaded = uploaded
+        .iter()
+        .find(|image| image.path.to_string_lossy().ends_with("_party.png"))
+        .unwrap();
+    assert!(feed.contains(&format!(
+        "href=\"https://example.org/wall/images/{}?token={SECRET}\"",
+        uploaded.cached_path.display()
+    )));
+    assert!(feed.contains("<title>b&amp;w.png</title>"));
+    assert!(!feed.contains("old.png"));
+
+    let response = server
+        .send(
+            Request::get("/feed.atom")
+                .header(header::AUTHORIZATION, format!("Bearer {SECRET}"))
+                .header(header::IF_MODIFIED_SINCE, last_modified)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+}
+
+#[cfg(feature = "notifications")]
+#[tokio::test]
+async fn additions_are_published_to_an_mqtt_broker() {
+    let broker = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("mqtt://{}/venue/lights", broker.local_addr().unwrap());
+    let server = TestServer::start_with_arguments(
+        |_| {},
+        &["--notify-url", &url, "--notify-credentials", "lights:pulse"],
+    )
+    .await;
+    let response = server.upload("party.png", png(100)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let (mut socket, _) = tokio::time::timeout(std::time::Duration::from_secs(5), broker.accept())
+        .await
+        .unwrap()
+        .unwrap();
+    let (kind, connect) = read_mqtt_packet(&mut socket).await;
+    assert_eq!(kind, 0x10, "CONNECT");
+    assert!(connect.ends_with(b"\0\x06lights\0\x05pulse"));
+    socket.write_all(&[0x20, 2, 0, 0]).await.unwrap();
+    let (kind, publish) = read_mqtt_packet(&mut socket).await;
+    assert_eq!(kind, 0x32, "PUBLISH with QoS 1");
+    let (topic, rest) =
+        publish[2..].split_at(u16::from_be_bytes([publish[0], publish[1]]) as usize);
+    assert_eq!(topic, b"venue/lights");
+    let (packet_id, payload) = rest.split_at(2);
+    let payload: Value = serde_json::from_slice(payload).unwrap();
+    assert!(payload["path"].as_str().unwrap().ends_with("_party.png"));
+    socket
+        .write_all(&[0x40, 2, packet_id[0], packet_id[1]])
+        .await
+        .unwrap();
+}
+
+/// The first byte and the rest of the next MQTT packet
+#[cfg(feature = "notifications")]
+async fn read_mqtt_packet(socket: &mut TcpStream) -> (u8, Vec<u8>) {
+    let kind = socket.read_u8().await.unwrap();
+    let mut length = 0;
+    let mut shift = 0;
+    loop {
+        let byte = socket.read_u8().await.unwrap();
+        length |= usize::from(byte & 0x7f) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    let mut body = vec![0; length];
+    socket.read_exact(&mut body).await.unwrap();
+    (kind, body)
+}
+
+#[tokio::test]
+async fn doctor_reports_what_keeps_the_server_from_starting() {
+    let directory = tempfile::tempdir().unwrap();
+    let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = taken.local_addr().unwrap().port().to_string();
+    let arguments = |port: &str| {
+        Arguments::parse_from([
+            "moments".as_ref(),
+            "--secret".as_ref(),
+            SECRET.as_ref(),
+            "--storage".as_ref(),
+            directory.path().join("storage").as_os_str(),
+            "--cache".as_ref(),
+            directory.path().join("cache").as_os_str(),
+            "--host".as_ref(),
+            "127.0.0.1".as_ref(),
+            "--port".as_ref(),
+            port.as_ref(),
+        ])
+    };
+
+    let diagnosis = diagnose(&arguments("0"), false).await;
+    assert!(
+        diagnosis.0.iter().all(
+            |check| matches!(check.outcome, Outcome::Pass | Outcome::Skip)
+                || check.name.ends_with("space")
+        ),
+        "{diagnosis}"
+    );
+    assert!(
+        directory.path().join("cache").is_dir(),
+        "created like at startup"
+    );
+
+    let diagnosis = diagnose(&arguments(&port), false).await;
+    let failed: Vec<_> = diagnosis
+        .0
+        .iter()
+        .filter(|check| check.outcome == Outcome::Fail && !check.name.ends_with("space"))
+        .map(|check| check.name)
+        .collect();
+    assert_eq!(failed, ["port"], "{diagnosis}");
+}
+
+/// Reference symbols of Kazuhiko Arase's QR code encoder with the mask forced to the one the
+/// penalty rules of ISO/IEC 18004 select, which that encoder deviates from, `#` for dark
+/// `moments` at version 1-H, mask 0
+const QR_VERSION_1_HIGH: [&str; 21] = [
+    "#######.###...#######",
+    "#.....#...#.#.#.....#",
+    "#.###.#..#..#.#.###.#",
+    "#.###.#.#..##.#.###.#",
+    "#.###.#..###..#.###.#",
+    "#.....#..#....#.....#",
+    "#######.#.#.#.#######",
+    ".........#.##........",
+    "..#.###.##..##...#..#",
+    "..####.#..##...#..###",
+    "#..##.#..##.#.#.##.##",
+    "#...#..##...###.#....",
+    "#.#.#####..###..#..#.",
+    "........###.###.#.###",
+    "#######...#..#.######",
+    "#.....#.#.####.#....#",
+    "#.###.#.#....####..##",
+    "#.###.#...###...#..#.",
+    "#.###.#.#....######.#",
+    "#.....#..#.....##..#.",
+    "#######..#.#...#...##",
+];
+
+/// `https://moments.local/` at version 2-M, mask 2
+const QR_VERSION_2_MEDIUM: [&str; 25] = [
+    "#######....#.#..#.#######",
+    "#.....#..##.####..#.....#",
+    "#.###.#.##....###.#.###.#",
+    "#.###.#.####.##...#.###.#",
+    "#.###.#.#...#...#.#.###.#",
+    "#.....#.##..##.#..#.....#",
+    "#######.#.#.#.#.#.#######",
+    "........#..#..#.#........",
+    "#.#####..##.###...#####..",
+    "######.#..##.......#...#.",
+    ".####.##..###..###.#.#.##",
+    "...#.#.######.#..#.##...#",
+    "#.#...##.....######.#.###",
+    "#.#.#...#.#.##..#..#.#.#.",
+    "#...#.###.#...##.#.###.##",
+    "#.#..#..#...#..######...#",
+    "#.#.###.###.##.######.#..",
+    "........###..#..#...##...",
+    "#######....####.#.#.#.###",
+    "#.....#.#..#..#.#...##...",
+    "#.###.#.##.#...######.###",
+    "#.###.#.#.#.##.####.#####",
+    "#.###.#.##...##.#....##.#",
+    "#.....#..##.##.#######..#",
+    "#######.#.#.#.#..########",
+];
+
+/// [`QR_VERSION_7_DATA`] at version 7-Q in blocks of two lengths, mask 7
+const QR_VERSION_7_QUARTILE: [&str; 45] = [
+    "#######.##.#.#####.#.#.##.#######...#.#######",
+    "#.....#....#.##.#....#.####.######.#..#.....#",
+    "#.###.#.#...#.#.##....####....#.#..#..#.###.#",
+    "#.###.#.#.###.##.#.#...#.##........##.#.###.#",
+    "#.###.#...##..####..#####.##..##.####.#.###.#",
+    "#.....#.###..#.....##...##.##.####....#.....#",
+    "#######.#.#.#.#.#.#.#.#.#.#.#.#.#.#.#.#######",
+    "........#..###..#.#.#...##.#..###.#..........",
+    ".#.#.#####.###.#.##.######.###....##.###.##.#",
+    "##...#.##..#..###.....###..#...........#..#.#",
+    ".##.###....#.#.##.#...####.#.#.##.###..#.#..#",
+    "...#.#..####..#..####...######.#.#....####...",
+    "##.#..#..#.#.##.#.#.##.###.##..####..####..#.",
+    "#..#...#....#..#.#.#.#.##..#.#.####..#.#...##",
+    ".##.#.#..#.#.#...#.###.####.#..#.#..#####.##.",
+    ".##.....#..#.###...#..##.#.#########..#.##.#.",
+    ".##...#####.####.#.#.....#.#..#.#.##.##..#..#",
+    "####.#.###.#.#...##.#.#...#..#..##....####.#.",
+    "..###.##.#.#...##.##.#.##..##......#....#.###",
+    ".#.#...#..##....##.###.#.#......#..#.#......#",
+    "...######......#..#######...#.##.#########.#.",
+    "###.#...#..#.#.#..###...##.###.#....#...###.#",
+    "..###.#.#....#...#..#.#.###..#...####.#.#.###",
+    "###.#...###.#..#..#.#...########....#...##.#.",
+    "###.#####.#...###..###########.##.#.######..#",
+    "...#....#####.#.#..###..#..##..#####..#.##..#",
+    "#.#...#..##..###.###...#..#.##.##....#.....#.",
+    "###.##.#..####..##.##.#.###.#.#.#....##.##.##",
+    "#.#######.#.#..#.####.##.##....###..###.#..##",
+    "###.#..#.##.....#.####...##..#...#..#.#..#...",
+    "#.....##..#.##.#..##...####.#...##...##.###.#",
+    "#.#..#.##.#.....#######...#.....#.#.#.###....",
+    "####.#####.###.#..###.#..#####.#...#.#.....#.",
+    "##..#..####.#..##.#####.#.#....###.#.##..####",
+    "....#.##.#..#...#.#.....#...#...#######.#####",
+    ".####......#.##..####....##.#....#.##...##...",
+    "#..##.#.#.##..#.##..#####..###..#.#.#####..##",
+    "........##.#..#...#.#...#.#....#..#.#...#..##",
+    "#######.#.##.#.....##.#.#.##.#.##...#.#.#.##.",
+    "#.....#.###..#..##..#...######.##.#.#...#..##",
+    "#.###.#.....####...#########.#..#########....",
+    "#.###.#.#.#####.###..####.#......#..###.##..#",
+    "#.###.#..#.##.#.#.......##.#.#.#...##.###.###",
+    "#.....#.###..######....##.##....####.#.###...",
+    "#######...#.###....##.#....#####..##.####..#.",
+];
+
+const QR_VERSION_7_DATA: &str =
+    "https://moments.example.org/upload/invited?invite=7f3a9c2e5b1d4f6a8c0e2b4d6f8a0c2e4b6d";
+
+/// Format information of ISO/IEC 18004 by error correction level and mask, most significant
+/// bit first
+const QR_FORMATS: [(char, [&str; 8]); 4] = [
+    (
+        'L',
+        [
+            "111011111000100",
+            "111001011110011",
+            "111110110101010",
+            "111100010011101",
+            "110011000101111",
+            "110001100011000",
+            "110110001000001",
+            "110100101110110",
+        ],
+    ),
+    (
+        'M',
+        [
+            "101010000010010",
+            "101000100100101",
+            "101111001111100",
+            "101101101001011",
+            "100010111111001",
+            "100000011001110",
+            "100111110010111",
+            "100101010100000",
+        ],
+    ),
+    (
+        'Q',
+        [
+            "011010101011111",
+            "011000001101000",
+            "011111100110001",
+            "011101000000110",
+            "010010010110100",
+            "010000110000011",
+            "010111011011010",
+            "010101111101101",
+        ],
+    ),
+    (
+        'H',
+        [
+            "001011010001001",
+            "001001110111110",
+            "001110011100111",
+            "001100111010000",
+            "000011101100010",
+            "000001001010101",
+            "000110100001100",
+            "000100000111011",
+        ],
+    ),
+];
+
+fn qr_rows(code: &QrCode) -> Vec<String> {
+    (0..code.size())
+        .map(|y| {
+            (0..code.size())
+                .map(|x| if code.is_dark(x, y) { '#' } else { '.' })
+                .collect()
+        })
+        .collect()
+}
+
+/// Error correction level and mask of a symbol, from the format information next to the top
+/// left finder pattern
+fn qr_format(modules: &[Vec<bool>]) -> (char, usize) {
+    let positions = (0..=5)
+        .map(|y| (8, y))
+        .chain([(8, 7), (8, 8), (7, 8)])
+        .chain((9..15).map(|i| (14 - i, 8)));
+    // least significant bit first
+    let bits: String = positions
+        .map(|(x, y)| if modules[y][x] { '1' } else { '0' })
+        .collect();
+    let bits: String = bits.chars().rev().collect();
+    QR_FORMATS
+        .iter()
+        .find_map(|(level, formats)| {
+            let mask = formats.iter().position(|format| *format == bits)?;
+            Some((*level, mask))
+        })
+        .unwrap_or_else(|| panic!("unknown format information {bits}"))
+}
+
+/// Decodes the byte mode data of a symbol up to version 6 with a single error correction
+/// block, independently of the encoder and without correcting errors
+fn decode_qr(modules: &[Vec<bool>]) -> Vec<u8> {
+    let size = modules.len();
+    let version = (size - 17) / 4;
+    assert!((1..=6).contains(&version), "version {version}");
+    let (level, mask) = qr_format(modules);
+    let single_block_until = match level {
+        'L' => 5,
+        'M' => 3,
+        _ => 2,
+    };
+    assert!(
+        version <= single_block_until,
+        "{version}-{level} has several blocks"
+    );
+
+    let is_function = |x: usize, y: usize| {
+        // finder patterns with their separators and the format information next to them
+        (y < 9 && (x < 9 || x >= size - 8))
+            || (x < 9 && y >= size - 8)
+            || x == 6
+            || y == 6
+            || (version >= 2 && x.abs_diff(size - 7) <= 2 && y.abs_diff(size - 7) <= 2)
+    };
+    let masked = |x: usize, y: usize| match mask {
+        0 => (y + x).is_multiple_of(2),
+        1 => y.is_multiple_of(2),
+        2 => x.is_multiple_of(3),
+        3 => (y + x).is_multiple_of(3),
+        4 => (y / 2 + x / 3).is_multiple_of(2),
+        5 => y * x % 2 + y * x % 3 == 0,
+        6 => (y * x % 2 + y * x % 3).is_multiple_of(2),
+        _ => ((y + x) % 2 + y * x % 3).is_multiple_of(2),
+    };
+    // the zigzag of two-module columns from the bottom right, skipping the timing column
+    let mut bits = Vec::new();
+    let mut right = size - 1;
+    let mut upward = true;
+    loop {
+        if right == 6 {
+            right = 5;
+        }
+        for step in 0..size {
+            let y = if upward { size - 1 - step } else { step };
+            for x in [right, right - 1] {
+                if !is_function(x, y) {
+                    bits.push(modules[y][x] != masked(x, y));
+                }
+            }
+        }
+        upward = !upward;
+        if right < 2 {
+            break;
+        }
+        right -= 2;
+    }
+    let number = |bits: &[bool]| {
+        bits.iter()
+            .fold(0, |number, &bit| number << 1 | usize::from(bit))
+    };
+    assert_eq!(number(&bits[..4]), 0b0100, "byte mode");
+    let length = number(&bits[4..12]);
+    bits[12..12 + length * 8]
+        .chunks(8)
+        .map(|byte| number(byte) as u8)
+        .collect()
+}
+
+#[test]
+fn qr_codes_match_reference_symbols() {
+    for (data, error_correction, reference) in [
+        ("moments", ErrorCorrection::High, &QR_VERSION_1_HIGH[..]),
+        (
+            "https://moments.local/",
+            ErrorCorrection::Medium,
+            &QR_VERSION_2_MEDIUM[..],
+        ),
+        (
+            QR_VERSION_7_DATA,
+            ErrorCorrection::Quartile,
+            &QR_VERSION_7_QUARTILE[..],
+        ),
+    ] {
+        let code = QrCode::encode(data.as_bytes(), error_correction).unwrap();
+        assert_eq!(qr_rows(&code), reference, "{data}");
+    }
+    // version information of ISO/IEC 18004 for version 7, below the top right finder pattern
+    let code = QrCode::encode(QR_VERSION_7_DATA.as_bytes(), ErrorCorrection::Quartile).unwrap();
+    let version: String = (0..18)
+        .rev()
+        .map(|i| {
+            if code.is_dark(code.size() - 11 + i % 3, i / 3) {
+                '1'
+            } else {
+                '0'
+            }
+        })
+        .collect();
+    assert_eq!(version, "000111110010010100");
+}
+
+#[test]
+fn qr_codes_grow_at_the_capacity_of_each_version() {
+    // the most bytes fitting into a version, for the error correction levels and the versions
+    // where the character count grows from 8 to 16 bits
+    for (error_correction, version, capacity) in [
+        (ErrorCorrection::Low, 1, 17),
+        (ErrorCorrection::Medium, 1, 14),
+        (ErrorCorrection::Quartile, 1, 11),
+        (ErrorCorrection::High, 1, 7),
+        (ErrorCorrection::Low, 9, 230),
+        (ErrorCorrection::High, 9, 98),
+        (ErrorCorrection::Low, 10, 271),
+    ] {
+        let size = |length: usize| {
+            QrCode::encode(&vec![b'a'; length], error_correction)
+                .unwrap()
+                .size()
+        };
+        assert_eq!(
+            size(capacity),
+            version * 4 + 17,
+            "{error_correction:?} {capacity}"
+        );
+        assert_eq!(
+            size(capacity + 1),
+            version * 4 + 21,
+            "{error_correction:?} {capacity}"
+        );
+    }
+    let code = QrCode::encode(&[b'a'; 2953], ErrorCorrection::Low).unwrap();
+    assert_eq!(code.size(), 177);
+    assert!(matches!(
+        QrCode::encode(&[b'a'; 2954], ErrorCorrection::Low),
+        Err(QrError::TooLong(2954))
+    ));
+    assert!(matches!(
+        QrCode::encode(&[b'a'; 1274], ErrorCorrection::High),
+        Err(QrError::TooLong(1274))
+    ));
+}
+
+/// The modules of a QR code answered as PNG
+async fn qr_png_modules(response: Response) -> Vec<Vec<bool>> {
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let image = image::load_from_memory(&body).unwrap().into_luma8();
+
+    // the top row of the top left finder pattern is seven modules wide, after a quiet zone of
+    // four
+    let dark = |x: u32, y: u32| image.get_pixel(x, y).0[0] < 128;
+    let start = (0..image.width()).find(|&x| dark(x, x)).unwrap();
+    let scale = start / 4;
+    assert_eq!(
+        (start..image.width())
+            .take_while(|&x| dark(x, start))
+            .count() as u32,
+        7 * scale
+    );
+    let size = (image.width() / scale - 8) as usize;
+    (0..size as u32)
+        .map(|y| {
+            (0..size as u32)
+                .map(|x| dark((x + 4) * scale + scale / 2, (y + 4) * scale + scale / 2))
+                .collect()
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn qr_code_decodes_to_the_uploader_url() {
+    let server =
+        TestServer::start_with_arguments(|_| {}, &["--public-url", "https://moments.example.org/"])
+            .await;
+    let response = server
+        .send(authenticated_get("/qr.png?error_correction=low&size=300"))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let modules = qr_png_modules(response).await;
+    assert_eq!(qr_format(&modules).0, 'L');
+    assert_eq!(
+        String::from_utf8(decode_qr(&modules)).unwrap(),
+        format!("https://moments.example.org/#{SECRET}")
+    );
+    // the encoder's own symbol decodes alike
+    let code = QrCode::encode(b"https://moments.local/", ErrorCorrection::Medium).unwrap();
+    let modules: Vec<Vec<bool>> = (0..code.size())
+        .map(|y| (0..code.size()).map(|x| code.is_dark(x, y)).collect())
+        .collect();
+    assert_eq!(decode_qr(&modules), b"https://moments.local/");
+}
+
+#[tokio::test]
+async fn routes_are_served_below_the_base_path() {
+    for base_path in ["", "/wall"] {
+        let arguments: &[&str] = match base_path {
+            "" => &[],
+            base_path => &["--base-path", base_path, "--trust-forwarded-prefix"],
+        };
+        let server = TestServer::start_with_arguments(
+            |storage| std::fs::write(storage.join("based.png"), png(74)).unwrap(),
+            arguments,
+        )
+        .await;
+        let get = |path: &str| {
+            Request::get(format!("{base_path}{path}"))
+                .header(header::AUTHORIZATION, format!("Bearer {SECRET}"))
+                .header(header::HOST, "moments.local")
+                .header("x-forwarded-prefix", "/proxy")
+        };
+        for path in [
+            "/version",
+            "/images/based.png",
+            "/",
+            "/upload.js",
+            "/feed.atom",
+        ] {
+            let response = server.send(get(path).body(Body::empty()).unwrap()).await;
+            assert_eq!(response.status(), StatusCode::OK, "{base_path}{path}");
+        }
+
+        // URLs handed out include the prefix, the forwarded one only if trusted
+        let expected = match base_path {
+            "" => "http://moments.local".to_string(),
+            base_path => format!("http://moments.local/proxy{base_path}"),
+        };
+        let request = get("/qr.png?error_correction=low&size=300");
+        let response = server.send(request.body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let modules = qr_png_modules(response).await;
+        let url = String::from_utf8(decode_qr(&modules)).unwrap();
+        assert_eq!(url, format!("{expected}/#{SECRET}"));
+        let response = server
+            .send(get("/feed.atom").body(Body::empty()).unwrap())
+            .await;
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let feed = String::from_utf8(body.to_vec()).unwrap();
+        assert!(feed.contains(&format!("{expected}/images/based.png?token=")));
+
+        if base_path.is_empty() {
+            continue;
+        }
+        for path in ["/version", "/images/based.png", "/wallpaper/version"] {
+            let response = server.send(authenticated_get(path)).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{path}");
+        }
+        let response = server.send(authenticated_get("/wall?token=guessed")).await;
+        assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(response.headers()[header::LOCATION], "/wall/?token=guessed");
+    }
+}
+
+#[tokio::test]
+async fn images_are_mirrored_to_another_instance() {
+    // a free port nothing listens on yet, so the first attempts fail
+    let address = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let url = format!("http://{address}");
+    let local = TestServer::start_with_arguments(
+        |storage| std::fs::write(storage.join("already.png"), png(60)).unwrap(),
+        &["--mirror-to", &url, "--mirror-secret", SECRET],
+    )
+    .await;
+    let response = local.upload("new.png", png(61)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let mut failing = false;
+    for _ in 0..100 {
+        if !local.statistics().await["mirror"]["last_error"].is_null() {
+            failing = true;
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    assert!(failing, "mirroring to nothing did not fail");
+
+    let remote = TestServer::start_with(|storage| {
+        std::fs::write(storage.join("already.png"), png(60)).unwrap();
+    })
+    .await;
+    let listener = TcpListener::bind(address).await.unwrap();
+    let app = build_router(&remote.moments);
+    tokio::spawn(async move {
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .unwrap();
+    });
+    let mut mirror = Value::Null;
+    for _ in 0..200 {
+        mirror = local.statistics().await["mirror"].clone();
+        if mirror["pending"] == 0 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    assert_eq!(mirror["pending"], 0, "{mirror}");
+    assert_eq!(mirror["mirrored"], 2);
+    assert_eq!(mirror["failed"], 0);
+    assert!(mirror["last_error"].is_null());
+    let images = remote
+        .moments
+        .indexer()
+        .index_including_hidden()
+        .await
+        .unwrap();
+    assert_eq!(images.len(), 2);
+    // the remote prefixes the upload time of its own
+    let paths: Vec<_> = images
+        .iter()
+        .map(|image| image.path.display().to_string())
+        .collect();
+    assert!(
+        paths.iter().any(|path| path.ends_with("_new.png")),
+        "{paths:?}"
+    );
+}